@@ -57,6 +57,11 @@ impl Resolver {
         Ok(addrs)
     }
 
+    /// Drop cached answers, so the next lookups query the nameservers
+    pub fn clear_cache(&self) {
+        self.0.clear_cache();
+    }
+
     pub fn inner(&self) -> Arc<TokioAsyncResolver> {
         self.0.clone()
     }
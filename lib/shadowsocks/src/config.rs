@@ -87,6 +87,16 @@ pub struct ServerConfig {
 
     /// Weight
     weight: ServerWeight,
+
+    /// SIP003 plugin the server is reached through
+    plugin: Option<PluginConfig>,
+}
+
+/// SIP003 plugin, e.g. `obfs-local` with options `obfs=http;obfs-host=example.com`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginConfig {
+    pub plugin: String,
+    pub plugin_opts: Option<String>,
 }
 
 /// Shadowsocks URL parsing Error
@@ -99,6 +109,7 @@ pub enum UrlParseError {
     InvalidAuthInfo,
     InvalidServerAddr,
     InvalidQueryString,
+    InvalidMethod,
 }
 
 impl From<url::ParseError> for UrlParseError {
@@ -126,6 +137,7 @@ impl ServerConfig {
             id: None,
             mode: Mode::TcpAndUdp,
             weight: Default::default(),
+            plugin: None,
         }
     }
 
@@ -152,6 +164,14 @@ impl ServerConfig {
         &self.weight
     }
 
+    pub fn plugin(&self) -> Option<&PluginConfig> {
+        self.plugin.as_ref()
+    }
+
+    pub fn set_plugin(&mut self, plugin: PluginConfig) {
+        self.plugin = Some(plugin);
+    }
+
     pub fn timeout(&self) -> Option<Duration> {
         self.timeout
     }
@@ -280,9 +300,27 @@ impl ServerConfig {
             }
         };
 
-        let method = method.parse().expect("method");
+        let method = method.parse().map_err(|_| UrlParseError::InvalidMethod)?;
         let mut svrconfig = ServerConfig::new(addr, pwd, method);
 
+        // SIP002, e.g. `?plugin=obfs-local%3Bobfs%3Dhttp`
+        if let Some((_, value)) = parsed.query_pairs().find(|(key, _)| key == "plugin") {
+            let mut parts = value.splitn(2, ';');
+            let plugin = match parts.next() {
+                Some(plugin) if !plugin.is_empty() => plugin.to_string(),
+                _ => return Err(UrlParseError::InvalidQueryString),
+            };
+            let plugin_opts = parts
+                .next()
+                .filter(|opts| !opts.is_empty())
+                .map(ToString::to_string);
+
+            svrconfig.set_plugin(PluginConfig {
+                plugin,
+                plugin_opts,
+            });
+        }
+
         if let Some(frag) = parsed.fragment() {
             let frag = percent_decode_str(frag).decode_utf8_lossy().to_string();
             svrconfig.remarks = Some(frag)
@@ -1,5 +1,7 @@
 mod aes_gcm;
+mod chacha20_poly1305;
 mod xchacha20_poly1305;
 
 pub use self::aes_gcm::{Aes128Gcm, Aes256Gcm};
+pub use self::chacha20_poly1305::ChaCha20Poly1305;
 pub use self::xchacha20_poly1305::XChaCha20Poly1305;
@@ -1,4 +1,4 @@
-use super::aead::{Aes128Gcm, Aes256Gcm};
+use super::aead::{Aes128Gcm, Aes256Gcm, ChaCha20Poly1305, XChaCha20Poly1305};
 use crate::crypto::CipherKind;
 use hkdf::Hkdf;
 use sha1::Sha1;
@@ -7,6 +7,8 @@ use sha1::Sha1;
 enum CipherVariant {
     Aes128Gcm(Aes128Gcm),
     Aes256Gcm(Aes256Gcm),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
 }
 
 impl CipherVariant {
@@ -14,6 +16,12 @@ impl CipherVariant {
         match kind {
             CipherKind::AES_128_GCM => CipherVariant::Aes128Gcm(Aes128Gcm::new(key)),
             CipherKind::AES_256_GCM => CipherVariant::Aes256Gcm(Aes256Gcm::new(key)),
+            CipherKind::CHACHA20_POLY1305 => {
+                CipherVariant::ChaCha20Poly1305(ChaCha20Poly1305::new(key))
+            }
+            CipherKind::XCHACHA20_POLY1305 => {
+                CipherVariant::XChaCha20Poly1305(XChaCha20Poly1305::new(key))
+            }
 
             _ => unreachable!(),
         }
@@ -23,6 +31,9 @@ impl CipherVariant {
         match *self {
             CipherVariant::Aes128Gcm(_) => Aes128Gcm::nonce_size(),
             CipherVariant::Aes256Gcm(_) => Aes256Gcm::nonce_size(),
+            CipherVariant::ChaCha20Poly1305(_) => ChaCha20Poly1305::nonce_size(),
+            // 24 bytes, the largest nonce `Cipher` keeps
+            CipherVariant::XChaCha20Poly1305(_) => XChaCha20Poly1305::nonce_size(),
         }
     }
 
@@ -30,6 +41,8 @@ impl CipherVariant {
         match *self {
             CipherVariant::Aes128Gcm(_) => CipherKind::AES_128_GCM,
             CipherVariant::Aes256Gcm(_) => CipherKind::AES_256_GCM,
+            CipherVariant::ChaCha20Poly1305(_) => CipherKind::CHACHA20_POLY1305,
+            CipherVariant::XChaCha20Poly1305(_) => CipherKind::XCHACHA20_POLY1305,
         }
     }
 
@@ -37,6 +50,8 @@ impl CipherVariant {
         match *self {
             CipherVariant::Aes128Gcm(ref mut c) => c.encrypt(nonce, out),
             CipherVariant::Aes256Gcm(ref mut c) => c.encrypt(nonce, out),
+            CipherVariant::ChaCha20Poly1305(ref mut c) => c.encrypt(nonce, out),
+            CipherVariant::XChaCha20Poly1305(ref mut c) => c.encrypt(nonce, out),
         }
     }
 
@@ -44,6 +59,8 @@ impl CipherVariant {
         match *self {
             CipherVariant::Aes128Gcm(ref mut c) => c.decrypt(nonce, out),
             CipherVariant::Aes256Gcm(ref mut c) => c.decrypt(nonce, out),
+            CipherVariant::ChaCha20Poly1305(ref mut c) => c.decrypt(nonce, out),
+            CipherVariant::XChaCha20Poly1305(ref mut c) => c.decrypt(nonce, out),
         }
     }
 }
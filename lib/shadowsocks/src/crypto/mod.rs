@@ -1,6 +1,7 @@
 pub mod aead;
 mod cipher;
 mod kind;
+pub mod replay;
 pub mod utils;
 pub mod v2;
 
@@ -19,6 +20,8 @@ pub enum CipherCategory {
 pub enum CipherKind {
     AES_128_GCM,
     AES_256_GCM,
+    CHACHA20_POLY1305,
+    XCHACHA20_POLY1305,
 
     AEAD2022_BLAKE3_AES_128_GCM,
     AEAD2022_BLAKE3_AES_256_GCM,
@@ -28,7 +31,13 @@ pub enum CipherKind {
 
 impl CipherKind {
     pub fn is_aead(&self) -> bool {
-        matches!(*self, CipherKind::AES_128_GCM | CipherKind::AES_256_GCM)
+        matches!(
+            *self,
+            CipherKind::AES_128_GCM
+                | CipherKind::AES_256_GCM
+                | CipherKind::CHACHA20_POLY1305
+                | CipherKind::XCHACHA20_POLY1305
+        )
     }
 
     pub fn is_aead2022(&self) -> bool {
@@ -43,7 +52,10 @@ impl CipherKind {
 
     pub fn category(&self) -> CipherCategory {
         match *self {
-            CipherKind::AES_128_GCM | CipherKind::AES_256_GCM => CipherCategory::Aead,
+            CipherKind::AES_128_GCM
+            | CipherKind::AES_256_GCM
+            | CipherKind::CHACHA20_POLY1305
+            | CipherKind::XCHACHA20_POLY1305 => CipherCategory::Aead,
             CipherKind::AEAD2022_BLAKE3_AES_128_GCM
             | CipherKind::AEAD2022_BLAKE3_AES_256_GCM
             | CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305
@@ -57,6 +69,8 @@ impl CipherKind {
             // AEAD
             CipherKind::AES_128_GCM => 128 / 8,
             CipherKind::AES_256_GCM => 256 / 8,
+            CipherKind::CHACHA20_POLY1305 => 256 / 8,
+            CipherKind::XCHACHA20_POLY1305 => 256 / 8,
 
             // AEAD2022
             CipherKind::AEAD2022_BLAKE3_AES_128_GCM => todo!(),
@@ -72,6 +86,8 @@ impl CipherKind {
             // AEAD
             CipherKind::AES_128_GCM => 16,
             CipherKind::AES_256_GCM => 16,
+            CipherKind::CHACHA20_POLY1305 => 16,
+            CipherKind::XCHACHA20_POLY1305 => 16,
 
             // AEAD 2022
             CipherKind::AEAD2022_BLAKE3_AES_128_GCM => todo!(),
@@ -105,6 +121,8 @@ impl Display for CipherKind {
         let s = match self {
             CipherKind::AES_128_GCM => "aes-128-gcm",
             CipherKind::AES_256_GCM => "aes-256-gcm",
+            CipherKind::CHACHA20_POLY1305 => "chacha20-ietf-poly1305",
+            CipherKind::XCHACHA20_POLY1305 => "xchacha20-ietf-poly1305",
             CipherKind::AEAD2022_BLAKE3_AES_128_GCM => "2022-blake3-aes-128-gcm",
             CipherKind::AEAD2022_BLAKE3_AES_256_GCM => "2022-blake3-aes-256-gcm",
             CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305 => "2022-blake3-chacha20-poly1305",
@@ -125,6 +143,8 @@ impl FromStr for CipherKind {
         match s {
             "aes-128-gcm" => Ok(CipherKind::AES_128_GCM),
             "aes-256-gcm" => Ok(CipherKind::AES_256_GCM),
+            "chacha20-ietf-poly1305" => Ok(CipherKind::CHACHA20_POLY1305),
+            "xchacha20-ietf-poly1305" => Ok(CipherKind::XCHACHA20_POLY1305),
             "2022-blake3-aes-128-gcm" => Ok(CipherKind::AEAD2022_BLAKE3_AES_128_GCM),
             "2022-blake3-aes-256-gcm" => Ok(CipherKind::AEAD2022_BLAKE3_AES_256_GCM),
             "2022-blake3-chacha20-poly1305" => Ok(CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305),
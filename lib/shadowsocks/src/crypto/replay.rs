@@ -0,0 +1,154 @@
+//! Salts seen recently, shared by all connections, so a recorded stream
+//! replayed to us, or our own request reflected back, is refused.
+//!
+//! It's a ping-pong bloom filter, two filters used in turn. When the
+//! current one is full, or older than `MAX_AGE`, the other one is cleared
+//! and becomes the current, so a salt is remembered for one to two rounds,
+//! and memory is bounded.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Salts each filter holds before the filters are switched
+const CAPACITY: usize = 10_000;
+
+/// Bits per salt and hashes per salt, the false positive rate is about
+/// 1e-7 when a filter is full
+const BITS_PER_ITEM: usize = 32;
+const HASHES: u64 = 22;
+
+/// The current filter is switched after this even if it's not full
+const MAX_AGE: Duration = Duration::from_secs(3600);
+
+static FILTER: Mutex<Option<PingPongBloom>> = Mutex::new(None);
+
+/// Whether `salt` is seen before, it's remembered if not
+pub fn check_and_set(salt: &[u8]) -> bool {
+    let mut filter = FILTER.lock().unwrap_or_else(|err| err.into_inner());
+    filter
+        .get_or_insert_with(PingPongBloom::new)
+        .check_and_set(salt, Instant::now())
+}
+
+struct Bloom {
+    bits: Vec<u64>,
+    items: usize,
+}
+
+impl Bloom {
+    fn new() -> Self {
+        Self {
+            bits: vec![0; CAPACITY * BITS_PER_ITEM / 64],
+            items: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+        self.items = 0;
+    }
+
+    /// Bit positions of the item, by double hashing
+    fn positions(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 64) as u64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    fn contains(&self, hashes: (u64, u64)) -> bool {
+        self.positions(hashes)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, hashes: (u64, u64)) {
+        let positions = self.positions(hashes).collect::<Vec<_>>();
+        for bit in positions {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.items += 1;
+    }
+}
+
+struct PingPongBloom {
+    filters: [Bloom; 2],
+    current: usize,
+    since: Instant,
+    /// Keyed hashers, so salts colliding on purpose can't be crafted
+    keys: (RandomState, RandomState),
+}
+
+impl PingPongBloom {
+    fn new() -> Self {
+        Self {
+            filters: [Bloom::new(), Bloom::new()],
+            current: 0,
+            since: Instant::now(),
+            keys: (RandomState::new(), RandomState::new()),
+        }
+    }
+
+    // `BuildHasher::hash_one` requires Rust 1.71
+    #[allow(clippy::manual_hash_one)]
+    fn hashes(&self, salt: &[u8]) -> (u64, u64) {
+        let hash = |key: &RandomState| {
+            let mut hasher = key.build_hasher();
+            salt.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        // an odd step visits different bits on every round
+        (hash(&self.keys.0), hash(&self.keys.1) | 1)
+    }
+
+    fn check_and_set(&mut self, salt: &[u8], now: Instant) -> bool {
+        let hashes = self.hashes(salt);
+        if self.filters.iter().any(|filter| filter.contains(hashes)) {
+            return true;
+        }
+
+        if self.filters[self.current].items >= CAPACITY
+            || now.saturating_duration_since(self.since) >= MAX_AGE
+        {
+            self.current = 1 - self.current;
+            self.filters[self.current].clear();
+            self.since = now;
+        }
+        self.filters[self.current].insert(hashes);
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay() {
+        let mut filter = PingPongBloom::new();
+        let now = Instant::now();
+
+        assert!(!filter.check_and_set(b"salt-1", now));
+        assert!(filter.check_and_set(b"salt-1", now));
+        assert!(!filter.check_and_set(b"salt-2", now));
+
+        // still remembered by the other filter after switching once
+        let later = now + MAX_AGE;
+        assert!(!filter.check_and_set(b"salt-3", later));
+        assert!(filter.check_and_set(b"salt-1", later));
+
+        // forgotten after switching twice
+        let much_later = later + MAX_AGE;
+        assert!(!filter.check_and_set(b"salt-4", much_later));
+        assert!(!filter.check_and_set(b"salt-1", much_later));
+
+        // switched when full
+        let mut filter = PingPongBloom::new();
+        for i in 0..CAPACITY * 2 + 1 {
+            assert!(!filter.check_and_set(&i.to_be_bytes(), now));
+        }
+        assert!(!filter.check_and_set(&0usize.to_be_bytes(), now));
+        assert!(filter.check_and_set(&(CAPACITY * 2).to_be_bytes(), now));
+    }
+}
@@ -9,9 +9,10 @@ mod tcp;
 mod udp;
 
 pub use addr::Address;
-pub use config::{ServerConfig, UrlParseError};
+pub use config::{PluginConfig, ServerConfig, UrlParseError};
 pub use error::{Error, ProtocolError};
 pub use option::{ConnectOpts, UdpSocketControlData};
+pub use sys::net::{create_udp_socket, AddrFamily};
 pub use tcp::proxy::ProxyStream;
 pub use udp::{ProxySocket, ProxySocketError};
 
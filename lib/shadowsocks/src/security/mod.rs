@@ -1,3 +0,0 @@
-mod replay;
-
-pub use replay::ReplayProtector;
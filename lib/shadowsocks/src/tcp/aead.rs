@@ -37,15 +37,15 @@ mod aes_gcm;
 use std::io::ErrorKind;
 use std::pin::Pin;
 use std::task::Poll;
-use std::{io, slice};
+use std::{io, mem, slice};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::{ready, task};
 use tokio::io::ReadBuf;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
 
-use crate::crypto::{Cipher, CipherKind};
+use super::pool;
+use crate::crypto::{replay, Cipher, CipherKind};
 
 /// AEAD packet payload must be smaller than 0x3FFF
 pub const MAX_PACKET_SIZE: usize = 0x3FFF;
@@ -63,6 +63,8 @@ pub enum ProtocolError {
     DecryptLengthError,
     #[error("buffer size too large ({0:#x}), AEAD encryption protocol requires buffer to be smaller than 0x3FFF, the higher two bits must be set to zero")]
     DataTooLong(usize),
+    #[error("salt is seen before, the stream may be replayed")]
+    RepeatedSalt,
 }
 
 impl From<ProtocolError> for io::Error {
@@ -88,6 +90,7 @@ pub struct DecryptedReader {
     cipher: Option<Cipher>,
     buffer: BytesMut,
     salt: Option<Bytes>,
+    salt_checked: bool,
     handshaked: bool,
 }
 
@@ -99,8 +102,9 @@ impl DecryptedReader {
             },
             kind,
             cipher: None,
-            buffer: BytesMut::with_capacity(kind.salt_len()),
+            buffer: pool::take(),
             salt: None,
+            salt_checked: false,
             handshaked: false,
         }
     }
@@ -109,12 +113,15 @@ impl DecryptedReader {
         self.salt.as_deref()
     }
 
-    pub fn poll_read_decrypted(
+    pub fn poll_read_decrypted<S>(
         &mut self,
         cx: &mut task::Context<'_>,
-        stream: &mut TcpStream,
+        stream: &mut S,
         buf: &mut ReadBuf<'_>,
-    ) -> Poll<Result<(), ProtocolError>> {
+    ) -> Poll<Result<(), ProtocolError>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
         loop {
             match self.state {
                 DecryptReadState::WaitSalt { ref key } => {
@@ -162,12 +169,15 @@ impl DecryptedReader {
         }
     }
 
-    fn poll_read_salt(
+    fn poll_read_salt<S>(
         &mut self,
         cx: &mut task::Context<'_>,
-        stream: &mut TcpStream,
+        stream: &mut S,
         key: &[u8],
-    ) -> Poll<Result<(), ProtocolError>> {
+    ) -> Poll<Result<(), ProtocolError>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
         let salt_len = self.kind.salt_len();
 
         let n = ready!(self.pool_read_exact(cx, stream, salt_len))?;
@@ -187,11 +197,14 @@ impl DecryptedReader {
         Ok(()).into()
     }
 
-    fn poll_read_length(
+    fn poll_read_length<S>(
         &mut self,
         cx: &mut task::Context<'_>,
-        stream: &mut TcpStream,
-    ) -> Poll<Result<Option<usize>, ProtocolError>> {
+        stream: &mut S,
+    ) -> Poll<Result<Option<usize>, ProtocolError>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
         let length_len = 2 + self.kind.tag_len();
 
         let n = ready!(self.pool_read_exact(cx, stream, length_len))?;
@@ -206,12 +219,15 @@ impl DecryptedReader {
         Ok(Some(length)).into()
     }
 
-    fn poll_read_data(
+    fn poll_read_data<S>(
         &mut self,
         cx: &mut task::Context<'_>,
-        stream: &mut TcpStream,
+        stream: &mut S,
         size: usize,
-    ) -> Poll<Result<(), ProtocolError>> {
+    ) -> Poll<Result<(), ProtocolError>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
         let data_len = size + self.kind.tag_len();
 
         let n = ready!(self.pool_read_exact(cx, stream, data_len))?;
@@ -225,13 +241,15 @@ impl DecryptedReader {
             return Err(ProtocolError::DecryptDataError).into();
         }
 
-        // NOTE: By default AEAD ignore replay attack requests
-        //
         // Check repeated salt after first successful decryption #442
-        // if let Some(ref salt) = self.salt {
-        //     todo!()
-        //     // check nonce replay
-        // }
+        if !self.salt_checked {
+            self.salt_checked = true;
+            if let Some(ref salt) = self.salt {
+                if replay::check_and_set(salt) {
+                    return Err(ProtocolError::RepeatedSalt).into();
+                }
+            }
+        }
 
         // Remote TAG
         self.buffer.truncate(size);
@@ -239,12 +257,15 @@ impl DecryptedReader {
         Ok(()).into()
     }
 
-    fn pool_read_exact(
+    fn pool_read_exact<S>(
         &mut self,
         cx: &mut task::Context<'_>,
-        stream: &mut TcpStream,
+        stream: &mut S,
         size: usize,
-    ) -> Poll<io::Result<usize>> {
+    ) -> Poll<io::Result<usize>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
         assert!(size != 0);
 
         while self.buffer.len() < size {
@@ -298,6 +319,12 @@ impl DecryptedReader {
     }
 }
 
+impl Drop for DecryptedReader {
+    fn drop(&mut self) {
+        pool::give(mem::take(&mut self.buffer));
+    }
+}
+
 enum EncryptWriteState {
     AssemblePacket,
     Writing { pos: usize },
@@ -315,7 +342,7 @@ impl EncryptedWriter {
     /// Creates a new EncryptedWriter
     pub fn new(kind: CipherKind, key: &[u8], nonce: &[u8]) -> Self {
         // nonce should be sent with the first packet
-        let mut buffer = BytesMut::with_capacity(nonce.len());
+        let mut buffer = pool::take();
         buffer.put(nonce);
 
         Self {
@@ -393,3 +420,64 @@ impl EncryptedWriter {
         }
     }
 }
+
+impl Drop for EncryptedWriter {
+    fn drop(&mut self) {
+        pool::give(mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use futures::future::poll_fn;
+    use futures::task::noop_waker_ref;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::crypto::utils::generate_nonce;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        for kind in [
+            CipherKind::AES_128_GCM,
+            CipherKind::CHACHA20_POLY1305,
+            CipherKind::XCHACHA20_POLY1305,
+        ] {
+            let key = vec![7u8; kind.key_len()];
+            let mut salt = vec![0u8; kind.salt_len()];
+            generate_nonce(kind, &mut salt);
+
+            // writing to a `Vec` never blocks
+            let mut encrypted = Vec::new();
+            let mut writer = EncryptedWriter::new(kind, &key, &salt);
+            let mut cx = task::Context::from_waker(noop_waker_ref());
+            for chunk in [&b"hello"[..], &b"world"[..]] {
+                let written = writer.poll_write_encrypted(&mut cx, &mut encrypted, chunk);
+                assert!(matches!(written, Poll::Ready(Ok(5))));
+            }
+            assert_eq!(&encrypted[..salt.len()], &salt[..]);
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.write_all(&encrypted).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            server.set_nonblocking(true).unwrap();
+            let mut server = TcpStream::from_std(server).unwrap();
+
+            let mut reader = DecryptedReader::new(kind, &key);
+            let mut decrypted = Vec::new();
+            while decrypted.len() < 10 {
+                let mut buf = [0u8; 16];
+                let mut buf = ReadBuf::new(&mut buf);
+                poll_fn(|cx| reader.poll_read_decrypted(cx, &mut server, &mut buf))
+                    .await
+                    .unwrap();
+                decrypted.extend_from_slice(buf.filled());
+            }
+            assert_eq!(decrypted, b"helloworld");
+            assert_eq!(reader.salt(), Some(&salt[..]));
+        }
+    }
+}
@@ -47,7 +47,7 @@
 use std::io::{Cursor, ErrorKind, Read};
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use std::{io, slice, task};
+use std::{io, mem, slice, task};
 
 use aes::cipher::{BlockEncrypt, KeyInit};
 use aes::Block;
@@ -58,6 +58,7 @@ use futures::ready;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tracing::{error, trace};
 
+use super::pool;
 use crate::config::method_support_eih;
 use crate::crypto::{Cipher, CipherKind};
 use crate::sys::get_now_timestamp;
@@ -129,7 +130,7 @@ impl DecryptedReader {
                 key: Bytes::copy_from_slice(key),
             },
             cipher: None,
-            buffer: BytesMut::new(),
+            buffer: pool::take(),
             kind,
             salt: None,
             request_salt: None,
@@ -425,6 +426,12 @@ impl DecryptedReader {
     }
 }
 
+impl Drop for DecryptedReader {
+    fn drop(&mut self) {
+        pool::give(mem::take(&mut self.buffer));
+    }
+}
+
 enum EncryptWriteState {
     AssembleHeader,
     AssemblePacket,
@@ -456,7 +463,7 @@ impl EncryptedWriter {
         identity_keys: &[Bytes],
     ) -> EncryptedWriter {
         // nonce should be sent with the first packet
-        let mut buffer = BytesMut::with_capacity(nonce.len() + identity_keys.len() * 16);
+        let mut buffer = pool::take();
         buffer.put(nonce);
 
         // Extensible Identity Headers
@@ -640,3 +647,9 @@ impl EncryptedWriter {
         }
     }
 }
+
+impl Drop for EncryptedWriter {
+    fn drop(&mut self) {
+        pool::give(mem::take(&mut self.buffer));
+    }
+}
@@ -4,11 +4,11 @@ use std::{io, task};
 
 use bytes::Bytes;
 use futures::ready;
-use tokio::io::{AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 
 use crate::crypto::utils::generate_nonce;
-use crate::crypto::{CipherCategory, CipherKind};
+use crate::crypto::{replay, CipherCategory, CipherKind};
 use crate::tcp::{aead, aead2022};
 
 /// TCP shadowsocks protocol error
@@ -62,12 +62,15 @@ impl DecryptedReader {
         }
     }
 
-    pub fn poll_read_decrypted(
+    pub fn poll_read_decrypted<S>(
         &mut self,
         cx: &mut Context<'_>,
-        stream: &mut TcpStream,
+        stream: &mut S,
         buf: &mut ReadBuf<'_>,
-    ) -> Poll<Result<(), ProtocolError>> {
+    ) -> Poll<Result<(), ProtocolError>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
         match *self {
             DecryptedReader::Aead(ref mut reader) => reader
                 .poll_read_decrypted(cx, stream, buf)
@@ -140,28 +143,36 @@ impl EncryptedWriter {
     }
 }
 
-/// A bidirectional stream for read/write encrypted data in shadowsocks' tunnel
-pub struct CryptoStream {
-    stream: TcpStream,
+/// A bidirectional stream for read/write encrypted data in shadowsocks' tunnel,
+/// over a TCP stream, or a transport wrapping it
+pub struct CryptoStream<S = TcpStream> {
+    stream: S,
     dec: DecryptedReader,
     enc: EncryptedWriter,
     kind: CipherKind,
     handshaked: bool,
 }
 
-impl CryptoStream {
-    pub fn from_stream(stream: TcpStream, kind: CipherKind, key: &[u8]) -> CryptoStream {
+impl<S> CryptoStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn from_stream(stream: S, kind: CipherKind, key: &[u8]) -> CryptoStream<S> {
         static EMPTY_IDENTITY: [Bytes; 0] = [];
 
         // No matter the cipher is aead or aead2022
         let prev_len = kind.salt_len();
 
         let iv = {
-            // TODO: unique nonce!?
-            //
-            // Shadowsocks-rust do not check if the salt is unique by default.
+            // Our salts are remembered too, so a request reflected back by
+            // the server is refused
             let mut local_salt = vec![0u8; prev_len];
-            generate_nonce(kind, &mut local_salt);
+            loop {
+                generate_nonce(kind, &mut local_salt);
+                if !replay::check_and_set(&local_salt) {
+                    break;
+                }
+            }
             local_salt
         };
 
@@ -189,6 +200,12 @@ impl CryptoStream {
         self.kind
     }
 
+    /// Get the underlying stream
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
     /// Get sent IV or Salt
     #[inline]
     pub fn sent_nonce(&self) -> &[u8] {
@@ -2,5 +2,6 @@ mod aead;
 mod aead2022;
 mod cipher;
 mod crypto;
+mod pool;
 pub mod proxy;
 mod utils;
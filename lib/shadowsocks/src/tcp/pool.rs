@@ -0,0 +1,62 @@
+//! A global pool of buffers shared by the encrypted streams and the relay
+//! copy loops. Each relayed connection needs a few buffers of about 16KiB,
+//! with tens of thousands of connections coming and going, allocating them
+//! every time costs the allocator much more than reusing them.
+
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+/// Capacity of new buffers, an AEAD chunk of the largest payload fits
+pub const BUFFER_SIZE: usize = 16 * 1024 + 128;
+
+/// Larger buffers are freed, e.g. ones grown for AEAD 2022 chunks
+const MAX_POOLED_SIZE: usize = 4 * BUFFER_SIZE;
+
+/// Idle buffers kept at most, about 8MiB of them
+const MAX_POOLED: usize = 512;
+
+static POOL: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+
+/// An empty buffer with room for `BUFFER_SIZE` bytes at least
+pub fn take() -> BytesMut {
+    let pooled = POOL.lock().unwrap_or_else(|err| err.into_inner()).pop();
+
+    pooled.unwrap_or_else(|| BytesMut::with_capacity(BUFFER_SIZE))
+}
+
+/// Put `buf` back to the pool, its content is dropped
+pub fn give(mut buf: BytesMut) {
+    buf.clear();
+    if buf.capacity() < BUFFER_SIZE || buf.capacity() > MAX_POOLED_SIZE {
+        return;
+    }
+
+    let mut pool = POOL.lock().unwrap_or_else(|err| err.into_inner());
+    if pool.len() < MAX_POOLED {
+        pool.push(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let mut buf = take();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= BUFFER_SIZE);
+        buf.extend_from_slice(b"data");
+        give(buf);
+
+        // whichever one is taken, it's empty
+        let buf = take();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= BUFFER_SIZE);
+
+        // too small to be pooled
+        give(BytesMut::with_capacity(16));
+        assert!(take().capacity() >= BUFFER_SIZE);
+    }
+}
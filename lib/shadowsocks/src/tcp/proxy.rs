@@ -1,6 +1,6 @@
 use std::io::ErrorKind;
 use std::net::{IpAddr, SocketAddr};
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::{io, mem};
@@ -13,7 +13,7 @@ use resolver::Resolver;
 use socket2::{Socket, TcpKeepalive};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::{TcpSocket, TcpStream};
-use tracing::error;
+use tracing::{debug, error};
 
 use super::crypto::CryptoStream;
 use crate::crypto::CipherKind;
@@ -35,9 +35,9 @@ enum ReadState {
 }
 
 pin_project! {
-    pub struct ProxyStream {
+    pub struct ProxyStream<S = TcpStream> {
         #[pin]
-        stream: CryptoStream,
+        stream: CryptoStream<S>,
 
         read_state: ReadState,
         write_state: WriteState,
@@ -52,14 +52,44 @@ impl ProxyStream {
         resolver: &Resolver,
         opts: &ConnectOpts,
     ) -> io::Result<Self> {
-        let stream = match conf.addr() {
-            Address::SocketAddress(addr) => connect_server_with_opts(*addr, opts).await?,
+        let stream = Self::connect_server(conf, resolver, opts).await?;
+
+        Ok(Self::from_stream(stream, conf, target_addr))
+    }
+
+    /// Connects shadowsocks server only, the stream can be used by
+    /// `from_stream` later, since nothing is sent before the first write
+    pub async fn connect_server(
+        conf: &ServerConfig,
+        resolver: &Resolver,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        match conf.addr() {
+            Address::SocketAddress(addr) => connect_server_with_opts(*addr, opts).await,
             Address::DomainNameAddress(domain, port) => {
                 let addr = resolver.resolve(domain, *port).await?;
-                connect_server_with_opts(addr, opts).await?
+                connect_server_with_opts(addr, opts).await
             }
-        };
+        }
+    }
 
+    /// Like `connect_server`, but the address of the server is resolved
+    /// by the caller
+    pub async fn connect_server_addr(
+        addr: SocketAddr,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        connect_server_with_opts(addr, opts).await
+    }
+}
+
+impl<S> ProxyStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Tunnel to `target_addr` over a stream connected to the server, or a
+    /// transport wrapping it
+    pub fn from_stream(stream: S, conf: &ServerConfig, target_addr: Address) -> Self {
         let stream = CryptoStream::from_stream(stream, conf.kind(), conf.key());
         let read_state = if conf.kind().is_aead2022() {
             ReadState::CheckRequestNonce
@@ -67,14 +97,18 @@ impl ProxyStream {
             ReadState::Established
         };
 
-        Ok(Self {
+        Self {
             stream,
             read_state,
             write_state: WriteState::Connect(target_addr),
-        })
+        }
     }
 
-    pub async fn proxy(self, local: TcpStream) -> io::Result<()> {
+    /// Relay data between `local` and the proxy server until one side is closed
+    pub async fn proxy<L>(self, local: L) -> io::Result<()>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+    {
         let kind = self.stream.kind();
 
         let (mut lr, mut lw) = tokio::io::split(local);
@@ -94,6 +128,16 @@ impl ProxyStream {
     }
 }
 
+impl<S> AsRawFd for ProxyStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + AsRawFd,
+{
+    /// The socket connected to the shadowsocks server
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.get_ref().as_raw_fd()
+    }
+}
+
 async fn connect_server_with_opts(addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
     let socket = match addr {
         SocketAddr::V4(..) => TcpSocket::new_v4()?,
@@ -150,6 +194,25 @@ fn set_common_sockopt_for_connect(
         }
     }
 
+    // TFO, connect returns at once and the SYN carries the first write,
+    // kernels before 4.11 don't support it, the normal handshake is used
+    if opts.tcp.fastopen {
+        let enable: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN_CONNECT,
+                &enable as *const _ as *const _,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            debug!(message = "set TCP_FASTOPEN_CONNECT failed", ?err);
+        }
+    }
+
     // Set `SO_SNDBUF`
     if let Some(buf_size) = opts.tcp.send_buffer_size {
         socket.set_send_buffer_size(buf_size)?;
@@ -194,7 +257,10 @@ fn set_common_sockopt_after_connect(stream: &TcpStream, opts: &ConnectOpts) -> i
     Ok(())
 }
 
-impl AsyncRead for ProxyStream {
+impl<S> AsyncRead for ProxyStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -276,7 +342,10 @@ fn make_first_packet_buffer(kind: CipherKind, addr: &Address, buf: &[u8]) -> Byt
     buffer
 }
 
-impl AsyncWrite for ProxyStream {
+impl<S> AsyncWrite for ProxyStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -1,9 +1,12 @@
 use std::future::Future;
 use std::io;
+use std::mem;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+use super::pool;
 use crate::crypto::{CipherCategory, CipherKind};
+use bytes::BytesMut;
 use futures::ready;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
@@ -13,17 +16,20 @@ struct CopyBuffer {
     pos: usize,
     cap: usize,
     amt: u64,
-    buf: Box<[u8]>,
+    buf: BytesMut,
 }
 
 impl CopyBuffer {
     fn new(buffer_size: usize) -> Self {
+        let mut buf = pool::take();
+        buf.resize(buffer_size, 0);
+
         Self {
             read_done: false,
             pos: 0,
             cap: 0,
             amt: 0,
-            buf: vec![0; buffer_size].into_boxed_slice(),
+            buf,
         }
     }
 
@@ -78,6 +84,12 @@ impl CopyBuffer {
     }
 }
 
+impl Drop for CopyBuffer {
+    fn drop(&mut self) {
+        pool::give(mem::take(&mut self.buf));
+    }
+}
+
 /// A future that asynchronously copies the entire contents of a reader into a writer.
 struct Copy<'a, R: ?Sized, W: ?Sized> {
     reader: &'a mut R,
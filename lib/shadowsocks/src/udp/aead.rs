@@ -124,3 +124,45 @@ async fn parse_packet(buf: &[u8]) -> Result<(usize, Address), ProtocolError> {
         Err(err) => Err(ProtocolError::InvalidAddress(err)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let addr = Address::SocketAddress("8.8.8.8:53".parse::<SocketAddr>().unwrap());
+
+        for kind in [
+            CipherKind::AES_256_GCM,
+            CipherKind::CHACHA20_POLY1305,
+            CipherKind::XCHACHA20_POLY1305,
+        ] {
+            let key = vec![7u8; kind.key_len()];
+
+            let mut packet = BytesMut::new();
+            encrypt_payload_aead(kind, &key, &addr, b"query", &mut packet);
+            assert_eq!(
+                packet.len(),
+                kind.salt_len() + addr.serialized_len() + 5 + kind.tag_len()
+            );
+
+            let mut tampered = packet.clone();
+            tampered[kind.salt_len()] ^= 1;
+            assert!(decrypt_payload_aead(kind, &key, &mut tampered)
+                .await
+                .is_err());
+
+            let (n, decrypted) = decrypt_payload_aead(kind, &key, &mut packet).await.unwrap();
+            assert_eq!(&packet[..n], b"query");
+            assert_eq!(decrypted.to_string(), addr.to_string());
+        }
+
+        assert_eq!(
+            "chacha20-ietf-poly1305".parse::<CipherKind>().unwrap(),
+            CipherKind::CHACHA20_POLY1305
+        );
+    }
+}
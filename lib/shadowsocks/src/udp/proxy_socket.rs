@@ -71,20 +71,24 @@ impl ProxySocket {
         resolver: &Resolver,
         opts: &ConnectOpts,
     ) -> Result<Self, ProxySocketError> {
-        let socket = match sc.addr() {
-            Address::SocketAddress(remote) => {
-                let socket = create_udp_socket(remote.into(), opts).await?;
-                socket.connect(remote).await?;
-                socket
-            }
-            Address::DomainNameAddress(domain, port) => {
-                let addr = resolver.resolve(domain, *port).await?;
-                let socket = create_udp_socket(addr.into(), opts).await?;
-                socket.connect(addr).await?;
-                socket
-            }
+        let addr = match sc.addr() {
+            Address::SocketAddress(remote) => *remote,
+            Address::DomainNameAddress(domain, port) => resolver.resolve(domain, *port).await?,
         };
 
+        Self::connect_server_addr(sc, addr, opts).await
+    }
+
+    /// Like `connect_with_opts`, but the address of the server is resolved
+    /// by the caller
+    pub async fn connect_server_addr(
+        sc: &ServerConfig,
+        addr: SocketAddr,
+        opts: &ConnectOpts,
+    ) -> Result<Self, ProxySocketError> {
+        let socket = create_udp_socket(addr.into(), opts).await?;
+        socket.connect(addr).await?;
+
         Ok(Self {
             socket,
             kind: sc.kind(),
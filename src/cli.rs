@@ -0,0 +1,269 @@
+//! Command line arguments, there are only a few of them, so they are
+//! parsed by hand.
+
+use std::path::PathBuf;
+
+use roxy::{Config, Override, OverrideError};
+use serde_yaml::{Mapping, Value};
+
+pub const USAGE: &str = "\
+Usage:
+    roxy [CONFIG OPTIONS]             run with ROXY_CONFIG or ./config.{yaml,yml,toml,json}
+    roxy check [CONFIG OPTIONS]       validate the config without starting any service
+    roxy convert <FILE> [-o <OUT>]    convert a Clash or sing-box config, print to stdout by default
+    roxy init [FILE] [--force]        write a commented default config, FILE defaults to config.yaml,
+                                      `-` prints it to stdout
+    roxy ctl [OPTIONS] <COMMAND>      manage a running roxy through its controller
+    roxy help                         print this message
+
+Config options:
+    -c, --config <FILE>              config file
+    --set <PATH=VALUE>               override a config value, e.g. --set dns.listen=0.0.0.0:53,
+                                     it can be repeated, the value is parsed as YAML
+    --log-level <LEVEL>              same as --set log.level=<LEVEL>
+    --dns-listen <ADDR>              same as --set dns.listen=<ADDR>
+    --controller-listen <ADDR>       same as --set controller.listen=<ADDR>
+    --profile <NAME,...>             same as --set profile=<NAME,...>
+    --thp-listen <ADDR,...>          replace inbounds with thp inbounds on <ADDR>,..., they are
+                                     named thp-0, thp-1 and so on
+
+Ctl options:
+    -a, --addr <ADDR>        controller address, default $ROXY_CONTROLLER or 127.0.0.1:9000
+    -s, --secret <SECRET>    controller secret, default $ROXY_CONTROLLER_SECRET
+
+Ctl commands:
+    connections              list active connections
+    traffic                  show total traffic
+    talkers [WINDOW]         show the most active clients, hosts and upstreams, WINDOW is
+                             1m (default), 5m or 1h
+    select [SERVER]          always use SERVER, restore load balance if it is omitted
+    reload                   fetch upstream servers now
+    server add <URL>         add an upstream server of the ss:// URL
+    server set <NAME> <URL>  replace the upstream server NAME
+    server remove <NAME>     remove the upstream server NAME
+    profile [NAME,...]       show profiles, or switch to NAME,..., `--reset` restores the ones
+                             in the config
+    dns-query <NAME> [TYPE]  resolve NAME like the DNS server does, TYPE defaults to A
+";
+
+const DEFAULT_CONTROLLER: &str = "127.0.0.1:9000";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown argument \"{0}\"")]
+    UnknownArgument(String),
+    #[error("missing value of \"{0}\"")]
+    MissingValue(String),
+    #[error("missing command")]
+    MissingCommand,
+    #[error(transparent)]
+    Override(#[from] OverrideError),
+}
+
+pub enum CtlCommand {
+    Connections,
+    Traffic,
+    Talkers(Option<String>),
+    Select(Option<String>),
+    Reload,
+    AddServer(String),
+    ReplaceServer {
+        name: String,
+        url: String,
+    },
+    RemoveServer(String),
+    /// Show profiles, or switch to the comma separated ones
+    Profile(Option<String>),
+    ResetProfile,
+    DnsQuery {
+        name: String,
+        query_type: Option<String>,
+    },
+}
+
+pub struct CtlArgs {
+    pub addr: String,
+    pub secret: Option<String>,
+    pub command: CtlCommand,
+}
+
+/// Where the config is loaded from, and values overridden
+pub struct ConfigArgs {
+    pub path: PathBuf,
+    pub overrides: Vec<Override>,
+}
+
+pub enum Command {
+    Run(ConfigArgs),
+    Check(ConfigArgs),
+    Convert {
+        input: PathBuf,
+        output: Option<PathBuf>,
+    },
+    Init {
+        path: PathBuf,
+        force: bool,
+    },
+    Ctl(CtlArgs),
+    Help,
+}
+
+impl Command {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let first = args.next();
+        match first.as_deref() {
+            None => parse_config(args).map(Command::Run),
+            Some("help" | "-h" | "--help") => Ok(Command::Help),
+            Some(arg) if arg.starts_with('-') => {
+                parse_config(first.into_iter().chain(args)).map(Command::Run)
+            }
+            Some("check") => parse_config(args).map(Command::Check),
+            Some("convert") => parse_convert(args),
+            Some("init") => parse_init(args),
+            Some("ctl") => parse_ctl(args).map(Command::Ctl),
+            Some(other) => Err(Error::UnknownArgument(other.to_string())),
+        }
+    }
+}
+
+/// Parse `-c <FILE>` and overrides, the default path is `Config::path()`
+fn parse_config(mut args: impl Iterator<Item = String>) -> Result<ConfigArgs, Error> {
+    let mut path = Config::path();
+    let mut overrides = vec![];
+
+    while let Some(arg) = args.next() {
+        let field = match arg.as_str() {
+            "-c" | "--config" => {
+                path = args.next().ok_or(Error::MissingValue(arg))?.into();
+                continue;
+            }
+            "--set" => {
+                let value = args.next().ok_or(Error::MissingValue(arg))?;
+                overrides.push(value.parse()?);
+                continue;
+            }
+            "--log-level" => "log.level",
+            "--dns-listen" => "dns.listen",
+            "--controller-listen" => "controller.listen",
+            "--profile" => "profile",
+            "--thp-listen" => {
+                let value = args.next().ok_or(Error::MissingValue(arg))?;
+                let inbounds = value
+                    .split(',')
+                    .enumerate()
+                    .map(|(index, addr)| {
+                        let mut inbound = Mapping::new();
+                        inbound.insert("name".into(), format!("thp-{}", index).into());
+                        inbound.insert("protocol".into(), "thp".into());
+                        inbound.insert("listen".into(), addr.into());
+                        Value::Mapping(inbound)
+                    })
+                    .collect::<Vec<_>>();
+                overrides.push(Override::new("inbounds", inbounds.into()));
+                continue;
+            }
+            _ => return Err(Error::UnknownArgument(arg)),
+        };
+
+        // shorthands take the value as is, it's not parsed as YAML
+        let value = args.next().ok_or(Error::MissingValue(arg))?;
+        overrides.push(Override::new(field, Value::from(value)));
+    }
+
+    Ok(ConfigArgs { path, overrides })
+}
+
+/// Parse `<FILE> [-o <OUT>]`
+fn parse_convert(mut args: impl Iterator<Item = String>) -> Result<Command, Error> {
+    let mut input = None;
+    let mut output = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(args.next().ok_or(Error::MissingValue(arg))?.into()),
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg.into()),
+            _ => return Err(Error::UnknownArgument(arg)),
+        }
+    }
+
+    Ok(Command::Convert {
+        input: input.ok_or_else(|| Error::MissingValue("convert".to_string()))?,
+        output,
+    })
+}
+
+/// Parse `[FILE] [--force]`
+fn parse_init(args: impl Iterator<Item = String>) -> Result<Command, Error> {
+    let mut path = None;
+    let mut force = false;
+
+    for arg in args {
+        match arg.as_str() {
+            "-f" | "--force" => force = true,
+            _ if path.is_none() && (arg == "-" || !arg.starts_with('-')) => path = Some(arg.into()),
+            _ => return Err(Error::UnknownArgument(arg)),
+        }
+    }
+
+    Ok(Command::Init {
+        path: path.unwrap_or_else(|| "config.yaml".into()),
+        force,
+    })
+}
+
+fn parse_ctl(mut args: impl Iterator<Item = String>) -> Result<CtlArgs, Error> {
+    let mut addr =
+        std::env::var("ROXY_CONTROLLER").unwrap_or_else(|_| DEFAULT_CONTROLLER.to_string());
+    let mut secret = std::env::var("ROXY_CONTROLLER_SECRET").ok();
+
+    let command = loop {
+        let arg = args.next().ok_or(Error::MissingCommand)?;
+
+        match arg.as_str() {
+            "-a" | "--addr" => addr = args.next().ok_or(Error::MissingValue(arg))?,
+            "-s" | "--secret" => secret = Some(args.next().ok_or(Error::MissingValue(arg))?),
+            "connections" => break CtlCommand::Connections,
+            "traffic" => break CtlCommand::Traffic,
+            "talkers" => break CtlCommand::Talkers(args.next()),
+            "select" => break CtlCommand::Select(args.next()),
+            "reload" => break CtlCommand::Reload,
+            "server" => {
+                let action = args.next().ok_or(Error::MissingValue(arg))?;
+                let value = args
+                    .next()
+                    .ok_or_else(|| Error::MissingValue(action.clone()))?;
+                match action.as_str() {
+                    "add" => break CtlCommand::AddServer(value),
+                    "set" => {
+                        let url = args.next().ok_or(Error::MissingValue(action))?;
+                        break CtlCommand::ReplaceServer { name: value, url };
+                    }
+                    "remove" => break CtlCommand::RemoveServer(value),
+                    _ => return Err(Error::UnknownArgument(action)),
+                }
+            }
+            "profile" => match args.next() {
+                Some(arg) if arg == "--reset" => break CtlCommand::ResetProfile,
+                names => break CtlCommand::Profile(names),
+            },
+            "dns-query" => {
+                let name = args.next().ok_or(Error::MissingValue(arg))?;
+                break CtlCommand::DnsQuery {
+                    name,
+                    query_type: args.next(),
+                };
+            }
+            _ => return Err(Error::UnknownArgument(arg)),
+        }
+    };
+
+    if let Some(arg) = args.next() {
+        return Err(Error::UnknownArgument(arg));
+    }
+
+    Ok(CtlArgs {
+        addr,
+        secret,
+        command,
+    })
+}
@@ -0,0 +1,383 @@
+//! Build a config in code, for applications embedding roxy as a library,
+//! so they don't have to write a YAML document and load it.
+//!
+//! ```no_run
+//! use roxy::inbound::{self, Protocol};
+//! use roxy::Config;
+//!
+//! let config = Config::builder()
+//!     .resolver("1.1.1.1:53".parse().unwrap())
+//!     .dns_listen("127.0.0.1:53")
+//!     .nameserver("1.1.1.1:53".parse().unwrap())
+//!     .server("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388#local")
+//!     .inbound(inbound::Config::new("lan", Protocol::Thp, "0.0.0.0:80".parse().unwrap()))
+//!     .rule("DOMAIN-SUFFIX,lan,direct".parse().unwrap())
+//!     .build()
+//!     .expect("valid config");
+//! ```
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tracing::Level;
+
+use super::{Config, Log, Problem, VERSION};
+use crate::relay::inbound;
+use crate::route::{Rule, ScriptConfig};
+use crate::sandbox::SandboxConfig;
+use crate::upstream::{
+    CheckConfig, GroupConfig, LimitConfig, LoadBalanceType, ProviderConfig, WarmConfig,
+};
+use crate::{controller, dns, upstream};
+
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub(super) fn new() -> Self {
+        Self {
+            config: Config {
+                version: VERSION,
+                worker: None,
+                user: None,
+                group: None,
+                resolvers: vec![],
+                log: Log::default(),
+                #[cfg(feature = "dns")]
+                dns: dns::Config {
+                    listen: "127.0.0.1:53".to_string(),
+                    allow: vec![],
+                    deny: vec![],
+                    cache: None,
+                    upstream: dns::UpstreamConfig {
+                        nameservers: vec![],
+                        proxy: false,
+                    },
+                    hosts: None,
+                    reject: None,
+                    hijack: None,
+                },
+                controller: None,
+                upstream: upstream::Config {
+                    load_balance: LoadBalanceType::default(),
+                    check: CheckConfig::default(),
+                    servers: vec![],
+                    provider: None,
+                    weights: BTreeMap::new(),
+                    tags: BTreeMap::new(),
+                    groups: BTreeMap::new(),
+                    via: BTreeMap::new(),
+                    resolve: BTreeMap::new(),
+                    re_resolve: None,
+                    shadow_tls: BTreeMap::new(),
+                    tls: BTreeMap::new(),
+                    history: None,
+                    limit: LimitConfig::default(),
+                    warm: WarmConfig::default(),
+                    breaker: None,
+                    fast_open: false,
+                    keepalive: None,
+                    interface: None,
+                    interfaces: BTreeMap::new(),
+                    mark: None,
+                    marks: BTreeMap::new(),
+                },
+                inbounds: vec![],
+                rules: vec![],
+                geoip: None,
+                asn: None,
+                script: None,
+                profile: vec![],
+                profiles: BTreeMap::new(),
+                remote: None,
+                upgrade: None,
+                sandbox: SandboxConfig::default(),
+                warnings: vec![],
+                sources: vec![],
+            },
+        }
+    }
+
+    /// Worker threads of the runtime, the default is the number of CPUs
+    pub fn worker(mut self, worker: usize) -> Self {
+        self.config.worker = Some(worker);
+        self
+    }
+
+    /// Add a nameserver to resolve upstream servers and endpoints
+    pub fn resolver(mut self, addr: SocketAddr) -> Self {
+        self.config.resolvers.push(addr);
+        self
+    }
+
+    pub fn log_level(mut self, level: Level) -> Self {
+        self.config.log.level = level;
+        self
+    }
+
+    /// Per-module directives, e.g. `roxy::dns=debug,shadowsocks=warn`
+    pub fn log_filter(mut self, filter: impl Into<String>) -> Self {
+        self.config.log.filter = Some(filter.into());
+        self
+    }
+
+    /// Address of the DNS server, the default is `127.0.0.1:53`
+    #[cfg(feature = "dns")]
+    pub fn dns_listen(mut self, listen: impl Into<String>) -> Self {
+        self.config.dns.listen = listen.into();
+        self
+    }
+
+    /// Add a nameserver queries are forwarded to
+    #[cfg(feature = "dns")]
+    pub fn nameserver(mut self, addr: SocketAddr) -> Self {
+        self.config.dns.upstream.nameservers.push(addr);
+        self
+    }
+
+    /// Cache at most `size` answers, each one for at most `ttl`
+    #[cfg(feature = "dns")]
+    pub fn dns_cache(mut self, size: usize, ttl: Duration) -> Self {
+        self.config.dns.cache = Some(dns::CacheConfig { size, ttl });
+        self
+    }
+
+    /// Answer `name` with `addr`
+    #[cfg(feature = "dns")]
+    pub fn host(mut self, name: impl Into<String>, addr: IpAddr) -> Self {
+        self.config
+            .dns
+            .hosts
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.into(), addr.to_string());
+        self
+    }
+
+    /// Reject domains in the list fetched from `endpoint`, it's fetched
+    /// again every `interval` if it's set
+    #[cfg(feature = "dns")]
+    pub fn reject(mut self, endpoint: impl Into<String>, interval: Option<Duration>) -> Self {
+        self.config.dns.reject = Some(dns::RejectConfig {
+            endpoint: endpoint.into(),
+            interval,
+        });
+        self
+    }
+
+    /// Answer domains in the list fetched from `endpoint` with `address`,
+    /// so their connections go to the inbounds
+    #[cfg(feature = "dns")]
+    pub fn hijack(
+        mut self,
+        endpoint: impl Into<String>,
+        address: IpAddr,
+        interval: Option<Duration>,
+    ) -> Self {
+        self.config.dns.hijack = Some(dns::HijackConfig {
+            endpoint: endpoint.into(),
+            address,
+            interval,
+        });
+        self
+    }
+
+    pub fn controller(mut self, config: controller::Config) -> Self {
+        self.config.controller = Some(config);
+        self
+    }
+
+    pub fn load_balance(mut self, load_balance: LoadBalanceType) -> Self {
+        self.config.upstream.load_balance = load_balance;
+        self
+    }
+
+    /// Weight of the server named `name`, used by `LoadBalanceType::Weighted`
+    pub fn weight(mut self, name: impl Into<String>, weight: u32) -> Self {
+        self.config.upstream.weights.insert(name.into(), weight);
+        self
+    }
+
+    /// Health check of upstream servers
+    pub fn check(mut self, timeout: Duration, interval: Duration) -> Self {
+        self.config.upstream.check = CheckConfig {
+            timeout,
+            interval,
+            ..CheckConfig::default()
+        };
+        self
+    }
+
+    /// Add an upstream server in `ss://` url format
+    pub fn server(mut self, url: impl Into<String>) -> Self {
+        self.config.upstream.servers.push(url.into());
+        self
+    }
+
+    /// Fetch upstream servers from the subscription `endpoint` every
+    /// `interval`
+    pub fn provider(mut self, endpoint: impl Into<String>, interval: Duration) -> Self {
+        self.config.upstream.provider = Some(ProviderConfig {
+            endpoint: endpoint.into(),
+            interval,
+            cache: None,
+        });
+        self
+    }
+
+    /// Tag the server named `name`, rules and inbounds use tags to pick
+    /// servers
+    pub fn tag(mut self, name: impl Into<String>, tag: impl Into<String>) -> Self {
+        let tags = self.config.upstream.tags.entry(name.into()).or_default();
+        tags.push(tag.into());
+        self
+    }
+
+    /// Load balance of servers tagged `tag`
+    pub fn group(mut self, tag: impl Into<String>, config: GroupConfig) -> Self {
+        self.config.upstream.groups.insert(tag.into(), config);
+        self
+    }
+
+    /// Connections of each server, unlimited by default
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.upstream.limit.max_connections = Some(max);
+        self
+    }
+
+    /// Connections of the server named `name`, overriding `max_connections`
+    pub fn server_max_connections(mut self, name: impl Into<String>, max: usize) -> Self {
+        self.config.upstream.limit.servers.insert(name.into(), max);
+        self
+    }
+
+    pub fn inbound(mut self, config: inbound::Config) -> Self {
+        self.config.inbounds.push(config);
+        self
+    }
+
+    /// Add a routing rule, rules are matched in order
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.config.rules.push(rule);
+        self
+    }
+
+    pub fn rules(mut self, rules: impl IntoIterator<Item = Rule>) -> Self {
+        self.config.rules.extend(rules);
+        self
+    }
+
+    /// Routing script asked before rules
+    pub fn script(mut self, config: ScriptConfig) -> Self {
+        self.config.script = Some(config);
+        self
+    }
+
+    /// Validate the config like `roxy check` does
+    pub fn build(self) -> Result<Config, Vec<Problem>> {
+        let mut problems = vec![];
+        self.config.validate(&mut problems);
+
+        if problems.is_empty() {
+            Ok(self.config)
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inbound::Protocol;
+
+    #[test]
+    fn build() {
+        let config = Config::builder()
+            .resolver("1.1.1.1:53".parse().unwrap())
+            .nameserver("8.8.8.8:53".parse().unwrap())
+            .host("router.lan", "192.168.1.1".parse().unwrap())
+            .server("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388#local")
+            .controller(controller::Config::new("127.0.0.1:9000", None))
+            .inbound(inbound::Config::new(
+                "lan",
+                Protocol::Thp,
+                "0.0.0.0:80".parse().unwrap(),
+            ))
+            .build()
+            .unwrap();
+
+        assert_eq!(config.version, VERSION);
+        assert_eq!(config.dns.listen, "127.0.0.1:53");
+        assert_eq!(config.upstream.servers.len(), 1);
+        assert_eq!(config.inbounds[0].name, "lan");
+
+        let problems = Config::builder()
+            .inbound(inbound::Config::new(
+                "lan",
+                Protocol::Thp,
+                "0.0.0.0:80".parse().unwrap(),
+            ))
+            .inbound(inbound::Config::new(
+                "lan",
+                Protocol::Thp,
+                "0.0.0.0:443".parse().unwrap(),
+            ))
+            .build()
+            .err()
+            .unwrap();
+        let paths = problems.iter().map(|p| p.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "resolvers",
+                "dns.upstream.nameservers",
+                "upstream",
+                "inbounds[1].name"
+            ]
+        );
+    }
+
+    #[test]
+    fn build_rules() {
+        let builder = || {
+            Config::builder()
+                .resolver("1.1.1.1:53".parse().unwrap())
+                .nameserver("8.8.8.8:53".parse().unwrap())
+                .server("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388#hk")
+                .server("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8389#jp")
+                .tag("hk", "asia")
+                .tag("jp", "asia")
+                .group("asia", GroupConfig::default())
+                .server_max_connections("hk", 10)
+                .rule("DOMAIN,ads.example.com,reject".parse().unwrap())
+        };
+        let rules = ["DOMAIN-SUFFIX,lan,direct", "MATCH,asia"]
+            .iter()
+            .map(|rule| rule.parse().unwrap());
+
+        let config = builder()
+            .rules(rules)
+            .script(ScriptConfig {
+                path: None,
+                code: Some("fn route(domain, dst_ip, dst_port, src_ip, process) {}".to_string()),
+                process: false,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(config.rules.len(), 3);
+        assert_eq!(config.upstream.tags["hk"], ["asia"]);
+        assert_eq!(config.upstream.limit.servers["hk"], 10);
+
+        // like `roxy check`, GEOIP rules require geoip and tags must exist
+        let problems = builder()
+            .rule("GEOIP,CN,direct".parse().unwrap())
+            .rule("MATCH,europe".parse().unwrap())
+            .build()
+            .err()
+            .unwrap();
+        let paths = problems.iter().map(|p| p.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["rules[1]", "rules[2]"]);
+    }
+}
@@ -0,0 +1,833 @@
+//! Validate config without starting any service, see `roxy check`
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hyper::Uri;
+
+use super::{Config, Error, Override};
+use crate::log::Template;
+use crate::net::KeepaliveConfig;
+use crate::relay::inbound::{Protocol, Route};
+use crate::route::{GeoIpConfig, Matcher, Outbound, ScriptConfig};
+use crate::upstream::{Endpoint, LoadBalanceType};
+
+/// Something wrong in the config, `path` is the location of the field,
+/// e.g. `dns.upstream.nameservers`
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl Config {
+    /// Load the config like `load_from`, but unknown fields and invalid
+    /// values are reported instead of being ignored or failing later.
+    pub fn check(
+        path: impl AsRef<Path>,
+        overrides: &[Override],
+    ) -> Result<(Self, Vec<Problem>), Error> {
+        let mut problems = vec![];
+        let config = Self::load_with(path.as_ref(), overrides, &mut |path| {
+            problems.push(Problem::new(path.to_string(), "unknown field"));
+        })?;
+
+        config.validate(&mut problems);
+
+        Ok((config, problems))
+    }
+
+    pub(super) fn validate(&self, problems: &mut Vec<Problem>) {
+        if self.resolvers.is_empty() {
+            problems.push(Problem::new(
+                "resolvers",
+                "at least one resolver is required",
+            ));
+        }
+
+        if let Some(user) = &self.user {
+            if crate::privilege::lookup_user(user).is_err() {
+                problems.push(Problem::new("user", "unknown user"));
+            }
+        }
+        if let Some(group) = &self.group {
+            if self.user.is_none() {
+                problems.push(Problem::new("group", "requires user"));
+            } else if crate::privilege::lookup_group(group).is_err() {
+                problems.push(Problem::new("group", "unknown group"));
+            }
+        }
+
+        if let Some(remote) = &self.remote {
+            check_endpoint(problems, "remote.url", &remote.url);
+            if let Some(signature) = &remote.signature {
+                check_endpoint(problems, "remote.signature", signature);
+            }
+            if remote.public_key().is_err() {
+                problems.push(Problem::new(
+                    "remote.public_key",
+                    "invalid minisign public key",
+                ));
+            }
+            if let Some(interval) = remote.interval {
+                check_duration(problems, "remote.interval", interval);
+            }
+        }
+
+        if let Some(geoip) = &self.geoip {
+            check_database(problems, "geoip", geoip);
+        }
+        if let Some(asn) = &self.asn {
+            check_database(problems, "asn", asn);
+        }
+        if let Some(script) = &self.script {
+            check_script(problems, script);
+        }
+
+        if let Some(upgrade) = &self.upgrade {
+            check_duration(problems, "upgrade.drain", upgrade.drain);
+        }
+
+        if let Some(filter) = &self.log.filter {
+            if let Err(err) = crate::trace::filter(&self.log) {
+                problems.push(Problem::new(
+                    "log.filter",
+                    format!("invalid filter \"{}\", {}", filter, err),
+                ));
+            }
+        }
+
+        let sinks = [
+            self.log.file.is_some(),
+            self.log.syslog.is_some(),
+            self.log.journald,
+        ];
+        if sinks.iter().filter(|set| **set).count() > 1 {
+            problems.push(Problem::new(
+                "log",
+                "only one of file, syslog and journald can be set",
+            ));
+        }
+
+        let rates = [
+            ("log.sampling.connections", self.log.sampling.connections),
+            ("log.sampling.dns", self.log.sampling.dns),
+        ];
+        for (path, rate) in rates {
+            if rate == Some(0) {
+                problems.push(Problem::new(path, "must be greater than 0"));
+            }
+        }
+
+        if let Some(access) = &self.log.access {
+            if let Err(err) = Template::parse(&access.template) {
+                problems.push(Problem::new(
+                    "log.access.template",
+                    format!("invalid template, {}", err),
+                ));
+            }
+        }
+
+        if let Some(otlp) = &self.log.otlp {
+            check_endpoint(problems, "log.otlp.endpoint", &otlp.endpoint);
+            check_duration(problems, "log.otlp.interval", otlp.interval);
+        }
+
+        if let Some(statsd) = &self.log.statsd {
+            if statsd.address.rsplit_once(':').is_none() {
+                problems.push(Problem::new(
+                    "log.statsd.address",
+                    format!(
+                        "invalid address \"{}\", it must be host:port",
+                        statsd.address
+                    ),
+                ));
+            }
+            check_duration(problems, "log.statsd.interval", statsd.interval);
+        }
+
+        #[cfg(feature = "dns")]
+        {
+            let dns = &self.dns;
+            check_addr(problems, "dns.listen", &dns.listen);
+            if dns.upstream.nameservers.is_empty() {
+                problems.push(Problem::new(
+                    "dns.upstream.nameservers",
+                    "at least one nameserver is required",
+                ));
+            }
+            if let Some(cache) = &dns.cache {
+                if cache.size == 0 {
+                    problems.push(Problem::new("dns.cache.size", "must be greater than 0"));
+                }
+                check_duration(problems, "dns.cache.ttl", cache.ttl);
+            }
+            if let Some(reject) = &dns.reject {
+                check_endpoint(problems, "dns.reject.endpoint", &reject.endpoint);
+            }
+            if let Some(hijack) = &dns.hijack {
+                check_endpoint(problems, "dns.hijack.endpoint", &hijack.endpoint);
+            }
+            // queries relayed through servers can't resolve the servers
+            if let (true, Ok(listen)) = (dns.upstream.proxy, dns.listen.parse::<SocketAddr>()) {
+                let own = |ns: &SocketAddr| {
+                    ns.port() == listen.port()
+                        && (ns.ip() == listen.ip()
+                            || listen.ip().is_unspecified() && ns.ip().is_loopback())
+                };
+                let message = "it's the DNS server of roxy, which relays queries through \
+                               upstream servers, use another nameserver to resolve them";
+                for (index, ns) in self.resolvers.iter().enumerate() {
+                    if own(ns) {
+                        problems.push(Problem::new(format!("resolvers[{}]", index), message));
+                    }
+                }
+                for (name, resolve) in &self.upstream.resolve {
+                    if resolve.nameservers.iter().any(own) {
+                        problems.push(Problem::new(
+                            format!("upstream.resolve.{}.nameservers", name),
+                            message,
+                        ));
+                    }
+                }
+            }
+        }
+
+        if let Some(controller) = &self.controller {
+            check_addr(problems, "controller.listen", &controller.listen);
+            if let Some(rl) = &controller.rate_limit {
+                if rl.requests == 0 {
+                    problems.push(Problem::new(
+                        "controller.rate_limit.requests",
+                        "must be greater than 0",
+                    ));
+                }
+                check_duration(problems, "controller.rate_limit.interval", rl.interval);
+            }
+            if let Some(dir) = &controller.capture_dir {
+                if !dir.is_dir() {
+                    problems.push(Problem::new("controller.capture_dir", "is not a directory"));
+                }
+            }
+        }
+
+        let upstream = &self.upstream;
+        for (index, url) in upstream.servers.iter().enumerate() {
+            if let Err(err) = Endpoint::from_url(url) {
+                problems.push(Problem::new(
+                    format!("upstream.servers[{}]", index),
+                    format!("invalid server url, {:?}", err),
+                ));
+            }
+        }
+        match &upstream.provider {
+            Some(provider) => {
+                check_endpoint(problems, "upstream.provider.endpoint", &provider.endpoint);
+                check_duration(problems, "upstream.provider.interval", provider.interval);
+            }
+            None if upstream.servers.is_empty() => problems.push(Problem::new(
+                "upstream",
+                "either servers or provider is required",
+            )),
+            None => {}
+        }
+        check_duration(problems, "upstream.check.timeout", upstream.check.timeout);
+        check_duration(problems, "upstream.check.interval", upstream.check.interval);
+        let thresholds = [
+            (
+                "upstream.check.healthy_threshold",
+                upstream.check.healthy_threshold,
+            ),
+            (
+                "upstream.check.unhealthy_threshold",
+                upstream.check.unhealthy_threshold,
+            ),
+        ];
+        for (path, threshold) in thresholds {
+            if threshold == 0 {
+                problems.push(Problem::new(path, "must be greater than 0"));
+            }
+        }
+        let limit = &upstream.limit;
+        if limit.max_connections == Some(0) {
+            problems.push(Problem::new(
+                "upstream.limit.max_connections",
+                "must be greater than 0",
+            ));
+        }
+        for (name, max) in &limit.servers {
+            if *max == 0 {
+                problems.push(Problem::new(
+                    format!("upstream.limit.servers.{}", name),
+                    "must be greater than 0",
+                ));
+            }
+        }
+        check_duration(
+            problems,
+            "upstream.limit.queue_timeout",
+            limit.queue_timeout,
+        );
+        for (name, resolve) in &upstream.resolve {
+            if !resolve.addresses.is_empty() && resolve.strategy.pick(&resolve.addresses).is_none()
+            {
+                problems.push(Problem::new(
+                    format!("upstream.resolve.{}.addresses", name),
+                    format!("no address matches strategy {:?}", resolve.strategy),
+                ));
+            }
+        }
+        if let Some(re_resolve) = &upstream.re_resolve {
+            check_duration(
+                problems,
+                "upstream.re_resolve.interval",
+                re_resolve.interval,
+            );
+        }
+        for (name, shadow_tls) in &upstream.shadow_tls {
+            if shadow_tls.server_name.is_empty() {
+                problems.push(Problem::new(
+                    format!("upstream.shadow_tls.{}.server_name", name),
+                    "must not be empty",
+                ));
+            }
+            if shadow_tls.password.is_empty() {
+                problems.push(Problem::new(
+                    format!("upstream.shadow_tls.{}.password", name),
+                    "must not be empty",
+                ));
+            }
+        }
+        for (name, tls) in &upstream.tls {
+            if let Err(err) = tls.check() {
+                problems.push(Problem::new(
+                    format!("upstream.tls.{}", name),
+                    err.to_string(),
+                ));
+            }
+        }
+        if let Some(breaker) = &upstream.breaker {
+            if !(breaker.failure_rate > 0.0 && breaker.failure_rate <= 1.0) {
+                problems.push(Problem::new(
+                    "upstream.breaker.failure_rate",
+                    "must be greater than 0 and at most 1",
+                ));
+            }
+            let counts = [
+                ("upstream.breaker.min_requests", breaker.min_requests),
+                ("upstream.breaker.window", breaker.window),
+            ];
+            for (path, count) in counts {
+                if count == 0 {
+                    problems.push(Problem::new(path, "must be greater than 0"));
+                }
+            }
+            check_duration(problems, "upstream.breaker.cooldown", breaker.cooldown);
+        }
+        for (tag, group) in &upstream.groups {
+            if !upstream.tags.values().any(|tags| tags.contains(tag)) {
+                problems.push(Problem::new(
+                    format!("upstream.groups.{}", tag),
+                    format!("no server is tagged \"{}\" in upstream.tags", tag),
+                ));
+            }
+            if !group.servers.is_empty() && group.load_balance != LoadBalanceType::Fallback {
+                problems.push(Problem::new(
+                    format!("upstream.groups.{}.servers", tag),
+                    "servers are used by fallback only",
+                ));
+            }
+            if !group.weights.is_empty() && group.load_balance != LoadBalanceType::Weighted {
+                problems.push(Problem::new(
+                    format!("upstream.groups.{}.weights", tag),
+                    "weights are used by weighted only",
+                ));
+            }
+            if let Some(interface) = &group.interface {
+                check_interface(
+                    problems,
+                    &format!("upstream.groups.{}.interface", tag),
+                    interface,
+                );
+            }
+        }
+        for (name, via) in &upstream.via {
+            if via == name || upstream.via.contains_key(via) {
+                problems.push(Problem::new(
+                    format!("upstream.via.{}", name),
+                    format!(
+                        "\"{}\" is chained itself, servers are chained by one hop",
+                        via
+                    ),
+                ));
+            }
+        }
+        if let Some(interface) = &upstream.interface {
+            check_interface(problems, "upstream.interface", interface);
+        }
+        for (name, interface) in &upstream.interfaces {
+            check_interface(
+                problems,
+                &format!("upstream.interfaces.{}", name),
+                interface,
+            );
+        }
+        if let Some(keepalive) = &upstream.keepalive {
+            check_keepalive(problems, "upstream.keepalive", keepalive);
+        }
+        if upstream.warm.connections > 0 {
+            check_duration(
+                problems,
+                "upstream.warm.idle_timeout",
+                upstream.warm.idle_timeout,
+            );
+        }
+
+        let mut names = HashSet::new();
+        for (index, inbound) in self.inbounds.iter().enumerate() {
+            if inbound.name.is_empty() {
+                problems.push(Problem::new(
+                    format!("inbounds[{}].name", index),
+                    "must not be empty",
+                ));
+            } else if !names.insert(&inbound.name) {
+                problems.push(Problem::new(
+                    format!("inbounds[{}].name", index),
+                    format!("\"{}\" is used more than once", inbound.name),
+                ));
+            }
+            if inbound.protocol == Protocol::Tproxy && inbound.route != Route::Proxy {
+                problems.push(Problem::new(
+                    format!("inbounds[{}].route", index),
+                    "tproxy inbounds relay by upstream servers only",
+                ));
+            }
+            if inbound.protocol == Protocol::Tproxy {
+                check_duration(
+                    problems,
+                    &format!("inbounds[{}].udp.idle_timeout", index),
+                    inbound.udp.idle_timeout,
+                );
+                if inbound.udp.max_sessions == 0 {
+                    problems.push(Problem::new(
+                        format!("inbounds[{}].udp.max_sessions", index),
+                        "must be greater than 0",
+                    ));
+                }
+            }
+            if !inbound.sniffing.http && !inbound.sniffing.tls {
+                problems.push(Problem::new(
+                    format!("inbounds[{}].sniffing", index),
+                    "at least one of http and tls is required to find the destination",
+                ));
+            }
+            if inbound.sniffing.redirect && inbound.protocol != Protocol::Redirect {
+                problems.push(Problem::new(
+                    format!("inbounds[{}].sniffing.redirect", index),
+                    "only applies to redirect inbounds",
+                ));
+            }
+            if !inbound.users.is_empty() && inbound.protocol != Protocol::Http {
+                problems.push(Problem::new(
+                    format!("inbounds[{}].users", index),
+                    "only http inbounds can authenticate clients",
+                ));
+            }
+            for (i, user) in inbound.users.iter().enumerate() {
+                if user.username.is_empty() || user.username.contains(':') {
+                    problems.push(Problem::new(
+                        format!("inbounds[{}].users[{}].username", index, i),
+                        "must be non-empty and without \":\"",
+                    ));
+                }
+            }
+            if let Some(ban) = &inbound.ban {
+                if ban.failures == 0 {
+                    problems.push(Problem::new(
+                        format!("inbounds[{}].ban.failures", index),
+                        "must be greater than 0",
+                    ));
+                }
+                check_duration(
+                    problems,
+                    &format!("inbounds[{}].ban.window", index),
+                    ban.window,
+                );
+                check_duration(
+                    problems,
+                    &format!("inbounds[{}].ban.duration", index),
+                    ban.duration,
+                );
+            }
+            if let Some(limit) = &inbound.client_limit {
+                if limit.max_connections == 0 {
+                    problems.push(Problem::new(
+                        format!("inbounds[{}].client_limit.max_connections", index),
+                        "must be greater than 0",
+                    ));
+                }
+            }
+            if let Some(rate) = &inbound.max_rate {
+                let mut rates = vec![
+                    (format!("inbounds[{}].max_rate.upload", index), rate.upload),
+                    (
+                        format!("inbounds[{}].max_rate.download", index),
+                        rate.download,
+                    ),
+                ];
+                for (i, client) in rate.clients.iter().enumerate() {
+                    let path = format!("inbounds[{}].max_rate.clients[{}]", index, i);
+                    rates.push((format!("{}.upload", path), client.upload));
+                    rates.push((format!("{}.download", path), client.download));
+                }
+                for (path, rate) in rates {
+                    if rate == Some(0) {
+                        problems.push(Problem::new(path, "must be greater than 0"));
+                    }
+                }
+            }
+            if let Some(interface) = &inbound.interface {
+                check_interface(
+                    problems,
+                    &format!("inbounds[{}].interface", index),
+                    interface,
+                );
+            }
+            if let Some(keepalive) = &inbound.keepalive {
+                check_keepalive(
+                    problems,
+                    &format!("inbounds[{}].keepalive", index),
+                    keepalive,
+                );
+            }
+            if inbound.acceptors == 0 {
+                problems.push(Problem::new(
+                    format!("inbounds[{}].acceptors", index),
+                    "must be greater than 0",
+                ));
+            }
+            if let Some(header) = &inbound.request_id {
+                if header.is_empty()
+                    || !header
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+                {
+                    problems.push(Problem::new(
+                        format!("inbounds[{}].request_id", index),
+                        format!("invalid header name \"{}\"", header),
+                    ));
+                }
+            }
+            if let Some(tag) = &inbound.upstream_tag {
+                let tagged = self.upstream.tags.values().any(|tags| tags.contains(tag));
+                if !tagged {
+                    problems.push(Problem::new(
+                        format!("inbounds[{}].upstream_tag", index),
+                        format!("no server is tagged \"{}\" in upstream.tags", tag),
+                    ));
+                }
+            }
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if matches!(rule.matcher, Matcher::GeoIp(_)) && self.geoip.is_none() {
+                problems.push(Problem::new(
+                    format!("rules[{}]", index),
+                    "GEOIP rules require geoip",
+                ));
+            }
+            if matches!(rule.matcher, Matcher::IpAsn(_)) && self.asn.is_none() {
+                problems.push(Problem::new(
+                    format!("rules[{}]", index),
+                    "IP-ASN rules require asn",
+                ));
+            }
+            #[cfg(not(target_os = "linux"))]
+            if rule.matcher.needs_process() {
+                problems.push(Problem::new(
+                    format!("rules[{}]", index),
+                    "PROCESS-NAME and PROCESS-PATH rules are only supported on Linux",
+                ));
+            }
+            if rule.mark.is_some() && rule.outbound == Outbound::Reject {
+                problems.push(Problem::new(
+                    format!("rules[{}]", index),
+                    "mark is not used by reject",
+                ));
+            }
+            if let Outbound::Tag(tag) = &rule.outbound {
+                let tagged = self.upstream.tags.values().any(|tags| tags.contains(tag));
+                if !tagged {
+                    problems.push(Problem::new(
+                        format!("rules[{}]", index),
+                        format!(
+                            "outbound must be direct, reject, proxy or a tag in upstream.tags, \
+                             no server is tagged \"{}\"",
+                            tag
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn check_database(problems: &mut Vec<Problem>, path: &str, database: &GeoIpConfig) {
+    match &database.url {
+        Some(url) => check_endpoint(problems, &format!("{}.url", path), url),
+        None if !database.path.exists() => problems.push(Problem::new(
+            format!("{}.path", path),
+            "doesn't exist, and no url to download it",
+        )),
+        None => {}
+    }
+    if let Some(interval) = database.interval {
+        let path = format!("{}.interval", path);
+        if database.url.is_none() {
+            problems.push(Problem::new(&path, "requires url"));
+        }
+        check_duration(problems, &path, interval);
+    }
+}
+
+fn check_script(problems: &mut Vec<Problem>, script: &ScriptConfig) {
+    if script.path.is_some() && script.code.is_some() {
+        problems.push(Problem::new(
+            "script",
+            "only one of path and code is allowed",
+        ));
+        return;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if script.process {
+        problems.push(Problem::new(
+            "script.process",
+            "processes are only looked up on Linux",
+        ));
+    }
+    #[cfg(feature = "script")]
+    if let Err(err) = crate::route::Script::load(script) {
+        problems.push(Problem::new("script", err.to_string()));
+    }
+    #[cfg(not(feature = "script"))]
+    problems.push(Problem::new(
+        "script",
+        "roxy is built without the script feature",
+    ));
+}
+
+fn check_addr(problems: &mut Vec<Problem>, path: &str, addr: &str) {
+    if let Err(err) = addr.parse::<SocketAddr>() {
+        problems.push(Problem::new(
+            path,
+            format!("invalid address \"{}\", {}", addr, err),
+        ));
+    }
+}
+
+fn check_endpoint(problems: &mut Vec<Problem>, path: &str, endpoint: &str) {
+    match Uri::from_str(endpoint) {
+        Ok(uri) => match uri.scheme_str() {
+            Some("http" | "https") => {}
+            _ => problems.push(Problem::new(
+                path,
+                format!("endpoint \"{}\" must be http or https", endpoint),
+            )),
+        },
+        Err(err) => problems.push(Problem::new(
+            path,
+            format!("invalid endpoint \"{}\", {}", endpoint, err),
+        )),
+    }
+}
+
+fn check_duration(problems: &mut Vec<Problem>, path: &str, duration: Duration) {
+    if duration.is_zero() {
+        problems.push(Problem::new(path, "must be greater than 0"));
+    }
+}
+
+/// Names of network interfaces are shorter than `IFNAMSIZ`, 16 bytes
+/// with the nul
+fn check_interface(problems: &mut Vec<Problem>, path: &str, interface: &str) {
+    if interface.is_empty() || interface.len() >= libc::IFNAMSIZ {
+        problems.push(Problem::new(
+            path,
+            format!("invalid interface name \"{}\"", interface),
+        ));
+    }
+}
+
+/// Keepalive options are set in whole seconds
+fn check_keepalive(problems: &mut Vec<Problem>, path: &str, keepalive: &KeepaliveConfig) {
+    let values = [
+        ("time", keepalive.time.as_secs()),
+        ("interval", keepalive.interval.as_secs()),
+        ("probes", keepalive.probes as u64),
+    ];
+    for (name, value) in values {
+        if value == 0 {
+            problems.push(Problem::new(
+                format!("{}.{}", path, name),
+                "must be greater than 0",
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+dns:
+  listen: 127.0.0.1
+  upstream:
+    nameservers: []
+controller:
+  listen: 127.0.0.1:9000
+upstream:
+  check:
+    timeout: 5s
+    interval: 1m
+  provider:
+    endpoint: ftp://example.com/servers
+    interval: 1h
+  groups:
+    asia:
+      load_balance: round_robin
+      servers: [hk-01]
+      weights:
+        hk-01: 2
+  via:
+    a: b
+    b: c
+  re_resolve:
+    interval: 0s
+  tls:
+    hk-01:
+      fingerprint: 3f:1a
+inbounds:
+  - name: lan
+    protocol: thp
+    listen: 0.0.0.0:1080
+    sniffing:
+      http: false
+      tls: false
+      redirect: true
+    users:
+      - username: ""
+        password: secret
+    client_limit:
+      max_connections: 0
+    max_rate:
+      download: 0B
+    keepalive:
+      interval: 500ms
+    interface: ""
+    request_id: "X Request Id"
+rules:
+  - DOMAIN-SUFFIX,example.com,direct
+  - MATCH,prxy
+  - GEOIP,CN,direct
+  - IP-ASN,15169,direct
+  - DST-PORT,25,reject,mark=255
+script:
+  code: "fn route(domain) {}"
+"#,
+        )
+        .unwrap();
+
+        let mut problems = vec![];
+        config.validate(&mut problems);
+
+        let paths = problems.iter().map(|p| p.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "resolvers",
+                "script",
+                "dns.listen",
+                "dns.upstream.nameservers",
+                "upstream.provider.endpoint",
+                "upstream.re_resolve.interval",
+                "upstream.tls.hk-01",
+                "upstream.groups.asia",
+                "upstream.groups.asia.servers",
+                "upstream.groups.asia.weights",
+                "upstream.via.a",
+                "inbounds[0].sniffing",
+                "inbounds[0].sniffing.redirect",
+                "inbounds[0].users",
+                "inbounds[0].users[0].username",
+                "inbounds[0].client_limit.max_connections",
+                "inbounds[0].max_rate.download",
+                "inbounds[0].interface",
+                "inbounds[0].keepalive.interval",
+                "inbounds[0].request_id",
+                "rules[1]",
+                "rules[2]",
+                "rules[3]",
+                "rules[4]"
+            ]
+        );
+    }
+
+    #[cfg(feature = "dns")]
+    #[test]
+    fn resolve_loop() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+resolvers: [127.0.0.1:53, 223.5.5.5:53]
+dns:
+  listen: 0.0.0.0:53
+  upstream:
+    nameservers: [8.8.8.8:53]
+    proxy: true
+upstream:
+  check:
+    timeout: 5s
+    interval: 1m
+  servers: ["ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388#hk-01"]
+  resolve:
+    hk-01:
+      nameservers: 127.0.0.1:53
+    jp-01:
+      nameservers: 127.0.0.1:5353
+"#,
+        )
+        .unwrap();
+
+        let mut problems = vec![];
+        config.validate(&mut problems);
+
+        let paths = problems.iter().map(|p| p.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            ["resolvers[0]", "upstream.resolve.hk-01.nameservers"]
+        );
+    }
+}
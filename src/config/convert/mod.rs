@@ -0,0 +1,182 @@
+//! Convert configs of other proxies to roxy's, so users can migrate
+//! without rewriting them.
+//!
+//! Roxy has far fewer features than them, things can't be mapped are
+//! dropped with warnings.
+
+mod clash;
+mod sing_box;
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_yaml::{Mapping, Value};
+
+pub(crate) use clash::convert_proxy as clash_proxy;
+
+/// Where Clash downloads `Country.mmdb` from, unless `geox-url` is set,
+/// sing-box has no such database, so its `geoip` rules use it too
+const MMDB_URL: &str =
+    "https://github.com/Dreamacro/maxmind-geoip/releases/latest/download/Country.mmdb";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("\"{0}\" must be a list")]
+    NotList(&'static str),
+    #[error("\"{0}\" must be a mapping")]
+    NotMapping(&'static str),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Clash,
+    SingBox,
+}
+
+impl Kind {
+    /// Detect the kind of `value`, `None` means it is roxy's
+    pub fn detect(value: &Value) -> Option<Kind> {
+        let map = value.as_mapping()?;
+        if map.contains_key("upstream") {
+            return None;
+        }
+
+        if map.contains_key("proxies") || map.contains_key("proxy-groups") {
+            Some(Kind::Clash)
+        } else if map.contains_key("outbounds") || map.contains_key("inbounds") {
+            Some(Kind::SingBox)
+        } else {
+            None
+        }
+    }
+
+    /// Convert `value` to roxy's config of the current version, returns
+    /// it and warnings of dropped parts.
+    pub fn convert(&self, value: &Value) -> Result<(Value, Vec<String>), Error> {
+        let (converted, warnings) = match self {
+            Kind::Clash => clash::convert(value)?,
+            Kind::SingBox => sing_box::convert(value)?,
+        };
+
+        let mut config = mapping([("version", super::VERSION.into())]);
+        if let Value::Mapping(converted) = converted {
+            config.extend(converted);
+        }
+
+        Ok((Value::Mapping(config), warnings))
+    }
+}
+
+/// `8.8.8.8`, `8.8.8.8:53` and `udp://8.8.8.8` are supported
+fn udp_nameserver(server: &str) -> Option<String> {
+    let server = server.strip_prefix("udp://").unwrap_or(server);
+    if server.contains("://") {
+        return None;
+    }
+
+    if server.parse::<std::net::IpAddr>().is_ok() {
+        return Some(format!("{}:53", server));
+    }
+
+    server
+        .parse::<std::net::SocketAddr>()
+        .ok()
+        .map(|addr| addr.to_string())
+}
+
+/// Build a SIP002 url, userinfo is base64 encoded. `plugin` is a SIP003
+/// plugin with its options, e.g. `obfs-local;obfs=http`.
+pub(crate) fn ss_url(
+    method: &str,
+    password: &str,
+    host: &str,
+    port: u64,
+    name: &str,
+    plugin: Option<&str>,
+) -> String {
+    let userinfo =
+        base64::encode_config(format!("{}:{}", method, password), base64::URL_SAFE_NO_PAD);
+
+    let mut url = format!("ss://{}@{}", userinfo, authority(host, port));
+    if let Some(plugin) = plugin {
+        url.push_str("?plugin=");
+        url.extend(utf8_percent_encode(plugin, NON_ALPHANUMERIC));
+    }
+    push_name(&mut url, name);
+
+    url
+}
+
+/// `http://` or `https://` url, `credentials` are the username and
+/// password
+fn http_url(
+    tls: bool,
+    credentials: Option<(&str, &str)>,
+    host: &str,
+    port: u64,
+    name: &str,
+) -> String {
+    let mut url = if tls { "https://" } else { "http://" }.to_string();
+    if let Some((username, password)) = credentials {
+        url.extend(utf8_percent_encode(username, NON_ALPHANUMERIC));
+        url.push(':');
+        url.extend(utf8_percent_encode(password, NON_ALPHANUMERIC));
+        url.push('@');
+    }
+    url.push_str(&authority(host, port));
+    push_name(&mut url, name);
+
+    url
+}
+
+/// A v2rayN share link of plain TCP without the legacy alter id
+fn vmess_url(uuid: &str, cipher: &str, host: &str, port: u64, name: &str) -> String {
+    let share = serde_json::json!({
+        "v": "2",
+        "ps": name,
+        "add": host,
+        "port": port.to_string(),
+        "id": uuid,
+        "aid": "0",
+        "net": "tcp",
+        "scy": cipher,
+    });
+
+    format!("vmess://{}", base64::encode(share.to_string()))
+}
+
+/// A plain TCP `vless://` url, over TLS if `sni` is set
+fn vless_url(uuid: &str, host: &str, port: u64, name: &str, sni: Option<&str>) -> String {
+    let mut url = format!(
+        "vless://{}@{}?encryption=none&type=tcp",
+        uuid,
+        authority(host, port)
+    );
+    if let Some(sni) = sni {
+        url.push_str("&security=tls&sni=");
+        url.extend(utf8_percent_encode(sni, NON_ALPHANUMERIC));
+    }
+    push_name(&mut url, name);
+
+    url
+}
+
+fn authority(host: &str, port: u64) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn push_name(url: &mut String, name: &str) {
+    if !name.is_empty() {
+        url.push('#');
+        url.extend(utf8_percent_encode(name, NON_ALPHANUMERIC));
+    }
+}
+
+fn mapping<const N: usize>(pairs: [(&str, Value); N]) -> Mapping {
+    pairs
+        .into_iter()
+        .map(|(k, v)| (Value::from(k), v))
+        .collect()
+}
@@ -0,0 +1,148 @@
+//! Expand environment variables in config values, `${VAR}` and
+//! `${VAR:-default}` are supported, `$${` escapes a literal `${`.
+//! Expanded values are always strings, so a password of digits stays one.
+
+use serde_yaml::Value;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("environment variable \"{0}\" is not set")]
+    Undefined(String),
+    #[error("unclosed \"${{\" in \"{0}\"")]
+    Unclosed(String),
+}
+
+/// Expand all string values of `value`, keys are not expanded.
+pub fn interpolate(value: &mut Value) -> Result<(), Error> {
+    interpolate_with(value, &|name| std::env::var(name).ok())
+}
+
+fn interpolate_with<F>(value: &mut Value, lookup: &F) -> Result<(), Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        Value::String(s) => {
+            if !s.contains("${") {
+                return Ok(());
+            }
+
+            *s = expand(s, lookup)?;
+        }
+        Value::Sequence(seq) => {
+            for v in seq {
+                interpolate_with(v, lookup)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (_, v) in map.iter_mut() {
+                interpolate_with(v, lookup)?;
+            }
+        }
+        Value::Tagged(tagged) => interpolate_with(&mut tagged.value, lookup)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn expand<F>(input: &str, lookup: &F) -> Result<String, Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find("${") {
+        // `$${` is an escaped `${`
+        if rest[..start].ends_with('$') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        output.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| Error::Unclosed(input.to_string()))?;
+        let expr = &rest[start + 2..start + end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+
+        match (lookup(name), default) {
+            (Some(v), Some(default)) if v.is_empty() => output.push_str(default),
+            (Some(v), _) => output.push_str(&v),
+            (None, Some(default)) => output.push_str(default),
+            (None, None) => return Err(Error::Undefined(name.to_string())),
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOST" => Some("example.com".to_string()),
+            "PORT" => Some("8080".to_string()),
+            "EMPTY" => Some(String::new()),
+            "PASSWORD" => Some("123456".to_string()),
+            "ENABLED" => Some("true".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand_str() {
+        for (input, want) in [
+            ("plain", "plain"),
+            ("${HOST}", "example.com"),
+            ("${HOST}:${PORT}", "example.com:8080"),
+            ("${MISSING:-default}", "default"),
+            ("${EMPTY:-default}", "default"),
+            ("${HOST:-default}", "example.com"),
+            ("$${HOST}", "${HOST}"),
+        ] {
+            assert_eq!(expand(input, &lookup).unwrap(), want, "{}", input);
+        }
+
+        assert!(matches!(
+            expand("${MISSING}", &lookup),
+            Err(Error::Undefined(name)) if name == "MISSING"
+        ));
+        assert!(matches!(expand("${HOST", &lookup), Err(Error::Unclosed(_))));
+    }
+
+    #[test]
+    fn interpolate_value() {
+        let mut value: Value = serde_yaml::from_str(
+            r#"
+listen: ${HOST}:${PORT}
+password: ${PASSWORD}
+enabled: ${ENABLED}
+list:
+  - ${HOST}
+"#,
+        )
+        .unwrap();
+
+        interpolate_with(&mut value, &lookup).unwrap();
+
+        assert_eq!(value["listen"], Value::from("example.com:8080"));
+        // not re-parsed as a number or a bool
+        assert_eq!(value["password"], Value::from("123456"));
+        assert_eq!(value["enabled"], Value::from("true"));
+        assert_eq!(value["list"][0], Value::from("example.com"));
+    }
+}
@@ -0,0 +1,113 @@
+//! Config can be written in YAML, TOML or JSON, they are all converted
+//! to `serde_yaml::Value`, so the rest of loading doesn't care.
+
+use std::path::Path;
+
+use serde_yaml::Value;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Detect format by the extension of `path`, and by `content` if the
+    /// extension is unknown.
+    pub fn detect(path: &Path, content: &[u8]) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => return Format::Yaml,
+            Some("toml") => return Format::Toml,
+            Some("json") => return Format::Json,
+            _ => {}
+        }
+
+        let content = String::from_utf8_lossy(content);
+        let mut lines = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        match lines.next() {
+            Some(line) if line.starts_with('{') => Format::Json,
+            // a table header, or `key = value`
+            Some(line) if line.starts_with('[') && line.ends_with(']') => Format::Toml,
+            Some(line) if is_toml_pair(line) => Format::Toml,
+            _ => Format::Yaml,
+        }
+    }
+
+    pub fn parse(&self, content: &[u8]) -> Result<Value, Error> {
+        let value = match self {
+            Format::Yaml => serde_yaml::from_slice(content)?,
+            Format::Toml => {
+                let value = toml::from_slice::<toml::Value>(content)?;
+                serde_yaml::to_value(value)?
+            }
+            Format::Json => serde_json::from_slice(content)?,
+        };
+
+        Ok(value)
+    }
+}
+
+fn is_toml_pair(line: &str) -> bool {
+    match (line.find('='), line.find(':')) {
+        (Some(eq), Some(colon)) => eq < colon,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect() {
+        for (path, content, want) in [
+            ("config.yaml", "", Format::Yaml),
+            ("config.toml", "", Format::Toml),
+            ("config.json", "", Format::Json),
+            ("config", "worker: 2", Format::Yaml),
+            ("config", "# comment\n{\"worker\": 2}", Format::Json),
+            ("config", "worker = 2", Format::Toml),
+            ("config", "[log]\nlevel = \"info\"", Format::Toml),
+            ("config", "- 1.1.1.1:53", Format::Yaml),
+        ] {
+            assert_eq!(
+                Format::detect(Path::new(path), content.as_bytes()),
+                want,
+                "{}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let yaml = Format::Yaml
+            .parse(b"log:\n  level: info\nresolvers:\n  - 1.1.1.1:53\n")
+            .unwrap();
+        let toml = Format::Toml
+            .parse(b"resolvers = [\"1.1.1.1:53\"]\n[log]\nlevel = \"info\"\n")
+            .unwrap();
+        let json = Format::Json
+            .parse(br#"{"log": {"level": "info"}, "resolvers": ["1.1.1.1:53"]}"#)
+            .unwrap();
+
+        assert_eq!(yaml, toml);
+        assert_eq!(yaml, json);
+    }
+}
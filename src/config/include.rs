@@ -0,0 +1,233 @@
+//! `include` merges other config files into the current one, e.g.
+//!
+//! ```yaml
+//! include:
+//!   - upstream.yaml
+//!   - dns/*.yaml
+//! ```
+//!
+//! Paths are relative to the file including them, and globs are expanded
+//! in alphabetical order. Included files are merged in order, then the
+//! including file is merged on top of them, so it always wins. Mappings
+//! are merged recursively, other values, including lists, are replaced.
+//! Files can be in different formats, e.g. a YAML file can include a TOML one.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_yaml::{Mapping, Value};
+
+use super::format::{self, Format};
+
+const INCLUDE: &str = "include";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("read {path:?} failed, {err}")]
+    Read { path: PathBuf, err: io::Error },
+    #[error("parse {path:?} failed, {err}")]
+    Parse { path: PathBuf, err: format::Error },
+    #[error("invalid include pattern \"{pattern}\", {err}")]
+    Pattern {
+        pattern: String,
+        err: glob::PatternError,
+    },
+    #[error("include of {0:?} must be a string or a list of strings")]
+    InvalidInclude(PathBuf),
+    #[error("{0:?} includes itself")]
+    Cycle(PathBuf),
+}
+
+/// Load the file at `path` and all files included by it, returns the
+/// merged value and its sources, `path`, the included files and the
+/// include globs, so files matching them later are noticed too.
+pub fn load(path: &Path) -> Result<(Value, Vec<PathBuf>), Error> {
+    let mut sources = vec![];
+    let value = load_file(path, &mut vec![], &mut sources)?;
+
+    Ok((value, sources))
+}
+
+fn load_file(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    sources: &mut Vec<PathBuf>,
+) -> Result<Value, Error> {
+    let canonical = path.canonicalize().map_err(|err| Error::Read {
+        path: path.to_path_buf(),
+        err,
+    })?;
+    if stack.contains(&canonical) {
+        return Err(Error::Cycle(path.to_path_buf()));
+    }
+
+    let content = std::fs::read(path).map_err(|err| Error::Read {
+        path: path.to_path_buf(),
+        err,
+    })?;
+    sources.push(path.to_path_buf());
+    let mut value = Format::detect(path, &content)
+        .parse(&content)
+        .map_err(|err| Error::Parse {
+            path: path.to_path_buf(),
+            err,
+        })?;
+
+    let patterns = match value.as_mapping_mut().and_then(|m| m.remove(INCLUDE)) {
+        None => return Ok(value),
+        Some(Value::String(pattern)) => vec![pattern],
+        Some(Value::Sequence(seq)) => seq
+            .into_iter()
+            .map(|v| match v {
+                Value::String(pattern) => Ok(pattern),
+                _ => Err(Error::InvalidInclude(path.to_path_buf())),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => return Err(Error::InvalidInclude(path.to_path_buf())),
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut merged = Value::Mapping(Mapping::new());
+
+    stack.push(canonical);
+    for pattern in patterns {
+        if is_glob(&pattern) {
+            sources.push(dir.join(&pattern));
+        }
+        for included in expand(dir, &pattern)? {
+            let fragment = load_file(&included, stack, sources)?;
+            merge(&mut merged, fragment);
+        }
+    }
+    stack.pop();
+
+    merge(&mut merged, value);
+
+    Ok(merged)
+}
+
+/// Expand the pattern relative to `dir`, a pattern without glob
+/// characters must match an existing file.
+fn expand(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let full = dir.join(pattern);
+
+    if !is_glob(pattern) {
+        return Ok(vec![full]);
+    }
+
+    let full = full.to_string_lossy();
+    let paths = glob::glob(&full).map_err(|err| Error::Pattern {
+        pattern: pattern.to_string(),
+        err,
+    })?;
+
+    let mut paths = paths
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// The pattern has glob characters
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Merge `overlay` into `base`, values of `overlay` win
+pub fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        // an empty file
+        (_, Value::Null) => {}
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_values() {
+        let mut base: Value = serde_yaml::from_str(
+            r#"
+log:
+  level: info
+  timestamp: true
+thp:
+  listen:
+    - 127.0.0.1:1080
+"#,
+        )
+        .unwrap();
+        let overlay: Value = serde_yaml::from_str(
+            r#"
+log:
+  level: debug
+thp:
+  listen:
+    - 127.0.0.1:1081
+worker: 2
+"#,
+        )
+        .unwrap();
+
+        merge(&mut base, overlay);
+
+        assert_eq!(base["log"]["level"], Value::from("debug"));
+        assert_eq!(base["log"]["timestamp"], Value::from(true));
+        assert_eq!(base["thp"]["listen"].as_sequence().unwrap().len(), 1);
+        assert_eq!(base["thp"]["listen"][0], Value::from("127.0.0.1:1081"));
+        assert_eq!(base["worker"], Value::from(2));
+    }
+
+    #[test]
+    fn include_files() {
+        let dir = std::env::temp_dir().join(format!("roxy-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        std::fs::write(
+            dir.join("conf.d/a.yaml"),
+            "worker: 1\nlog:\n  level: warn\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("conf.d/b.yaml"), "worker: 2\n").unwrap();
+        std::fs::write(
+            dir.join("config.yaml"),
+            "include: conf.d/*.yaml\nlog:\n  level: info\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("cycle.yaml"), "include: cycle.yaml\n").unwrap();
+
+        let (value, sources) = load(&dir.join("config.yaml")).unwrap();
+        assert_eq!(
+            sources,
+            [
+                dir.join("config.yaml"),
+                dir.join("conf.d/*.yaml"),
+                dir.join("conf.d/a.yaml"),
+                dir.join("conf.d/b.yaml")
+            ]
+        );
+        assert_eq!(value["worker"], Value::from(2));
+        assert_eq!(value["log"]["level"], Value::from("info"));
+        assert!(value.get(INCLUDE).is_none());
+
+        assert!(matches!(
+            load(&dir.join("cycle.yaml")),
+            Err(Error::Cycle(_))
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+//! Configs have a `version`, a config without it is version 1. Older
+//! layouts are migrated to the current one step by step when loading,
+//! every change is reported as a warning, so users can update the file.
+
+use serde_yaml::{Mapping, Value};
+
+/// Version of the current layout
+pub const VERSION: u64 = 3;
+
+const KEY: &str = "version";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("version must be a positive integer")]
+    Invalid,
+    #[error(
+        "version {0} is newer than supported version {}, upgrade roxy",
+        VERSION
+    )]
+    Unsupported(u64),
+}
+
+/// Migrate a config of `from` to `from + 1`
+struct Migration {
+    from: u64,
+    migrate: fn(&mut Mapping, &mut Vec<String>),
+}
+
+const MIGRATIONS: [Migration; 2] = [
+    Migration {
+        from: 1,
+        migrate: v1_to_v2,
+    },
+    Migration {
+        from: 2,
+        migrate: v2_to_v3,
+    },
+];
+
+/// Migrate `value` to the current layout, and set its version
+pub fn migrate(value: &mut Value, warnings: &mut Vec<String>) -> Result<(), Error> {
+    let map = match value.as_mapping_mut() {
+        Some(map) => map,
+        None => return Ok(()),
+    };
+
+    let version = match map.get(KEY) {
+        None => 1,
+        Some(version) => version.as_u64().filter(|v| *v > 0).ok_or(Error::Invalid)?,
+    };
+    if version > VERSION {
+        return Err(Error::Unsupported(version));
+    }
+
+    let before = warnings.len();
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= version) {
+        (migration.migrate)(map, warnings);
+    }
+    if warnings.len() > before {
+        warnings.push(format!(
+            "config is migrated from version {} to {}, update it and set \"version: {}\"",
+            version, VERSION, VERSION
+        ));
+    }
+
+    map.insert(KEY.into(), VERSION.into());
+
+    Ok(())
+}
+
+/// `dns.hijack.hijack` is renamed to `dns.hijack.address`
+fn v1_to_v2(config: &mut Mapping, warnings: &mut Vec<String>) {
+    let hijack = config
+        .get_mut("dns")
+        .and_then(|dns| dns.get_mut("hijack"))
+        .and_then(Value::as_mapping_mut);
+
+    if let Some(hijack) = hijack {
+        if let Some(address) = hijack.remove("hijack") {
+            hijack.insert("address".into(), address);
+            warnings.push("\"dns.hijack.hijack\" is renamed to \"dns.hijack.address\"".to_string());
+        }
+    }
+}
+
+/// `thp.listen` is replaced by `inbounds`, an inbound per address
+fn v2_to_v3(config: &mut Mapping, warnings: &mut Vec<String>) {
+    let thp = match config.remove("thp") {
+        Some(thp) => thp,
+        None => return,
+    };
+
+    let listen = match thp.get("listen") {
+        Some(Value::Sequence(listen)) => listen.clone(),
+        Some(listen @ Value::String(_)) => vec![listen.clone()],
+        _ => vec![],
+    };
+    let inbounds = listen
+        .iter()
+        .enumerate()
+        .map(|(index, addr)| {
+            let name = if listen.len() == 1 {
+                "thp".to_string()
+            } else {
+                format!("thp-{}", index)
+            };
+
+            let mut inbound = Mapping::new();
+            inbound.insert("name".into(), name.into());
+            inbound.insert("protocol".into(), "thp".into());
+            inbound.insert("listen".into(), addr.clone());
+            Value::Mapping(inbound)
+        })
+        .collect::<Vec<_>>();
+
+    if !inbounds.is_empty() {
+        let existing = config
+            .entry("inbounds".into())
+            .or_insert_with(|| Value::Sequence(vec![]));
+        if let Value::Sequence(existing) = existing {
+            existing.extend(inbounds);
+        }
+    }
+    warnings.push("\"thp\" is replaced by \"inbounds\" with protocol thp".to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_v1() {
+        let mut value: Value = serde_yaml::from_str(
+            r#"
+dns:
+  hijack:
+    endpoint: https://example.com/gfw.txt
+    hijack: 127.0.0.1
+"#,
+        )
+        .unwrap();
+        let mut warnings = vec![];
+
+        migrate(&mut value, &mut warnings).unwrap();
+        assert_eq!(value[KEY], Value::from(VERSION));
+        assert_eq!(value["dns"]["hijack"]["address"], Value::from("127.0.0.1"));
+        assert!(value["dns"]["hijack"].get("hijack").is_none());
+        assert_eq!(warnings.len(), 2);
+
+        // nothing changed, nothing to warn
+        let mut warnings = vec![];
+        migrate(&mut value, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+
+        let mut value: Value = serde_yaml::from_str("version: 4").unwrap();
+        assert!(matches!(
+            migrate(&mut value, &mut warnings),
+            Err(Error::Unsupported(4))
+        ));
+        let mut value: Value = serde_yaml::from_str("version: latest").unwrap();
+        assert!(matches!(
+            migrate(&mut value, &mut warnings),
+            Err(Error::Invalid)
+        ));
+    }
+
+    #[test]
+    fn migrate_v2() {
+        let mut value: Value = serde_yaml::from_str(
+            r#"
+version: 2
+thp:
+  listen:
+    - 127.0.0.1:1080
+    - "[::1]:1080"
+"#,
+        )
+        .unwrap();
+        let mut warnings = vec![];
+
+        migrate(&mut value, &mut warnings).unwrap();
+        assert!(value.get("thp").is_none());
+        assert_eq!(value["inbounds"][0]["name"], Value::from("thp-0"));
+        assert_eq!(value["inbounds"][0]["protocol"], Value::from("thp"));
+        assert_eq!(value["inbounds"][1]["listen"], Value::from("[::1]:1080"));
+        assert_eq!(warnings.len(), 2);
+    }
+}
@@ -0,0 +1,169 @@
+//! Verify minisign signatures, both legacy (`Ed`) and prehashed (`ED`)
+//! signatures are supported, see https://jedisct1.github.io/minisign/
+
+use std::str::FromStr;
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+const ALG_LEGACY: &[u8; 2] = b"Ed";
+const ALG_PREHASHED: &[u8; 2] = b"ED";
+const TRUSTED_COMMENT: &str = "trusted comment: ";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum Error {
+    #[error("invalid public key")]
+    InvalidPublicKey,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("signature is made by another key")]
+    KeyMismatch,
+    #[error("signature verification failed")]
+    Mismatch,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublicKey {
+    id: [u8; 8],
+    key: [u8; 32],
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    /// The base64 line, or the whole `.pub` file with the untrusted comment
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let line = s
+            .lines()
+            .map(str::trim)
+            .rfind(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+            .ok_or(Error::InvalidPublicKey)?;
+        let data = base64::decode(line).map_err(|_| Error::InvalidPublicKey)?;
+        if data.len() != 42 || &data[..2] != ALG_LEGACY {
+            return Err(Error::InvalidPublicKey);
+        }
+
+        let mut id = [0u8; 8];
+        let mut key = [0u8; 32];
+        id.copy_from_slice(&data[2..10]);
+        key.copy_from_slice(&data[10..]);
+
+        Ok(PublicKey { id, key })
+    }
+}
+
+impl PublicKey {
+    /// Verify `content` with the content of a `.minisig` file
+    pub fn verify(&self, content: &[u8], signature: &str) -> Result<(), Error> {
+        let mut lines = signature
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with("untrusted comment:"));
+
+        let sig = lines
+            .next()
+            .and_then(|line| base64::decode(line).ok())
+            .filter(|sig| sig.len() == 74)
+            .ok_or(Error::InvalidSignature)?;
+        let comment = lines
+            .next()
+            .and_then(|line| line.strip_prefix(TRUSTED_COMMENT))
+            .ok_or(Error::InvalidSignature)?;
+        let global = lines
+            .next()
+            .and_then(|line| base64::decode(line).ok())
+            .filter(|sig| sig.len() == 64)
+            .ok_or(Error::InvalidSignature)?;
+
+        let (alg, rest) = sig.split_at(2);
+        let (id, sig) = rest.split_at(8);
+        if id != self.id {
+            return Err(Error::KeyMismatch);
+        }
+
+        let key = UnparsedPublicKey::new(&ED25519, &self.key);
+        let verified = if alg == ALG_PREHASHED {
+            key.verify(blake2b_simd::blake2b(content).as_bytes(), sig)
+        } else if alg == ALG_LEGACY {
+            key.verify(content, sig)
+        } else {
+            return Err(Error::InvalidSignature);
+        };
+        verified.map_err(|_| Error::Mismatch)?;
+
+        // the trusted comment is signed along with the signature
+        let mut signed = sig.to_vec();
+        signed.extend_from_slice(comment.as_bytes());
+        key.verify(&signed, &global).map_err(|_| Error::Mismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::*;
+
+    /// Sign like minisign does
+    fn sign(pair: &Ed25519KeyPair, id: [u8; 8], content: &[u8], prehashed: bool) -> String {
+        let (alg, sig) = if prehashed {
+            (
+                ALG_PREHASHED,
+                pair.sign(blake2b_simd::blake2b(content).as_bytes()),
+            )
+        } else {
+            (ALG_LEGACY, pair.sign(content))
+        };
+        let comment = "timestamp:1660000000\tfile:config.yaml";
+
+        let mut line = alg.to_vec();
+        line.extend_from_slice(&id);
+        line.extend_from_slice(sig.as_ref());
+
+        let mut signed = sig.as_ref().to_vec();
+        signed.extend_from_slice(comment.as_bytes());
+        let global = pair.sign(&signed);
+
+        format!(
+            "untrusted comment: signature from minisign secret key\n{}\n{}{}\n{}\n",
+            base64::encode(line),
+            TRUSTED_COMMENT,
+            comment,
+            base64::encode(global.as_ref())
+        )
+    }
+
+    #[test]
+    fn verify() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let id = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        let mut public = ALG_LEGACY.to_vec();
+        public.extend_from_slice(&id);
+        public.extend_from_slice(pair.public_key().as_ref());
+        let key: PublicKey = format!(
+            "untrusted comment: minisign public key\n{}\n",
+            base64::encode(public)
+        )
+        .parse()
+        .unwrap();
+
+        let content = b"log:\n  level: info\n";
+        for prehashed in [false, true] {
+            let signature = sign(&pair, id, content, prehashed);
+            assert_eq!(key.verify(content, &signature), Ok(()));
+            assert_eq!(
+                key.verify(b"log:\n  level: debug\n", &signature),
+                Err(Error::Mismatch)
+            );
+
+            let tampered = signature.replace("file:config.yaml", "file:other.yaml");
+            assert_eq!(key.verify(content, &tampered), Err(Error::Mismatch));
+        }
+
+        let other = sign(&pair, [0; 8], content, true);
+        assert_eq!(key.verify(content, &other), Err(Error::KeyMismatch));
+        assert_eq!("RWQ".parse::<PublicKey>(), Err(Error::InvalidPublicKey));
+    }
+}
@@ -0,0 +1,494 @@
+mod builder;
+mod check;
+mod convert;
+mod env;
+mod format;
+mod include;
+mod migrate;
+mod minisign;
+mod overrides;
+mod profile;
+mod remote;
+mod secret;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Formatter;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serializer};
+use tracing::Level;
+
+pub use builder::ConfigBuilder;
+pub use check::Problem;
+pub use convert::Kind;
+pub(crate) use convert::{clash_proxy, ss_url};
+pub use migrate::VERSION;
+pub use overrides::{Error as OverrideError, Override};
+pub use profile::{Profiles, ProfilesStat};
+pub use remote::RemoteConfig;
+
+use crate::log::{
+    AccessConfig, FileConfig, Format as LogFormat, OtlpConfig, SamplingConfig, StatsdConfig,
+    SyslogConfig,
+};
+use crate::relay::inbound;
+use crate::route::{GeoIpConfig, Rule, ScriptConfig};
+use crate::sandbox::SandboxConfig;
+use crate::upgrade::UpgradeConfig;
+use crate::{controller, dns, upstream};
+
+const fn default_timestamp() -> bool {
+    true
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Log {
+    #[serde(
+        deserialize_with = "deserialize_log_level",
+        serialize_with = "serialize_log_level"
+    )]
+    pub level: Level,
+
+    /// Per-module directives, e.g. `roxy::dns=debug,shadowsocks=warn`,
+    /// it can be changed at runtime with controller's `PUT /logging`
+    #[serde(default)]
+    pub filter: Option<String>,
+
+    /// `text` or `json`
+    #[serde(default)]
+    pub format: LogFormat,
+
+    #[serde(default = "default_timestamp")]
+    pub timestamp: bool,
+
+    /// Trace 1 in N connections and DNS queries at debug level
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+
+    /// Write to a rotated file instead of stdout
+    #[serde(default)]
+    pub file: Option<FileConfig>,
+
+    /// Send to a syslog daemon instead of stdout
+    #[serde(default)]
+    pub syslog: Option<SyslogConfig>,
+
+    /// Send to journald with structured fields instead of stdout
+    #[serde(default)]
+    pub journald: bool,
+
+    /// Per-connection access records, apart from diagnostic logs
+    #[serde(default)]
+    pub access: Option<AccessConfig>,
+
+    /// Export spans and metrics to an OpenTelemetry collector
+    #[serde(default)]
+    pub otlp: Option<OtlpConfig>,
+
+    /// Push metrics to a StatsD server
+    #[serde(default)]
+    pub statsd: Option<StatsdConfig>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: Level::INFO,
+            filter: None,
+            format: LogFormat::default(),
+            timestamp: true,
+            sampling: SamplingConfig::default(),
+            file: None,
+            syslog: None,
+            journald: false,
+            access: None,
+            otlp: None,
+            statsd: None,
+        }
+    }
+}
+
+/// A fully commented config with every section, see `roxy init`
+pub const TEMPLATE: &str = include_str!("../../examples/config.yaml");
+
+const fn default_version() -> u64 {
+    VERSION
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    /// Version of the layout, older ones are migrated when loading
+    #[serde(default = "default_version")]
+    pub version: u64,
+
+    /// Worker threads for tokio runtime, if it is not set,
+    /// use num_cpu::get()
+    pub worker: Option<usize>,
+
+    #[serde(default, with = "crate::serde::socket_addrs")]
+    pub resolvers: Vec<SocketAddr>,
+
+    /// Run as this user after startup, see `privilege`
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// Run as this group, the primary group of `user` by default
+    #[serde(default)]
+    pub group: Option<String>,
+
+    /// Configuration for tracing logs
+    #[serde(default)]
+    pub log: Log,
+
+    #[cfg(feature = "dns")]
+    pub dns: dns::Config,
+
+    // TODO: make controller optional
+    pub controller: Option<controller::Config>,
+
+    pub upstream: upstream::Config,
+
+    /// Listeners accepting connections from clients
+    #[serde(default)]
+    pub inbounds: Vec<inbound::Config>,
+
+    /// Routing rules of connections of all inbounds, see `route`
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+
+    /// The country database of `GEOIP` rules
+    #[serde(default)]
+    pub geoip: Option<GeoIpConfig>,
+
+    /// The autonomous system database of `IP-ASN` rules
+    #[serde(default)]
+    pub asn: Option<GeoIpConfig>,
+
+    /// Routing script asked before `rules`
+    #[serde(default)]
+    pub script: Option<ScriptConfig>,
+
+    /// Active profiles, they are merged on top of the rest in order
+    #[serde(default, deserialize_with = "crate::serde::list")]
+    pub profile: Vec<String>,
+
+    /// Named overlays of the config, see `profile`
+    #[serde(default)]
+    pub profiles: BTreeMap<String, serde_yaml::Value>,
+
+    /// Fetch the config from a remote endpoint, it's merged on top of
+    /// this one
+    #[serde(default)]
+    pub remote: Option<RemoteConfig>,
+
+    /// Hand listeners over to a new binary on `SIGUSR2`
+    #[serde(default)]
+    pub upgrade: Option<UpgradeConfig>,
+
+    /// seccomp and Landlock, both are enabled by default
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+
+    /// Parts can't be converted when loading other kinds of config,
+    /// e.g. Clash's, they should be logged once the logger is ready.
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+
+    /// Files the config is loaded from, the config file, included files
+    /// and include globs, they are watched for hot reload
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("load config failed, {0}")]
+    Include(#[from] include::Error),
+
+    #[error("deserialize config failed, {0}")]
+    Deserialize(#[from] serde_path_to_error::Error<serde_yaml::Error>),
+
+    #[error("interpolate config failed, {0}")]
+    Env(#[from] env::Error),
+
+    #[error("resolve secret failed, {0}")]
+    Secret(#[from] secret::Error),
+
+    #[error("migrate config failed, {0}")]
+    Migrate(#[from] migrate::Error),
+
+    #[error("override config failed, {0}")]
+    Override(#[from] overrides::Error),
+
+    #[error("apply profile failed, {0}")]
+    Profile(#[from] profile::Error),
+
+    #[error("load remote config failed, {0}")]
+    Remote(#[from] remote::Error),
+
+    #[error("convert config failed, {0}")]
+    Convert(#[from] convert::Error),
+
+    #[error("{0:?} is neither a Clash nor a sing-box config")]
+    UnknownKind(PathBuf),
+
+    #[error("serialize config failed, {0}")]
+    Serialize(serde_yaml::Error),
+
+    #[error("inbound name \"{0}\" is used more than once")]
+    DuplicateInbound(String),
+}
+
+/// Sections changed between two configs
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub log: bool,
+    pub dns: bool,
+    pub controller: bool,
+    pub upstream: bool,
+    pub inbounds: bool,
+    pub rules: bool,
+
+    /// `worker`, `resolvers`, `user`, `group`, `remote`, `geoip`, `asn`,
+    /// `upgrade`, `sandbox`, log sinks, `log.sampling`, `log.access`, `log.otlp` and
+    /// `log.statsd` can't be changed without restarting
+    pub restart: bool,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        *self == Diff::default()
+    }
+}
+
+impl Config {
+    /// Path of the config file, `ROXY_CONFIG` or the first existing one of
+    /// `config.yaml`, `config.yml`, `config.toml` and `config.json`
+    pub fn path() -> PathBuf {
+        if let Ok(path) = std::env::var("ROXY_CONFIG") {
+            return PathBuf::from(path);
+        }
+
+        ["config.yaml", "config.yml", "config.toml", "config.json"]
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from("config.yaml"))
+    }
+
+    /// Build a config in code instead of loading a file
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    pub fn load() -> Result<Self, Error> {
+        Self::load_from(Self::path())
+    }
+
+    pub fn load_from(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::load_with(path.as_ref(), &[], &mut |_| {})
+    }
+
+    /// Load the config and apply `overrides` from the command line, see
+    /// `roxy --set`
+    pub fn load_with_overrides(
+        path: impl AsRef<Path>,
+        overrides: &[Override],
+    ) -> Result<Self, Error> {
+        Self::load_with(path.as_ref(), overrides, &mut |_| {})
+    }
+
+    /// Load the config, `unknown` is called with the path of every
+    /// field which is not recognized.
+    fn load_with<F>(path: &Path, overrides: &[Override], unknown: &mut F) -> Result<Self, Error>
+    where
+        F: FnMut(serde_ignored::Path),
+    {
+        let (mut value, sources) = include::load(path)?;
+        let mut warnings = vec![];
+        if let Some(kind) = Kind::detect(&value) {
+            let (converted, dropped) = kind.convert(&value)?;
+            value = converted;
+            warnings = dropped;
+        }
+        migrate::migrate(&mut value, &mut warnings)?;
+        // the remote config is fetched with its url and key
+        secret::resolve_section(&mut value, "remote")?;
+        if let Some(remote) = remote::section(&value)? {
+            remote::merge_cached(&remote, &mut value)?;
+        }
+        env::interpolate(&mut value)?;
+        profile::apply(&mut value, overrides)?;
+        overrides::apply(&mut value, overrides)?;
+        secret::resolve(&mut value)?;
+
+        let de = serde_ignored::Deserializer::new(value, unknown);
+        let mut cfg: Config = serde_path_to_error::deserialize(de)?;
+        cfg.warnings = warnings;
+        cfg.sources = sources;
+
+        // inbounds are identified by name on hot reload
+        let mut names = HashSet::new();
+        if let Some(dup) = cfg.inbounds.iter().find(|ic| !names.insert(&ic.name)) {
+            return Err(Error::DuplicateInbound(dup.name.clone()));
+        }
+
+        Ok(cfg)
+    }
+
+    /// Convert a Clash or sing-box config to roxy's, returns it in YAML
+    /// and warnings of dropped parts, see `roxy convert`
+    pub fn convert(path: impl AsRef<Path>) -> Result<(String, Vec<String>), Error> {
+        let path = path.as_ref();
+        let (value, _) = include::load(path)?;
+        let kind = Kind::detect(&value).ok_or_else(|| Error::UnknownKind(path.to_path_buf()))?;
+
+        let (converted, warnings) = kind.convert(&value)?;
+        let yaml = serde_yaml::to_string(&converted).map_err(Error::Serialize)?;
+
+        Ok((yaml, warnings))
+    }
+
+    /// Fetch the remote config to its cache, returns true if it changed,
+    /// then the config should be loaded again.
+    pub async fn fetch_remote(&self, resolver: resolver::Resolver) -> Result<bool, Error> {
+        match &self.remote {
+            Some(remote) => Ok(remote.fetch(resolver).await?),
+            None => Ok(false),
+        }
+    }
+
+    pub fn diff(&self, new: &Config) -> Diff {
+        Diff {
+            log: self.log != new.log,
+            #[cfg(feature = "dns")]
+            dns: self.dns != new.dns,
+            #[cfg(not(feature = "dns"))]
+            dns: false,
+            controller: self.controller != new.controller,
+            upstream: self.upstream != new.upstream,
+            inbounds: self.inbounds != new.inbounds,
+            rules: self.rules != new.rules || self.script != new.script,
+            restart: self.worker != new.worker
+                || self.resolvers != new.resolvers
+                || self.user != new.user
+                || self.group != new.group
+                || self.remote != new.remote
+                || self.geoip != new.geoip
+                || self.asn != new.asn
+                || self.upgrade != new.upgrade
+                || self.sandbox != new.sandbox
+                || self.log.sampling != new.log.sampling
+                || self.log.file != new.log.file
+                || self.log.syslog != new.log.syslog
+                || self.log.journald != new.log.journald
+                || self.log.access != new.log.access
+                || self.log.otlp != new.log.otlp
+                || self.log.statsd != new.log.statsd,
+        }
+    }
+
+    pub fn worker(&self) -> usize {
+        if let Some(worker) = self.worker {
+            worker
+        } else {
+            num_cpus::get()
+        }
+    }
+}
+
+fn deserialize_log_level<'de, D>(deserializer: D) -> Result<Level, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct Visitor {}
+
+    impl<'de> serde::de::Visitor<'de> for Visitor {
+        type Value = Level;
+
+        fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+            formatter.write_str("trace, debug, info, warn and error")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Level::from_str(v)
+                .map_err(|err| serde::de::Error::custom(format!("invalid level {}", err)))
+        }
+    }
+
+    deserializer.deserialize_any(Visitor {})
+}
+
+fn serialize_log_level<S>(l: &Level, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_str(l.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+resolvers:
+  - 1.1.1.1:53
+dns:
+  listen: 127.0.0.1:53
+  upstream:
+    nameservers:
+      - 1.1.1.1:53
+upstream:
+  check:
+    timeout: 5s
+    interval: 1m
+  provider:
+    endpoint: https://example.com/servers
+    interval: 1h
+inbounds:
+  - name: lan
+    protocol: thp
+    listen: 127.0.0.1:1080
+"#;
+
+    #[test]
+    fn template() {
+        let path = std::env::temp_dir().join(format!("roxy-template-{}.yaml", std::process::id()));
+        std::fs::write(&path, TEMPLATE).unwrap();
+
+        let (config, problems) = Config::check(&path, &[]).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(config.version, VERSION);
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn diff() {
+        let old: Config = serde_yaml::from_str(CONFIG).unwrap();
+        let new: Config = serde_yaml::from_str(CONFIG).unwrap();
+        assert!(old.diff(&new).is_empty());
+
+        let new: Config =
+            serde_yaml::from_str(&CONFIG.replace("127.0.0.1:1080", "127.0.0.1:1081")).unwrap();
+        assert_eq!(
+            old.diff(&new),
+            Diff {
+                inbounds: true,
+                ..Default::default()
+            }
+        );
+
+        let new: Config = serde_yaml::from_str(&CONFIG.replace("1h", "2h")).unwrap();
+        let diff = old.diff(&new);
+        assert!(diff.upstream);
+        assert!(!diff.dns);
+        assert!(!diff.restart);
+    }
+}
@@ -0,0 +1,166 @@
+//! Override config values from the command line, e.g.
+//! `--set dns.listen=0.0.0.0:53`. Values are parsed as YAML, so
+//! `--set thp.listen=[0.0.0.0:1080]` sets a list, and missing mappings
+//! on the path are created. A number indexes a list, e.g. `thp.listen.0`.
+
+use std::str::FromStr;
+
+use serde_yaml::{Mapping, Value};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("override \"{0}\" must be like path.to.field=value")]
+    Invalid(String),
+    #[error("value of \"{path}\" is not valid, {err}")]
+    Value {
+        path: String,
+        err: serde_yaml::Error,
+    },
+    #[error("\"{path}\" can't be set, \"{parent}\" is neither a mapping nor a list")]
+    NotContainer { path: String, parent: String },
+    #[error("\"{path}\" can't be set, index {index} is out of range")]
+    OutOfRange { path: String, index: usize },
+}
+
+/// A value set by `--set path=value`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Override {
+    path: String,
+    value: Value,
+}
+
+impl Override {
+    pub fn new(path: impl Into<String>, value: Value) -> Self {
+        Self {
+            path: path.into(),
+            value,
+        }
+    }
+
+    pub(super) fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub(super) fn value(&self) -> &Value {
+        &self.value
+    }
+
+    fn apply(&self, config: &mut Value) -> Result<(), Error> {
+        let mut current = config;
+        let mut visited = vec![];
+
+        for key in self.path.split('.') {
+            if current.is_null() {
+                *current = Value::Mapping(Mapping::new());
+            }
+
+            current = match current {
+                Value::Mapping(map) => {
+                    if !map.contains_key(key) {
+                        map.insert(key.into(), Value::Null);
+                    }
+                    map.get_mut(key).expect("inserted above")
+                }
+                Value::Sequence(seq) if key.parse::<usize>().is_ok() => {
+                    let index = key.parse::<usize>().expect("checked above");
+                    seq.get_mut(index).ok_or_else(|| Error::OutOfRange {
+                        path: self.path.clone(),
+                        index,
+                    })?
+                }
+                _ => {
+                    return Err(Error::NotContainer {
+                        path: self.path.clone(),
+                        parent: visited.join("."),
+                    })
+                }
+            };
+            visited.push(key);
+        }
+
+        *current = self.value.clone();
+
+        Ok(())
+    }
+}
+
+impl FromStr for Override {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, value) = s
+            .split_once('=')
+            .filter(|(path, _)| !path.is_empty() && !path.split('.').any(str::is_empty))
+            .ok_or_else(|| Error::Invalid(s.to_string()))?;
+
+        let value = if value.is_empty() {
+            Value::String(String::new())
+        } else {
+            serde_yaml::from_str(value).map_err(|err| Error::Value {
+                path: path.to_string(),
+                err,
+            })?
+        };
+
+        Ok(Override::new(path, value))
+    }
+}
+
+/// Apply overrides in order, so the last one wins
+pub fn apply(config: &mut Value, overrides: &[Override]) -> Result<(), Error> {
+    for o in overrides {
+        o.apply(config)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_overrides() {
+        let mut config: Value = serde_yaml::from_str(
+            r#"
+log:
+  level: info
+thp:
+  listen:
+    - 127.0.0.1:1080
+"#,
+        )
+        .unwrap();
+
+        let overrides = [
+            "log.level=debug",
+            "dns.listen=0.0.0.0:53",
+            "thp.listen.0=0.0.0.0:1080",
+            "worker=2",
+            "controller.secret=",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect::<Vec<Override>>();
+        apply(&mut config, &overrides).unwrap();
+
+        assert_eq!(config["log"]["level"], Value::from("debug"));
+        assert_eq!(config["dns"]["listen"], Value::from("0.0.0.0:53"));
+        assert_eq!(config["thp"]["listen"][0], Value::from("0.0.0.0:1080"));
+        assert_eq!(config["worker"], Value::from(2));
+        assert_eq!(config["controller"]["secret"], Value::from(""));
+
+        let invalid: Override = "log.level.x=debug".parse().unwrap();
+        assert!(matches!(
+            invalid.apply(&mut config),
+            Err(Error::NotContainer { .. })
+        ));
+        let invalid: Override = "thp.listen.3=0.0.0.0:1080".parse().unwrap();
+        assert!(matches!(
+            invalid.apply(&mut config),
+            Err(Error::OutOfRange { .. })
+        ));
+        assert!("log.level".parse::<Override>().is_err());
+        assert!("log..level=info".parse::<Override>().is_err());
+    }
+}
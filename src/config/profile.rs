@@ -0,0 +1,222 @@
+//! Named profiles in one config, e.g. `home` and `travel` with their own
+//! upstream servers and DNS rules. A profile is an overlay, it's merged on
+//! top of the rest of the config, and several ones can be stacked.
+//!
+//! ```yaml
+//! profile: home, adblock
+//! profiles:
+//!   home:
+//!     upstream:
+//!       servers: [ss://...]
+//!   travel:
+//!     upstream:
+//!       provider: {...}
+//!   adblock:
+//!     dns:
+//!       reject: {...}
+//! ```
+//!
+//! The active profiles are selected by `profile`, `--profile` at startup
+//! or `PUT /profile` of the controller, which reloads the config.
+
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_yaml::Value;
+use tokio::sync::Notify;
+
+use super::include::merge;
+use super::{Config, Override};
+
+const KEY: &str = "profile";
+const PROFILES: &str = "profiles";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("profile must be a name or a list of names")]
+    Invalid,
+    #[error("profile \"{0}\" is not defined")]
+    Unknown(String),
+    #[error("profile \"{0}\" must be a mapping")]
+    NotMapping(String),
+}
+
+/// Names in a string separated by commas or whitespace, or a list
+fn names(value: &Value) -> Result<Vec<String>, Error> {
+    match value {
+        Value::Null => Ok(vec![]),
+        Value::String(s) => Ok(s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(ToString::to_string)
+            .collect()),
+        Value::Sequence(seq) => seq
+            .iter()
+            .map(|name| name.as_str().map(ToString::to_string).ok_or(Error::Invalid))
+            .collect(),
+        _ => Err(Error::Invalid),
+    }
+}
+
+/// Merge the selected profiles on top of `value` in order, an override
+/// of `profile` takes precedence over the one in the config.
+pub fn apply(value: &mut Value, overrides: &[Override]) -> Result<(), Error> {
+    let selected = overrides
+        .iter()
+        .rev()
+        .find(|o| o.path() == KEY)
+        .map(Override::value)
+        .or_else(|| value.get(KEY));
+    let selected = match selected {
+        Some(selected) => names(selected)?,
+        None => return Ok(()),
+    };
+
+    for name in selected {
+        let mut profile = value
+            .get(PROFILES)
+            .and_then(|profiles| profiles.get(&name))
+            .cloned()
+            .ok_or_else(|| Error::Unknown(name.clone()))?;
+
+        match &mut profile {
+            Value::Mapping(map) => {
+                // profiles can't select other profiles
+                map.remove(KEY);
+                map.remove(PROFILES);
+            }
+            Value::Null => continue,
+            _ => return Err(Error::NotMapping(name)),
+        }
+
+        merge(value, profile);
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct State {
+    /// Profiles of the running config
+    active: Vec<String>,
+    available: Vec<String>,
+
+    /// Selected by the controller, they replace the ones in the config
+    /// when it's loaded
+    selected: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct ProfilesStat {
+    pub active: Vec<String>,
+    pub profiles: Vec<String>,
+}
+
+/// Profiles of the running config, shared with the controller so it can
+/// switch them.
+#[derive(Clone, Default)]
+pub struct Profiles {
+    state: Arc<Mutex<State>>,
+    changed: Arc<Notify>,
+}
+
+impl Profiles {
+    /// Record the profiles of a newly applied config
+    pub fn update(&self, config: &Config) {
+        let mut state = self.state.lock();
+        state.active = config.profile.clone();
+        state.available = config.profiles.keys().cloned().collect();
+    }
+
+    /// Switch to `names`, or back to the ones in the config if it's None.
+    /// Returns false if any of them is not defined.
+    pub fn select(&self, names: Option<Vec<String>>) -> bool {
+        {
+            let mut state = self.state.lock();
+            if let Some(names) = &names {
+                if names.iter().any(|name| !state.available.contains(name)) {
+                    return false;
+                }
+            }
+
+            state.selected = names;
+        }
+
+        self.changed.notify_one();
+        true
+    }
+
+    /// The override to apply when loading the config, if profiles are
+    /// selected by the controller
+    pub fn selected(&self) -> Option<Override> {
+        self.state
+            .lock()
+            .selected
+            .as_ref()
+            .map(|names| Override::new(KEY, names.clone().into()))
+    }
+
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    pub fn stat(&self) -> ProfilesStat {
+        let state = self.state.lock();
+
+        ProfilesStat {
+            active: state.active.clone(),
+            profiles: state.available.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+log:
+  level: info
+upstream:
+  servers:
+    - ss://base
+profile: home
+profiles:
+  home:
+    upstream:
+      servers:
+        - ss://home
+  travel:
+    log:
+      level: debug
+    upstream:
+      servers:
+        - ss://travel
+  verbose:
+    log:
+      level: trace
+"#;
+
+    #[test]
+    fn apply_profiles() {
+        let mut value: Value = serde_yaml::from_str(CONFIG).unwrap();
+        apply(&mut value, &[]).unwrap();
+        assert_eq!(value["upstream"]["servers"][0], Value::from("ss://home"));
+        assert_eq!(value["log"]["level"], Value::from("info"));
+
+        // overlays are applied in order
+        let mut value: Value = serde_yaml::from_str(CONFIG).unwrap();
+        let overrides = [Override::new(KEY, "travel, verbose".into())];
+        apply(&mut value, &overrides).unwrap();
+        assert_eq!(value["upstream"]["servers"][0], Value::from("ss://travel"));
+        assert_eq!(value["log"]["level"], Value::from("trace"));
+
+        let mut value: Value = serde_yaml::from_str(CONFIG).unwrap();
+        let overrides = [Override::new(KEY, "office".into())];
+        assert!(matches!(
+            apply(&mut value, &overrides),
+            Err(Error::Unknown(name)) if name == "office"
+        ));
+    }
+}
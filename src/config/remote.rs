@@ -0,0 +1,205 @@
+//! Load the config from a remote endpoint, e.g. for boxes managed by a
+//! fleet, the local config only needs `resolvers` and `remote`
+//!
+//! ```yaml
+//! remote:
+//!   url: https://config.example.com/roxy.yaml
+//!   public_key: RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3
+//!   interval: 10m
+//!   cache: /var/lib/roxy/remote.yaml
+//! ```
+//!
+//! The remote config must be signed by minisign, it and its signature
+//! are saved to `cache` after verification, and verified again when
+//! loading. The remote config is merged on top of the local one, so the
+//! local one can provide defaults.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use hyper::{StatusCode, Uri};
+use resolver::Resolver;
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use super::format::{self, Format};
+use super::include::merge;
+use super::minisign::{self, PublicKey};
+
+const KEY: &str = "remote";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid remote section, {0}")]
+    Invalid(serde_yaml::Error),
+    #[error("invalid public key, {0}")]
+    PublicKey(minisign::Error),
+    #[error("invalid uri \"{0}\"")]
+    InvalidUri(String),
+    #[error("fetch {uri} failed, {err}")]
+    Http { uri: String, err: hyper::Error },
+    #[error("fetch {uri} failed, unexpected status code {status}")]
+    Status { uri: String, status: StatusCode },
+    #[error("verify remote config failed, {0}")]
+    Verify(minisign::Error),
+    #[error("read {path:?} failed, {err}")]
+    Read { path: PathBuf, err: io::Error },
+    #[error("write {path:?} failed, {err}")]
+    Write { path: PathBuf, err: io::Error },
+    #[error("parse remote config failed, {0}")]
+    Parse(format::Error),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RemoteConfig {
+    pub url: String,
+
+    /// Url of the minisign signature, the default is `url` + `.minisig`
+    #[serde(default)]
+    pub signature: Option<String>,
+
+    /// Minisign public key, the base64 line of the `.pub` file
+    pub public_key: String,
+
+    /// Fetch the config periodically, changes are applied like hot reload
+    #[serde(default, with = "crate::serde::duration::option")]
+    pub interval: Option<Duration>,
+
+    /// Where the verified config is kept, it's used on startup if the
+    /// endpoint is not reachable
+    pub cache: PathBuf,
+}
+
+impl RemoteConfig {
+    fn signature_url(&self) -> String {
+        self.signature
+            .clone()
+            .unwrap_or_else(|| format!("{}.minisig", self.url))
+    }
+
+    fn signature_path(&self) -> PathBuf {
+        let mut path = self.cache.clone().into_os_string();
+        path.push(".minisig");
+        path.into()
+    }
+
+    pub(super) fn public_key(&self) -> Result<PublicKey, Error> {
+        PublicKey::from_str(&self.public_key).map_err(Error::PublicKey)
+    }
+
+    /// Fetch and verify the remote config, then save it to `cache`.
+    /// Returns false if it is not changed.
+    pub async fn fetch(&self, resolver: Resolver) -> Result<bool, Error> {
+        let key = self.public_key()?;
+        let client = crate::http::HttpClient::new(resolver);
+
+        let content = get(&client, &self.url).await?;
+        let signature = get(&client, &self.signature_url()).await?;
+        let signature = String::from_utf8_lossy(&signature);
+        key.verify(&content, &signature).map_err(Error::Verify)?;
+        // make sure it can be parsed, a broken one should not replace the cache
+        Format::detect(&self.cache, &content)
+            .parse(&content)
+            .map_err(Error::Parse)?;
+
+        // the signature is compared too, it's missing if the last write was
+        // interrupted
+        let cached = std::fs::read(&self.cache).ok();
+        let cached_signature = std::fs::read_to_string(self.signature_path()).ok();
+        if cached.as_deref() == Some(&content[..])
+            && cached_signature.as_deref() == Some(&signature[..])
+        {
+            return Ok(false);
+        }
+
+        // the content goes first, the signature completes the cache, a
+        // cache without it is ignored when loading
+        let _ = std::fs::remove_file(self.signature_path());
+        write(&self.cache, &content)?;
+        write(&self.signature_path(), signature.as_bytes())?;
+
+        Ok(true)
+    }
+}
+
+/// The `remote` section of `value`
+pub fn section(value: &Value) -> Result<Option<RemoteConfig>, Error> {
+    match value.get(KEY) {
+        None | Some(Value::Null) => Ok(None),
+        Some(section) => serde_yaml::from_value(section.clone())
+            .map(Some)
+            .map_err(Error::Invalid),
+    }
+}
+
+/// Merge the cached remote config on top of `value`, nothing happens if
+/// it has not been fetched completely yet.
+pub fn merge_cached(config: &RemoteConfig, value: &mut Value) -> Result<(), Error> {
+    let content = match std::fs::read(&config.cache) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(Error::Read {
+                path: config.cache.clone(),
+                err,
+            })
+        }
+    };
+    // the signature is written last, the cache is being written or the
+    // write is interrupted without it
+    let path = config.signature_path();
+    let signature = match std::fs::read_to_string(&path) {
+        Ok(signature) => signature,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(Error::Read { path, err }),
+    };
+    config
+        .public_key()?
+        .verify(&content, &signature)
+        .map_err(Error::Verify)?;
+
+    let mut remote = Format::detect(&config.cache, &content)
+        .parse(&content)
+        .map_err(Error::Parse)?;
+    // the remote config can't point to another one
+    if let Some(map) = remote.as_mapping_mut() {
+        map.remove(KEY);
+    }
+
+    merge(value, remote);
+
+    Ok(())
+}
+
+async fn get(client: &crate::http::HttpClient, uri: &str) -> Result<Vec<u8>, Error> {
+    let parsed = Uri::from_str(uri).map_err(|_| Error::InvalidUri(uri.to_string()))?;
+    let resp = client.get(parsed).await.map_err(|err| Error::Http {
+        uri: uri.to_string(),
+        err,
+    })?;
+
+    let (parts, body) = resp.into_parts();
+    if parts.status != StatusCode::OK {
+        return Err(Error::Status {
+            uri: uri.to_string(),
+            status: parts.status,
+        });
+    }
+
+    hyper::body::to_bytes(body)
+        .await
+        .map(|data| data.to_vec())
+        .map_err(|err| Error::Http {
+            uri: uri.to_string(),
+            err,
+        })
+}
+
+fn write(path: &Path, content: &[u8]) -> Result<(), Error> {
+    crate::fs::write_atomic(path, content).map_err(|err| Error::Write {
+        path: path.to_path_buf(),
+        err,
+    })
+}
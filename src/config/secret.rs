@@ -0,0 +1,230 @@
+//! Credentials can be kept out of the config file, every string field,
+//! and `servers`, has `_file` and `_env` variants, e.g.
+//!
+//! ```yaml
+//! controller:
+//!   secret_file: /run/secrets/controller
+//! upstream:
+//!   servers_env: ROXY_SERVERS
+//! ```
+//!
+//! Trailing newlines of files are trimmed, `servers` is split by
+//! whitespace, so a file can hold one url per line.
+
+use std::io;
+use std::path::PathBuf;
+
+use serde_yaml::{Mapping, Value};
+
+/// Fields are lists, values of `_file` and `_env` are split by whitespace
+const LISTS: [&str; 1] = ["servers"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("read {path:?} of \"{field}\" failed, {err}")]
+    Read {
+        field: String,
+        path: PathBuf,
+        err: io::Error,
+    },
+    #[error("environment variable \"{name}\" of \"{field}\" is not set")]
+    Undefined { field: String, name: String },
+    #[error("only one of \"{0}\", \"{0}_file\" and \"{0}_env\" can be set")]
+    Conflict(String),
+    #[error("\"{0}\" must be a string")]
+    NotString(String),
+}
+
+/// Replace `<field>_file` and `<field>_env` with `<field>`
+pub fn resolve(value: &mut Value) -> Result<(), Error> {
+    resolve_with(value, &|name| std::env::var(name).ok())
+}
+
+/// Like `resolve`, but only fields in the section `key`, for sections
+/// needed before the whole config is resolved, e.g. `remote`
+pub fn resolve_section(value: &mut Value, key: &str) -> Result<(), Error> {
+    match value.get_mut(key) {
+        Some(section) => resolve_value(section, key, &|name| std::env::var(name).ok()),
+        None => Ok(()),
+    }
+}
+
+fn resolve_with<F>(value: &mut Value, lookup: &F) -> Result<(), Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    resolve_value(value, "", lookup)
+}
+
+/// Resolve fields of all mappings in `value`, `path` is where it is,
+/// e.g. `inbounds[0].users`
+fn resolve_value<F>(value: &mut Value, path: &str, lookup: &F) -> Result<(), Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        Value::Mapping(map) => {
+            // only string values, keys of other mappings may be names of
+            // servers, e.g. `upstream.weights`
+            let fields = map
+                .iter()
+                .filter(|(_, value)| value.is_string())
+                .filter_map(|(key, _)| key.as_str())
+                .filter_map(|key| {
+                    key.strip_suffix("_file")
+                        .or_else(|| key.strip_suffix("_env"))
+                })
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            for field in fields {
+                resolve_field(map, &join(path, &field), &field, lookup)?;
+            }
+
+            for (key, value) in map.iter_mut() {
+                let key = key.as_str().unwrap_or_default();
+                resolve_value(value, &join(path, key), lookup)?;
+            }
+        }
+        Value::Sequence(seq) => {
+            for (i, value) in seq.iter_mut().enumerate() {
+                resolve_value(value, &format!("{}[{}]", path, i), lookup)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn resolve_field<F>(map: &mut Mapping, path: &str, field: &str, lookup: &F) -> Result<(), Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let file = map.remove(format!("{}_file", field));
+    let env = map.remove(format!("{}_env", field));
+
+    let secret = match (file, env) {
+        (None, None) => return Ok(()),
+        (Some(_), Some(_)) => return Err(Error::Conflict(path.to_string())),
+        (Some(file), None) => {
+            let file = PathBuf::from(as_str(&file, path, "_file")?);
+            let content = std::fs::read_to_string(&file).map_err(|err| Error::Read {
+                field: path.to_string(),
+                path: file,
+                err,
+            })?;
+            content.trim_end_matches(['\r', '\n']).to_string()
+        }
+        (None, Some(env)) => {
+            let name = as_str(&env, path, "_env")?;
+            lookup(name).ok_or_else(|| Error::Undefined {
+                field: path.to_string(),
+                name: name.to_string(),
+            })?
+        }
+    };
+
+    if map.contains_key(field) {
+        return Err(Error::Conflict(path.to_string()));
+    }
+
+    let secret = if LISTS.contains(&field) {
+        secret
+            .split_whitespace()
+            .map(Value::from)
+            .collect::<Vec<_>>()
+            .into()
+    } else {
+        Value::from(secret)
+    };
+    map.insert(field.into(), secret);
+
+    Ok(())
+}
+
+fn as_str<'a>(value: &'a Value, path: &str, suffix: &str) -> Result<&'a str, Error> {
+    value
+        .as_str()
+        .ok_or_else(|| Error::NotString(format!("{}{}", path, suffix)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_secrets() {
+        let file = std::env::temp_dir().join(format!("roxy-secret-{}", std::process::id()));
+        std::fs::write(&file, "s3cret\n").unwrap();
+
+        let mut value: Value = serde_yaml::from_str(&format!(
+            r#"
+controller:
+  secret_file: {}
+upstream:
+  servers_env: SERVERS
+  provider:
+    endpoint: https://example.com/sub
+  shadow_tls:
+    hk:
+      password_env: SHADOW_TLS
+  weights:
+    hk_file: 2
+inbounds:
+  - name: http
+    users:
+      - username: user
+        password_file: {}
+"#,
+            file.display(),
+            file.display()
+        ))
+        .unwrap();
+        let lookup = |name: &str| match name {
+            "SERVERS" => Some("ss://a@a.example.com:8388\nss://b@b.example.com:8388".to_string()),
+            "SHADOW_TLS" => Some("p@ss".to_string()),
+            _ => None,
+        };
+
+        resolve_with(&mut value, &lookup).unwrap();
+        std::fs::remove_file(file).unwrap();
+
+        assert_eq!(value["controller"]["secret"], Value::from("s3cret"));
+        assert!(value["controller"].get("secret_file").is_none());
+        assert_eq!(value["upstream"]["servers"].as_sequence().unwrap().len(), 2);
+        assert_eq!(
+            value["upstream"]["provider"]["endpoint"],
+            Value::from("https://example.com/sub")
+        );
+        assert_eq!(
+            value["upstream"]["shadow_tls"]["hk"]["password"],
+            Value::from("p@ss")
+        );
+        assert_eq!(value["upstream"]["weights"]["hk_file"], Value::from(2));
+        assert_eq!(
+            value["inbounds"][0]["users"][0]["password"],
+            Value::from("s3cret")
+        );
+
+        let mut value: Value =
+            serde_yaml::from_str("controller:\n  secret: a\n  secret_env: SERVERS\n").unwrap();
+        assert!(matches!(
+            resolve_with(&mut value, &lookup),
+            Err(Error::Conflict(_))
+        ));
+
+        let mut value: Value = serde_yaml::from_str("controller:\n  secret_env: NONE\n").unwrap();
+        assert!(matches!(
+            resolve_with(&mut value, &lookup),
+            Err(Error::Undefined { .. })
+        ));
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use hyper::{Method, StatusCode};
+use parking_lot::Mutex;
+use ring::digest;
+
+use crate::DateTime;
+
+/// Append-only log of mutating controller requests, and of requests
+/// refused by `allow`, `deny`, the rate limit or the secret, one line per
+/// request, e.g.
+///
+/// `2022-09-08T10:00:00.000000Z 192.168.1.2:51234 3f2a9c1e PUT /logging 200`
+///
+/// The third field tells tokens apart without logging them, it's the
+/// first 4 bytes of the SHA-256 of the bearer token, or `-` without one
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(
+        &self,
+        remote: SocketAddr,
+        token: Option<&str>,
+        method: &Method,
+        path: &str,
+        status: StatusCode,
+    ) {
+        let line = format!(
+            "{} {} {} {} {} {}\n",
+            DateTime::now(),
+            remote,
+            token.map(token_id).unwrap_or_else(|| "-".to_string()),
+            method,
+            path,
+            status.as_u16()
+        );
+
+        if let Err(err) = self.file.lock().write_all(line.as_bytes()) {
+            error!(message = "write audit log failed", ?err);
+        }
+    }
+}
+
+/// Hex of the first 4 bytes of the SHA-256 of `token`
+fn token_id(token: &str) -> String {
+    digest::digest(&digest::SHA256, token.as_bytes()).as_ref()[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Only requests which change the state of roxy are audited
+pub fn is_mutating(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_id_of_sha256() {
+        // echo -n secret | sha256sum
+        assert_eq!(token_id("secret"), "2bb80d53");
+        assert_ne!(token_id("secret"), token_id("secret2"));
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Idle buckets are dropped once the table grows beyond this
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Clients are limited by their IP, and by the bearer token they present,
+/// so changing IPs doesn't get a token more requests
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Key {
+    Ip(IpAddr),
+    /// Hash of the token, tokens are not kept
+    Token(u64),
+}
+
+impl Key {
+    pub fn token(token: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        token.hash(&mut hasher);
+        Key::Token(hasher.finish())
+    }
+}
+
+/// Token bucket rate limiter keyed by client IP or token, every client can
+/// make `burst` requests at once, and regains `burst` requests per
+/// `interval`.
+pub struct RateLimiter {
+    burst: f64,
+    /// tokens per second
+    rate: f64,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, interval: Duration) -> Self {
+        let burst = burst as f64;
+
+        Self {
+            burst,
+            rate: burst / interval.as_secs_f64(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for `key`, returns false if the client is exhausted
+    pub fn allow(&self, key: Key) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            let (burst, rate) = (self.burst, self.rate);
+            buckets
+                .retain(|_, b| b.tokens + now.duration_since(b.last).as_secs_f64() * rate < burst);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            last: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exhaust() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let a = Key::Ip("10.0.0.1".parse().unwrap());
+        let b = Key::Ip("10.0.0.2".parse().unwrap());
+
+        assert!(limiter.allow(a));
+        assert!(limiter.allow(a));
+        assert!(!limiter.allow(a));
+
+        // other clients are not affected
+        assert!(limiter.allow(b));
+
+        let token = Key::token("secret");
+        assert!(limiter.allow(token));
+        assert!(limiter.allow(Key::token("secret")));
+        assert!(!limiter.allow(token));
+        assert!(limiter.allow(Key::token("other")));
+    }
+}
@@ -1,5 +1,7 @@
+mod audit;
+mod limit;
 mod response;
 mod server;
 mod stats;
 
-pub use server::{Config, Server};
+pub use server::{Config, Error, Server};
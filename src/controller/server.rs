@@ -1,53 +1,186 @@
 use std::convert::Infallible;
 use std::io;
-use std::net::{AddrParseError, SocketAddr};
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, StatusCode};
-use serde::Deserialize;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use percent_encoding::percent_decode_str;
+use ring::constant_time;
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::{Name, RecordType};
 
 use super::{
+    audit::{is_mutating, AuditLog},
+    limit::{Key, RateLimiter},
     response::{err_resp, IntoResponse},
     stats,
 };
-use crate::Upstream;
+use crate::dns::{Cache, CacheDump, Handler};
+use crate::log::{Filter, Handle as LogHandle};
+use crate::net::Cidr;
+use crate::relay::{CaptureConfig, CaptureError, TalkersWindow};
+use crate::upgrade;
+use crate::upstream::{Error as UpstreamError, ServerEdit};
+use crate::{Connections, Profiles, Upstream};
 
-#[derive(Deserialize)]
+/// Default page size of `GET /dns/cache`
+const DEFAULT_CACHE_PAGE_SIZE: usize = 100;
+
+/// Servers are managed by `/upstream/servers/{name}`
+const SERVERS_PREFIX: &str = "/upstream/servers/";
+
+/// Default number of each kind of `GET /talkers`
+const DEFAULT_TALKERS_LIMIT: usize = 10;
+
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct RateLimitConfig {
+    /// Requests a client can make in `interval`
+    pub requests: u32,
+
+    #[serde(with = "crate::serde::duration")]
+    pub interval: Duration,
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct Config {
-    listen: String,
+    pub(crate) listen: String,
+
+    /// If it is set, all requests must contain `Authorization: Bearer <secret>`
+    #[serde(default)]
+    secret: Option<String>,
+
+    /// Limit requests per client IP and per bearer token
+    #[serde(default)]
+    pub(crate) rate_limit: Option<RateLimitConfig>,
+
+    /// Mutating and refused requests are appended to this file
+    #[serde(default)]
+    pub(crate) audit_log: Option<PathBuf>,
+
+    /// Packet captures are written in this directory, they are refused
+    /// without it
+    #[serde(default)]
+    pub(crate) capture_dir: Option<PathBuf>,
+
+    /// Source networks allowed to request, all sources are allowed if it's
+    /// empty
+    #[serde(default, with = "crate::serde::networks")]
+    pub(crate) allow: Vec<Cidr>,
+
+    /// Source networks refused even if they are in `allow`
+    #[serde(default, with = "crate::serde::networks")]
+    pub(crate) deny: Vec<Cidr>,
+}
+
+impl Config {
+    /// A controller listening on `listen` without rate limit and audit log
+    pub fn new(listen: impl Into<String>, secret: Option<String>) -> Self {
+        Self {
+            listen: listen.into(),
+            secret,
+            rate_limit: None,
+            audit_log: None,
+            capture_dir: None,
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid listen address, {0}")]
+    InvalidListen(#[from] AddrParseError),
+    #[error("open audit log failed, {0}")]
+    AuditLog(#[from] io::Error),
 }
 
-#[derive(Clone)]
 struct State {
     upstream: Upstream,
+    logging: LogHandle,
+    dns: Option<Arc<Handler>>,
+    connections: Connections,
+    profiles: Profiles,
+
+    secret: Option<String>,
+    limiter: Option<RateLimiter>,
+    audit: Option<AuditLog>,
+    capture_dir: Option<PathBuf>,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl State {
+    fn allowed(&self, ip: &IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+            && !self.deny.iter().any(|cidr| cidr.contains(ip))
+    }
 }
 
 pub struct Server {
     listen: SocketAddr,
-
-    upstream: Upstream,
+    state: State,
 }
 
 impl Server {
-    pub fn new(config: Config, upstream: Upstream) -> Result<Self, AddrParseError> {
+    pub fn new(
+        config: Config,
+        upstream: Upstream,
+        logging: LogHandle,
+        dns: Option<Arc<Handler>>,
+        connections: Connections,
+        profiles: Profiles,
+    ) -> Result<Self, Error> {
         let listen = config.listen.parse::<SocketAddr>()?;
+        let limiter = config
+            .rate_limit
+            .map(|rl| RateLimiter::new(rl.requests, rl.interval));
+        let audit = match config.audit_log {
+            Some(path) => Some(AuditLog::open(path)?),
+            None => None,
+        };
 
-        Ok(Self { listen, upstream })
+        Ok(Self {
+            listen,
+            state: State {
+                upstream,
+                logging,
+                dns,
+                connections,
+                profiles,
+                secret: config.secret,
+                limiter,
+                audit,
+                capture_dir: config.capture_dir,
+                allow: config.allow,
+                deny: config.deny,
+            },
+        })
     }
 
     pub async fn serve(self) -> io::Result<()> {
-        let state = Arc::new(State {
-            upstream: self.upstream,
-        });
+        let state = Arc::new(self.state);
 
-        let service = make_service_fn(move |_conn| {
+        let service = make_service_fn(move |conn: &AddrStream| {
             let cs = state.clone();
+            let remote = conn.remote_addr();
 
-            async { Ok::<_, Infallible>(service_fn(move |req| Self::handle(req, cs.clone()))) }
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| Self::handle(req, remote, cs.clone())))
+            }
         });
 
-        let server = hyper::Server::bind(&self.listen).serve(service);
+        // handed over to the new process on upgrade, like inbounds
+        let (listener, _registration) = upgrade::tcp_listener(self.listen)?;
+        let server = hyper::Server::from_tcp(listener)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+            .serve(service);
 
         info!(message = "controller start", listen = ?self.listen);
         if let Err(err) = server.await {
@@ -57,10 +190,91 @@ impl Server {
         Ok(())
     }
 
-    async fn handle(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Infallible> {
-        let path = req.uri().path();
+    async fn handle(
+        req: Request<Body>,
+        remote: SocketAddr,
+        state: Arc<State>,
+    ) -> Result<Response<Body>, Infallible> {
+        if !state.allowed(&remote.ip()) {
+            debug!(message = "controller source is not allowed", ?remote);
 
-        match (req.method(), path) {
+            return Ok(Self::refuse(&req, remote, &state, StatusCode::FORBIDDEN));
+        }
+
+        if let Some(limiter) = &state.limiter {
+            let limited = !limiter.allow(Key::Ip(remote.ip()))
+                || bearer(&req)
+                    .map(|token| !limiter.allow(Key::token(token)))
+                    .unwrap_or(false);
+            if limited {
+                debug!(message = "controller request rate limited", ?remote);
+
+                return Ok(Self::refuse(
+                    &req,
+                    remote,
+                    &state,
+                    StatusCode::TOO_MANY_REQUESTS,
+                ));
+            }
+        }
+
+        if let Some(secret) = &state.secret {
+            let authorized = bearer(&req)
+                .map(|token| {
+                    constant_time::verify_slices_are_equal(token.as_bytes(), secret.as_bytes())
+                        .is_ok()
+                })
+                .unwrap_or(false);
+            if !authorized {
+                warn!(
+                    message = "unauthorized controller request",
+                    ?remote,
+                    path = req.uri().path()
+                );
+
+                return Ok(Self::refuse(&req, remote, &state, StatusCode::UNAUTHORIZED));
+            }
+        }
+
+        let method = req.method().clone();
+        let path = req.uri().to_string();
+        let token = bearer(&req).map(str::to_string);
+        let resp = Self::route(req, &state).await?;
+
+        if let Some(audit) = &state.audit {
+            if is_mutating(&method) {
+                audit.record(remote, token.as_deref(), &method, &path, resp.status());
+            }
+        }
+
+        Ok(resp)
+    }
+
+    /// Respond `status` to a refused request, which is audited whatever
+    /// its method
+    fn refuse(
+        req: &Request<Body>,
+        remote: SocketAddr,
+        state: &State,
+        status: StatusCode,
+    ) -> Response<Body> {
+        if let Some(audit) = &state.audit {
+            audit.record(
+                remote,
+                bearer(req),
+                req.method(),
+                &req.uri().to_string(),
+                status,
+            );
+        }
+
+        status_resp(status)
+    }
+
+    async fn route(req: Request<Body>, state: &State) -> Result<Response<Body>, Infallible> {
+        let path = req.uri().path().to_string();
+
+        match (req.method(), path.as_str()) {
             (&Method::GET, "/stats") => match stats::ProcStat::read() {
                 Ok(stats) => Ok(stats.into_resp()),
                 Err(err) => {
@@ -70,14 +284,339 @@ impl Server {
                 }
             },
             (&Method::GET, "/upstream") => {
-                let stats = state.upstream.stats().await;
+                let tag = query_param(req.uri(), "tag");
+                let stats = state.upstream.stats(tag).await;
                 Ok(stats.into_resp())
             }
+            (&Method::GET, "/upstream/provider") => match state.upstream.usage() {
+                Some(usage) => Ok(usage.into_resp()),
+                None => Ok(not_found()),
+            },
+            (&Method::PUT, "/upstream/select") => {
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+                let name = String::from_utf8_lossy(&body).trim().to_string();
+                let name = if name.is_empty() { None } else { Some(name) };
+
+                if state.upstream.select(name).await {
+                    Ok(status_resp(StatusCode::OK))
+                } else {
+                    Ok(not_found())
+                }
+            }
+            (&Method::DELETE, "/upstream/select") => {
+                state.upstream.select(None).await;
+                Ok(status_resp(StatusCode::OK))
+            }
+            (&Method::POST, "/upstream/servers") => {
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+                let url = String::from_utf8_lossy(&body);
+
+                match state.upstream.add_server(url.trim()).await {
+                    Ok(()) => Ok(status_resp(StatusCode::CREATED)),
+                    Err(err) => Ok(upstream_err(err)),
+                }
+            }
+            (&Method::GET, path) if path.starts_with(SERVERS_PREFIX) => {
+                match state.upstream.stat(&server_name(path)).await {
+                    Some(stat) => Ok(stat.into_resp()),
+                    None => Ok(not_found()),
+                }
+            }
+            (&Method::PUT, path) if path.starts_with(SERVERS_PREFIX) => {
+                let name = server_name(path);
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+                let url = String::from_utf8_lossy(&body);
+
+                match state.upstream.replace_server(&name, url.trim()).await {
+                    Ok(()) => Ok(status_resp(StatusCode::OK)),
+                    Err(err) => Ok(upstream_err(err)),
+                }
+            }
+            (&Method::PATCH, path) if path.starts_with(SERVERS_PREFIX) => {
+                let name = server_name(path);
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+                let edit = match serde_json::from_slice::<ServerEdit>(&body) {
+                    Ok(edit) => edit,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+
+                match state.upstream.edit_server(&name, edit).await {
+                    Ok(()) => Ok(status_resp(StatusCode::OK)),
+                    Err(err) => Ok(upstream_err(err)),
+                }
+            }
+            (&Method::DELETE, path) if path.starts_with(SERVERS_PREFIX) => {
+                match state.upstream.remove_server(&server_name(path)).await {
+                    Ok(()) => Ok(status_resp(StatusCode::OK)),
+                    Err(err) => Ok(upstream_err(err)),
+                }
+            }
+            (&Method::POST, "/reload") => {
+                state.upstream.reload();
+                Ok(status_resp(StatusCode::ACCEPTED))
+            }
+            (&Method::GET, "/profile") => Ok(state.profiles.stat().into_resp()),
+            (&Method::PUT, "/profile") => {
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+                let names = String::from_utf8_lossy(&body)
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|name| !name.is_empty())
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+
+                // the config is reloaded with the profiles
+                if state.profiles.select(Some(names)) {
+                    Ok(status_resp(StatusCode::ACCEPTED))
+                } else {
+                    Ok(not_found())
+                }
+            }
+            (&Method::DELETE, "/profile") => {
+                state.profiles.select(None);
+                Ok(status_resp(StatusCode::ACCEPTED))
+            }
+            (&Method::GET, "/connections") => Ok(state.connections.list().into_resp()),
+            (&Method::GET, "/traffic") => Ok(state.connections.traffic().into_resp()),
+            (&Method::GET, "/metrics") => Ok(metrics(&state.connections)),
+            (&Method::GET, "/talkers") => Ok(talkers(&state.connections, req.uri())),
+            (&Method::GET, "/capture") => match state.connections.capture().stat() {
+                Some(stat) => Ok(stat.into_resp()),
+                None => Ok(not_found()),
+            },
+            (&Method::POST, "/capture") => {
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+                let config = match serde_json::from_slice::<CaptureConfig>(&body) {
+                    Ok(config) => config,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+
+                let capture = state.connections.capture();
+                match capture.start(state.capture_dir.as_deref(), config) {
+                    Ok(()) => Ok(status_resp(StatusCode::CREATED)),
+                    Err(err @ CaptureError::Running) => Ok(err_resp(StatusCode::CONFLICT, err)),
+                    Err(err @ CaptureError::Disabled) => Ok(err_resp(StatusCode::FORBIDDEN, err)),
+                    Err(err @ (CaptureError::Client(_) | CaptureError::InvalidPath(_))) => {
+                        Ok(err_resp(StatusCode::BAD_REQUEST, err))
+                    }
+                    Err(CaptureError::Create { path, err })
+                        if err.kind() == io::ErrorKind::AlreadyExists =>
+                    {
+                        Ok(err_resp(
+                            StatusCode::CONFLICT,
+                            CaptureError::Create { path, err },
+                        ))
+                    }
+                    Err(err) => Ok(err_resp(StatusCode::INTERNAL_SERVER_ERROR, err)),
+                }
+            }
+            (&Method::DELETE, "/capture") => {
+                if state.connections.capture().stop() {
+                    Ok(status_resp(StatusCode::OK))
+                } else {
+                    Ok(not_found())
+                }
+            }
+            (&Method::GET, "/dns/cache") => Ok(dns_cache(
+                state.dns.as_ref().and_then(|dns| dns.cache()).as_ref(),
+                req.uri(),
+            )),
+            (&Method::GET, "/dns/query") => match &state.dns {
+                Some(dns) => Ok(dns_query(dns, req.uri()).await),
+                None => Ok(not_found()),
+            },
+            (&Method::GET, "/logging") => Ok(logging(&state.logging.current())),
+            (&Method::PUT, "/logging") => {
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                };
+                let directives = String::from_utf8_lossy(&body);
+
+                match state.logging.reload(&directives) {
+                    Ok(filter) => {
+                        info!(message = "log filter changed", %filter);
+
+                        Ok(logging(&filter))
+                    }
+                    Err(err) => Ok(err_resp(StatusCode::BAD_REQUEST, err)),
+                }
+            }
             _ => Ok(not_found()),
         }
     }
 }
 
+#[derive(Serialize)]
+struct CachePage {
+    total: usize,
+    offset: usize,
+    entries: Vec<CacheDump>,
+}
+
+/// Dump DNS cache, `name` filters entries of the domain, `offset`
+/// and `limit` are used to paginate.
+fn dns_cache(cache: Option<&Cache>, uri: &Uri) -> Response<Body> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return not_found(),
+    };
+
+    let name = match query_param(uri, "name") {
+        Some(name) => match Name::from_str(name) {
+            Ok(mut name) => {
+                name.set_fqdn(true);
+                Some(name)
+            }
+            Err(err) => return err_resp(StatusCode::BAD_REQUEST, err),
+        },
+        None => None,
+    };
+
+    let offset = match query_param(uri, "offset").map(usize::from_str) {
+        Some(Ok(offset)) => offset,
+        Some(Err(err)) => return err_resp(StatusCode::BAD_REQUEST, err),
+        None => 0,
+    };
+    let limit = match query_param(uri, "limit").map(usize::from_str) {
+        Some(Ok(limit)) => limit,
+        Some(Err(err)) => return err_resp(StatusCode::BAD_REQUEST, err),
+        None => DEFAULT_CACHE_PAGE_SIZE,
+    };
+
+    let (total, entries) = cache.dump(name.as_ref(), offset, limit);
+
+    CachePage {
+        total,
+        offset,
+        entries,
+    }
+    .into_resp()
+}
+
+/// The most active clients, hosts and upstreams, `window` is one of `1m`,
+/// `5m` and `1h`, `limit` is the number of each kind
+fn talkers(connections: &Connections, uri: &Uri) -> Response<Body> {
+    let window = match query_param(uri, "window").map(TalkersWindow::from_str) {
+        Some(Ok(window)) => window,
+        Some(Err(err)) => return err_resp(StatusCode::BAD_REQUEST, err),
+        None => TalkersWindow::default(),
+    };
+    let limit = match query_param(uri, "limit").map(usize::from_str) {
+        Some(Ok(limit)) => limit,
+        Some(Err(err)) => return err_resp(StatusCode::BAD_REQUEST, err),
+        None => DEFAULT_TALKERS_LIMIT,
+    };
+
+    connections.top_talkers(window, limit).into_resp()
+}
+
+/// Resolve `name` with the DNS handler, `type` is the record type and
+/// defaults to `A`
+async fn dns_query(dns: &Handler, uri: &Uri) -> Response<Body> {
+    let name = match query_param(uri, "name").map(Name::from_str) {
+        Some(Ok(mut name)) => {
+            name.set_fqdn(true);
+            name
+        }
+        Some(Err(err)) => return err_resp(StatusCode::BAD_REQUEST, err),
+        None => return status_resp(StatusCode::BAD_REQUEST),
+    };
+    let query_type = match query_param(uri, "type").map(RecordType::from_str) {
+        Some(Ok(query_type)) => query_type,
+        Some(Err(err)) => return err_resp(StatusCode::BAD_REQUEST, err),
+        None => RecordType::A,
+    };
+
+    match dns.query(name, query_type).await {
+        Ok(answers) => answers
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .into_resp(),
+        Err(err) => err_resp(StatusCode::BAD_GATEWAY, err),
+    }
+}
+
+/// Percent-decoded server name of `/upstream/servers/{name}`
+fn server_name(path: &str) -> String {
+    let name = path.trim_start_matches(SERVERS_PREFIX);
+    percent_decode_str(name).decode_utf8_lossy().into_owned()
+}
+
+fn upstream_err(err: UpstreamError) -> Response<Body> {
+    let status = match err {
+        UpstreamError::InvalidServer(..) => StatusCode::BAD_REQUEST,
+        UpstreamError::UnknownServer(_) => StatusCode::NOT_FOUND,
+        UpstreamError::DuplicateServer(_) | UpstreamError::NoServers => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    err_resp(status, err)
+}
+
+/// Find the value of `key` in the query string, values are not
+/// percent-decoded
+fn query_param<'a>(uri: &'a Uri, key: &str) -> Option<&'a str> {
+    uri.query()?
+        .split('&')
+        .find_map(|pair| match pair.split_once('=') {
+            Some((k, v)) if k == key => Some(v),
+            _ => None,
+        })
+}
+
+/// Metrics in the Prometheus text format
+fn metrics(connections: &Connections) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(connections.metrics()))
+        .unwrap()
+}
+
+/// Current log filter in the same form `PUT /logging` accepts
+fn logging(filter: &Filter) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(filter.to_string()))
+        .unwrap()
+}
+
+/// The token of `Authorization: Bearer <token>`
+fn bearer(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Response with status code and it's canonical reason as body
+fn status_resp(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(status.canonical_reason().unwrap_or_default().into())
+        .unwrap()
+}
+
 /// HTTP status code 404
 fn not_found() -> Response<Body> {
     Response::builder()
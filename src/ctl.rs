@@ -0,0 +1,241 @@
+//! `roxy ctl`, a tiny client of the controller
+
+use hyper::body::Bytes;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+
+use crate::cli::{CtlArgs, CtlCommand};
+
+#[derive(Deserialize)]
+struct Connection {
+    id: u64,
+    src: String,
+    host: String,
+    port: u16,
+    upstream: Option<String>,
+    start: String,
+    upload: u64,
+    download: u64,
+    #[serde(default)]
+    tcp: Option<TcpStats>,
+}
+
+#[derive(Deserialize)]
+struct TcpStats {
+    /// Microseconds
+    rtt: u32,
+}
+
+#[derive(Deserialize)]
+struct Traffic {
+    upload: u64,
+    download: u64,
+    connections: usize,
+}
+
+#[derive(Deserialize)]
+struct Talker {
+    key: String,
+    upload: u64,
+    download: u64,
+}
+
+#[derive(Deserialize)]
+struct TopTalkers {
+    clients: Vec<Talker>,
+    hosts: Vec<Talker>,
+    upstreams: Vec<Talker>,
+}
+
+#[derive(Deserialize)]
+struct Profiles {
+    active: Vec<String>,
+    profiles: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    #[error(transparent)]
+    Http(#[from] hyper::Error),
+    #[error(transparent)]
+    InvalidRequest(#[from] hyper::http::Error),
+    #[error("decode response failed, {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("{0}, {1}")]
+    Status(StatusCode, String),
+}
+
+/// Run the command and returns the exit code
+pub fn run(args: CtlArgs) -> i32 {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .expect("build tokio runtime failed");
+
+    match runtime.block_on(execute(args)) {
+        Ok(()) => 0,
+
+        #[allow(clippy::print_stderr)]
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+#[allow(clippy::print_stdout)]
+async fn execute(args: CtlArgs) -> Result<(), Error> {
+    let ctl = Ctl {
+        client: Client::new(),
+        addr: args.addr,
+        secret: args.secret,
+    };
+
+    match args.command {
+        CtlCommand::Connections => {
+            let body = ctl.request(Method::GET, "/connections", "").await?;
+            let connections: Vec<Connection> = serde_json::from_slice(&body)?;
+
+            println!(
+                "{:<8} {:<22} {:<40} {:<24} {:>12} {:>12} {:>8}  START",
+                "ID", "SOURCE", "DESTINATION", "UPSTREAM", "UPLOAD", "DOWNLOAD", "RTT"
+            );
+            for conn in connections {
+                let rtt = match conn.tcp {
+                    Some(tcp) => format!("{}ms", tcp.rtt / 1000),
+                    None => "-".to_string(),
+                };
+                println!(
+                    "{:<8} {:<22} {:<40} {:<24} {:>12} {:>12} {:>8}  {}",
+                    conn.id,
+                    conn.src,
+                    format!("{}:{}", conn.host, conn.port),
+                    conn.upstream.as_deref().unwrap_or("-"),
+                    conn.upload,
+                    conn.download,
+                    rtt,
+                    conn.start
+                );
+            }
+        }
+        CtlCommand::Traffic => {
+            let body = ctl.request(Method::GET, "/traffic", "").await?;
+            let traffic: Traffic = serde_json::from_slice(&body)?;
+
+            println!("connections: {}", traffic.connections);
+            println!("upload:      {}", traffic.upload);
+            println!("download:    {}", traffic.download);
+        }
+        CtlCommand::Talkers(window) => {
+            let path = format!("/talkers?window={}", window.as_deref().unwrap_or("1m"));
+            let body = ctl.request(Method::GET, &path, "").await?;
+            let top: TopTalkers = serde_json::from_slice(&body)?;
+
+            for (kind, talkers) in [
+                ("CLIENT", top.clients),
+                ("HOST", top.hosts),
+                ("UPSTREAM", top.upstreams),
+            ] {
+                println!("{:<40} {:>12} {:>12}", kind, "UPLOAD", "DOWNLOAD");
+                for talker in talkers {
+                    println!(
+                        "{:<40} {:>12} {:>12}",
+                        talker.key, talker.upload, talker.download
+                    );
+                }
+                println!();
+            }
+        }
+        CtlCommand::Select(name) => {
+            let name = name.unwrap_or_default();
+            ctl.request(Method::PUT, "/upstream/select", &name).await?;
+        }
+        CtlCommand::Reload => {
+            ctl.request(Method::POST, "/reload", "").await?;
+        }
+        CtlCommand::AddServer(url) => {
+            ctl.request(Method::POST, "/upstream/servers", &url).await?;
+        }
+        CtlCommand::ReplaceServer { name, url } => {
+            ctl.request(Method::PUT, &server_path(&name), &url).await?;
+        }
+        CtlCommand::RemoveServer(name) => {
+            ctl.request(Method::DELETE, &server_path(&name), "").await?;
+        }
+        CtlCommand::Profile(None) => {
+            let body = ctl.request(Method::GET, "/profile", "").await?;
+            let profiles: Profiles = serde_json::from_slice(&body)?;
+
+            for name in profiles.profiles {
+                let mark = if profiles.active.contains(&name) {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{} {}", mark, name);
+            }
+        }
+        CtlCommand::Profile(Some(names)) => {
+            ctl.request(Method::PUT, "/profile", &names).await?;
+        }
+        CtlCommand::ResetProfile => {
+            ctl.request(Method::DELETE, "/profile", "").await?;
+        }
+        CtlCommand::DnsQuery { name, query_type } => {
+            let path = format!(
+                "/dns/query?name={}&type={}",
+                name,
+                query_type.as_deref().unwrap_or("A")
+            );
+            let body = ctl.request(Method::GET, &path, "").await?;
+            let answers: Vec<String> = serde_json::from_slice(&body)?;
+
+            for answer in answers {
+                println!("{}", answer);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn server_path(name: &str) -> String {
+    format!(
+        "/upstream/servers/{}",
+        utf8_percent_encode(name, NON_ALPHANUMERIC)
+    )
+}
+
+struct Ctl {
+    client: Client<hyper::client::HttpConnector>,
+    addr: String,
+    secret: Option<String>,
+}
+
+impl Ctl {
+    async fn request(&self, method: Method, path: &str, body: &str) -> Result<Bytes, Error> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", self.addr, path));
+        if let Some(secret) = &self.secret {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", secret));
+        }
+
+        let req = builder.body(Body::from(body.to_string()))?;
+        let resp = self.client.request(req).await?;
+        let (parts, body) = resp.into_parts();
+        let data = hyper::body::to_bytes(body).await?;
+
+        if !parts.status.is_success() {
+            return Err(Error::Status(
+                parts.status,
+                String::from_utf8_lossy(&data).trim().to_string(),
+            ));
+        }
+
+        Ok(data)
+    }
+}
@@ -32,6 +32,54 @@ impl DateTime {
     pub fn now() -> Self {
         std::time::SystemTime::now().into()
     }
+
+    /// The current time in the local timezone of the system, it's still
+    /// displayed with `Z`
+    pub fn now_local() -> Self {
+        let now = std::time::SystemTime::now();
+        let secs = now
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as libc::time_t)
+            .unwrap_or_default();
+
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+            return now.into();
+        }
+        let offset = std::time::Duration::from_secs(tm.tm_gmtoff.unsigned_abs() as u64);
+        let local = if tm.tm_gmtoff >= 0 {
+            now.checked_add(offset)
+        } else {
+            now.checked_sub(offset)
+        };
+
+        local.unwrap_or(now).into()
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    /// Day of the week, 0 is Monday and 6 is Sunday
+    pub fn weekday(&self) -> u8 {
+        // Sakamoto's method, 0 is Sunday
+        const OFFSETS: [i64; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let year = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let day = year + year.div_euclid(4) - year.div_euclid(100)
+            + year.div_euclid(400)
+            + OFFSETS[self.month as usize - 1]
+            + self.day as i64;
+
+        ((day + 6).rem_euclid(7)) as u8
+    }
 }
 
 impl fmt::Display for DateTime {
@@ -221,4 +269,16 @@ mod tests {
         case("2345-06-07T08:09:01.000000Z", 11847456541, 0);
         case("-2345-06-07T08:09:01.000000Z", -136154620259, 0);
     }
+
+    #[test]
+    fn weekday() {
+        let weekday = |secs: u64| DateTime::from(UNIX_EPOCH + Duration::from_secs(secs)).weekday();
+
+        // 1970-01-01 is a Thursday
+        assert_eq!(weekday(0), 3);
+        // 2000-02-29, 2024-03-03 and 2026-10-19
+        assert_eq!(weekday(951_782_400), 1);
+        assert_eq!(weekday(1_709_424_000), 6);
+        assert_eq!(weekday(1_792_368_000), 0);
+    }
 }
@@ -4,40 +4,66 @@ use std::time::Duration;
 
 use serde::Deserialize;
 
-#[derive(Deserialize)]
+use crate::net::Cidr;
+
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct CacheConfig {
     pub size: usize,
     #[serde(with = "crate::serde::duration")]
     pub ttl: Duration,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct UpstreamConfig {
+    #[serde(with = "crate::serde::socket_addrs")]
     pub(crate) nameservers: Vec<SocketAddr>,
+
+    /// Relay queries through the shadowsocks upstream servers, so the
+    /// nameservers see the server's address instead of ours
+    #[serde(default)]
+    pub proxy: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct RejectConfig {
     pub endpoint: String,
     #[serde(default, with = "crate::serde::duration::option")]
     pub interval: Option<Duration>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct HijackConfig {
     pub endpoint: String,
-    pub hijack: IpAddr,
+    /// Answer of hijacked domains, it should be the address roxy listens on
+    pub address: IpAddr,
 
     #[serde(default, with = "crate::serde::duration::option")]
     pub interval: Option<Duration>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct Config {
     pub listen: String,
+
+    /// Source networks allowed to query, all sources are allowed if it's
+    /// empty, so a server listening on all interfaces is not open to WAN
+    #[serde(default, with = "crate::serde::networks")]
+    pub allow: Vec<Cidr>,
+
+    /// Source networks refused even if they are in `allow`
+    #[serde(default, with = "crate::serde::networks")]
+    pub deny: Vec<Cidr>,
+
     pub cache: Option<CacheConfig>,
     pub upstream: UpstreamConfig,
     pub hosts: Option<BTreeMap<String, String>>,
     pub reject: Option<RejectConfig>,
     pub hijack: Option<HijackConfig>,
 }
+
+impl Config {
+    pub fn allowed(&self, ip: &IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+            && !self.deny.iter().any(|cidr| cidr.contains(ip))
+    }
+}
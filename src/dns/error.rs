@@ -1,6 +1,8 @@
 use std::fmt::{Display, Formatter};
+use std::io;
 use std::net::AddrParseError;
 
+use trust_dns_proto::error::ProtoError;
 use trust_dns_resolver::error::ResolveError;
 
 use crate::dns::rule;
@@ -14,11 +16,22 @@ pub enum Error {
     Reject(rule::Error),
 
     Hijack(rule::Error),
+
+    Proto(ProtoError),
+
+    Relay(io::Error),
 }
 
 impl Display for Error {
-    fn fmt(&self, _f: &mut Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Resolve(err) => write!(f, "resolve failed, {}", err),
+            Error::InvalidIpAddress(err) => write!(f, "invalid ip address, {}", err),
+            Error::Reject(err) => write!(f, "load reject rules failed, {}", err),
+            Error::Hijack(err) => write!(f, "load hijack rules failed, {}", err),
+            Error::Proto(err) => write!(f, "invalid dns message, {}", err),
+            Error::Relay(err) => write!(f, "relay query through upstream failed, {}", err),
+        }
     }
 }
 
@@ -30,6 +43,18 @@ impl From<ResolveError> for Error {
     }
 }
 
+impl From<ProtoError> for Error {
+    fn from(err: ProtoError) -> Self {
+        Self::Proto(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Relay(err)
+    }
+}
+
 impl From<AddrParseError> for Error {
     fn from(err: AddrParseError) -> Self {
         Self::InvalidIpAddress(err)
@@ -4,14 +4,17 @@ use std::time::{Duration, Instant};
 
 use lru_cache::LruCache;
 use parking_lot::Mutex;
+use serde::Serialize;
 use trust_dns_proto::op::{Edns, Query, ResponseCode};
-use trust_dns_proto::rr::Record;
+use trust_dns_proto::rr::{Name, Record};
 
 use crate::dns::Request;
 use crate::dns::Response;
 
 pub struct Entry {
     expire_at: Instant,
+    /// Who answered this query
+    source: String,
 
     answers: Vec<Record>,
     name_servers: Vec<Record>,
@@ -21,6 +24,18 @@ pub struct Entry {
     edns: Option<Edns>,
 }
 
+/// Snapshot of a cached entry, used for debugging
+#[derive(Serialize)]
+pub struct Dump {
+    pub name: String,
+    pub query_type: String,
+    pub answers: Vec<String>,
+    /// Remaining seconds before this entry expires
+    pub ttl: u64,
+    pub source: String,
+}
+
+#[derive(Clone)]
 pub struct Cache {
     ttl: Duration,
     lru: Arc<Mutex<LruCache<Query, Entry>>>,
@@ -40,7 +55,7 @@ impl Cache {
 
         match cached.get_mut(query) {
             Some(entry) => {
-                if entry.expire_at > Instant::now() {
+                if entry.expire_at <= Instant::now() {
                     // cache expired
                     cached.remove(query);
                     return None;
@@ -64,7 +79,7 @@ impl Cache {
         }
     }
 
-    pub fn put(&self, resp: &Response) {
+    pub fn put(&self, resp: &Response, source: &str) {
         let query = resp.query;
         let mut cached = self.lru.lock();
 
@@ -76,6 +91,7 @@ impl Cache {
             query.clone(),
             Entry {
                 expire_at: Instant::now().add(self.ttl),
+                source: source.to_string(),
                 answers: resp.answers.clone(),
                 name_servers: resp.name_servers.clone(),
                 soa: resp.soa.clone(),
@@ -85,4 +101,37 @@ impl Cache {
             },
         );
     }
+
+    /// Dumps cached entries whose query name is `name`, or all entries if
+    /// `name` is None. Entries are ordered from least to most recently used,
+    /// returns the total number of matched entries and the requested page.
+    pub fn dump(&self, name: Option<&Name>, offset: usize, limit: usize) -> (usize, Vec<Dump>) {
+        let now = Instant::now();
+        let cached = self.lru.lock();
+
+        let mut total = 0;
+        let mut entries = vec![];
+        for (query, entry) in cached.iter() {
+            if let Some(name) = name {
+                if query.name() != name {
+                    continue;
+                }
+            }
+
+            total += 1;
+            if total <= offset || entries.len() >= limit {
+                continue;
+            }
+
+            entries.push(Dump {
+                name: query.name().to_string(),
+                query_type: query.query_type().to_string(),
+                answers: entry.answers.iter().map(ToString::to_string).collect(),
+                ttl: entry.expire_at.saturating_duration_since(now).as_secs(),
+                source: entry.source.clone(),
+            });
+        }
+
+        (total, entries)
+    }
 }
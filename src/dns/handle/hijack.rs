@@ -21,17 +21,23 @@ impl Hijack {
 
         let hijacker = Self {
             trie: Arc::new(RwLock::new(trie)),
-            hijack: config.hijack,
+            hijack: config.address,
         };
 
         if let Some(interval) = config.interval {
             let endpoint = config.endpoint;
-            let trie = hijacker.trie.clone();
+            let trie = Arc::downgrade(&hijacker.trie);
 
             tokio::spawn(async move {
                 loop {
                     tokio::time::sleep(interval).await;
 
+                    // rules are replaced by config reloading
+                    let trie = match trie.upgrade() {
+                        Some(trie) => trie,
+                        None => return,
+                    };
+
                     match rule::load(&endpoint, resolver.clone()).await {
                         Ok((new_trie, total)) => {
                             info!(message = "reload hijack rules success", total);
@@ -3,68 +3,100 @@ mod hijack;
 mod reject;
 mod upstream;
 
-use std::collections::BTreeMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 
-use cache::Cache;
+pub use cache::{Cache, Dump};
 use hijack::Hijack;
+use parking_lot::RwLock;
 use reject::Reject;
 use resolver::Resolver;
-use trust_dns_proto::rr::{RData, Record};
+use trust_dns_proto::op::{Message, Query};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_proto::xfer::SerialMessage;
 use upstream::Upstream;
 
-use super::config::{CacheConfig, HijackConfig, RejectConfig};
-use super::{Error, Request, Response};
-use crate::dns::UpstreamConfig;
+use super::{Config, Error, Request, Response};
+use crate::route::Outbound;
+use crate::Router;
+
+/// Rules are rebuilt when config changed, the handler keeps serving
+/// with the old ones until the new ones are ready.
+struct Rules {
+    config: Config,
 
-pub struct Handler {
     cache: Option<Cache>,
-    hijacker: Option<Hijack>,
-    reject: Option<Reject>,
-    upstream: Upstream,
+    hijacker: Option<Arc<Hijack>>,
+    reject: Option<Arc<Reject>>,
+    upstream: Arc<Upstream>,
+}
+
+pub struct Handler {
+    rules: RwLock<Arc<Rules>>,
+    resolver: Resolver,
+    /// Shadowsocks servers queries are relayed through if
+    /// `upstream.proxy` is set
+    proxy: crate::Upstream,
+    /// Queries of domains routed to `reject` are rejected
+    router: Router,
 }
 
 impl Handler {
     pub async fn new(
-        cache: Option<CacheConfig>,
-        _hosts: Option<BTreeMap<String, String>>,
-        reject: Option<RejectConfig>,
-        hijack: Option<HijackConfig>,
-        upstream: UpstreamConfig,
+        config: Config,
         resolver: Resolver,
+        proxy: crate::Upstream,
+        router: Router,
     ) -> Result<Self, Error> {
-        let cache = cache.map(|c| Cache::new(c.size, c.ttl));
+        let rules = Rules::build(config, None, &resolver, &proxy).await?;
 
-        let reject = match reject {
-            Some(rc) => {
-                let reject = Reject::new(rc, resolver.clone())
-                    .await
-                    .map_err(Error::Reject)?;
-                Some(reject)
-            }
-            None => None,
-        };
+        Ok(Self {
+            rules: RwLock::new(Arc::new(rules)),
+            resolver,
+            proxy,
+            router,
+        })
+    }
 
-        let hijacker = match hijack {
-            Some(hc) => Some(Hijack::new(hc, resolver).await.map_err(Error::Hijack)?),
-            None => None,
-        };
+    /// Rebuild the changed parts, the others, e.g. cached answers, are kept
+    pub async fn update(&self, config: Config) -> Result<(), Error> {
+        let old = self.rules.read().clone();
+        let rules = Rules::build(config, Some(&old), &self.resolver, &self.proxy).await?;
 
-        let upstream = Upstream::new(upstream.nameservers)?;
+        *self.rules.write() = Arc::new(rules);
 
-        Ok(Self {
-            cache,
-            reject,
-            hijacker,
-            upstream,
-        })
+        Ok(())
+    }
+
+    /// Whether queries from `ip` are served, it follows hot reloads
+    pub fn allowed(&self, ip: &IpAddr) -> bool {
+        self.rules.read().config.allowed(ip)
+    }
+
+    #[inline]
+    pub fn cache(&self) -> Option<Cache> {
+        self.rules.read().cache.clone()
+    }
+
+    /// Resolve `name` the same way as a query received by the server
+    pub async fn query(&self, name: Name, query_type: RecordType) -> Result<Vec<Record>, Error> {
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name, query_type));
+
+        let src = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let req = Request::from_message(SerialMessage::new(msg.to_vec()?, src), src)?;
+        let resp = self.handle(&req).await?;
+
+        Ok(resp.answers)
     }
 
     pub async fn handle<'q>(&self, req: &'q Request) -> Result<Response<'q>, Error> {
         let name = req.query().name();
+        let rules = self.rules.read().clone();
 
         // try cache
-        if let Some(cache) = &self.cache {
+        if let Some(cache) = &rules.cache {
             match cache.get(req) {
                 Some(resp) => {
                     return Ok(resp);
@@ -74,7 +106,7 @@ impl Handler {
         }
 
         // hijack
-        if let Some(hijacker) = &self.hijacker {
+        if let Some(hijacker) = &rules.hijacker {
             if let Some(to) = hijacker.hijacking(name) {
                 debug!(message = "hijack dns request", ?name, ?to);
 
@@ -92,21 +124,80 @@ impl Handler {
         }
 
         // try reject
-        if let Some(reject) = &self.reject {
+        if let Some(reject) = &rules.reject {
             if reject.deny(name) {
                 debug!(message = "request match reject rules", ?name,);
 
                 return Ok(Response::no_records(req.header, req.query()));
             }
         }
+        if self.router.route_domain(&name.to_ascii()) == Some(Outbound::Reject) {
+            debug!(message = "request match reject routing rule", ?name);
+
+            return Ok(Response::no_records(req.header, req.query()));
+        }
 
         // try upstream
-        match (self.upstream.resolve(req).await, &self.cache) {
+        match (rules.upstream.resolve(req).await, &rules.cache) {
             (Ok(resp), Some(cache)) => {
-                cache.put(&resp);
+                cache.put(&resp, rules.upstream.name());
                 Ok(resp)
             }
             (result, _) => result,
         }
     }
 }
+
+impl Rules {
+    /// Build rules from `config`, parts not changed are taken from `old`
+    async fn build(
+        config: Config,
+        old: Option<&Rules>,
+        resolver: &Resolver,
+        proxy: &crate::Upstream,
+    ) -> Result<Self, Error> {
+        let cache = match (old, &config.cache) {
+            (Some(old), Some(cc)) if old.config.cache.as_ref() == Some(cc) => old.cache.clone(),
+            (_, Some(cc)) => Some(Cache::new(cc.size, cc.ttl)),
+            (_, None) => None,
+        };
+
+        let reject = match (old, &config.reject) {
+            (Some(old), Some(rc)) if old.config.reject.as_ref() == Some(rc) => old.reject.clone(),
+            (_, Some(rc)) => {
+                let reject = Reject::new(rc.clone(), resolver.clone())
+                    .await
+                    .map_err(Error::Reject)?;
+                Some(Arc::new(reject))
+            }
+            (_, None) => None,
+        };
+
+        let hijacker = match (old, &config.hijack) {
+            (Some(old), Some(hc)) if old.config.hijack.as_ref() == Some(hc) => old.hijacker.clone(),
+            (_, Some(hc)) => {
+                let hijacker = Hijack::new(hc.clone(), resolver.clone())
+                    .await
+                    .map_err(Error::Hijack)?;
+                Some(Arc::new(hijacker))
+            }
+            (_, None) => None,
+        };
+
+        let upstream = match old {
+            Some(old) if old.config.upstream == config.upstream => old.upstream.clone(),
+            _ => {
+                let proxy = config.upstream.proxy.then(|| proxy.clone());
+                Arc::new(Upstream::new(config.upstream.nameservers.clone(), proxy)?)
+            }
+        };
+
+        Ok(Self {
+            config,
+            cache,
+            hijacker,
+            reject,
+            upstream,
+        })
+    }
+}
@@ -26,12 +26,18 @@ impl Reject {
 
         if let Some(interval) = config.interval {
             let endpoint = config.endpoint;
-            let trie = rejector.trie.clone();
+            let trie = Arc::downgrade(&rejector.trie);
 
             tokio::spawn(async move {
                 loop {
                     tokio::time::sleep(interval).await;
 
+                    // rules are replaced by config reloading
+                    let trie = match trie.upgrade() {
+                        Some(trie) => trie,
+                        None => return,
+                    };
+
                     match rule::load(&endpoint, resolver.clone()).await {
                         Ok((new_trie, total)) => {
                             info!(message = "reload reject rules success", total);
@@ -1,24 +1,46 @@
+use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use rand::{thread_rng, Rng};
+use shadowsocks::{Address, MAXIMUM_UDP_PAYLOAD_SIZE};
+use trust_dns_proto::op::Message;
 use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
 use trust_dns_resolver::error::ResolveError;
 use trust_dns_resolver::TokioAsyncResolver;
 
 use super::{Error, Request, Response};
 
+/// How long to wait for the reply of a relayed query, per nameserver
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Upstream {
+    name: String,
     resolver: Arc<TokioAsyncResolver>,
+
+    /// Queries are relayed through the shadowsocks servers if it's set
+    proxy: Option<crate::Upstream>,
+    nameservers: Vec<SocketAddr>,
 }
 
 impl Upstream {
-    pub fn new(addrs: Vec<SocketAddr>) -> Result<Self, ResolveError> {
+    pub fn new(
+        addrs: Vec<SocketAddr>,
+        proxy: Option<crate::Upstream>,
+    ) -> Result<Self, ResolveError> {
+        let name = addrs
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+
         let mut opts = ResolverOpts::default();
         opts.cache_size = 1024;
         opts.num_concurrent_reqs = 64; // default is 2, 64 should be large enough
 
         let mut conf = ResolverConfig::new();
-        addrs.into_iter().for_each(|addr| {
+        addrs.iter().copied().for_each(|addr| {
             conf.add_name_server(NameServerConfig {
                 socket_addr: addr,
                 protocol: Protocol::Udp,
@@ -31,11 +53,24 @@ impl Upstream {
         let resolver = TokioAsyncResolver::tokio(conf, opts)?;
 
         Ok(Self {
+            name,
             resolver: Arc::new(resolver),
+            proxy,
+            nameservers: addrs,
         })
     }
 
+    /// Nameservers of this upstream, e.g. `8.8.8.8:53,1.1.1.1:53`
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub async fn resolve<'q>(&self, req: &'q Request) -> Result<Response<'q>, Error> {
+        if let Some(proxy) = &self.proxy {
+            return self.relay(proxy, req).await;
+        }
+
         let query = req.query();
         let ips = self.resolver.lookup_ip(query.name().clone()).await?;
         let lookup = ips.as_lookup();
@@ -51,4 +86,66 @@ impl Upstream {
             None,
         ))
     }
+
+    /// Send the query to nameservers in order through a shadowsocks
+    /// server, the first reply is used
+    async fn relay<'q>(
+        &self,
+        proxy: &crate::Upstream,
+        req: &'q Request,
+    ) -> Result<Response<'q>, Error> {
+        let query = req.query();
+        let mut msg = Message::new();
+        msg.set_id(thread_rng().gen());
+        msg.set_recursion_desired(true);
+        msg.add_query(query.clone());
+        let packet = msg.to_vec()?;
+
+        let mut last = io::Error::new(io::ErrorKind::NotFound, "no nameservers");
+        for nameserver in &self.nameservers {
+            let reply = match exchange(proxy, *nameserver, &packet).await {
+                Ok(reply) => Message::from_vec(&reply)?,
+                Err(err) => {
+                    debug!(message = "relay query failed", ?nameserver, ?err);
+                    last = err;
+                    continue;
+                }
+            };
+            if reply.id() != msg.id() {
+                continue;
+            }
+
+            return Ok(Response::new(
+                req.header,
+                query,
+                reply.answers().to_vec(),
+                reply.name_servers().to_vec(),
+                vec![],
+                vec![],
+                vec![],
+                None,
+            ));
+        }
+
+        Err(Error::Relay(last))
+    }
+}
+
+async fn exchange(
+    proxy: &crate::Upstream,
+    nameserver: SocketAddr,
+    packet: &[u8],
+) -> io::Result<Vec<u8>> {
+    let server = proxy.pick(&nameserver.ip().to_string(), None).await;
+    let mut socket = proxy.udp_socket(&server, None).await?;
+    socket.set_timeouts(Some(RELAY_TIMEOUT), Some(RELAY_TIMEOUT));
+
+    let target = Address::SocketAddress(nameserver);
+    socket.send(&target, packet, &Default::default()).await?;
+
+    let mut buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+    let (n, _, _) = socket.recv(&mut buf).await?;
+    buf.truncate(n);
+
+    Ok(buf)
 }
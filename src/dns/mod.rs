@@ -6,6 +6,7 @@ mod handle;
 mod rule;
 mod server;
 
-pub use config::{Config, UpstreamConfig};
+pub use config::{CacheConfig, Config, HijackConfig, RejectConfig, UpstreamConfig};
 pub use error::Error;
+pub use handle::{Cache, Dump as CacheDump, Handler};
 pub use server::{Request, Response, Server};
@@ -10,6 +10,7 @@ use futures_util::StreamExt;
 use resolver::Resolver;
 use tokio::net;
 use tokio::net::TcpListener;
+use tracing::Instrument;
 use trust_dns_proto::iocompat::AsyncIoTokioAsStd;
 use trust_dns_proto::tcp::TcpStream;
 use trust_dns_proto::udp::UdpStream;
@@ -19,6 +20,7 @@ use trust_dns_resolver::error::ResolveErrorKind;
 use super::config::Config;
 use super::handle::Handler;
 use super::Error;
+use crate::{upgrade, Router, Upstream};
 pub use request::Request;
 pub use response::Response;
 
@@ -28,23 +30,32 @@ pub struct Server {
 }
 
 impl Server {
-    pub async fn new(config: Config, resolver: Resolver) -> Result<Self, Error> {
-        let handler = Handler::new(
-            config.cache,
-            config.hosts,
-            config.reject,
-            config.hijack,
-            config.upstream,
-            resolver,
-        )
-        .await?;
+    pub async fn new(
+        config: Config,
+        resolver: Resolver,
+        proxy: Upstream,
+        router: Router,
+    ) -> Result<Self, Error> {
+        let addr = config.listen.clone();
+        let handler = Handler::new(config, resolver, proxy, router).await?;
 
         Ok(Self {
-            addr: config.listen,
+            addr,
             handler: Arc::new(handler),
         })
     }
 
+    /// Serve on `addr` with an existing handler, e.g. the listen address
+    /// is changed but rules are not
+    pub fn with_handler(addr: String, handler: Arc<Handler>) -> Self {
+        Self { addr, handler }
+    }
+
+    /// Handler of this server, it can be used to query without DNS protocol
+    pub fn handler(&self) -> Arc<Handler> {
+        self.handler.clone()
+    }
+
     pub async fn serve(self) -> io::Result<()> {
         info!(message = "Starting DNS service", addr = self.addr);
 
@@ -54,11 +65,23 @@ impl Server {
         }
     }
 
+    /// Listen address, it's checked to be a socket address when loading
+    fn listen(&self) -> io::Result<SocketAddr> {
+        self.addr
+            .parse()
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))
+    }
+
     async fn serve_tcp(&self) -> io::Result<()> {
-        let listener = TcpListener::bind(&self.addr).await?;
+        let (listener, _registration) = upgrade::tcp_listener(self.listen()?)?;
+        let listener = TcpListener::from_std(listener)?;
 
         loop {
             let (stream, src) = listener.accept().await?;
+            if !self.handler.allowed(&src.ip()) {
+                debug!(message = "source is not allowed", ?src);
+                continue;
+            }
 
             // verify that the src address is safe for responses
             if let Err(err) = sanitize_src_address(src) {
@@ -91,26 +114,41 @@ impl Server {
                         }
                     };
 
-                    match handle.handle(&req).await {
-                        Ok(resp) => {
-                            let msg = match resp.message(src) {
-                                Ok(msg) => msg,
-                                Err(err) => {
-                                    warn!(message = "encode response message failed", ?src, ?err);
-
-                                    return;
+                    // sampled by `log.sampling.dns`
+                    let span = info_span!("dns_query", %src, name = %req.query().name());
+                    let sent = async {
+                        match handle.handle(&req).await {
+                            Ok(resp) => {
+                                let msg = match resp.message(src) {
+                                    Ok(msg) => msg,
+                                    Err(err) => {
+                                        warn!(
+                                            message = "encode response message failed",
+                                            ?src,
+                                            ?err
+                                        );
+
+                                        return false;
+                                    }
+                                };
+
+                                if let Err(err) = stream_handle.send(msg) {
+                                    warn!(message = "send response message failed", ?src, ?err);
+
+                                    return false;
                                 }
-                            };
-
-                            if let Err(err) = stream_handle.send(msg) {
-                                warn!(message = "send response message failed", ?src, ?err);
-
-                                return;
+                            }
+                            Err(err) => {
+                                warn!(message = "handle dns request failed", ?src, ?err);
                             }
                         }
-                        Err(err) => {
-                            warn!(message = "handle dns request failed", ?src, ?err);
-                        }
+
+                        true
+                    }
+                    .instrument(span)
+                    .await;
+                    if !sent {
+                        return;
                     }
                 }
             });
@@ -118,7 +156,8 @@ impl Server {
     }
 
     async fn serve_udp(&self) -> io::Result<()> {
-        let socket = net::UdpSocket::bind(&self.addr).await?;
+        let (socket, _registration) = upgrade::udp_socket(self.listen()?)?;
+        let socket = net::UdpSocket::from_std(socket)?;
         // create the new UdpStream, the IP address isn't relevant, and ideally goes
         // essentially no where. the address used is acquired from the inbound queries.
         let (mut buf, stream_handle) =
@@ -136,6 +175,11 @@ impl Server {
             let src = msg.addr();
             debug!("received udp request from: {}", src);
 
+            if !self.handler.allowed(&src.ip()) {
+                debug!(message = "source is not allowed", ?src);
+                continue;
+            }
+
             // verify that the src address is safe for response
             if let Err(err) = sanitize_src_address(src) {
                 warn!(message = "address can not be responded to", ?err, ?src);
@@ -153,41 +197,46 @@ impl Server {
             let mut sender = stream_handle.with_remote_addr(src);
             let handler = Arc::clone(&self.handler);
 
-            tokio::spawn(async move {
-                let name = req.query().name();
-                debug!(message = "serve dns request", ?name);
+            // sampled by `log.sampling.dns`
+            let span = info_span!("dns_query", %src, name = %req.query().name());
+            tokio::spawn(
+                async move {
+                    let name = req.query().name();
+                    debug!(message = "serve dns request", ?name);
 
-                let result = handler.handle(&req).await;
+                    let result = handler.handle(&req).await;
 
-                match result {
-                    Ok(resp) => {
-                        let msg = match resp.message(src) {
-                            Ok(msg) => msg,
-                            Err(err) => {
-                                error!(message = "encode response message failed", ?err, ?src);
+                    match result {
+                        Ok(resp) => {
+                            let msg = match resp.message(src) {
+                                Ok(msg) => msg,
+                                Err(err) => {
+                                    error!(message = "encode response message failed", ?err, ?src);
 
-                                return;
-                            }
-                        };
+                                    return;
+                                }
+                            };
 
-                        if let Err(err) = sender.send(msg) {
-                            warn!(message = "send dns response failed", ?err, ?src);
+                            if let Err(err) = sender.send(msg) {
+                                warn!(message = "send dns response failed", ?err, ?src);
+                            }
                         }
-                    }
 
-                    Err(err) => {
-                        if let Error::Resolve(ref re) = err {
-                            if let ResolveErrorKind::NoRecordsFound { query, .. } = re.kind() {
-                                debug!(message = "no record", ?query);
+                        Err(err) => {
+                            if let Error::Resolve(ref re) = err {
+                                if let ResolveErrorKind::NoRecordsFound { query, .. } = re.kind() {
+                                    debug!(message = "no record", ?query);
 
-                                return;
+                                    return;
+                                }
                             }
-                        }
 
-                        warn!(message = "handle dns request failed", ?err, ?name);
+                            warn!(message = "handle dns request failed", ?err, ?name);
+                        }
                     }
                 }
-            });
+                .instrument(span),
+            );
         }
 
         Err(io::Error::new(
@@ -0,0 +1,10 @@
+use std::io;
+use std::path::Path;
+
+/// Write to a temporary file then rename it, so the file is never partial
+pub fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+
+    std::fs::write(&tmp, content).and_then(|_| std::fs::rename(&tmp, path))
+}
@@ -1,5 +1,6 @@
 use hyper::client::HttpConnector;
-use hyper::{Body, Client, Response, Uri};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, Response, Uri};
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 
 pub struct HttpClient(Client<HttpsConnector<HttpConnector<sealed::Resolver>>>);
@@ -24,6 +25,22 @@ impl HttpClient {
     pub async fn get(&self, uri: Uri) -> Result<Response<Body>, hyper::Error> {
         self.0.get(uri).await
     }
+
+    pub async fn post(
+        &self,
+        uri: Uri,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .expect("build request");
+
+        self.0.request(req).await
+    }
 }
 
 mod sealed {
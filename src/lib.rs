@@ -2,18 +2,32 @@ mod config;
 pub mod controller;
 mod datetime;
 pub mod dns;
+mod fs;
 mod http;
 mod log;
+pub mod net;
+mod privilege;
 mod relay;
+mod route;
+mod sandbox;
 mod serde;
 mod trace;
+pub mod upgrade;
 mod upstream;
 
 #[macro_use]
 extern crate tracing;
 
-pub use config::Config;
+pub use config::{
+    Config, ConfigBuilder, Diff, Override, OverrideError, Problem, Profiles, ProfilesStat,
+    RemoteConfig, TEMPLATE as CONFIG_TEMPLATE,
+};
 pub use datetime::DateTime;
-pub use relay::thp;
-pub use trace::init as trace_init;
-pub use upstream::Upstream;
+pub use log::Handle as LogHandle;
+pub use log::Statsd;
+pub use privilege::drop_privileges;
+pub use relay::{inbound, Connections, UdpReply, UdpSessions};
+pub use route::{GeoIp, GeoIpConfig, Router, ScriptConfig};
+pub use sandbox::apply as sandbox;
+pub use trace::{filter as trace_filter, init as trace_init};
+pub use upstream::{LoadBalanceType, Upstream};
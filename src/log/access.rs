@@ -0,0 +1,259 @@
+//! Access log, one record per relayed connection when it's closed, apart
+//! from diagnostic logs, e.g.
+//!
+//! ```yaml
+//! log:
+//!   access:
+//!     template: "{timestamp} {client} {host}:{port} {upstream} {upload} {download} {duration}"
+//!     file:
+//!       path: /var/log/roxy/access.log
+//!       rotate: 1d
+//! ```
+//!
+//! Fields of the template are
+//!
+//! - `timestamp`, when the connection is closed
+//! - `id`, id of the connection, the same one as the controller's
+//! - `inbound`, name of the inbound accepted it
+//! - `client`, address of the client
+//! - `host` and `port`, the sniffed destination
+//! - `destination`, address actually connected, the upstream server or
+//!   the destination itself of direct connections
+//! - `rule`, `proxy` or `direct`
+//! - `upstream`, name of the upstream server, or `direct`
+//! - `upload` and `download`, bytes
+//! - `duration`, milliseconds
+//! - `close`, why it's closed, `done` or the error
+//!
+//! `-` is written for a field without value, e.g. `upstream` of a
+//! connection failed to dial.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use serde::Deserialize;
+
+use super::file::{FileConfig, RotatingFile};
+use crate::relay::Connection;
+use crate::DateTime;
+
+pub const DEFAULT_TEMPLATE: &str = "{timestamp} {client} {inbound} {host}:{port} {rule} {upstream} {destination} {upload} {download} {duration} {close}";
+
+fn default_template() -> String {
+    DEFAULT_TEMPLATE.to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    #[serde(default = "default_template")]
+    pub template: String,
+
+    /// Write to a rotated file instead of stdout
+    #[serde(default)]
+    pub file: Option<FileConfig>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Timestamp,
+    Id,
+    Inbound,
+    Client,
+    Host,
+    Port,
+    Destination,
+    Rule,
+    Upstream,
+    Upload,
+    Download,
+    Duration,
+    Close,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        let field = match name {
+            "timestamp" => Field::Timestamp,
+            "id" => Field::Id,
+            "inbound" => Field::Inbound,
+            "client" => Field::Client,
+            "host" => Field::Host,
+            "port" => Field::Port,
+            "destination" => Field::Destination,
+            "rule" => Field::Rule,
+            "upstream" => Field::Upstream,
+            "upload" => Field::Upload,
+            "download" => Field::Download,
+            "duration" => Field::Duration,
+            "close" => Field::Close,
+            _ => return None,
+        };
+
+        Some(field)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum TemplateError {
+    #[error("unknown field \"{0}\"")]
+    UnknownField(String),
+    #[error("unclosed \"{{\"")]
+    Unclosed,
+}
+
+/// Parsed template, `{name}` is replaced by the field
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = vec![];
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+
+            let end = rest[start..].find('}').ok_or(TemplateError::Unclosed)? + start;
+            let name = &rest[start + 1..end];
+            let field =
+                Field::parse(name).ok_or_else(|| TemplateError::UnknownField(name.to_string()))?;
+            parts.push(Part::Field(field));
+
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Render a record of the connection, without the trailing newline
+    fn render(&self, buf: &mut String, conn: &Connection) {
+        for part in &self.parts {
+            let field = match part {
+                Part::Literal(s) => {
+                    buf.push_str(s);
+                    continue;
+                }
+                Part::Field(field) => field,
+            };
+
+            let result = match field {
+                Field::Timestamp => write!(buf, "{}", DateTime::now()),
+                Field::Id => write!(buf, "{}", conn.id()),
+                Field::Inbound => write!(buf, "{}", conn.inbound()),
+                Field::Client => write!(buf, "{}", conn.src()),
+                Field::Host => write!(buf, "{}", conn.host()),
+                Field::Port => write!(buf, "{}", conn.port()),
+                Field::Destination => {
+                    write!(buf, "{}", conn.destination().as_deref().unwrap_or("-"))
+                }
+                Field::Rule => write!(buf, "{}", conn.route().as_str()),
+                Field::Upstream => write!(buf, "{}", conn.upstream().as_deref().unwrap_or("-")),
+                Field::Upload => write!(buf, "{}", conn.upload()),
+                Field::Download => write!(buf, "{}", conn.download()),
+                Field::Duration => write!(
+                    buf,
+                    "{}",
+                    conn.start().elapsed().unwrap_or_default().as_millis()
+                ),
+                Field::Close => write!(buf, "{}", conn.close_reason().as_deref().unwrap_or("-")),
+            };
+            result.expect("write access log to buffer failed");
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+pub struct AccessLog {
+    template: Template,
+    sink: Sink,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessConfig) -> io::Result<Self> {
+        let template = Template::parse(&config.template)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let sink = match &config.file {
+            Some(file) => Sink::File(RotatingFile::open(file.clone())?),
+            None => Sink::Stdout,
+        };
+
+        Ok(Self { template, sink })
+    }
+
+    pub fn write(&self, conn: &Connection) {
+        let mut buf = String::new();
+        self.template.render(&mut buf, conn);
+        buf.push('\n');
+
+        match &self.sink {
+            Sink::Stdout => {
+                let _ = std::io::stdout().write_all(buf.as_bytes());
+            }
+            Sink::File(file) => {
+                if let Err(err) = file.write(buf.as_bytes()) {
+                    warn!(message = "write access log failed", %err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inbound::Route;
+    use crate::Connections;
+
+    #[test]
+    fn render() {
+        assert_eq!(
+            Template::parse("{client} {foo}"),
+            Err(TemplateError::UnknownField("foo".to_string()))
+        );
+        assert_eq!(Template::parse("{client"), Err(TemplateError::Unclosed));
+
+        let connections = Connections::default();
+        let tracked = connections.track(
+            "lan",
+            Route::Proxy,
+            "192.168.1.2:50000".parse().unwrap(),
+            "192.168.1.1:80".parse().unwrap(),
+            "example.com".to_string(),
+            443,
+        );
+        let conn = tracked.connection();
+        conn.set_upstream("tokyo".to_string());
+
+        let template = Template::parse(
+            "[{inbound}] {client} -> {host}:{port} via {upstream} {destination} {rule}",
+        )
+        .unwrap();
+        let mut buf = String::new();
+        template.render(&mut buf, conn);
+        assert_eq!(
+            buf,
+            "[lan] 192.168.1.2:50000 -> example.com:443 via tokyo - proxy"
+        );
+
+        assert!(Template::parse(DEFAULT_TEMPLATE).is_ok());
+    }
+}
@@ -0,0 +1,160 @@
+//! Write logs to a file, which is rotated by size or age, e.g.
+//!
+//! ```yaml
+//! log:
+//!   file:
+//!     path: /var/log/roxy/roxy.log
+//!     max_size: 100MiB
+//!     rotate: 1d
+//!     keep: 7
+//! ```
+//!
+//! Rotated files are `roxy.log.1` (the newest), `roxy.log.2` and so on,
+//! at most `keep` of them are kept.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+const fn default_keep() -> usize {
+    5
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub path: PathBuf,
+
+    /// Rotate when the file would grow over this
+    #[serde(default, with = "crate::serde::size::option")]
+    pub max_size: Option<u64>,
+
+    /// Rotate when the file has been written for this long
+    #[serde(default, with = "crate::serde::duration::option")]
+    pub rotate: Option<Duration>,
+
+    /// Number of rotated files to keep, older ones are removed
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+pub struct RotatingFile {
+    config: FileConfig,
+    state: Mutex<State>,
+}
+
+impl RotatingFile {
+    pub fn open(config: FileConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+
+        let state = open(&config.path)?;
+
+        Ok(Self {
+            config,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Write a whole line, the file is rotated before writing if needed
+    pub fn write(&self, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock();
+
+        let oversize = self
+            .config
+            .max_size
+            .map(|max| state.size > 0 && state.size + buf.len() as u64 > max)
+            .unwrap_or(false);
+        let expired = self
+            .config
+            .rotate
+            .map(|rotate| state.opened.elapsed().unwrap_or_default() >= rotate)
+            .unwrap_or(false);
+        if oversize || expired {
+            rotate(&self.config.path, self.config.keep)?;
+            *state = open(&self.config.path)?;
+        }
+
+        state.file.write_all(buf)?;
+        state.size += buf.len() as u64;
+
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<State> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+
+    Ok(State {
+        file,
+        size,
+        opened: SystemTime::now(),
+    })
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    name.into()
+}
+
+/// Shift `path.N` to `path.N+1`, then `path` to `path.1`, the one
+/// beyond `keep` is overwritten.
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return std::fs::remove_file(path);
+    }
+
+    for index in (1..keep).rev() {
+        match std::fs::rename(rotated(path, index), rotated(path, index + 1)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+
+    std::fs::rename(path, rotated(path, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("roxy-log-{}", std::process::id()));
+        let path = dir.join("roxy.log");
+        let file = RotatingFile::open(FileConfig {
+            path: path.clone(),
+            max_size: Some(10),
+            rotate: None,
+            keep: 2,
+        })
+        .unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write(line.as_bytes()).unwrap();
+        }
+
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "fourth\n");
+        assert_eq!(read(&rotated(&path, 1)), "third\n");
+        assert_eq!(read(&rotated(&path, 2)), "second\n");
+        assert!(!rotated(&path, 3).exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use tracing::level_filters::LevelFilter;
+use tracing::{Level, Metadata};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("invalid level \"{0}\"")]
+    InvalidLevel(String),
+    #[error("empty target in directive \"{0}\"")]
+    EmptyTarget(String),
+}
+
+/// A `target=level` pair, the target is matched as a module path prefix.
+#[derive(Clone, Debug, PartialEq)]
+struct Directive {
+    target: String,
+    level: LevelFilter,
+}
+
+impl Directive {
+    fn matches(&self, target: &str) -> bool {
+        match target.strip_prefix(self.target.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with("::"),
+            None => false,
+        }
+    }
+}
+
+/// Filter works like `RUST_LOG`, e.g. `info,roxy::dns=debug,shadowsocks=warn`.
+///
+/// The directive without target sets the global level, others override it
+/// for the matched module and all of its submodules. The most specific
+/// target wins.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    global: LevelFilter,
+    directives: Vec<Directive>,
+}
+
+impl Filter {
+    pub fn new(level: Level) -> Self {
+        Self {
+            global: LevelFilter::from_level(level),
+            directives: vec![],
+        }
+    }
+
+    /// Parse directives, the global level is `global` if directives
+    /// don't specify one.
+    pub fn parse(global: LevelFilter, directives: &str) -> Result<Self, ParseError> {
+        let mut filter = Self {
+            global,
+            directives: vec![],
+        };
+
+        for part in directives.split(',') {
+            let part = part.trim();
+            if part.is_empty() {
+                continue;
+            }
+
+            match part.split_once('=') {
+                Some((target, level)) => {
+                    let target = target.trim();
+                    if target.is_empty() {
+                        return Err(ParseError::EmptyTarget(part.to_string()));
+                    }
+
+                    let level = parse_level(level.trim())?;
+                    filter.directives.retain(|d| d.target != target);
+                    filter.directives.push(Directive {
+                        target: target.to_string(),
+                        level,
+                    });
+                }
+                None => filter.global = parse_level(part)?,
+            }
+        }
+
+        // longer targets are more specific, so check them first
+        filter
+            .directives
+            .sort_by_key(|directive| Reverse(directive.target.len()));
+
+        Ok(filter)
+    }
+
+    #[inline]
+    pub fn global(&self) -> LevelFilter {
+        self.global
+    }
+
+    pub fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let target = metadata.target();
+        let level = self
+            .directives
+            .iter()
+            .find(|d| d.matches(target))
+            .map(|d| d.level)
+            .unwrap_or(self.global);
+
+        *metadata.level() <= level
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.global)?;
+
+        for d in &self.directives {
+            write!(f, ",{}={}", d.target, d.level)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, ParseError> {
+    LevelFilter::from_str(s).map_err(|_| ParseError::InvalidLevel(s.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let filter = Filter::parse(LevelFilter::INFO, "roxy::dns=debug, shadowsocks=off").unwrap();
+        assert_eq!(filter.global, LevelFilter::INFO);
+        assert_eq!(filter.directives.len(), 2);
+        assert_eq!(filter.to_string(), "info,shadowsocks=off,roxy::dns=debug");
+
+        let filter = Filter::parse(LevelFilter::INFO, "warn,roxy=trace").unwrap();
+        assert_eq!(filter.global, LevelFilter::WARN);
+
+        assert!(Filter::parse(LevelFilter::INFO, "roxy=loud").is_err());
+        assert!(Filter::parse(LevelFilter::INFO, "=debug").is_err());
+    }
+
+    #[test]
+    fn directive_matches() {
+        let d = Directive {
+            target: "roxy::dns".to_string(),
+            level: LevelFilter::DEBUG,
+        };
+
+        assert!(d.matches("roxy::dns"));
+        assert!(d.matches("roxy::dns::server"));
+        assert!(!d.matches("roxy::dnsx"));
+        assert!(!d.matches("roxy"));
+    }
+}
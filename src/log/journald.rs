@@ -0,0 +1,122 @@
+//! Send logs to journald with its native protocol, so fields of events
+//! are kept as journal fields, e.g. `connection_id` and `upstream` of
+//! relay events can be queried with
+//!
+//! ```text
+//! journalctl -u roxy CONNECTION_ID=42
+//! ```
+//!
+//! `MESSAGE` is the formatted line, `PRIORITY` is mapped from the level.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+use tracing::{Event, Level};
+
+use super::logger::FieldVisitor;
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+const IDENTIFIER: &str = "roxy";
+
+pub struct Journald {
+    socket: UnixDatagram,
+}
+
+impl Journald {
+    pub fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SOCKET)?;
+
+        Ok(Self { socket })
+    }
+
+    /// Send the event, `line` is the formatted one without the trailing
+    /// newline.
+    pub fn write(&self, event: &Event<'_>, line: &[u8]) -> io::Result<()> {
+        let mut buf = vec![];
+        encode(&mut buf, event, line);
+
+        self.socket.send(&buf).map(|_| ())
+    }
+}
+
+fn encode(buf: &mut Vec<u8>, event: &Event<'_>, line: &[u8]) {
+    let metadata = event.metadata();
+    let priority = match *metadata.level() {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        _ => "7",
+    };
+
+    field(buf, "MESSAGE", line);
+    field(buf, "PRIORITY", priority.as_bytes());
+    field(buf, "SYSLOG_IDENTIFIER", IDENTIFIER.as_bytes());
+    field(buf, "TARGET", metadata.target().as_bytes());
+
+    let mut fields = vec![];
+    event.record(&mut FieldVisitor(&mut fields));
+    for (name, value) in fields {
+        if name == "message" {
+            continue;
+        }
+
+        field(buf, &field_name(name), value.as_bytes());
+    }
+}
+
+/// Journal field names are uppercase letters, digits and underscores,
+/// and they can't start with an underscore, which are trusted fields.
+fn field_name(name: &str) -> String {
+    let name = name.strip_prefix("r#").unwrap_or(name);
+    let name = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit())
+        .to_string()
+}
+
+/// `NAME=value\n`, or `NAME\n<length in u64 le>value\n` if the value
+/// contains newlines
+fn field(buf: &mut Vec<u8>, name: &str, value: &[u8]) {
+    if name.is_empty() {
+        return;
+    }
+
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains(&b'\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value);
+    buf.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_field() {
+        assert_eq!(field_name("connection_id"), "CONNECTION_ID");
+        assert_eq!(field_name("r#type"), "TYPE");
+        assert_eq!(field_name("_pid"), "PID");
+
+        let mut buf = vec![];
+        field(&mut buf, "UPSTREAM", b"tokyo");
+        assert_eq!(buf, b"UPSTREAM=tokyo\n");
+
+        let mut buf = vec![];
+        field(&mut buf, "MESSAGE", b"a\nb");
+        assert_eq!(buf, b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+    }
+}
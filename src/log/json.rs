@@ -0,0 +1,124 @@
+//! One JSON object per line, e.g.
+//!
+//! ```json
+//! {"timestamp":"2022-09-01T08:00:00.000000Z","level":"INFO","target":"roxy::dns","message":"start dns server","fields":{"listen":"0.0.0.0:53"}}
+//! ```
+//!
+//! `timestamp`, `level`, `target` and `message` are always at the top
+//! level, other fields of the event are in `fields`, and the entered spans
+//! are in `spans`, the outermost first, e.g.
+//! `[{"name":"connection","id":"1","host":"example.com"}]`.
+
+use std::error::Error;
+use std::fmt::Debug;
+
+use serde_json::{Map, Number, Value};
+use tracing::field::{Field, Visit};
+use tracing::Event;
+
+use super::logger::Entered;
+use crate::DateTime;
+
+/// Append the event to `buf`, without the trailing newline
+pub fn format(buf: &mut String, event: &Event<'_>, spans: &[Entered], timestamp: bool) {
+    let metadata = event.metadata();
+    let mut visitor = Visitor {
+        message: None,
+        fields: Map::new(),
+    };
+    event.record(&mut visitor);
+
+    let mut object = Map::new();
+    if timestamp {
+        object.insert("timestamp".to_string(), DateTime::now().to_string().into());
+    }
+    object.insert("level".to_string(), metadata.level().as_str().into());
+    object.insert("target".to_string(), metadata.target().into());
+    object.insert(
+        "message".to_string(),
+        visitor.message.unwrap_or_default().into(),
+    );
+    if !visitor.fields.is_empty() {
+        object.insert("fields".to_string(), Value::Object(visitor.fields));
+    }
+    if !spans.is_empty() {
+        let spans = spans
+            .iter()
+            .map(|span| {
+                let mut object = Map::new();
+                object.insert("name".to_string(), span.name.into());
+                for (name, value) in &span.attributes {
+                    object.insert(name.to_string(), value.as_str().into());
+                }
+
+                Value::Object(object)
+            })
+            .collect();
+        object.insert("spans".to_string(), Value::Array(spans));
+    }
+
+    buf.push_str(&Value::Object(object).to_string());
+}
+
+struct Visitor {
+    message: Option<String>,
+    fields: Map<String, Value>,
+}
+
+impl Visitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        let name = field.name();
+        let name = name.strip_prefix("r#").unwrap_or(name);
+
+        self.fields.insert(name.to_string(), value);
+    }
+}
+
+impl Visit for Visitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        match Number::from_f64(value) {
+            Some(n) => self.insert(field, Value::Number(n)),
+            None => self.insert(field, value.to_string().into()),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(value.to_string());
+        } else {
+            self.insert(field, value.into());
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        let mut message = value.to_string();
+        let mut source = value.source();
+        while let Some(err) = source {
+            message.push_str(", ");
+            message.push_str(&err.to_string());
+            source = err.source();
+        }
+
+        self.insert(field, message.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        } else {
+            self.insert(field, format!("{:?}", value).into());
+        }
+    }
+}
@@ -1,37 +1,364 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Write;
 use std::fmt::{Debug, Display};
 use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
+use parking_lot::{Mutex, RwLock};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
 use tracing::field::Field;
 use tracing::span::{Attributes, Record};
-use tracing::{field, Event, Id, Level, Metadata, Subscriber};
+use tracing::subscriber::Interest;
+use tracing::{field, Event, Id, Metadata, Subscriber};
 
+use super::access::AccessLog;
+use super::file::RotatingFile;
+use super::filter::{Filter, ParseError};
+use super::journald::Journald;
+use super::otlp::{self, Exporter};
+use super::sampling::{Sampler, SamplingConfig};
+use super::syslog::Syslog;
 use crate::DateTime;
 
+thread_local! {
+    /// Spans entered by this thread, the last one is the current span
+    static STACK: RefCell<Vec<u64>> = RefCell::new(vec![]);
+}
+
+/// An open span, it's exported when the last reference is closed
+struct SpanData {
+    name: &'static str,
+    target: &'static str,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    attributes: Vec<(&'static str, String)>,
+    start: SystemTime,
+    refs: usize,
+
+    /// Enabled by the filter, or created only for sampling
+    visible: bool,
+    /// Sampled, so are its children
+    sampled: bool,
+}
+
+/// A span entered by the current thread, its context is written in lines
+pub(super) struct Entered {
+    pub name: &'static str,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+/// Layout of log lines
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `<timestamp> <level> <module> <message> key=value...`
+    #[default]
+    Text,
+    /// One JSON object per line, for Loki, Elasticsearch and so on
+    Json,
+}
+
+/// Where log lines are written
+pub enum Sink {
+    Stdout,
+    File(RotatingFile),
+    Syslog(Syslog),
+    Journald(Journald),
+}
+
+impl Sink {
+    fn write(&self, event: &Event<'_>, buf: &[u8]) {
+        match self {
+            Sink::Stdout => {
+                let _ = std::io::stdout()
+                    .write(buf)
+                    .expect("write log to stdout failed");
+            }
+            Sink::File(file) => {
+                // there is nowhere else to log the failure
+                if let Err(err) = file.write(buf) {
+                    let _ = writeln!(std::io::stderr(), "write log file failed, {}", err);
+                    let _ = std::io::stderr().write_all(buf);
+                }
+            }
+            Sink::Syslog(syslog) => {
+                if let Err(err) = syslog.write(event.metadata().level(), buf) {
+                    let _ = writeln!(std::io::stderr(), "write syslog failed, {}", err);
+                    let _ = std::io::stderr().write_all(buf);
+                }
+            }
+            Sink::Journald(journald) => {
+                let line = buf.strip_suffix(b"\n").unwrap_or(buf);
+                if let Err(err) = journald.write(event, line) {
+                    let _ = writeln!(std::io::stderr(), "write journald failed, {}", err);
+                    let _ = std::io::stderr().write_all(buf);
+                }
+            }
+        }
+    }
+}
+
 pub struct Logger {
-    level: Level,
+    filter: Arc<RwLock<Filter>>,
+    sink: Sink,
+    format: Format,
     timestamp: bool,
+
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    exporter: Option<Exporter>,
+    access: Option<Arc<AccessLog>>,
+    sampler: Option<Sampler>,
 }
 
 impl Logger {
-    pub fn new(level: Level, timestamp: bool) -> Self {
-        Self { level, timestamp }
+    pub fn new(filter: Filter, sink: Sink, format: Format, timestamp: bool) -> Self {
+        Self {
+            filter: Arc::new(RwLock::new(filter)),
+            sink,
+            format,
+            timestamp,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+            exporter: None,
+            access: None,
+            sampler: None,
+        }
+    }
+
+    /// Closed spans are pushed to the exporter
+    pub fn with_exporter(mut self, exporter: Exporter) -> Self {
+        self.exporter = Some(exporter);
+        self
+    }
+
+    /// Trace sampled connections and DNS queries at debug level regardless
+    /// of the filter
+    pub fn with_sampling(mut self, config: &SamplingConfig) -> Self {
+        if !config.is_empty() {
+            self.sampler = Some(Sampler::new(config));
+        }
+        self
+    }
+
+    /// Whether the current span of this thread is sampled
+    fn in_sampled(&self) -> bool {
+        let current = STACK.with(|stack| stack.borrow().last().copied());
+        match current {
+            Some(id) => self
+                .spans
+                .lock()
+                .get(&id)
+                .map(|data| data.sampled)
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// The access log isn't written by the logger, it's only kept for
+    /// `Handle::access_log`
+    pub fn with_access_log(mut self, access: AccessLog) -> Self {
+        self.access = Some(Arc::new(access));
+        self
+    }
+
+    /// Spans entered by this thread, the outermost first
+    fn entered(&self) -> Vec<Entered> {
+        STACK.with(|stack| {
+            let stack = stack.borrow();
+            if stack.is_empty() {
+                return vec![];
+            }
+
+            let spans = self.spans.lock();
+            stack
+                .iter()
+                .filter_map(|id| spans.get(id))
+                .map(|data| Entered {
+                    name: data.name,
+                    attributes: data.attributes.clone(),
+                })
+                .collect()
+        })
+    }
+
+    fn format_text(&self, buf: &mut String, event: &Event<'_>, spans: &[Entered]) {
+        let metadata = event.metadata();
+
+        // write timestamp
+        if self.timestamp {
+            let date = DateTime::now();
+            write!(buf, "{} ", date).expect("write timestamp to log buffer failed");
+        }
+
+        // write level
+        write!(buf, "{:5} ", metadata.level()).expect("write level to log buffer failed");
+
+        // write module
+        if let Some(module) = metadata.module_path() {
+            buf.push_str(module);
+            buf.push(' ');
+        }
+
+        // write spans, e.g. `connection{id=1 host=example.com}:dial: `
+        for span in spans {
+            buf.push_str(span.name);
+            if !span.attributes.is_empty() {
+                buf.push('{');
+                for (i, (name, value)) in span.attributes.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(' ');
+                    }
+                    write!(buf, "{}={}", name, value).expect("write span to log buffer failed");
+                }
+                buf.push('}');
+            }
+            buf.push(':');
+        }
+        if !spans.is_empty() {
+            buf.push(' ');
+        }
+
+        event.record(&mut Visitor { buf });
+    }
+
+    /// Returns a handle which can change the filter of this logger at runtime
+    pub fn handle(&self) -> Handle {
+        Handle {
+            filter: self.filter.clone(),
+            exporter: self.exporter.clone(),
+            access: self.access.clone(),
+        }
+    }
+}
+
+/// Handle to change the filter of a running `Logger`
+#[derive(Clone)]
+pub struct Handle {
+    filter: Arc<RwLock<Filter>>,
+    exporter: Option<Exporter>,
+    access: Option<Arc<AccessLog>>,
+}
+
+impl Handle {
+    /// The OTLP exporter, it must be spawned once the runtime is ready
+    pub fn exporter(&self) -> Option<Exporter> {
+        self.exporter.clone()
+    }
+
+    /// The access log, closed connections of `Connections` are written
+    /// to it
+    pub fn access_log(&self) -> Option<Arc<AccessLog>> {
+        self.access.clone()
+    }
+
+    pub fn current(&self) -> Filter {
+        self.filter.read().clone()
+    }
+
+    /// Replace the filter, the global level is kept if `directives`
+    /// don't specify one.
+    pub fn reload(&self, directives: &str) -> Result<Filter, ParseError> {
+        let level = self.filter.read().global();
+        let filter = Filter::parse(level, directives)?;
+        self.set(filter.clone());
+
+        Ok(filter)
+    }
+
+    pub fn set(&self, filter: Filter) {
+        *self.filter.write() = filter;
+
+        // callsites cache the result of `enabled`, they must be re-evaluated
+        tracing::callsite::rebuild_interest_cache();
     }
 }
 
 impl Subscriber for Logger {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if self.filter.read().enabled(metadata) {
+            Interest::always()
+        } else if matches!(&self.sampler, Some(sampler) if sampler.interested(metadata)) {
+            // it depends on whether the current span is sampled
+            Interest::sometimes()
+        } else {
+            Interest::never()
+        }
+    }
+
     fn enabled(&self, metadata: &Metadata<'_>) -> bool {
-        *metadata.level() <= self.level
+        if self.filter.read().enabled(metadata) {
+            return true;
+        }
+
+        match &self.sampler {
+            Some(sampler) if sampler.interested(metadata) => {
+                sampler.is_sampled_span(metadata) || self.in_sampled()
+            }
+            _ => false,
+        }
     }
 
-    fn new_span(&self, _span: &Attributes<'_>) -> Id {
-        panic!("trace is not supported")
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let parent = if let Some(parent) = span.parent() {
+            Some(parent.into_u64())
+        } else if span.is_contextual() {
+            STACK.with(|stack| stack.borrow().last().copied())
+        } else {
+            None
+        };
+
+        let metadata = span.metadata();
+        let visible = self.filter.read().enabled(metadata);
+
+        let mut spans = self.spans.lock();
+        let (trace_id, parent, parent_sampled) = match parent.and_then(|parent| spans.get(&parent))
+        {
+            Some(parent) => (parent.trace_id, Some(parent.span_id), parent.sampled),
+            None => (thread_rng().gen(), None, false),
+        };
+        let sampled = parent_sampled
+            || self
+                .sampler
+                .as_ref()
+                .map(|sampler| sampler.sample(metadata))
+                .unwrap_or(false);
+
+        let mut attributes = vec![];
+        span.record(&mut FieldVisitor(&mut attributes));
+
+        spans.insert(
+            id,
+            SpanData {
+                name: metadata.name(),
+                target: metadata.target(),
+                trace_id,
+                span_id: thread_rng().gen(),
+                parent,
+                attributes,
+                start: SystemTime::now(),
+                refs: 1,
+                visible,
+                sampled,
+            },
+        );
+
+        Id::from_u64(id)
     }
 
-    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(data) = self.spans.lock().get_mut(&span.into_u64()) {
+            values.record(&mut FieldVisitor(&mut data.attributes));
+        }
+    }
 
     fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
 
@@ -44,7 +371,7 @@ impl Subscriber for Logger {
             let borrow = buf.try_borrow_mut();
             let mut a;
             let mut b;
-            let mut buf = match borrow {
+            let buf = match borrow {
                 Ok(buf) => {
                     a = buf;
                     &mut *a
@@ -55,39 +382,83 @@ impl Subscriber for Logger {
                 }
             };
 
-            let metadata = event.metadata();
-
-            // write timestamp
-            if self.timestamp {
-                let date = DateTime::now();
-                write!(&mut buf, "{} ", date).expect("write timestamp to log buffer failed");
+            let spans = self.entered();
+            match self.format {
+                Format::Text => self.format_text(buf, event, &spans),
+                Format::Json => super::json::format(buf, event, &spans, self.timestamp),
             }
 
-            // write level
-            write!(&mut buf, "{:5} ", metadata.level()).expect("write level to log buffer failed");
+            buf.push('\n');
+            self.sink.write(event, buf.as_bytes());
+            buf.clear();
+        });
+    }
+
+    fn enter(&self, span: &Id) {
+        STACK.with(|stack| stack.borrow_mut().push(span.into_u64()));
+    }
 
-            // write module
-            if let Some(module) = metadata.module_path() {
-                buf.push_str(module);
-                buf.push(' ');
+    fn exit(&self, span: &Id) {
+        let id = span.into_u64();
+        STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            if let Some(pos) = stack.iter().rposition(|entered| *entered == id) {
+                stack.remove(pos);
             }
+        });
+    }
 
-            event.record(&mut Visitor { buf });
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.spans.lock().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
 
-            buf.push('\n');
+        span.clone()
+    }
 
-            let mut writer = std::io::stdout();
-            let _ = writer
-                .write(buf.as_bytes())
-                .expect("write log to stdout failed");
+    fn try_close(&self, span: Id) -> bool {
+        let data = {
+            let mut spans = self.spans.lock();
+            match spans.get_mut(&span.into_u64()) {
+                Some(data) if data.refs > 1 => {
+                    data.refs -= 1;
+                    return false;
+                }
+                Some(_) => spans.remove(&span.into_u64()),
+                None => return false,
+            }
+        };
 
-            buf.clear();
-        });
+        // spans created only for sampling are exported only if sampled
+        let data = data.filter(|data| data.visible || data.sampled);
+        if let (Some(exporter), Some(data)) = (&self.exporter, data) {
+            exporter.push(otlp::Span {
+                trace_id: data.trace_id,
+                span_id: data.span_id,
+                parent: data.parent,
+                name: data.name,
+                target: data.target,
+                attributes: data.attributes,
+                start: data.start,
+                end: SystemTime::now(),
+            });
+        }
+
+        true
     }
+}
 
-    fn enter(&self, _span: &Id) {}
+/// Collects fields of spans and events as strings
+pub(super) struct FieldVisitor<'a>(pub(super) &'a mut Vec<(&'static str, String)>);
 
-    fn exit(&self, _span: &Id) {}
+impl<'a> field::Visit for FieldVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
 }
 
 /// Renders an error into a list of sources, *including* the error
@@ -1,3 +1,20 @@
+mod access;
+mod file;
+mod filter;
+mod journald;
+mod json;
 mod logger;
+mod otlp;
+mod sampling;
+mod statsd;
+mod syslog;
 
-pub use logger::Logger;
+pub use access::{AccessConfig, AccessLog, Template};
+pub use file::{FileConfig, RotatingFile};
+pub use filter::{Filter, ParseError};
+pub use journald::Journald;
+pub use logger::{Format, Handle, Logger, Sink};
+pub use otlp::{Exporter, OtlpConfig};
+pub use sampling::SamplingConfig;
+pub use statsd::{Statsd, StatsdConfig};
+pub use syslog::{Syslog, SyslogConfig};
@@ -0,0 +1,293 @@
+//! Export spans and metrics to an OpenTelemetry collector with OTLP/HTTP
+//! in JSON encoding, so there is no need of protobuf and gRPC.
+//!
+//! ```yaml
+//! log:
+//!   otlp:
+//!     endpoint: http://127.0.0.1:4318
+//!     service_name: roxy
+//!     resource:
+//!       deployment.environment: prod
+//!     interval: 10s
+//! ```
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{StatusCode, Uri};
+use parking_lot::Mutex;
+use resolver::Resolver;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::http::HttpClient;
+use crate::Connections;
+
+/// Spans are dropped if the collector can't keep up
+const MAX_PENDING_SPANS: usize = 4096;
+
+const SCOPE: &str = "roxy";
+
+fn default_service_name() -> String {
+    "roxy".to_string()
+}
+
+const fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// Base url of the OTLP/HTTP receiver, `/v1/traces` and `/v1/metrics`
+    /// are appended
+    pub endpoint: String,
+
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+
+    /// Extra resource attributes, e.g. `deployment.environment: prod`
+    #[serde(default)]
+    pub resource: BTreeMap<String, String>,
+
+    /// Interval of exporting spans and metrics
+    #[serde(default = "default_interval", with = "crate::serde::duration")]
+    pub interval: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid uri \"{0}\"")]
+    InvalidUri(String),
+    #[error(transparent)]
+    Http(#[from] hyper::Error),
+    #[error("unexpected status code {0}")]
+    Status(StatusCode),
+}
+
+/// A closed span
+pub struct Span {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent: Option<[u8; 8]>,
+    pub name: &'static str,
+    pub target: &'static str,
+    pub attributes: Vec<(&'static str, String)>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+/// Collects closed spans, and exports them with metrics periodically
+#[derive(Clone)]
+pub struct Exporter {
+    config: Arc<OtlpConfig>,
+    spans: Arc<Mutex<Vec<Span>>>,
+    start: SystemTime,
+}
+
+impl Exporter {
+    pub fn new(config: OtlpConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            spans: Arc::new(Mutex::new(vec![])),
+            start: SystemTime::now(),
+        }
+    }
+
+    pub fn push(&self, span: Span) {
+        let mut spans = self.spans.lock();
+        if spans.len() < MAX_PENDING_SPANS {
+            spans.push(span);
+        }
+    }
+
+    /// Export every `interval`, it never returns
+    pub async fn run(self, resolver: Resolver, connections: Connections) {
+        let client = HttpClient::new(resolver);
+        let mut ticker = tokio::time::interval(self.config.interval);
+        // the first tick completes immediately
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let spans = std::mem::take(&mut *self.spans.lock());
+            if !spans.is_empty() {
+                if let Err(err) = self.post(&client, "traces", self.traces(spans)).await {
+                    warn!(message = "export spans failed", %err);
+                }
+            }
+
+            let metrics = self.metrics(&connections);
+            if let Err(err) = self.post(&client, "metrics", metrics).await {
+                warn!(message = "export metrics failed", %err);
+            }
+        }
+    }
+
+    async fn post(&self, client: &HttpClient, signal: &str, body: Value) -> Result<(), Error> {
+        let uri = format!(
+            "{}/v1/{}",
+            self.config.endpoint.trim_end_matches('/'),
+            signal
+        );
+        let uri = Uri::from_str(&uri).map_err(|_| Error::InvalidUri(uri))?;
+
+        let resp = client
+            .post(uri, "application/json", body.to_string().into_bytes())
+            .await?;
+        if !resp.status().is_success() {
+            return Err(Error::Status(resp.status()));
+        }
+
+        Ok(())
+    }
+
+    fn resource(&self) -> Value {
+        let mut attributes = vec![attribute("service.name", &self.config.service_name)];
+        attributes.extend(
+            self.config
+                .resource
+                .iter()
+                .map(|(key, value)| attribute(key, value)),
+        );
+
+        json!({ "attributes": attributes })
+    }
+
+    fn traces(&self, spans: Vec<Span>) -> Value {
+        let spans = spans
+            .iter()
+            .map(|span| {
+                json!({
+                    "traceId": hex(&span.trace_id),
+                    "spanId": hex(&span.span_id),
+                    "parentSpanId": span.parent.as_ref().map(|id| hex(id)).unwrap_or_default(),
+                    "name": span.name,
+                    // SPAN_KIND_INTERNAL
+                    "kind": 1,
+                    "startTimeUnixNano": nanos(span.start),
+                    "endTimeUnixNano": nanos(span.end),
+                    "attributes": std::iter::once(attribute("code.namespace", span.target))
+                        .chain(span.attributes.iter().map(|(key, value)| attribute(key, value)))
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "resourceSpans": [{
+                "resource": self.resource(),
+                "scopeSpans": [{
+                    "scope": { "name": SCOPE },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    fn metrics(&self, connections: &Connections) -> Value {
+        let traffic = connections.traffic();
+        let now = nanos(SystemTime::now());
+        let start = nanos(self.start);
+        let counter = |name: &str, value: u64| {
+            json!({
+                "name": name,
+                "unit": "By",
+                "sum": {
+                    // AGGREGATION_TEMPORALITY_CUMULATIVE
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                    "dataPoints": [{
+                        "asInt": value.to_string(),
+                        "startTimeUnixNano": start,
+                        "timeUnixNano": now,
+                    }],
+                },
+            })
+        };
+
+        json!({
+            "resourceMetrics": [{
+                "resource": self.resource(),
+                "scopeMetrics": [{
+                    "scope": { "name": SCOPE },
+                    "metrics": [
+                        {
+                            "name": "roxy.connections",
+                            "gauge": {
+                                "dataPoints": [{
+                                    "asInt": traffic.connections.to_string(),
+                                    "timeUnixNano": now,
+                                }],
+                            },
+                        },
+                        counter("roxy.traffic.upload", traffic.upload),
+                        counter("roxy.traffic.download", traffic.download),
+                    ],
+                }],
+            }],
+        })
+    }
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// OTLP/JSON encodes 64 bit integers as strings
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let exporter = Exporter::new(OtlpConfig {
+            endpoint: "http://127.0.0.1:4318".to_string(),
+            service_name: default_service_name(),
+            resource: [("host.name".to_string(), "gateway".to_string())].into(),
+            interval: default_interval(),
+        });
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+
+        let traces = exporter.traces(vec![Span {
+            trace_id: [0xab; 16],
+            span_id: [1; 8],
+            parent: None,
+            name: "connection",
+            target: "roxy::relay",
+            attributes: vec![("host", "example.com".to_string())],
+            start,
+            end: start + Duration::from_millis(5),
+        }]);
+        let resource = &traces["resourceSpans"][0]["resource"]["attributes"];
+        assert_eq!(resource[0]["value"]["stringValue"], "roxy");
+        assert_eq!(resource[1]["key"], "host.name");
+
+        let span = &traces["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "abababababababababababababababab");
+        assert_eq!(span["spanId"], "0101010101010101");
+        assert_eq!(span["parentSpanId"], "");
+        assert_eq!(span["startTimeUnixNano"], "1000000000");
+        assert_eq!(span["endTimeUnixNano"], "1005000000");
+        assert_eq!(span["attributes"][1]["value"]["stringValue"], "example.com");
+
+        let metrics = exporter.metrics(&Connections::default());
+        let metrics = &metrics["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["gauge"]["dataPoints"][0]["asInt"], "0");
+        assert_eq!(metrics[1]["name"], "roxy.traffic.upload");
+    }
+}
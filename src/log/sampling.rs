@@ -0,0 +1,99 @@
+//! Sample relayed connections and DNS queries for detailed tracing, so
+//! it can stay enabled in production, e.g.
+//!
+//! ```yaml
+//! log:
+//!   level: info
+//!   sampling:
+//!     connections: 100
+//!     dns: 1000
+//! ```
+//!
+//! logs 1 in 100 connections and 1 in 1000 DNS queries at debug level,
+//! with their spans exported if `otlp` is set, the rest are logged as the
+//! filter says.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Deserialize;
+use tracing::{Level, Metadata};
+
+/// Name of the span of each relayed connection
+pub const CONNECTION_SPAN: &str = "connection";
+
+/// Name of the span of each DNS query
+pub const DNS_QUERY_SPAN: &str = "dns_query";
+
+/// Level of sampled spans and their children
+pub const SAMPLED_LEVEL: Level = Level::DEBUG;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingConfig {
+    /// Trace 1 in N relayed connections
+    #[serde(default)]
+    pub connections: Option<u64>,
+
+    /// Trace 1 in N DNS queries
+    #[serde(default)]
+    pub dns: Option<u64>,
+}
+
+impl SamplingConfig {
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_none() && self.dns.is_none()
+    }
+}
+
+struct Rate {
+    span: &'static str,
+    every: u64,
+    count: AtomicU64,
+}
+
+pub struct Sampler {
+    rates: Vec<Rate>,
+}
+
+impl Sampler {
+    pub fn new(config: &SamplingConfig) -> Self {
+        let rates = [
+            (CONNECTION_SPAN, config.connections),
+            (DNS_QUERY_SPAN, config.dns),
+        ]
+        .into_iter()
+        .filter_map(|(span, every)| {
+            every.filter(|every| *every > 0).map(|every| Rate {
+                span,
+                every,
+                count: AtomicU64::new(0),
+            })
+        })
+        .collect();
+
+        Self { rates }
+    }
+
+    /// Spans sampled from must always be created, and everything at
+    /// `SAMPLED_LEVEL` may be enabled in a sampled span
+    pub fn interested(&self, metadata: &Metadata<'_>) -> bool {
+        !self.rates.is_empty() && *metadata.level() <= SAMPLED_LEVEL
+    }
+
+    /// Whether the span is sampled from
+    pub fn is_sampled_span(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() && self.rates.iter().any(|rate| rate.span == metadata.name())
+    }
+
+    /// Decide whether a new span is sampled, the first one is
+    pub fn sample(&self, metadata: &Metadata<'_>) -> bool {
+        if !metadata.is_span() {
+            return false;
+        }
+
+        match self.rates.iter().find(|rate| rate.span == metadata.name()) {
+            Some(rate) => rate.count.fetch_add(1, Ordering::Relaxed) % rate.every == 0,
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Push metrics to a StatsD server over UDP, e.g. Telegraf or the Datadog
+//! agent, tags are sent in the DogStatsD format.
+//!
+//! ```yaml
+//! log:
+//!   statsd:
+//!     address: 127.0.0.1:8125
+//!     prefix: roxy
+//!     tags:
+//!       host: gateway
+//!     interval: 10s
+//! ```
+//!
+//! Every `interval` the number of connections is sent as a gauge and the
+//! traffic as counters, and the dial, handshake and time to first byte of
+//! closed connections are sent as timers, their throughput as histograms,
+//! so are RTT, retransmits and delivery rate of upstream sockets, tagged by
+//! `upstream`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+use crate::Connections;
+
+/// Lines are batched into datagrams no larger than this, which fits the
+/// common MTU
+const MAX_DATAGRAM: usize = 1432;
+
+fn default_prefix() -> String {
+    "roxy".to_string()
+}
+
+const fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// `host:port` of the StatsD server
+    pub address: String,
+
+    /// Prefix of metric names, e.g. `roxy.connections`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+
+    /// Tags added to every metric
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+
+    #[serde(default = "default_interval", with = "crate::serde::duration")]
+    pub interval: Duration,
+}
+
+pub struct Statsd {
+    config: StatsdConfig,
+    upload: u64,
+    download: u64,
+}
+
+impl Statsd {
+    pub fn new(config: StatsdConfig) -> Self {
+        Self {
+            config,
+            upload: 0,
+            download: 0,
+        }
+    }
+
+    /// Send every `interval`, it never returns
+    pub async fn run(mut self, connections: Connections) {
+        // start keeping samples
+        connections.take_samples();
+
+        let mut ticker = tokio::time::interval(self.config.interval);
+        // the first tick completes immediately
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let lines = self.lines(&connections);
+            if let Err(err) = self.send(&lines).await {
+                warn!(message = "send statsd metrics failed", %err);
+            }
+        }
+    }
+
+    async fn send(&self, lines: &[String]) -> io::Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(&self.config.address).await?;
+
+        for datagram in batch(lines) {
+            socket.send(datagram.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    fn lines(&mut self, connections: &Connections) -> Vec<String> {
+        let traffic = connections.traffic();
+        let upload = traffic.upload.saturating_sub(self.upload);
+        let download = traffic.download.saturating_sub(self.download);
+        self.upload = traffic.upload;
+        self.download = traffic.download;
+
+        let mut lines = vec![
+            self.line("connections", traffic.connections, "g", None),
+            self.line("traffic.upload", upload, "c", None),
+            self.line("traffic.download", download, "c", None),
+        ];
+
+        for sample in connections.take_samples() {
+            let line = match sample.metric {
+                metric @ ("throughput" | "delivery_rate" | "retransmits") => {
+                    self.line(metric, sample.value.round(), "h", Some(&sample.upstream))
+                }
+                // seconds to milliseconds
+                metric => self.line(
+                    metric,
+                    (sample.value * 1000.0 * 1000.0).round() / 1000.0,
+                    "ms",
+                    Some(&sample.upstream),
+                ),
+            };
+            lines.push(line);
+        }
+
+        lines
+    }
+
+    /// `prefix.name:value|type|#tag:value,upstream:name`
+    fn line(
+        &self,
+        name: &str,
+        value: impl std::fmt::Display,
+        kind: &str,
+        upstream: Option<&str>,
+    ) -> String {
+        let mut line = String::new();
+        if !self.config.prefix.is_empty() {
+            line.push_str(&self.config.prefix);
+            line.push('.');
+        }
+        let _ = write!(line, "{}:{}|{}", name, value, kind);
+
+        let tags = self
+            .config
+            .tags
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(upstream.map(|upstream| ("upstream", upstream)))
+            .map(|(key, value)| format!("{}:{}", key, sanitize(value)))
+            .collect::<Vec<_>>();
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+
+        line
+    }
+}
+
+/// `|`, `,` and `#` are separators of the format
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '|' | ',' | '#' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Join lines with `\n` into datagrams
+fn batch(lines: &[String]) -> Vec<String> {
+    let mut datagrams = vec![];
+    let mut current = String::new();
+
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let statsd = Statsd::new(StatsdConfig {
+            address: "127.0.0.1:8125".to_string(),
+            prefix: default_prefix(),
+            tags: [("host".to_string(), "gateway".to_string())].into(),
+            interval: default_interval(),
+        });
+
+        assert_eq!(
+            statsd.line("connections", 3, "g", None),
+            "roxy.connections:3|g|#host:gateway"
+        );
+        assert_eq!(
+            statsd.line("dial", 12.5, "ms", Some("hk|01")),
+            "roxy.dial:12.5|ms|#host:gateway,upstream:hk_01"
+        );
+
+        let lines = vec!["a".repeat(1000), "b".repeat(1000), "c".to_string()];
+        let datagrams = batch(&lines);
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[1], format!("{}\nc", "b".repeat(1000)));
+    }
+}
@@ -0,0 +1,225 @@
+//! Send logs to a syslog daemon in RFC 5424 format, e.g.
+//!
+//! ```yaml
+//! log:
+//!   syslog:
+//!     address: udp://10.0.0.1:514
+//!     facility: daemon
+//! ```
+//!
+//! `address` is `unix:///dev/log`, `udp://host:port` or `tcp://host:port`,
+//! messages over TCP are framed by octet counting (RFC 6587).
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tracing::Level;
+
+use crate::DateTime;
+
+const APP_NAME: &str = "roxy";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    Unix(String),
+    Udp(String),
+    Tcp(String),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            Some(("unix", path)) => Ok(Address::Unix(path.to_string())),
+            Some(("udp", addr)) => Ok(Address::Udp(addr.to_string())),
+            Some(("tcp", addr)) => Ok(Address::Tcp(addr.to_string())),
+            _ => Err(format!(
+                "invalid syslog address \"{}\", it must be unix://, udp:// or tcp://",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Unix(path) => write!(f, "unix://{}", path),
+            Address::Udp(addr) => write!(f, "udp://{}", addr),
+            Address::Tcp(addr) => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Facilities of RFC 5424, only the ones make sense for roxy
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    User = 1,
+    #[default]
+    Daemon = 3,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+fn default_address() -> Address {
+    Address::Unix("/dev/log".to_string())
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    #[serde(default = "default_address")]
+    pub address: Address,
+
+    #[serde(default)]
+    pub facility: Facility,
+}
+
+enum Conn {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    /// Connected lazily, and again after a failed write
+    Tcp(Option<TcpStream>),
+}
+
+pub struct Syslog {
+    config: SyslogConfig,
+    hostname: String,
+    pid: u32,
+    conn: Mutex<Conn>,
+}
+
+impl Syslog {
+    pub fn connect(config: SyslogConfig) -> io::Result<Self> {
+        let conn = match &config.address {
+            Address::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Conn::Unix(socket)
+            }
+            Address::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+                Conn::Udp(socket)
+            }
+            Address::Tcp(addr) => Conn::Tcp(Some(TcpStream::connect(addr)?)),
+        };
+
+        Ok(Self {
+            config,
+            hostname: hostname(),
+            pid: std::process::id(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Send a formatted log line, the trailing newline is dropped
+    pub fn write(&self, level: &Level, line: &[u8]) -> io::Result<()> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let mut msg = self.header(level).into_bytes();
+        msg.extend_from_slice(line);
+
+        match &mut *self.conn.lock() {
+            Conn::Unix(socket) => socket.send(&msg).map(|_| ()),
+            Conn::Udp(socket) => socket.send(&msg).map(|_| ()),
+            Conn::Tcp(stream) => {
+                let addr = match &self.config.address {
+                    Address::Tcp(addr) => addr,
+                    _ => unreachable!("tcp connection of non tcp address"),
+                };
+                let conn = match stream {
+                    Some(conn) => conn,
+                    None => stream.insert(TcpStream::connect(addr)?),
+                };
+
+                let result = write!(conn, "{} ", msg.len()).and_then(|_| conn.write_all(&msg));
+                if result.is_err() {
+                    *stream = None;
+                }
+
+                result
+            }
+        }
+    }
+
+    /// `<PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD `
+    fn header(&self, level: &Level) -> String {
+        let severity = match *level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        let pri = self.config.facility as u8 * 8 + severity;
+
+        format!(
+            "<{}>1 {} {} {} {} - - ",
+            pri,
+            DateTime::now(),
+            self.hostname,
+            APP_NAME,
+            self.pid
+        )
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        // NILVALUE of RFC 5424
+        return "-".to_string();
+    }
+
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config: SyslogConfig = serde_yaml::from_str(&format!(
+            "{{address: 'udp://{}', facility: local0}}",
+            server.local_addr().unwrap()
+        ))
+        .unwrap();
+        let syslog = Syslog::connect(config).unwrap();
+
+        syslog
+            .write(&Level::WARN, b"WARN roxy something\n")
+            .unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        let msg = std::str::from_utf8(&buf[..n]).unwrap();
+        // local0 * 8 + warning
+        assert!(msg.starts_with("<132>1 "), "{}", msg);
+        assert!(msg.ends_with("- - WARN roxy something"), "{}", msg);
+
+        assert!("http://127.0.0.1".parse::<Address>().is_err());
+    }
+}
@@ -1,20 +1,200 @@
+mod cli;
+#[cfg(feature = "controller")]
+mod ctl;
+mod reload;
 mod signals;
 
 #[cfg(feature = "scudo")]
 #[global_allocator]
 static SCUDO_ALLOCATOR: scudo::GlobalScudoAllocator = scudo::GlobalScudoAllocator;
 
+use std::collections::HashMap;
+use std::path::Path;
 use std::process::exit;
+use std::sync::Arc;
 
-use futures_util::stream::FuturesUnordered;
-use futures_util::{StreamExt, TryFutureExt};
 use resolver::Resolver;
-use tracing::{error, info};
+use tokio::sync::Notify;
+use tracing::{info, warn};
 
-use roxy::{controller, dns, thp, trace_init, Config, Upstream};
+use roxy::{
+    dns, drop_privileges, sandbox, trace_init, upgrade, Config, Connections, GeoIp, GeoIpConfig,
+    Override, Profiles, Router, Statsd, Upstream, CONFIG_TEMPLATE,
+};
+
+use crate::cli::{Command, ConfigArgs};
+use crate::reload::Services;
 
 fn main() {
-    let conf = match Config::load() {
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+
+        #[allow(clippy::print_stderr)]
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            exit(2);
+        }
+    };
+
+    match command {
+        Command::Run(args) => run(args),
+        Command::Check(args) => exit(check(&args)),
+        Command::Convert { input, output } => exit(convert(&input, output.as_deref())),
+        Command::Init { path, force } => exit(init(&path, force)),
+
+        #[cfg(feature = "controller")]
+        Command::Ctl(args) => exit(ctl::run(args)),
+
+        #[cfg(not(feature = "controller"))]
+        #[allow(clippy::print_stderr)]
+        Command::Ctl(_) => {
+            eprintln!("roxy is built without the controller feature");
+            exit(2);
+        }
+
+        #[allow(clippy::print_stdout)]
+        Command::Help => print!("{}", cli::USAGE),
+    }
+}
+
+/// Validate the config, and returns the exit code
+#[allow(clippy::print_stdout, clippy::print_stderr)]
+fn check(args: &ConfigArgs) -> i32 {
+    let path = &args.path;
+    match Config::check(path, &args.overrides) {
+        Ok((config, problems)) if problems.is_empty() => {
+            for warning in &config.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            println!("{} is valid", path.display());
+            0
+        }
+        Ok((config, problems)) => {
+            for warning in &config.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            for problem in &problems {
+                eprintln!("error: {}", problem);
+            }
+            eprintln!("{} has {} problem(s)", path.display(), problems.len());
+            1
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            1
+        }
+    }
+}
+
+/// Convert a foreign config, and returns the exit code
+#[allow(clippy::print_stdout, clippy::print_stderr)]
+fn convert(input: &Path, output: Option<&Path>) -> i32 {
+    let (yaml, warnings) = match Config::convert(input) {
+        Ok(converted) => converted,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return 1;
+        }
+    };
+    for warning in &warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    match output {
+        Some(output) => {
+            if let Err(err) = std::fs::write(output, yaml) {
+                eprintln!("error: write {} failed, {}", output.display(), err);
+                return 1;
+            }
+            eprintln!("{} is converted to {}", input.display(), output.display());
+        }
+        None => print!("{}", yaml),
+    }
+
+    0
+}
+
+/// Write the config template, and returns the exit code
+#[allow(clippy::print_stdout, clippy::print_stderr)]
+fn init(path: &Path, force: bool) -> i32 {
+    if path == Path::new("-") {
+        print!("{}", CONFIG_TEMPLATE);
+        return 0;
+    }
+
+    if path.exists() && !force {
+        eprintln!(
+            "error: {} exists, use --force to overwrite it",
+            path.display()
+        );
+        return 1;
+    }
+
+    if let Err(err) = std::fs::write(path, CONFIG_TEMPLATE) {
+        eprintln!("error: write {} failed, {}", path.display(), err);
+        return 1;
+    }
+    eprintln!(
+        "{} is created, edit upstream servers before running",
+        path.display()
+    );
+
+    0
+}
+
+/// Load a database of `GEOIP` or `IP-ASN` rules and keep it updated, the
+/// rules don't match until it's loaded
+fn open_database(config: Option<&GeoIpConfig>, resolver: &Resolver) -> GeoIp {
+    let config = match config {
+        Some(config) => config,
+        None => return GeoIp::default(),
+    };
+
+    let database = GeoIp::open(config).unwrap_or_else(|err| {
+        warn!(message = "load geoip database failed", %err, path = ?config.path);
+        GeoIp::default()
+    });
+    tokio::spawn(database.clone().update(config.clone(), resolver.clone()));
+
+    database
+}
+
+/// Fetch the remote config before anything starts, the cached one is
+/// used if it fails
+#[allow(clippy::print_stderr)]
+fn fetch_remote(conf: Config, path: &Path, overrides: &[Override]) -> Config {
+    if conf.remote.is_none() {
+        return conf;
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime failed");
+    let fetched = runtime.block_on(async {
+        let resolver = Resolver::new(conf.resolvers.clone()).expect("initial resolver failed");
+        conf.fetch_remote(resolver).await
+    });
+
+    match fetched {
+        Ok(true) => match Config::load_with_overrides(path, overrides) {
+            Ok(new) => new,
+            Err(err) => {
+                eprintln!("load remote config failed, use the cached one, {}", err);
+                conf
+            }
+        },
+        Ok(false) => conf,
+        Err(err) => {
+            eprintln!("fetch remote config failed, use the cached one, {}", err);
+            conf
+        }
+    }
+}
+
+fn run(args: ConfigArgs) {
+    let ConfigArgs { path, overrides } = args;
+    let conf = match Config::load_with_overrides(&path, &overrides) {
         Ok(conf) => conf,
 
         #[allow(clippy::print_stderr)]
@@ -23,8 +203,58 @@ fn main() {
             exit(1);
         }
     };
+    let conf = fetch_remote(conf, &path, &overrides);
+
+    let logging = match trace_init(&conf.log) {
+        Ok(handle) => handle,
+
+        #[allow(clippy::print_stderr)]
+        Err(err) => {
+            eprintln!("init logger failed, {}", err);
+            exit(1);
+        }
+    };
+
+    // started by an upgrade, listeners of the old process are served
+    match upgrade::inherit() {
+        Ok(0) => {}
+        Ok(received) => info!(
+            message = "listeners received from the old process",
+            received
+        ),
+
+        #[allow(clippy::print_stderr)]
+        Err(err) => {
+            eprintln!("take over listeners failed, {}", err);
+            exit(1);
+        }
+    }
 
-    trace_init(conf.log.level, conf.log.timestamp);
+    // log files are opened already, and no thread is spawned yet. The
+    // process is not root if it's started by an upgrade.
+    if conf.user.is_some() && unsafe { libc::geteuid() } == 0 {
+        match drop_privileges(&conf) {
+            Ok(()) => {}
+
+            #[allow(clippy::print_stderr)]
+            Err(err) => {
+                eprintln!("drop privileges failed, {}", err);
+                exit(1);
+            }
+        }
+    }
+
+    // after privileges are dropped, which needs system calls the sandbox
+    // forbids
+    match sandbox(&conf) {
+        Ok(()) => {}
+
+        #[allow(clippy::print_stderr)]
+        Err(err) => {
+            eprintln!("apply sandbox failed, {}", err);
+            exit(1);
+        }
+    }
 
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(conf.worker())
@@ -37,66 +267,122 @@ fn main() {
 
     runtime.block_on(async move {
         info!(message = "starting", worker = conf.worker());
-
-        let mut tasks = FuturesUnordered::new();
+        for warning in &conf.warnings {
+            warn!(message = "config warning", warning);
+        }
 
         // Build resolver for query provider's endpoint and server domain.
         info!(message = "use custom dns servers", resolvers = ?conf.resolvers);
         // Serde will make sure conf.resolvers is not empty, cause we don't use default for this field.
-        let resolver = Resolver::new(conf.resolvers).expect("initial resolver failed");
-
-        // init DNS server
-        let dns = dns::Server::new(conf.dns, resolver.clone())
-            .await
-            .expect("build dns server");
-        tasks.push(tokio::spawn(dns.serve().inspect_err(|err| {
-            error!(message = "dns server serve failed", ?err);
-        })));
+        let resolver = Resolver::new(conf.resolvers.clone()).expect("initial resolver failed");
 
         // init upstream
-        let upstream = Upstream::new(conf.upstream, resolver.clone())
+        let upstream = Upstream::new(conf.upstream.clone(), resolver.clone())
             .await
             .expect("init upstream failed");
 
+        let geoip = open_database(conf.geoip.as_ref(), &resolver);
+        let asn = open_database(conf.asn.as_ref(), &resolver);
+        let router = Router::new(conf.rules.clone(), geoip, asn);
+        router.update_script(conf.script.as_ref());
+
+        // init DNS server, queries may be relayed through the upstream
+        let dns = dns::Server::new(
+            conf.dns.clone(),
+            resolver.clone(),
+            upstream.clone(),
+            router.clone(),
+        )
+        .await
+        .expect("build dns server");
+
+        let connections = match logging.access_log() {
+            Some(access) => Connections::with_access_log(access),
+            None => Connections::default(),
+        };
+
+        let mut services = Services {
+            resolver,
+            upstream,
+            logging,
+            dns: dns.handler(),
+            connections,
+            profiles: Profiles::default(),
+            router,
+            dns_server: None,
+            controller: None,
+            inbounds: HashMap::new(),
+            config: conf,
+        };
+
+        services.profiles.update(&services.config);
+
+        tokio::spawn(services.connections.clone().run_talkers());
+        if let Some(exporter) = services.logging.exporter() {
+            tokio::spawn(exporter.run(services.resolver.clone(), services.connections.clone()));
+        }
+        if let Some(statsd) = services.config.log.statsd.clone() {
+            tokio::spawn(Statsd::new(statsd).run(services.connections.clone()));
+        }
+
+        // Services fail at startup make the process exit
+        services.dns_server = Some(services.dns_service(services.config.dns.listen.clone(), true));
+
         // init controller, our RESTful service
-        if let Some(cc) = conf.controller {
-            let svr =
-                controller::Server::new(cc, upstream.clone()).expect("create controller server");
-            tasks.push(tokio::spawn(svr.serve().inspect_err(|err| {
-                error!(message = "controller failed", ?err);
-            })));
-        }
-
-        if let Some(tc) = conf.thp {
-            tasks.push(tokio::spawn(
-                thp::serve(tc, upstream, resolver).inspect_err(|err| {
-                    error!(message = "transparent http proxy serve failed", ?err);
-                }),
-            ));
-        }
-
-        // Mimic Golang's errgroup
-        let tasks = async move {
-            while let Some(result) = tasks.next().await {
-                match result {
-                    Ok(Ok(())) => continue,
-                    Ok(Err(_err)) => {
-                        // Some task is returned with error, shutdown root CancellationToken, and exit
-                        exit(1);
-                    }
-                    Err(err) => {
-                        // This should never happened
-                        panic!("async task join failed, {}", err);
+        if let Some(cc) = services.config.controller.clone() {
+            let svc = services
+                .controller_service(cc, true)
+                .expect("create controller server");
+            services.controller = Some(svc);
+        }
+
+        for ic in services.config.inbounds.clone() {
+            let svc = services.inbound_service(ic.clone(), true);
+            services.inbounds.insert(ic.name, svc);
+        }
+
+        let remote_changed = Arc::new(Notify::new());
+        if let Some(remote) = services.config.remote.clone() {
+            if let Some(interval) = remote.interval {
+                tokio::spawn(reload::poll_remote(
+                    remote,
+                    interval,
+                    services.resolver.clone(),
+                    remote_changed.clone(),
+                ));
+            }
+        }
+
+        let upgraded = Arc::new(Notify::new());
+        if let Some(config) = services.config.upgrade.clone() {
+            let upgraded = upgraded.clone();
+            tokio::spawn(async move {
+                let mut requests = crate::signals::upgrade();
+                while requests.recv().await.is_some() {
+                    info!(message = "upgrade requested");
+                    match upgrade::handover(&config).await {
+                        Ok(()) => {
+                            upgraded.notify_one();
+                            return;
+                        }
+                        Err(err) => warn!(message = "upgrade failed, keep serving", %err),
                     }
                 }
-            }
-        };
+            });
+        }
+        tokio::spawn(upgrade::ready());
 
+        let drain = services.config.upgrade.as_ref().map(|config| config.drain);
+        let connections = services.connections.clone();
         tokio::select! {
             _ = crate::signals::shutdown() => {
                 // shutdown signal received
             },
-            _ = tasks => {}
+            _ = reload::watch(path, overrides, remote_changed, upgraded, services) => {
+                // the new process is serving, relaying connections are kept
+                // until they are closed
+                upgrade::drain(&connections, drain.unwrap_or_default()).await;
+            }
         }
     });
 
@@ -0,0 +1,289 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ParseError {
+    #[error("invalid address \"{0}\"")]
+    InvalidAddr(String),
+    #[error("invalid prefix length \"{0}\"")]
+    InvalidPrefix(String),
+    #[error("range \"{0}\" must be in the same family and start <= end")]
+    InvalidRange(String),
+}
+
+/// A network like `10.0.0.0/8` or `fd00::/8`, host bits are cleared,
+/// so `10.1.2.3/8` is `10.0.0.0/8`. A bare address is a network of itself.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let width = width(&addr);
+        if prefix > width {
+            return None;
+        }
+
+        let addr = from_bits(&addr, to_bits(&addr) & mask(prefix, width));
+
+        Some(Self { addr, prefix })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        if self.addr.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+
+        let width = width(ip);
+        to_bits(ip) & mask(self.prefix, width) == to_bits(&self.addr)
+    }
+
+    /// Bits of the network, from the most significant one
+    pub(super) fn bits(&self) -> impl Iterator<Item = bool> {
+        bits(&self.addr).take(self.prefix as usize)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| ParseError::InvalidAddr(s.to_string()))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .map_err(|_| ParseError::InvalidPrefix(s.to_string()))?,
+            None => width(&addr),
+        };
+
+        Cidr::new(addr, prefix).ok_or_else(|| ParseError::InvalidPrefix(s.to_string()))
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// An inclusive address range like `10.0.0.1-10.0.0.20`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    start: IpAddr,
+    end: IpAddr,
+}
+
+impl Range {
+    /// The smallest set of networks covering the range
+    pub fn cidrs(&self) -> Vec<Cidr> {
+        let width = width(&self.start);
+        let end = value(&self.end);
+        let mut start = value(&self.start);
+        let mut cidrs = vec![];
+
+        loop {
+            // the largest block aligned at `start` and not exceeding `end`
+            let mut host = if start == 0 {
+                width
+            } else {
+                (start.trailing_zeros() as u8).min(width)
+            };
+            while host > 0 && start + span(host) > end {
+                host -= 1;
+            }
+
+            let addr = from_value(&self.start, start);
+            cidrs.push(Cidr {
+                addr,
+                prefix: width - host,
+            });
+
+            let last = start + span(host);
+            if last >= end {
+                break;
+            }
+            start = last + 1;
+        }
+
+        cidrs
+    }
+}
+
+impl FromStr for Range {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| ParseError::InvalidRange(s.to_string()))?;
+        let parse = |addr: &str| {
+            addr.trim()
+                .parse::<IpAddr>()
+                .map_err(|_| ParseError::InvalidAddr(s.to_string()))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+
+        if start.is_ipv4() != end.is_ipv4() || value(&start) > value(&end) {
+            return Err(ParseError::InvalidRange(s.to_string()));
+        }
+
+        Ok(Range { start, end })
+    }
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+fn width(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Numeric value of the address
+fn value(addr: &IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(v4) => u32::from(*v4) as u128,
+        IpAddr::V6(v6) => u128::from(*v6),
+    }
+}
+
+fn from_value(family: &IpAddr, value: u128) -> IpAddr {
+    match family {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(value as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(value)),
+    }
+}
+
+/// The address aligned to the most significant bit, so IPv4 and IPv6
+/// can be masked the same way
+fn to_bits(addr: &IpAddr) -> u128 {
+    value(addr) << (128 - width(addr) as u32)
+}
+
+fn from_bits(family: &IpAddr, bits: u128) -> IpAddr {
+    from_value(family, bits >> (128 - width(family) as u32))
+}
+
+fn mask(prefix: u8, width: u8) -> u128 {
+    let prefix = prefix.min(width) as u32;
+    if prefix == 0 {
+        0
+    } else {
+        u128::MAX << (128 - prefix)
+    }
+}
+
+/// Number of addresses with `host` bits minus one, so `::/0` fits
+fn span(host: u8) -> u128 {
+    if host >= 128 {
+        u128::MAX
+    } else {
+        (1 << host) - 1
+    }
+}
+
+pub(super) fn bits(addr: &IpAddr) -> impl Iterator<Item = bool> {
+    let bits = to_bits(addr);
+    (0..width(addr) as u32).map(move |i| bits & (1 << (127 - i)) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cidr() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert!(cidr.contains(&"10.255.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::a00:1".parse().unwrap()));
+
+        let cidr: Cidr = "fd00::1".parse().unwrap();
+        assert_eq!(cidr.to_string(), "fd00::1/128");
+
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains(&"1.2.3.4".parse().unwrap()));
+
+        assert!(matches!(
+            "10.0.0.0/33".parse::<Cidr>(),
+            Err(ParseError::InvalidPrefix(_))
+        ));
+        assert!(matches!(
+            "example.com/8".parse::<Cidr>(),
+            Err(ParseError::InvalidAddr(_))
+        ));
+    }
+
+    #[test]
+    fn range_to_cidrs() {
+        let tests = [
+            ("10.0.0.0-10.0.0.255", vec!["10.0.0.0/24"]),
+            (
+                "10.0.0.1-10.0.0.20",
+                vec![
+                    "10.0.0.1/32",
+                    "10.0.0.2/31",
+                    "10.0.0.4/30",
+                    "10.0.0.8/29",
+                    "10.0.0.16/30",
+                    "10.0.0.20/32",
+                ],
+            ),
+            ("0.0.0.0-255.255.255.255", vec!["0.0.0.0/0"]),
+            ("::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", vec!["::/0"]),
+            ("fd00::-fd00::1", vec!["fd00::/127"]),
+        ];
+
+        for (input, want) in tests {
+            let range: Range = input.parse().unwrap();
+            let got = range
+                .cidrs()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            assert_eq!(got, want, "{}", input);
+        }
+
+        assert!("10.0.0.2-10.0.0.1".parse::<Range>().is_err());
+        assert!("10.0.0.1-::1".parse::<Range>().is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! TCP keepalive of relayed sockets, so NAT middleboxes and firewalls
+//! between roxy and its peers don't drop idle long-lived connections
+//! silently, and dead peers are found out.
+//!
+//! ```yaml
+//! upstream:
+//!   keepalive:
+//!     time: 1m
+//!     interval: 15s
+//!     probes: 4
+//! inbounds:
+//!   - name: lan
+//!     protocol: thp
+//!     listen: 0.0.0.0:1080
+//!     keepalive:
+//!       time: 2m
+//! ```
+//!
+//! Keepalive of an inbound applies to its client sockets, and to the
+//! destination sockets of its direct connections, the upstream one applies
+//! to connections to upstream servers.
+//!
+//! Probes start after `time` without traffic, and are sent every
+//! `interval`, the connection is closed once `probes` of them are not
+//! answered.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::serde::duration;
+
+const fn default_time() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_interval() -> Duration {
+    Duration::from_secs(15)
+}
+
+const fn default_probes() -> u32 {
+    4
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KeepaliveConfig {
+    /// Idle time before the first probe
+    #[serde(default = "default_time", with = "duration")]
+    pub time: Duration,
+
+    #[serde(default = "default_interval", with = "duration")]
+    pub interval: Duration,
+
+    /// Unanswered probes to close the connection
+    #[serde(default = "default_probes")]
+    pub probes: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            time: default_time(),
+            interval: default_interval(),
+            probes: default_probes(),
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    /// Enable keepalive on `socket`, times are in whole seconds, at least 1
+    pub fn apply(&self, socket: &impl AsRawFd) -> io::Result<()> {
+        let secs = |d: Duration| d.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+        let fd = socket.as_raw_fd();
+
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs(self.time))?;
+        setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPINTVL,
+            secs(self.interval),
+        )?;
+        setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPCNT,
+            self.probes.min(libc::c_int::MAX as u32) as libc::c_int,
+        )
+    }
+}
+
+fn setsockopt(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn getsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+
+        value
+    }
+
+    #[test]
+    fn apply() {
+        let config: KeepaliveConfig = serde_yaml::from_str("{time: 2m, probes: 3}").unwrap();
+        assert_eq!(config.interval, default_interval());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        config.apply(&stream).unwrap();
+
+        let fd = stream.as_raw_fd();
+        assert_eq!(getsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 120);
+        assert_eq!(getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 15);
+        assert_eq!(getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 3);
+    }
+}
@@ -0,0 +1,10 @@
+//! Networks and address lookups, shared by rules which match addresses,
+//! e.g. ACLs and routing, and options of relayed sockets.
+
+mod cidr;
+mod keepalive;
+mod trie;
+
+pub use cidr::{Cidr, ParseError, Range};
+pub use keepalive::KeepaliveConfig;
+pub use trie::IpTrie;
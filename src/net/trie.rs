@@ -0,0 +1,160 @@
+use std::net::IpAddr;
+
+use super::cidr::{bits, Cidr};
+
+struct Node<T> {
+    children: [Option<usize>; 2],
+    value: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Self {
+            children: [None, None],
+            value: None,
+        }
+    }
+}
+
+/// A binary trie of networks, lookups return the value of the longest
+/// matching prefix, e.g. with `10.0.0.0/8` and `10.1.0.0/16` inserted,
+/// `10.1.2.3` matches the latter. Nodes are kept in a Vec, so it's cheap
+/// to build and drop, which happens when rules are reloaded.
+pub struct IpTrie<T> {
+    v4: Vec<Node<T>>,
+    v6: Vec<Node<T>>,
+    len: usize,
+}
+
+impl<T> Default for IpTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> IpTrie<T> {
+    pub fn new() -> Self {
+        Self {
+            v4: vec![Node::new()],
+            v6: vec![Node::new()],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert the network, returns the old value if it is inserted before
+    pub fn insert(&mut self, cidr: Cidr, value: T) -> Option<T> {
+        let nodes = if cidr.addr().is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+
+        let mut index = 0;
+        for bit in cidr.bits() {
+            index = match nodes[index].children[bit as usize] {
+                Some(child) => child,
+                None => {
+                    nodes.push(Node::new());
+                    let child = nodes.len() - 1;
+                    nodes[index].children[bit as usize] = Some(child);
+                    child
+                }
+            };
+        }
+
+        let old = nodes[index].value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+
+        old
+    }
+
+    /// Value of the longest network containing `ip`
+    pub fn longest_match(&self, ip: &IpAddr) -> Option<&T> {
+        let nodes = if ip.is_ipv4() { &self.v4 } else { &self.v6 };
+
+        let mut index = 0;
+        let mut matched = nodes[0].value.as_ref();
+        for bit in bits(ip) {
+            index = match nodes[index].children[bit as usize] {
+                Some(child) => child,
+                None => break,
+            };
+
+            if let Some(value) = &nodes[index].value {
+                matched = Some(value);
+            }
+        }
+
+        matched
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.longest_match(ip).is_some()
+    }
+}
+
+impl<T> FromIterator<(Cidr, T)> for IpTrie<T> {
+    fn from_iter<I: IntoIterator<Item = (Cidr, T)>>(iter: I) -> Self {
+        let mut trie = IpTrie::new();
+        for (cidr, value) in iter {
+            trie.insert(cidr, value);
+        }
+
+        trie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_match() {
+        let trie = [
+            ("10.0.0.0/8", "private"),
+            ("10.1.0.0/16", "office"),
+            ("10.1.2.3", "printer"),
+            ("fd00::/8", "ula"),
+        ]
+        .into_iter()
+        .map(|(cidr, value)| (cidr.parse().unwrap(), value))
+        .collect::<IpTrie<_>>();
+        assert_eq!(trie.len(), 4);
+
+        let tests = [
+            ("10.0.0.1", Some("private")),
+            ("10.1.0.1", Some("office")),
+            ("10.1.2.3", Some("printer")),
+            ("11.0.0.1", None),
+            ("fd12::1", Some("ula")),
+            ("fe80::1", None),
+            // IPv4-mapped addresses are not IPv4
+            ("::ffff:10.0.0.1", None),
+        ];
+        for (ip, want) in tests {
+            assert_eq!(
+                trie.longest_match(&ip.parse().unwrap()).copied(),
+                want,
+                "{}",
+                ip
+            );
+        }
+
+        let mut trie = IpTrie::new();
+        assert_eq!(trie.insert("0.0.0.0/0".parse().unwrap(), 1), None);
+        assert_eq!(trie.insert("0.0.0.0/0".parse().unwrap(), 2), Some(1));
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.longest_match(&"1.2.3.4".parse().unwrap()), Some(&2));
+        assert!(!trie.contains(&"::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,214 @@
+//! Run as an unprivileged user, e.g.
+//!
+//! ```yaml
+//! user: nobody
+//! group: nogroup
+//! ```
+//!
+//! Privileges are dropped before any service starts, `CAP_NET_BIND_SERVICE`
+//! is kept, so privileged ports can still be bound by inbounds and the DNS
+//! server, on startup and on hot reload. `CAP_NET_ADMIN` is kept too if
+//! `tproxy` inbounds or marks are configured at startup, they open
+//! transparent sockets and set `SO_MARK` at runtime, and `CAP_NET_RAW` if
+//! interfaces are, `SO_BINDTODEVICE` needs it on kernels before 5.7.
+
+use std::ffi::CString;
+use std::io;
+
+use crate::inbound::Protocol;
+use crate::Config;
+
+/// `linux/capability.h`
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown user \"{0}\"")]
+    UnknownUser(String),
+    #[error("unknown group \"{0}\"")]
+    UnknownGroup(String),
+    #[error("{0} failed, {1}")]
+    Syscall(&'static str, io::Error),
+}
+
+/// Uid and primary gid of the user
+pub fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), Error> {
+    let cname = CString::new(name).map_err(|_| Error::UnknownUser(name.to_string()))?;
+
+    // it's called before the runtime starts, so the static buffer of
+    // getpwnam is not shared
+    let passwd = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if passwd.is_null() {
+        return Err(Error::UnknownUser(name.to_string()));
+    }
+
+    unsafe { Ok(((*passwd).pw_uid, (*passwd).pw_gid)) }
+}
+
+pub fn lookup_group(name: &str) -> Result<libc::gid_t, Error> {
+    let cname = CString::new(name).map_err(|_| Error::UnknownGroup(name.to_string()))?;
+
+    let group = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if group.is_null() {
+        return Err(Error::UnknownGroup(name.to_string()));
+    }
+
+    unsafe { Ok((*group).gr_gid) }
+}
+
+/// Capabilities kept after switching users, features of `config` need
+/// some of them at runtime
+fn kept(config: &Config) -> u32 {
+    let mut caps = 1 << CAP_NET_BIND_SERVICE;
+    // replies are sent by a new transparent socket for each remote peer,
+    // and SO_MARK is set on every outbound socket
+    let transparent = config
+        .inbounds
+        .iter()
+        .any(|inbound| inbound.protocol == Protocol::Tproxy);
+    let marked = config.upstream.mark.is_some()
+        || !config.upstream.marks.is_empty()
+        || config.inbounds.iter().any(|inbound| inbound.mark.is_some())
+        || config.rules.iter().any(|rule| rule.mark.is_some());
+    if transparent || marked {
+        caps |= 1 << CAP_NET_ADMIN;
+    }
+
+    let bound = config.upstream.interface.is_some()
+        || !config.upstream.interfaces.is_empty()
+        || config
+            .upstream
+            .groups
+            .values()
+            .any(|group| group.interface.is_some())
+        || config
+            .inbounds
+            .iter()
+            .any(|inbound| inbound.interface.is_some());
+    if bound {
+        caps |= 1 << CAP_NET_RAW;
+    }
+
+    caps
+}
+
+/// Switch to `user` and `group` of `config`, the primary group of the user
+/// is used if `group` is not set. It must be called while the process has
+/// only one thread.
+pub fn drop_privileges(config: &Config) -> Result<(), Error> {
+    let user = match &config.user {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    let (uid, primary) = lookup_user(user)?;
+    let gid = match &config.group {
+        Some(group) => lookup_group(group)?,
+        None => primary,
+    };
+
+    // keep permitted capabilities across setuid, they are reduced to the
+    // kept ones right after
+    check("prctl", unsafe {
+        libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0)
+    })?;
+    check("setgroups", unsafe { libc::setgroups(1, &gid) })?;
+    check("setgid", unsafe { libc::setgid(gid) })?;
+    check("setuid", unsafe { libc::setuid(uid) })?;
+
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+    data[0].effective = kept(config);
+    data[0].permitted = kept(config);
+    check("capset", unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapUserHeader,
+            data.as_mut_ptr(),
+        ) as libc::c_int
+    })?;
+    check("prctl", unsafe {
+        libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0)
+    })?;
+
+    Ok(())
+}
+
+fn check(name: &'static str, ret: libc::c_int) -> Result<(), Error> {
+    if ret != 0 {
+        return Err(Error::Syscall(name, io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        assert!(matches!(
+            lookup_user("no-such-user-of-roxy"),
+            Err(Error::UnknownUser(_))
+        ));
+    }
+
+    #[test]
+    fn kept_capabilities() {
+        let builder = || {
+            Config::builder()
+                .resolver("1.1.1.1:53".parse().unwrap())
+                .nameserver("8.8.8.8:53".parse().unwrap())
+                .server("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388#local")
+        };
+
+        let config = builder().build().unwrap();
+        assert_eq!(kept(&config), 1 << CAP_NET_BIND_SERVICE);
+
+        let mut config = builder().build().unwrap();
+        config.upstream.marks.insert("local".to_string(), 255);
+        assert_eq!(
+            kept(&config),
+            1 << CAP_NET_BIND_SERVICE | 1 << CAP_NET_ADMIN
+        );
+
+        let mut config = builder().build().unwrap();
+        config.upstream.interface = Some("eth0".to_string());
+        assert_eq!(kept(&config), 1 << CAP_NET_BIND_SERVICE | 1 << CAP_NET_RAW);
+
+        let config = builder()
+            .inbound(crate::inbound::Config::new(
+                "quic",
+                Protocol::Tproxy,
+                "0.0.0.0:1080".parse().unwrap(),
+            ))
+            .build()
+            .unwrap();
+        assert_eq!(
+            kept(&config),
+            1 << CAP_NET_BIND_SERVICE | 1 << CAP_NET_ADMIN
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Ban sources which keep failing the handshake, e.g. scanners probing a
+//! public port with garbage, so they stop costing sniffing work
+//!
+//! ```yaml
+//! inbounds:
+//!   - name: public
+//!     protocol: thp
+//!     listen: 0.0.0.0:443
+//!     ban:
+//!       failures: 5
+//!       window: 1m
+//!       duration: 10m
+//! ```
+//!
+//! A source failing 5 times in a minute is refused for 10 minutes,
+//! connections from it are closed as soon as they are accepted.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::serde::duration;
+
+/// Sources without recent failures are dropped once the table grows
+/// beyond this
+const MAX_IDLE_SOURCES: usize = 4096;
+
+const fn default_failures() -> usize {
+    5
+}
+
+const fn default_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_duration() -> Duration {
+    Duration::from_secs(600)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BanConfig {
+    /// Failures in `window` to ban a source
+    #[serde(default = "default_failures")]
+    pub failures: usize,
+
+    #[serde(default = "default_window", with = "duration")]
+    pub window: Duration,
+
+    /// How long a source is banned
+    #[serde(default = "default_duration", with = "duration")]
+    pub duration: Duration,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            failures: default_failures(),
+            window: default_window(),
+            duration: default_duration(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Source {
+    failures: VecDeque<Instant>,
+    until: Option<Instant>,
+}
+
+impl Source {
+    fn idle(&self, now: Instant, window: Duration) -> bool {
+        let banned = self.until.map(|until| until > now).unwrap_or(false);
+        let recent = self
+            .failures
+            .back()
+            .map(|last| now.duration_since(*last) < window)
+            .unwrap_or(false);
+
+        !banned && !recent
+    }
+}
+
+pub struct Bans {
+    config: BanConfig,
+    sources: Mutex<HashMap<IpAddr, Source>>,
+}
+
+impl Bans {
+    pub fn new(config: BanConfig) -> Self {
+        Self {
+            config,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn banned(&self, ip: &IpAddr) -> bool {
+        let now = Instant::now();
+
+        self.sources
+            .lock()
+            .get(ip)
+            .and_then(|source| source.until)
+            .map(|until| until > now)
+            .unwrap_or(false)
+    }
+
+    /// Record a failed handshake, returns true if the source is banned
+    /// by this one
+    pub fn fail(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut sources = self.sources.lock();
+
+        if sources.len() > MAX_IDLE_SOURCES {
+            let window = self.config.window;
+            sources.retain(|_, source| !source.idle(now, window));
+        }
+
+        let source = sources.entry(ip).or_default();
+        while let Some(first) = source.failures.front() {
+            if now.duration_since(*first) < self.config.window {
+                break;
+            }
+            source.failures.pop_front();
+        }
+        source.failures.push_back(now);
+
+        if source.failures.len() < self.config.failures {
+            return false;
+        }
+
+        source.failures.clear();
+        source.until = Some(now + self.config.duration);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban() {
+        let bans = Bans::new(BanConfig {
+            failures: 3,
+            ..Default::default()
+        });
+        let a = "203.0.113.1".parse().unwrap();
+        let b = "203.0.113.2".parse().unwrap();
+
+        assert!(!bans.fail(a));
+        assert!(!bans.fail(a));
+        assert!(!bans.banned(&a));
+        assert!(bans.fail(a));
+        assert!(bans.banned(&a));
+
+        // other sources are not affected
+        assert!(!bans.fail(b));
+        assert!(!bans.banned(&b));
+    }
+}
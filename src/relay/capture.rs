@@ -0,0 +1,488 @@
+//! On-demand capture of relayed connections to a pcap file, started and
+//! stopped by the controller, e.g.
+//!
+//! ```text
+//! curl -X POST -d '{"path": "roxy.pcap", "host": "example.com", "max_size": "10MiB", "duration": "1m"}' \
+//!     http://127.0.0.1:9000/capture
+//! curl -X DELETE http://127.0.0.1:9000/capture
+//! ```
+//!
+//! The plaintext between clients and roxy is captured, which is the
+//! traffic before AEAD encryption of proxied connections, IP and TCP
+//! headers are made up from the client and local addresses, so Wireshark
+//! can follow the streams. Only connections accepted after the capture
+//! started are captured.
+//!
+//! `path` is a file name in `controller.capture_dir`, captures are refused
+//! without it. Files are never overwritten, so a client of the controller
+//! can't clobber files writable by roxy.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::connections::Connection;
+use crate::net::{Cidr, ParseError};
+
+/// LINKTYPE_RAW, packets begin with the IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+
+const SNAPLEN: u32 = 65535;
+
+/// Payload of each packet, so the IP total length never overflows
+const MAX_SEGMENT: usize = 65000;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// File name in the capture directory
+    pub path: PathBuf,
+
+    /// Capture connections to this host or its subdomains only
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// Capture connections from this address or network only
+    #[serde(default)]
+    pub client: Option<String>,
+
+    /// Capture connections accepted by this inbound only
+    #[serde(default)]
+    pub inbound: Option<String>,
+
+    /// Stop when the file would grow over this
+    #[serde(default, with = "crate::serde::size::option")]
+    pub max_size: Option<u64>,
+
+    /// Stop after this long
+    #[serde(default, with = "crate::serde::duration::option")]
+    pub duration: Option<Duration>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("a capture is running")]
+    Running,
+    #[error("captures are disabled, controller.capture_dir is not set")]
+    Disabled,
+    #[error("path must be a file name in controller.capture_dir, not {0:?}")]
+    InvalidPath(PathBuf),
+    #[error("invalid client, {0}")]
+    Client(#[from] ParseError),
+    #[error("create {path:?} failed, {err}")]
+    Create { path: PathBuf, err: io::Error },
+}
+
+#[derive(Serialize)]
+pub struct CaptureStat {
+    pub path: PathBuf,
+    pub packets: u64,
+    pub size: u64,
+    pub finished: bool,
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    packets: u64,
+    size: u64,
+    finished: bool,
+}
+
+struct Session {
+    config: CaptureConfig,
+    client: Option<Cidr>,
+    deadline: Option<Instant>,
+    writer: Mutex<Writer>,
+}
+
+impl Session {
+    fn matches(&self, conn: &Connection) -> bool {
+        if let Some(host) = &self.config.host {
+            let matched = conn.host() == host
+                || conn
+                    .host()
+                    .strip_suffix(host.as_str())
+                    .map(|prefix| prefix.ends_with('.'))
+                    .unwrap_or(false);
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(client) = &self.client {
+            if !client.contains(&conn.src().ip()) {
+                return false;
+            }
+        }
+        if let Some(inbound) = &self.config.inbound {
+            if conn.inbound() != inbound {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn write(&self, packet: &[u8]) {
+        let mut writer = self.writer.lock();
+        if writer.finished {
+            return;
+        }
+
+        let record = 16 + packet.len() as u64;
+        let expired = self
+            .deadline
+            .map(|deadline| Instant::now() >= deadline)
+            .unwrap_or(false);
+        let oversize = self
+            .config
+            .max_size
+            .map(|max| writer.size + record > max)
+            .unwrap_or(false);
+        if expired || oversize {
+            writer.finish();
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&now.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+
+        let result = writer
+            .file
+            .write_all(&header)
+            .and_then(|_| writer.file.write_all(packet));
+        match result {
+            Ok(_) => {
+                writer.packets += 1;
+                writer.size += record;
+            }
+            Err(err) => {
+                warn!(message = "write capture failed", path = ?self.config.path, %err);
+                writer.finish();
+            }
+        }
+    }
+}
+
+impl Writer {
+    fn finish(&mut self) {
+        self.finished = true;
+        let _ = self.file.flush();
+    }
+}
+
+/// `name` in `dir`, if it's a bare file name
+fn capture_path(dir: &Path, name: &Path) -> Result<PathBuf, Error> {
+    let mut components = name.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(file)), None) if !name.as_os_str().as_bytes().contains(&b'/') => {
+            Ok(dir.join(file))
+        }
+        _ => Err(Error::InvalidPath(name.to_path_buf())),
+    }
+}
+
+/// The capture session, shared by the controller and relays
+#[derive(Clone, Default)]
+pub struct Capture {
+    session: Arc<Mutex<Option<Arc<Session>>>>,
+}
+
+impl Capture {
+    /// Start capturing to `config.path` in `dir`
+    pub fn start(&self, dir: Option<&Path>, mut config: CaptureConfig) -> Result<(), Error> {
+        let dir = dir.ok_or(Error::Disabled)?;
+        config.path = capture_path(dir, &config.path)?;
+
+        let mut session = self.session.lock();
+        if let Some(running) = &*session {
+            if !running.writer.lock().finished {
+                return Err(Error::Running);
+            }
+        }
+
+        let client = config.client.as_deref().map(str::parse).transpose()?;
+        let create = |path: &PathBuf| -> io::Result<BufWriter<File>> {
+            let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+            let mut file = BufWriter::new(file);
+            // pcap global header, little endian, version 2.4
+            file.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+            file.write_all(&2u16.to_le_bytes())?;
+            file.write_all(&4u16.to_le_bytes())?;
+            file.write_all(&[0; 8])?;
+            file.write_all(&SNAPLEN.to_le_bytes())?;
+            file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+            Ok(file)
+        };
+        let file = create(&config.path).map_err(|err| Error::Create {
+            path: config.path.clone(),
+            err,
+        })?;
+
+        *session = Some(Arc::new(Session {
+            deadline: config.duration.map(|duration| Instant::now() + duration),
+            client,
+            config,
+            writer: Mutex::new(Writer {
+                file,
+                packets: 0,
+                size: 24,
+                finished: false,
+            }),
+        }));
+
+        Ok(())
+    }
+
+    /// Stop the capture and flush the file, returns false if there is none
+    pub fn stop(&self) -> bool {
+        match self.session.lock().take() {
+            Some(session) => {
+                session.writer.lock().finish();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stat(&self) -> Option<CaptureStat> {
+        self.session.lock().as_ref().map(|session| {
+            let mut writer = session.writer.lock();
+            let expired = session
+                .deadline
+                .map(|deadline| Instant::now() >= deadline)
+                .unwrap_or(false);
+            if expired && !writer.finished {
+                writer.finish();
+            }
+
+            CaptureStat {
+                path: session.config.path.clone(),
+                packets: writer.packets,
+                size: writer.size,
+                finished: writer.finished,
+            }
+        })
+    }
+
+    /// The flow of a new connection, if it's selected by the capture
+    pub(super) fn flow(&self, conn: &Connection) -> Option<Flow> {
+        let session = self.session.lock().clone()?;
+        if session.writer.lock().finished || !session.matches(conn) {
+            return None;
+        }
+
+        let flow = Flow {
+            session,
+            client: conn.src(),
+            local: conn.local(),
+            upload: AtomicU32::new(0),
+            download: AtomicU32::new(0),
+        };
+        flow.handshake();
+
+        Some(flow)
+    }
+}
+
+/// A captured connection, it tracks sequence numbers of both directions
+pub(super) struct Flow {
+    session: Arc<Session>,
+    client: SocketAddr,
+    local: SocketAddr,
+    /// Next sequence number from the client and from roxy
+    upload: AtomicU32,
+    download: AtomicU32,
+}
+
+impl Flow {
+    fn handshake(&self) {
+        self.segment(true, SYN, 0, 0, &[]);
+        self.segment(false, SYN | ACK, 0, 1, &[]);
+        self.segment(true, ACK, 1, 1, &[]);
+        self.upload.store(1, Ordering::Relaxed);
+        self.download.store(1, Ordering::Relaxed);
+    }
+
+    /// Data read from the client
+    pub fn upload(&self, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            let seq = self.upload.fetch_add(chunk.len() as u32, Ordering::Relaxed);
+            let ack = self.download.load(Ordering::Relaxed);
+            self.segment(true, PSH | ACK, seq, ack, chunk);
+        }
+    }
+
+    /// Data written to the client
+    pub fn download(&self, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            let seq = self
+                .download
+                .fetch_add(chunk.len() as u32, Ordering::Relaxed);
+            let ack = self.upload.load(Ordering::Relaxed);
+            self.segment(false, PSH | ACK, seq, ack, chunk);
+        }
+    }
+
+    fn segment(&self, from_client: bool, flags: u8, seq: u32, ack: u32, payload: &[u8]) {
+        let (src, dst) = if from_client {
+            (self.client, self.local)
+        } else {
+            (self.local, self.client)
+        };
+
+        if let Some(packet) = packet(src, dst, flags, seq, ack, payload) {
+            self.session.write(&packet);
+        }
+    }
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        let upload = self.upload.load(Ordering::Relaxed);
+        let download = self.download.load(Ordering::Relaxed);
+        self.segment(true, FIN | ACK, upload, download, &[]);
+        self.segment(false, FIN | ACK, download, upload + 1, &[]);
+    }
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// An IP packet with a TCP segment, checksums are left zero
+fn packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    flags: u8,
+    seq: u32,
+    ack: u32,
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    // data offset 5 words, flags, window, checksum, urgent pointer
+    tcp.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let mut packet = vec![];
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total = u16::try_from(20 + tcp.len()).ok()?;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total.to_be_bytes());
+            // id, don't fragment, ttl, protocol and checksum
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let len = u16::try_from(tcp.len()).ok()?;
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&len.to_be_bytes());
+            // next header and hop limit
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&ipv6(src).octets());
+            packet.extend_from_slice(&ipv6(dst).octets());
+        }
+    }
+    packet.extend_from_slice(&tcp);
+
+    Some(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_in_dir() {
+        let dir = Path::new("/var/lib/roxy/captures");
+        assert_eq!(
+            capture_path(dir, Path::new("roxy.pcap")).unwrap(),
+            dir.join("roxy.pcap")
+        );
+        for name in ["/etc/passwd", "../passwd", "..", ".", "a/b.pcap", "a/", ""] {
+            assert!(capture_path(dir, Path::new(name)).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn never_overwrite() {
+        let dir = std::env::temp_dir().join(format!("roxy-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("old.pcap"), "old").unwrap();
+
+        let config = |path: &str| -> CaptureConfig {
+            serde_json::from_value(serde_json::json!({ "path": path })).unwrap()
+        };
+        let capture = Capture::default();
+        assert!(matches!(
+            capture.start(None, config("new.pcap")),
+            Err(Error::Disabled)
+        ));
+        assert!(matches!(
+            capture.start(Some(&dir), config("old.pcap")),
+            Err(Error::Create { err, .. }) if err.kind() == io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(std::fs::read(dir.join("old.pcap")).unwrap(), b"old");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encode_packet() {
+        let v4 = packet(
+            "192.168.1.2:50000".parse().unwrap(),
+            "192.168.1.1:80".parse().unwrap(),
+            PSH | ACK,
+            1,
+            1,
+            b"GET",
+        )
+        .unwrap();
+        assert_eq!(v4.len(), 20 + 20 + 3);
+        assert_eq!(&v4[2..4], &43u16.to_be_bytes());
+        assert_eq!(&v4[12..16], &[192, 168, 1, 2]);
+        assert_eq!(&v4[20..22], &50000u16.to_be_bytes());
+        assert_eq!(v4[33], PSH | ACK);
+        assert_eq!(&v4[40..], b"GET");
+
+        // mixed families are written as IPv6 with mapped addresses
+        let v6 = packet(
+            "[::1]:50000".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+            SYN,
+            0,
+            0,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(v6[0], 0x60);
+        assert_eq!(v6.len(), 40 + 20);
+    }
+}
@@ -0,0 +1,179 @@
+//! Cap concurrent connections of each source, so one misbehaving device,
+//! e.g. a P2P client opening thousands of connections, can't exhaust the
+//! sockets and memory of the router
+//!
+//! ```yaml
+//! inbounds:
+//!   - name: lan
+//!     protocol: thp
+//!     listen: 0.0.0.0:1080
+//!     client_limit:
+//!       max_connections: 256
+//!       overflow: queue
+//!       queue_timeout: 1s
+//! ```
+//!
+//! A source with `max_connections` in flight has new ones closed, or with
+//! `queue`, waiting up to `queue_timeout` for one of them to close first.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+use crate::serde::duration;
+
+/// What to do with a new connection of a source at its cap
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Close the connection
+    #[default]
+    Reject,
+    /// Wait for `queue_timeout` until a connection of the source closed
+    Queue,
+}
+
+const fn default_queue_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientLimitConfig {
+    /// In-flight connections of each source
+    pub max_connections: usize,
+
+    #[serde(default)]
+    pub overflow: Overflow,
+
+    #[serde(default = "default_queue_timeout", with = "duration")]
+    pub queue_timeout: Duration,
+}
+
+/// Connections of sources of an inbound, shared by its acceptors
+pub struct ClientLimits {
+    config: ClientLimitConfig,
+    /// Sources with connections in flight, or waiting for one
+    sources: Arc<Mutex<HashMap<IpAddr, Arc<Semaphore>>>>,
+}
+
+/// A slot of the source's connections, it's released when dropped
+pub struct ClientPermit {
+    ip: IpAddr,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+    sources: Arc<Mutex<HashMap<IpAddr, Arc<Semaphore>>>>,
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let mut sources = self.sources.lock();
+        self.permit.take();
+        // only the table and this one hold it, nobody is waiting
+        if Arc::strong_count(&self.semaphore) == 2 {
+            sources.remove(&self.ip);
+        }
+    }
+}
+
+impl ClientLimits {
+    pub fn new(config: ClientLimitConfig) -> Self {
+        Self {
+            config,
+            sources: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Take a slot of `ip`, None if it's at the cap, after waiting if the
+    /// overflow is `Queue`
+    pub async fn acquire(&self, ip: IpAddr) -> Option<ClientPermit> {
+        let semaphore = self
+            .sources
+            .lock()
+            .entry(ip)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.config.max_connections)))
+            .clone();
+
+        let permit = match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) if self.config.overflow == Overflow::Queue => {
+                let acquire = semaphore.clone().acquire_owned();
+                time::timeout(self.config.queue_timeout, acquire)
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+            }
+            Err(_) => None,
+        };
+
+        // the entry is removed with the last permit, or this one
+        let permit = ClientPermit {
+            ip,
+            semaphore,
+            permit,
+            sources: self.sources.clone(),
+        };
+        if permit.permit.is_some() {
+            Some(permit)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limit() {
+        let limits = ClientLimits::new(ClientLimitConfig {
+            max_connections: 2,
+            overflow: Overflow::Reject,
+            queue_timeout: default_queue_timeout(),
+        });
+        let a = "192.168.1.10".parse().unwrap();
+        let b = "192.168.1.11".parse().unwrap();
+
+        let first = limits.acquire(a).await.unwrap();
+        let _second = limits.acquire(a).await.unwrap();
+        assert!(limits.acquire(a).await.is_none());
+        // other sources are not affected
+        let other = limits.acquire(b).await.unwrap();
+        assert_eq!(limits.sources.lock().len(), 2);
+
+        drop(first);
+        assert!(limits.acquire(a).await.is_some());
+        drop(other);
+        assert_eq!(limits.sources.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn queue() {
+        let limits = Arc::new(ClientLimits::new(ClientLimitConfig {
+            max_connections: 1,
+            overflow: Overflow::Queue,
+            queue_timeout: Duration::from_millis(100),
+        }));
+        let a: IpAddr = "192.168.1.10".parse().unwrap();
+
+        // times out while the first one is open
+        let first = limits.acquire(a).await.unwrap();
+        assert!(limits.acquire(a).await.is_none());
+
+        let queued = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.acquire(a).await.is_some() }
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        drop(first);
+        assert!(queued.await.unwrap());
+        assert_eq!(limits.sources.lock().len(), 0);
+    }
+}
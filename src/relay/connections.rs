@@ -0,0 +1,479 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+use pin_project_lite::pin_project;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::capture::{Capture, Flow};
+use super::inbound::Route;
+use super::metrics::{Metrics, Sample};
+use super::talkers::{Talkers, TopTalkers, Window, SLOT};
+use super::tcp_info::{Socket, TcpStats};
+use crate::log::AccessLog;
+use crate::DateTime;
+
+/// A relayed connection, upload is the traffic from client to upstream
+pub struct Connection {
+    id: u64,
+    inbound: String,
+    route: Route,
+    src: SocketAddr,
+    /// Address the client connected to
+    local: SocketAddr,
+    host: String,
+    port: u16,
+    start: SystemTime,
+    upstream: Mutex<Option<String>>,
+    destination: Mutex<Option<String>>,
+    close: Mutex<Option<String>>,
+
+    /// Since `start`
+    dialed: Mutex<Option<Duration>>,
+    first_byte: Mutex<Option<Duration>>,
+
+    /// The upstream socket while relaying, and its last stats once closed
+    socket: Mutex<Option<Socket>>,
+    tcp: Mutex<Option<TcpStats>>,
+
+    upload: AtomicU64,
+    download: AtomicU64,
+    /// Traffic already added to the talkers
+    reported: Mutex<(u64, u64)>,
+}
+
+impl Connection {
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn inbound(&self) -> &str {
+        &self.inbound
+    }
+
+    pub fn route(&self) -> Route {
+        self.route
+    }
+
+    pub fn src(&self) -> SocketAddr {
+        self.src
+    }
+
+    pub fn local(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn start(&self) -> SystemTime {
+        self.start
+    }
+
+    pub fn upload(&self) -> u64 {
+        self.upload.load(Ordering::Relaxed)
+    }
+
+    pub fn download(&self) -> u64 {
+        self.download.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream(&self) -> Option<String> {
+        self.upstream.lock().clone()
+    }
+
+    pub fn set_upstream(&self, name: String) {
+        *self.upstream.lock() = Some(name);
+    }
+
+    /// Address actually connected, the upstream server or the destination
+    pub fn destination(&self) -> Option<String> {
+        self.destination.lock().clone()
+    }
+
+    pub fn set_destination(&self, addr: String) {
+        *self.destination.lock() = Some(addr);
+    }
+
+    /// Time to connect the upstream server or the destination
+    pub fn dialed(&self) -> Option<Duration> {
+        *self.dialed.lock()
+    }
+
+    pub fn set_dialed(&self) {
+        *self.dialed.lock() = Some(self.start.elapsed().unwrap_or_default());
+    }
+
+    /// Time to the first byte sent to the client
+    pub fn first_byte(&self) -> Option<Duration> {
+        *self.first_byte.lock()
+    }
+
+    pub fn add_upload(&self, n: u64) {
+        self.upload.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_download(&self, n: u64) {
+        let before = self.download.fetch_add(n, Ordering::Relaxed);
+        if before == 0 && n > 0 {
+            *self.first_byte.lock() = Some(self.start.elapsed().unwrap_or_default());
+        }
+    }
+
+    /// Watch the socket connected to the upstream server or the
+    /// destination, for its `TCP_INFO`
+    pub fn set_socket(&self, fd: RawFd) {
+        match Socket::dup(fd) {
+            Ok(socket) => *self.socket.lock() = Some(socket),
+            Err(err) => debug!(message = "duplicate upstream socket failed", ?err),
+        }
+    }
+
+    /// `TCP_INFO` of the upstream socket, it's read right now if relaying,
+    /// or the last one read when closed
+    pub fn tcp_stats(&self) -> Option<TcpStats> {
+        if let Some(socket) = &*self.socket.lock() {
+            return socket.stats().ok();
+        }
+
+        self.tcp.lock().clone()
+    }
+
+    /// Read the last stats, and release the upstream socket
+    fn close_socket(&self) {
+        if let Some(socket) = self.socket.lock().take() {
+            *self.tcp.lock() = socket.stats().ok();
+        }
+    }
+
+    /// Why the connection is closed, `done` or the error
+    pub fn close_reason(&self) -> Option<String> {
+        self.close.lock().clone()
+    }
+
+    pub fn set_close_reason(&self, reason: String) {
+        *self.close.lock() = Some(reason);
+    }
+
+    /// Add traffic since the last report to the talkers
+    fn report(&self, talkers: &mut Talkers) {
+        let upload = self.upload();
+        let download = self.download();
+        let mut reported = self.reported.lock();
+
+        talkers.record(
+            self.src.ip(),
+            &self.host,
+            self.upstream.lock().as_deref(),
+            upload - reported.0,
+            download - reported.1,
+        );
+        *reported = (upload, download);
+    }
+
+    pub fn stat(&self) -> ConnectionStat {
+        ConnectionStat {
+            id: self.id,
+            src: self.src,
+            host: self.host.clone(),
+            port: self.port,
+            upstream: self.upstream.lock().clone(),
+            start: DateTime::from(self.start).to_string(),
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+            tcp: self.tcp_stats(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ConnectionStat {
+    pub id: u64,
+    pub src: SocketAddr,
+    pub host: String,
+    pub port: u16,
+    pub upstream: Option<String>,
+    pub start: String,
+    pub upload: u64,
+    pub download: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<TcpStats>,
+}
+
+#[derive(Default, Serialize)]
+pub struct Traffic {
+    pub upload: u64,
+    pub download: u64,
+    pub connections: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, Arc<Connection>>>,
+    access: Option<Arc<AccessLog>>,
+    metrics: Metrics,
+    capture: Capture,
+    talkers: Mutex<Talkers>,
+
+    // traffic of closed connections
+    upload: AtomicU64,
+    download: AtomicU64,
+}
+
+/// Registry of active connections, and total traffic of all relays
+#[derive(Clone, Default)]
+pub struct Connections {
+    inner: Arc<Inner>,
+}
+
+impl Connections {
+    /// Closed connections are written to the access log
+    pub fn with_access_log(access: Arc<AccessLog>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                access: Some(access),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Register a new connection accepted by `inbound`, it will be
+    /// unregistered when the returned `Tracked` dropped.
+    pub fn track(
+        &self,
+        inbound: &str,
+        route: Route,
+        src: SocketAddr,
+        local: SocketAddr,
+        host: String,
+        port: u16,
+    ) -> Tracked {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let conn = Arc::new(Connection {
+            id,
+            inbound: inbound.to_string(),
+            route,
+            src,
+            local,
+            host,
+            port,
+            start: SystemTime::now(),
+            upstream: Mutex::new(None),
+            destination: Mutex::new(None),
+            close: Mutex::new(None),
+            dialed: Mutex::new(None),
+            first_byte: Mutex::new(None),
+            socket: Mutex::new(None),
+            tcp: Mutex::new(None),
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            reported: Mutex::new((0, 0)),
+        });
+
+        self.inner.active.lock().insert(id, conn.clone());
+
+        Tracked {
+            connections: self.clone(),
+            conn,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionStat> {
+        self.inner
+            .active
+            .lock()
+            .values()
+            .map(|conn| conn.stat())
+            .collect()
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        let active = self.inner.active.lock();
+        let mut traffic = Traffic {
+            upload: self.inner.upload.load(Ordering::Relaxed),
+            download: self.inner.download.load(Ordering::Relaxed),
+            connections: active.len(),
+        };
+
+        for conn in active.values() {
+            traffic.upload += conn.upload.load(Ordering::Relaxed);
+            traffic.download += conn.download.load(Ordering::Relaxed);
+        }
+
+        traffic
+    }
+
+    /// Collect traffic of active connections for the talkers every
+    /// slot, it never returns
+    pub async fn run_talkers(self) {
+        let mut ticker = tokio::time::interval(SLOT);
+
+        loop {
+            ticker.tick().await;
+
+            self.collect_talkers();
+            self.inner.talkers.lock().expire();
+        }
+    }
+
+    fn collect_talkers(&self) {
+        let active = self.inner.active.lock();
+        let mut talkers = self.inner.talkers.lock();
+        for conn in active.values() {
+            conn.report(&mut talkers);
+        }
+    }
+
+    /// The `limit` most active clients, hosts and upstreams in the window
+    pub fn top_talkers(&self, window: Window, limit: usize) -> TopTalkers {
+        self.collect_talkers();
+
+        self.inner.talkers.lock().top(window, limit)
+    }
+
+    /// Capture of new connections, controlled by the controller
+    pub fn capture(&self) -> &Capture {
+        &self.inner.capture
+    }
+
+    /// Histogram samples observed since the last call, for push based
+    /// exporters
+    pub fn take_samples(&self) -> Vec<Sample> {
+        self.inner.metrics.take_samples()
+    }
+
+    /// Histograms and traffic in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        self.inner.metrics.render(&self.traffic())
+    }
+}
+
+/// Guard of a registered connection
+pub struct Tracked {
+    connections: Connections,
+    conn: Arc<Connection>,
+}
+
+impl Tracked {
+    #[inline]
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.conn
+    }
+
+    /// Wrap the client side stream, so traffic of it can be counted,
+    /// and captured if it's selected by the capture
+    pub fn count<S>(&self, stream: S) -> Counted<S> {
+        Counted {
+            inner: stream,
+            conn: self.conn.clone(),
+            capture: self.connections.inner.capture.flow(&self.conn),
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let inner = &self.connections.inner;
+
+        inner.active.lock().remove(&self.conn.id);
+        self.conn.close_socket();
+        inner
+            .upload
+            .fetch_add(self.conn.upload.load(Ordering::Relaxed), Ordering::Relaxed);
+        inner.download.fetch_add(
+            self.conn.download.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+
+        self.conn.report(&mut inner.talkers.lock());
+        inner.metrics.observe(&self.conn);
+        if let Some(access) = &inner.access {
+            access.write(&self.conn);
+        }
+    }
+}
+
+pin_project! {
+    /// Count bytes read from(upload) and written to(download) the client
+    pub struct Counted<S> {
+        #[pin]
+        inner: S,
+        conn: Arc<Connection>,
+        capture: Option<Flow>,
+    }
+}
+
+impl<S> Counted<S> {
+    /// Whether the traffic is captured, it must be read and written
+    /// through this then
+    pub fn captured(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+
+        let n = buf.filled().len() - before;
+        this.conn.add_upload(n as u64);
+        if let (Some(flow), true) = (this.capture, n > 0) {
+            flow.upload(&buf.filled()[before..]);
+        }
+
+        result
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let result = this.inner.poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(n)) = result {
+            this.conn.add_download(n as u64);
+            if let Some(flow) = this.capture {
+                flow.download(&buf[..n]);
+            }
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,313 @@
+//! Inbounds accept connections from clients, any number of them can be
+//! declared, each one has its own protocol, address, allowed sources,
+//! sniffing and route.
+//!
+//! ```yaml
+//! inbounds:
+//!   - name: lan
+//!     protocol: thp
+//!     listen: 0.0.0.0:1080
+//!     allow: 192.168.0.0/16
+//!     deny: 192.168.100.0/24
+//!   - name: direct
+//!     protocol: thp
+//!     listen: 127.0.0.1:1081
+//!     sniffing:
+//!       tls: false
+//!     route: direct
+//!   - name: browser
+//!     protocol: http
+//!     listen: 0.0.0.0:8080
+//!     users:
+//!       - username: alice
+//!         password: secret
+//!   - name: router
+//!     protocol: redirect
+//!     listen: 0.0.0.0:1081
+//!   - name: quic
+//!     protocol: tproxy
+//!     listen: 0.0.0.0:1080
+//! ```
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use resolver::Resolver;
+use serde::Deserialize;
+
+use super::{thp, tproxy};
+use super::{BanConfig, ClientLimitConfig, RateConfig, UdpConfig};
+use crate::net::{Cidr, KeepaliveConfig};
+use crate::relay::Connections;
+use crate::{Router, Upstream};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Transparent http proxy, the destination is sniffed from the Host
+    /// header or TLS SNI
+    Thp,
+    /// Explicit http proxy, the destination is taken from `CONNECT` or the
+    /// absolute URI of the request, sniffing doesn't apply
+    Http,
+    /// TCP redirected by iptables REDIRECT or DNAT, Linux only, the
+    /// destination is the original one, domains are sniffed for routing
+    /// rules only with `sniffing.redirect`
+    Redirect,
+    /// UDP redirected by TPROXY, Linux only, the destination is the
+    /// original one of each datagram, sniffing doesn't apply
+    Tproxy,
+}
+
+/// Where the connections go if no other rule matches
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    /// Relay by the upstream servers
+    #[default]
+    Proxy,
+    /// Connect to the destination directly
+    Direct,
+    /// Close the connection, e.g. only destinations matching rules are
+    /// allowed
+    Reject,
+}
+
+impl Route {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Route::Proxy => "proxy",
+            Route::Direct => "direct",
+            Route::Reject => "reject",
+        }
+    }
+}
+
+const fn default_sniff() -> bool {
+    true
+}
+
+const fn default_acceptors() -> usize {
+    1
+}
+
+/// Protocols sniffed to find the destination
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Sniffing {
+    #[serde(default = "default_sniff")]
+    pub http: bool,
+
+    #[serde(default = "default_sniff")]
+    pub tls: bool,
+
+    /// Sniff connections of `redirect` inbounds too, their domains are
+    /// matched by routing rules, but they still go to the original
+    /// addresses
+    #[serde(default)]
+    pub redirect: bool,
+}
+
+impl Default for Sniffing {
+    fn default() -> Self {
+        Self {
+            http: true,
+            tls: true,
+            redirect: false,
+        }
+    }
+}
+
+/// Credentials of a client
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Unique name, used in logs and to apply changes on hot reload
+    pub name: String,
+
+    pub protocol: Protocol,
+
+    pub listen: SocketAddr,
+
+    /// Accept loops, each one has its own listener bound with
+    /// `SO_REUSEPORT`, so the kernel spreads connections across them,
+    /// which helps on servers with many cores
+    #[serde(default = "default_acceptors")]
+    pub acceptors: usize,
+
+    /// Source networks allowed to connect, all sources are allowed if it's
+    /// empty. Transparent protocols can't authenticate clients, so this is
+    /// the way to restrict them.
+    #[serde(default, with = "crate::serde::networks")]
+    pub allow: Vec<Cidr>,
+
+    /// Source networks refused even if they are in `allow`
+    #[serde(default, with = "crate::serde::networks")]
+    pub deny: Vec<Cidr>,
+
+    /// Clients must send one of these credentials, by Basic
+    /// `Proxy-Authorization` for `http` inbounds, any client is accepted if
+    /// it's empty
+    #[serde(default)]
+    pub users: Vec<User>,
+
+    #[serde(default)]
+    pub sniffing: Sniffing,
+
+    #[serde(default)]
+    pub route: Route,
+
+    /// Relay by upstream servers with this tag only, see `upstream.tags`
+    #[serde(default)]
+    pub upstream_tag: Option<String>,
+
+    /// Add this header to plaintext HTTP requests, e.g. `X-Request-Id`,
+    /// the value is the connection id
+    #[serde(default)]
+    pub request_id: Option<String>,
+
+    /// Refuse sources which keep failing the handshake for a while
+    #[serde(default)]
+    pub ban: Option<BanConfig>,
+
+    /// Cap of in-flight connections of each source, see `client_limit`
+    #[serde(default)]
+    pub client_limit: Option<ClientLimitConfig>,
+
+    /// Bandwidth caps of each connection, see `throttle`
+    #[serde(default)]
+    pub max_rate: Option<RateConfig>,
+
+    /// TCP Fast Open, clients which have a cookie send the request in the
+    /// SYN
+    #[serde(default)]
+    pub fast_open: bool,
+
+    /// TCP keepalive of client sockets, and destination sockets of direct
+    /// connections, disabled if it's not set
+    #[serde(default)]
+    pub keepalive: Option<KeepaliveConfig>,
+
+    /// Connect to destinations of direct connections through this network
+    /// interface, by `SO_BINDTODEVICE`, see `upstream.interface` for the
+    /// proxied ones
+    #[serde(default)]
+    pub interface: Option<String>,
+
+    /// `SO_MARK` of the destination sockets of direct connections, see
+    /// `upstream.mark` for the proxied ones
+    #[serde(default)]
+    pub mark: Option<u32>,
+
+    /// Session table of `tproxy` inbounds
+    #[serde(default)]
+    pub udp: UdpConfig,
+}
+
+impl Config {
+    /// An inbound allowing all sources, with default sniffing and route
+    pub fn new(name: impl Into<String>, protocol: Protocol, listen: SocketAddr) -> Self {
+        Self {
+            name: name.into(),
+            protocol,
+            listen,
+            acceptors: default_acceptors(),
+            allow: vec![],
+            deny: vec![],
+            users: vec![],
+            sniffing: Sniffing::default(),
+            route: Route::default(),
+            upstream_tag: None,
+            request_id: None,
+            ban: None,
+            client_limit: None,
+            max_rate: None,
+            fast_open: false,
+            keepalive: None,
+            interface: None,
+            mark: None,
+            udp: UdpConfig::default(),
+        }
+    }
+
+    pub fn allowed(&self, ip: &IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+            && !self.deny.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+pub async fn serve(
+    config: Config,
+    upstream: Upstream,
+    resolver: Resolver,
+    connections: Connections,
+    router: Router,
+) -> io::Result<()> {
+    match config.protocol {
+        Protocol::Thp | Protocol::Http | Protocol::Redirect => {
+            thp::serve(config, upstream, resolver, connections, router).await
+        }
+        Protocol::Tproxy => tproxy::serve(config, upstream, resolver, router).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+name: lan
+protocol: thp
+listen: 0.0.0.0:1080
+allow: 192.168.0.0/16, 10.0.0.1
+deny: 192.168.100.0/24
+sniffing:
+  tls: false
+route: direct
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.protocol, Protocol::Thp);
+        assert_eq!(config.route, Route::Direct);
+        assert!(config.sniffing.http);
+        assert!(!config.sniffing.tls);
+        assert!(config.allowed(&"192.168.1.1".parse().unwrap()));
+        assert!(config.allowed(&"10.0.0.1".parse().unwrap()));
+        assert!(!config.allowed(&"10.0.0.2".parse().unwrap()));
+        assert!(!config.allowed(&"192.168.100.1".parse().unwrap()));
+
+        let config: Config =
+            serde_yaml::from_str("{name: any, protocol: thp, listen: '[::]:1080'}").unwrap();
+        assert_eq!(config.route, Route::Proxy);
+        assert_eq!(config.acceptors, 1);
+        assert_eq!(config.sniffing, Sniffing::default());
+        assert!(config.allowed(&"10.0.0.2".parse().unwrap()));
+
+        let config: Config =
+            serde_yaml::from_str("{name: browser, protocol: http, listen: 127.0.0.1:8080}")
+                .unwrap();
+        assert_eq!(config.protocol, Protocol::Http);
+
+        let config: Config =
+            serde_yaml::from_str("{name: quic, protocol: tproxy, listen: 0.0.0.0:1080}").unwrap();
+        assert_eq!(config.protocol, Protocol::Tproxy);
+        assert_eq!(config.udp, UdpConfig::default());
+
+        let config: Config =
+            serde_yaml::from_str("{name: router, protocol: redirect, listen: 0.0.0.0:1081}")
+                .unwrap();
+        assert_eq!(config.protocol, Protocol::Redirect);
+        assert!(!config.sniffing.redirect);
+    }
+}
@@ -0,0 +1,353 @@
+//! Histograms of relayed connections by upstream, to quantify the quality
+//! of upstream servers over time. They are exposed in the Prometheus text
+//! format by `GET /metrics` of the controller.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use super::connections::{Connection, Traffic};
+use super::inbound::Route;
+
+/// Seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Bytes per second
+const THROUGHPUT_BUCKETS: &[f64] = &[
+    1024.0,
+    10240.0,
+    102400.0,
+    1048576.0,
+    10485760.0,
+    104857600.0,
+];
+
+/// Segments
+const RETRANSMIT_BUCKETS: &[f64] = &[0.0, 1.0, 5.0, 10.0, 50.0, 100.0];
+
+struct Histogram {
+    /// Not cumulative, the last one is `+Inf`
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &[f64]) -> Self {
+        Self {
+            counts: vec![0; buckets.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+/// An observation of a histogram, kept for push based exporters like
+/// StatsD
+pub struct Sample {
+    /// `dial`, `handshake`, `ttfb`, `rtt` in seconds, `throughput`,
+    /// `delivery_rate` in bytes per second or `retransmits`
+    pub metric: &'static str,
+    pub upstream: String,
+    pub value: f64,
+}
+
+/// Samples are dropped if the exporter can't keep up
+const MAX_PENDING_SAMPLES: usize = 4096;
+
+/// Histograms of one metric, by upstream
+struct Family {
+    metric: &'static str,
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    histograms: BTreeMap<String, Histogram>,
+}
+
+impl Family {
+    fn new(
+        metric: &'static str,
+        name: &'static str,
+        help: &'static str,
+        buckets: &'static [f64],
+    ) -> Self {
+        Self {
+            metric,
+            name,
+            help,
+            buckets,
+            histograms: BTreeMap::new(),
+        }
+    }
+
+    fn observe(&mut self, upstream: &str, value: f64) {
+        let buckets = self.buckets;
+        let histogram = self
+            .histograms
+            .entry(upstream.to_string())
+            .or_insert_with(|| Histogram::new(buckets));
+
+        let index = buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(buckets.len());
+        histogram.counts[index] += 1;
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    fn render(&self, buf: &mut String) -> std::fmt::Result {
+        writeln!(buf, "# HELP {} {}", self.name, self.help)?;
+        writeln!(buf, "# TYPE {} histogram", self.name)?;
+
+        for (upstream, histogram) in &self.histograms {
+            let upstream = escape(upstream);
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                match self.buckets.get(i) {
+                    Some(bound) => writeln!(
+                        buf,
+                        "{}_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                        self.name, upstream, bound, cumulative
+                    )?,
+                    None => writeln!(
+                        buf,
+                        "{}_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+                        self.name, upstream, cumulative
+                    )?,
+                }
+            }
+            writeln!(
+                buf,
+                "{}_sum{{upstream=\"{}\"}} {}",
+                self.name, upstream, histogram.sum
+            )?;
+            writeln!(
+                buf,
+                "{}_count{{upstream=\"{}\"}} {}",
+                self.name, upstream, histogram.count
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Inner {
+    dial: Family,
+    handshake: Family,
+    ttfb: Family,
+    throughput: Family,
+    rtt: Family,
+    retransmits: Family,
+    delivery_rate: Family,
+
+    /// Only kept when there is an exporter taking them
+    samples: Option<Vec<Sample>>,
+}
+
+impl Inner {
+    fn observe(&mut self, family: fn(&mut Inner) -> &mut Family, upstream: &str, value: f64) {
+        let family = family(self);
+        family.observe(upstream, value);
+        let metric = family.metric;
+
+        if let Some(samples) = &mut self.samples {
+            if samples.len() < MAX_PENDING_SAMPLES {
+                samples.push(Sample {
+                    metric,
+                    upstream: upstream.to_string(),
+                    value,
+                });
+            }
+        }
+    }
+}
+
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                dial: Family::new(
+                    "dial",
+                    "roxy_dial_seconds",
+                    "Time to connect the upstream server, or the destination of direct connections",
+                    LATENCY_BUCKETS,
+                ),
+                handshake: Family::new(
+                    "handshake",
+                    "roxy_handshake_seconds",
+                    "Time from connected to the first response of the upstream server, which completes the AEAD handshake",
+                    LATENCY_BUCKETS,
+                ),
+                ttfb: Family::new(
+                    "ttfb",
+                    "roxy_time_to_first_byte_seconds",
+                    "Time from accepted to the first byte sent to the client",
+                    LATENCY_BUCKETS,
+                ),
+                throughput: Family::new(
+                    "throughput",
+                    "roxy_connection_throughput_bytes_per_second",
+                    "Average throughput of closed connections",
+                    THROUGHPUT_BUCKETS,
+                ),
+                rtt: Family::new(
+                    "rtt",
+                    "roxy_upstream_rtt_seconds",
+                    "Smoothed RTT of the upstream socket when closed, from TCP_INFO",
+                    LATENCY_BUCKETS,
+                ),
+                retransmits: Family::new(
+                    "retransmits",
+                    "roxy_upstream_retransmits",
+                    "Segments retransmitted by the upstream socket, from TCP_INFO",
+                    RETRANSMIT_BUCKETS,
+                ),
+                delivery_rate: Family::new(
+                    "delivery_rate",
+                    "roxy_upstream_delivery_rate_bytes_per_second",
+                    "Delivery rate of the upstream socket when closed, from TCP_INFO",
+                    THROUGHPUT_BUCKETS,
+                ),
+                samples: None,
+            }),
+        }
+    }
+}
+
+impl Metrics {
+    /// Record a closed connection, it's skipped if no upstream server was
+    /// connected.
+    pub fn observe(&self, conn: &Connection) {
+        let upstream = match conn.upstream() {
+            Some(upstream) => upstream,
+            None => return,
+        };
+        let dialed = conn.dialed();
+        let tcp = conn.tcp_stats();
+        let first_byte = conn.first_byte();
+        let elapsed = conn.start().elapsed().unwrap_or_default();
+
+        let mut inner = self.inner.lock();
+        if let Some(dialed) = dialed {
+            inner.observe(|inner| &mut inner.dial, &upstream, dialed.as_secs_f64());
+        }
+        if let Some(first_byte) = first_byte {
+            inner.observe(|inner| &mut inner.ttfb, &upstream, first_byte.as_secs_f64());
+        }
+        if let (Route::Proxy, Some(dialed), Some(first_byte)) = (conn.route(), dialed, first_byte) {
+            let handshake = first_byte.checked_sub(dialed).unwrap_or_default();
+            inner.observe(
+                |inner| &mut inner.handshake,
+                &upstream,
+                handshake.as_secs_f64(),
+            );
+        }
+
+        let bytes = conn.upload() + conn.download();
+        if bytes > 0 && elapsed > Duration::ZERO {
+            let throughput = bytes as f64 / elapsed.as_secs_f64();
+            inner.observe(|inner| &mut inner.throughput, &upstream, throughput);
+        }
+
+        if let Some(tcp) = tcp {
+            inner.observe(|inner| &mut inner.rtt, &upstream, tcp.rtt().as_secs_f64());
+            inner.observe(
+                |inner| &mut inner.retransmits,
+                &upstream,
+                tcp.retransmits as f64,
+            );
+            if let Some(rate) = tcp.delivery_rate {
+                inner.observe(|inner| &mut inner.delivery_rate, &upstream, rate as f64);
+            }
+        }
+    }
+
+    /// Take samples observed since the last call, the first call starts
+    /// keeping them
+    pub fn take_samples(&self) -> Vec<Sample> {
+        let mut inner = self.inner.lock();
+        inner.samples.replace(vec![]).unwrap_or_default()
+    }
+
+    /// Render histograms with the traffic in the Prometheus text format
+    pub fn render(&self, traffic: &Traffic) -> String {
+        let mut buf = String::new();
+        self.render_to(&mut buf, traffic)
+            .expect("write metrics to buffer failed");
+
+        buf
+    }
+
+    fn render_to(&self, buf: &mut String, traffic: &Traffic) -> std::fmt::Result {
+        writeln!(buf, "# HELP roxy_connections Active connections")?;
+        writeln!(buf, "# TYPE roxy_connections gauge")?;
+        writeln!(buf, "roxy_connections {}", traffic.connections)?;
+        writeln!(
+            buf,
+            "# HELP roxy_upload_bytes_total Traffic from clients to upstream"
+        )?;
+        writeln!(buf, "# TYPE roxy_upload_bytes_total counter")?;
+        writeln!(buf, "roxy_upload_bytes_total {}", traffic.upload)?;
+        writeln!(
+            buf,
+            "# HELP roxy_download_bytes_total Traffic from upstream to clients"
+        )?;
+        writeln!(buf, "# TYPE roxy_download_bytes_total counter")?;
+        writeln!(buf, "roxy_download_bytes_total {}", traffic.download)?;
+
+        let inner = self.inner.lock();
+        inner.dial.render(buf)?;
+        inner.handshake.render(buf)?;
+        inner.ttfb.render(buf)?;
+        inner.throughput.render(buf)?;
+        inner.rtt.render(buf)?;
+        inner.retransmits.render(buf)?;
+        inner.delivery_rate.render(buf)
+    }
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let mut family = Family::new("dial", "roxy_dial_seconds", "Dial", &[0.1, 1.0]);
+        family.observe("tokyo", 0.05);
+        family.observe("tokyo", 0.5);
+        family.observe("tokyo", 3.0);
+
+        let mut buf = String::new();
+        family.render(&mut buf).unwrap();
+        assert_eq!(
+            buf,
+            r#"# HELP roxy_dial_seconds Dial
+# TYPE roxy_dial_seconds histogram
+roxy_dial_seconds_bucket{upstream="tokyo",le="0.1"} 1
+roxy_dial_seconds_bucket{upstream="tokyo",le="1"} 2
+roxy_dial_seconds_bucket{upstream="tokyo",le="+Inf"} 3
+roxy_dial_seconds_sum{upstream="tokyo"} 3.55
+roxy_dial_seconds_count{upstream="tokyo"} 3
+"#
+        );
+    }
+}
@@ -1 +1,21 @@
-pub mod thp;
+mod ban;
+mod capture;
+mod client_limit;
+mod connections;
+pub mod inbound;
+mod metrics;
+pub(crate) mod splice;
+mod talkers;
+mod tcp_info;
+mod thp;
+mod throttle;
+mod tproxy;
+mod udp;
+
+pub use ban::BanConfig;
+pub use capture::{CaptureConfig, Error as CaptureError};
+pub use client_limit::ClientLimitConfig;
+pub use connections::{Connection, Connections};
+pub use talkers::Window as TalkersWindow;
+pub use throttle::RateConfig;
+pub use udp::{Reply as UdpReply, Sessions as UdpSessions, UdpConfig};
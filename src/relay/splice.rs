@@ -0,0 +1,234 @@
+//! Relay between two TCP sockets with splice(2), bytes are moved through a
+//! pipe in the kernel and never copied to userspace, which saves CPU on
+//! direct connections. Encrypted legs can't use it, and captured
+//! connections are copied as usual, since their data is needed. If splice
+//! is refused, e.g. by a seccomp filter, bytes are copied in userspace.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Bytes moved by a splice at most, the default capacity of pipes
+const CHUNK: usize = 64 * 1024;
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0 as libc::c_int; 2];
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe {
+            Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            }
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(n as usize)
+}
+
+/// splice(2) or pipes are refused by seccomp, missing in the kernel, or
+/// not supported by the sockets
+fn unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EPERM | libc::ENOSYS | libc::EINVAL)
+    )
+}
+
+/// Move bytes of `from` to `to` until `from` is closed, then shut down the
+/// write side of `to`. `moved` is called with the size of each chunk.
+async fn splice_one<F>(from: &TcpStream, to: &TcpStream, moved: F) -> io::Result<u64>
+where
+    F: Fn(u64),
+{
+    let pipe = match Pipe::new() {
+        Ok(pipe) => pipe,
+        Err(err) if unsupported(&err) => {
+            debug!(message = "pipe refused, copy in userspace", ?err);
+            return copy_one(from, to, moved).await;
+        }
+        Err(err) => return Err(err),
+    };
+    let mut total = 0;
+
+    loop {
+        from.readable().await?;
+        let n = match from.try_io(Interest::READABLE, || {
+            splice(from.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK)
+        }) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            // nothing is read from `from` yet, it can be copied instead
+            Err(err) if total == 0 && unsupported(&err) => {
+                debug!(message = "splice refused, copy in userspace", ?err);
+                return copy_one(from, to, moved).await;
+            }
+            Err(err) => return Err(err),
+        };
+
+        // the pipe is drained before reading more, so it never fills up
+        let mut left = n;
+        while left > 0 {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)
+            }) {
+                Ok(m) => left -= m,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        moved(n as u64);
+        total += n as u64;
+    }
+
+    shutdown_write(to)?;
+    Ok(total)
+}
+
+/// [`splice_one`] through a buffer in userspace
+async fn copy_one<F>(from: &TcpStream, to: &TcpStream, moved: F) -> io::Result<u64>
+where
+    F: Fn(u64),
+{
+    let mut buf = vec![0u8; CHUNK];
+    let mut total = 0;
+
+    loop {
+        from.readable().await?;
+        let n = match from.try_read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        };
+
+        let mut written = 0;
+        while written < n {
+            to.writable().await?;
+            match to.try_write(&buf[written..n]) {
+                Ok(m) => written += m,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        moved(n as u64);
+        total += n as u64;
+    }
+
+    shutdown_write(to)?;
+    Ok(total)
+}
+
+fn shutdown_write(to: &TcpStream) -> io::Result<()> {
+    if unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) } != 0 {
+        let err = io::Error::last_os_error();
+        // the peer may have closed it already
+        if err.kind() != io::ErrorKind::NotConnected {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Relay both directions until both are closed, like
+/// `tokio::io::copy_bidirectional`, returns bytes of `a` to `b` and of `b`
+/// to `a`
+pub async fn copy_bidirectional<U, D>(
+    a: &TcpStream,
+    b: &TcpStream,
+    a_to_b: U,
+    b_to_a: D,
+) -> io::Result<(u64, u64)>
+where
+    U: Fn(u64),
+    D: Fn(u64),
+{
+    futures::future::try_join(splice_one(a, b, a_to_b), splice_one(b, a, b_to_a)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn relay() {
+        let (mut client, local) = pair().await;
+        let (remote, mut server) = pair().await;
+
+        let upload = AtomicU64::new(0);
+        let relay = copy_bidirectional(
+            &local,
+            &remote,
+            |n| {
+                upload.fetch_add(n, Ordering::Relaxed);
+            },
+            |_| {},
+        );
+
+        let data = vec![7u8; 256 * 1024];
+        let peers = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut received = vec![];
+            server.read_to_end(&mut received).await.unwrap();
+            server.write_all(b"done").await.unwrap();
+            server.shutdown().await.unwrap();
+
+            let mut reply = vec![];
+            client.read_to_end(&mut reply).await.unwrap();
+            (received, reply)
+        };
+
+        let (copied, (received, reply)) = tokio::join!(relay, peers);
+        assert_eq!(copied.unwrap(), (data.len() as u64, 4));
+        assert_eq!(upload.load(Ordering::Relaxed), data.len() as u64);
+        assert_eq!(received, data);
+        assert_eq!(reply, b"done");
+    }
+}
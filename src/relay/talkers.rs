@@ -0,0 +1,256 @@
+//! Rolling traffic by client, destination host and upstream, over the
+//! last minute, 5 minutes and hour, a quick view of who is eating the
+//! bandwidth. Traffic of active connections is collected every `SLOT`,
+//! so long lived connections are spread over the windows correctly.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// Traffic is bucketed by this
+pub const SLOT: Duration = Duration::from_secs(10);
+
+/// The longest window, older slots are dropped
+const MAX_SLOTS: u64 = 3600 / SLOT.as_secs();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Window {
+    #[default]
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+}
+
+impl Window {
+    fn slots(&self) -> u64 {
+        let secs = match self {
+            Window::OneMinute => 60,
+            Window::FiveMinutes => 300,
+            Window::OneHour => 3600,
+        };
+
+        secs / SLOT.as_secs()
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[error("unknown window \"{0}\", it must be 1m, 5m or 1h")]
+pub struct UnknownWindow(String);
+
+impl FromStr for Window {
+    type Err = UnknownWindow;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1m" => Ok(Window::OneMinute),
+            "5m" => Ok(Window::FiveMinutes),
+            "1h" => Ok(Window::OneHour),
+            _ => Err(UnknownWindow(s.to_string())),
+        }
+    }
+}
+
+/// Traffic of one key in consecutive slots, empty slots are not stored
+#[derive(Default)]
+struct Series {
+    /// slot, upload, download
+    slots: VecDeque<(u64, u64, u64)>,
+}
+
+impl Series {
+    fn add(&mut self, slot: u64, upload: u64, download: u64) {
+        match self.slots.back_mut() {
+            Some((last, up, down)) if *last == slot => {
+                *up += upload;
+                *down += download;
+            }
+            _ => self.slots.push_back((slot, upload, download)),
+        }
+    }
+
+    fn expire(&mut self, now: u64) {
+        while let Some((slot, _, _)) = self.slots.front() {
+            if slot + MAX_SLOTS > now {
+                break;
+            }
+            self.slots.pop_front();
+        }
+    }
+
+    fn sum(&self, since: u64) -> (u64, u64) {
+        self.slots
+            .iter()
+            .rev()
+            .take_while(|(slot, _, _)| *slot >= since)
+            .fold((0, 0), |(upload, download), (_, up, down)| {
+                (upload + up, download + down)
+            })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Talker {
+    pub key: String,
+    pub upload: u64,
+    pub download: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopTalkers {
+    pub clients: Vec<Talker>,
+    pub hosts: Vec<Talker>,
+    pub upstreams: Vec<Talker>,
+}
+
+#[derive(Default)]
+pub struct Talkers {
+    clients: HashMap<IpAddr, Series>,
+    hosts: HashMap<String, Series>,
+    upstreams: HashMap<String, Series>,
+}
+
+impl Talkers {
+    /// Add traffic of a connection since the last call
+    pub fn record(
+        &mut self,
+        client: IpAddr,
+        host: &str,
+        upstream: Option<&str>,
+        upload: u64,
+        download: u64,
+    ) {
+        if upload == 0 && download == 0 {
+            return;
+        }
+
+        let slot = current_slot();
+        self.clients
+            .entry(client)
+            .or_default()
+            .add(slot, upload, download);
+        add(&mut self.hosts, host, slot, upload, download);
+        if let Some(upstream) = upstream {
+            add(&mut self.upstreams, upstream, slot, upload, download);
+        }
+    }
+
+    /// Drop slots out of the longest window, and keys without traffic
+    pub fn expire(&mut self) {
+        let now = current_slot();
+
+        expire(&mut self.clients, now);
+        expire(&mut self.hosts, now);
+        expire(&mut self.upstreams, now);
+    }
+
+    /// The `limit` most active ones of each kind, by total traffic
+    pub fn top(&self, window: Window, limit: usize) -> TopTalkers {
+        let since = (current_slot() + 1).saturating_sub(window.slots());
+
+        TopTalkers {
+            clients: top(&self.clients, since, limit),
+            hosts: top(&self.hosts, since, limit),
+            upstreams: top(&self.upstreams, since, limit),
+        }
+    }
+}
+
+fn current_slot() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / SLOT.as_secs()
+}
+
+/// Like `entry`, but the key is allocated only if it's new
+fn add(map: &mut HashMap<String, Series>, key: &str, slot: u64, upload: u64, download: u64) {
+    match map.get_mut(key) {
+        Some(series) => series.add(slot, upload, download),
+        None => {
+            let mut series = Series::default();
+            series.add(slot, upload, download);
+            map.insert(key.to_string(), series);
+        }
+    }
+}
+
+fn expire<K: Eq + Hash>(map: &mut HashMap<K, Series>, now: u64) {
+    map.retain(|_, series| {
+        series.expire(now);
+        !series.slots.is_empty()
+    });
+}
+
+fn top<K: ToString>(map: &HashMap<K, Series>, since: u64, limit: usize) -> Vec<Talker> {
+    let mut talkers = map
+        .iter()
+        .filter_map(|(key, series)| {
+            let (upload, download) = series.sum(since);
+            if upload == 0 && download == 0 {
+                return None;
+            }
+
+            Some(Talker {
+                key: key.to_string(),
+                upload,
+                download,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    talkers.sort_by(|a, b| {
+        (b.upload + b.download)
+            .cmp(&(a.upload + a.download))
+            .then_with(|| a.key.cmp(&b.key))
+    });
+    talkers.truncate(limit);
+
+    talkers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling() {
+        let mut series = Series::default();
+        series.add(100, 1, 10);
+        series.add(100, 1, 10);
+        series.add(105, 2, 20);
+        series.add(130, 3, 30);
+
+        assert_eq!(series.sum(130), (3, 30));
+        assert_eq!(series.sum(105), (5, 50));
+        assert_eq!(series.sum(0), (7, 70));
+
+        series.expire(100 + MAX_SLOTS);
+        assert_eq!(series.sum(0), (5, 50));
+
+        let mut talkers = Talkers::default();
+        let client = "192.168.1.2".parse().unwrap();
+        talkers.record(client, "example.com", Some("tokyo"), 10, 100);
+        talkers.record(client, "example.org", None, 1, 1);
+        talkers.record(client, "example.net", None, 0, 0);
+
+        let top = talkers.top(Window::OneMinute, 1);
+        assert_eq!(
+            top.hosts,
+            vec![Talker {
+                key: "example.com".to_string(),
+                upload: 10,
+                download: 100,
+            }]
+        );
+        assert_eq!(top.clients[0].upload, 11);
+        assert_eq!(top.upstreams.len(), 1);
+
+        assert_eq!("5m".parse(), Ok(Window::FiveMinutes));
+        assert!("2m".parse::<Window>().is_err());
+    }
+}
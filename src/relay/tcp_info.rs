@@ -0,0 +1,167 @@
+//! Statistics of relayed sockets from the kernel, by `TCP_INFO`. It's
+//! cheap enough to read for every connection, unlike attaching eBPF
+//! probes, which needs privileges and a recent kernel.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// `struct tcp_info` of `linux/tcp.h`, up to `tcpi_delivery_rate`, which
+/// is added in 4.9. Older kernels fill less, and the length tells.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct RawTcpInfo {
+    state: u8,
+    ca_state: u8,
+    retransmits: u8,
+    probes: u8,
+    backoff: u8,
+    options: u8,
+    wscale: u8,
+    app_limited: u8,
+
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+
+    unacked: u32,
+    sacked: u32,
+    lost: u32,
+    retrans: u32,
+    fackets: u32,
+
+    last_data_sent: u32,
+    last_ack_sent: u32,
+    last_data_recv: u32,
+    last_ack_recv: u32,
+
+    pmtu: u32,
+    rcv_ssthresh: u32,
+    rtt: u32,
+    rttvar: u32,
+    snd_ssthresh: u32,
+    snd_cwnd: u32,
+    advmss: u32,
+    reordering: u32,
+
+    rcv_rtt: u32,
+    rcv_space: u32,
+
+    total_retrans: u32,
+
+    pacing_rate: u64,
+    max_pacing_rate: u64,
+    bytes_acked: u64,
+    bytes_received: u64,
+    segs_out: u32,
+    segs_in: u32,
+
+    notsent_bytes: u32,
+    min_rtt: u32,
+    data_segs_in: u32,
+    data_segs_out: u32,
+
+    delivery_rate: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TcpStats {
+    /// Smoothed round trip time, in microseconds
+    pub rtt: u32,
+    /// Variance of `rtt`, in microseconds
+    pub rtt_var: u32,
+    /// Segments retransmitted in total
+    pub retransmits: u32,
+    /// Bytes per second, it's `None` on kernels older than 4.9
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_rate: Option<u64>,
+}
+
+impl TcpStats {
+    #[inline]
+    pub fn rtt(&self) -> Duration {
+        Duration::from_micros(self.rtt as u64)
+    }
+}
+
+/// A duplicate of a relayed socket, so it can be read until the relay is
+/// done, even if the stream is owned by others, e.g. `ProxyStream::proxy`.
+/// The socket is closed when both are dropped.
+pub struct Socket {
+    fd: OwnedFd,
+}
+
+impl Socket {
+    pub fn dup(fd: RawFd) -> io::Result<Self> {
+        let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    pub fn stats(&self) -> io::Result<TcpStats> {
+        let mut info = RawTcpInfo::default();
+        let mut len = mem::size_of::<RawTcpInfo>() as libc::socklen_t;
+
+        let ret = unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut RawTcpInfo as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let delivery_rate = if len as usize >= mem::size_of::<RawTcpInfo>() {
+            Some(info.delivery_rate)
+        } else {
+            None
+        };
+
+        Ok(TcpStats {
+            rtt: info.rtt,
+            rtt_var: info.rttvar,
+            retransmits: info.total_retrans,
+            delivery_rate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn stats() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let socket = Socket::dup(client.as_raw_fd()).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).unwrap();
+
+        // the socket is still readable after the stream is closed
+        drop(client);
+        let stats = socket.stats().unwrap();
+        assert!(stats.rtt > 0);
+        assert_eq!(stats.retransmits, 0);
+    }
+}
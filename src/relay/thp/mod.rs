@@ -1,6 +1,10 @@
-//! Transparent Http proxy
+//! Transparent Http proxy, and the explicit and REDIRECT ones sharing its
+//! relay
 
+mod proxy;
+mod redirect;
+mod request_id;
 mod server;
 mod sniffing;
 
-pub use server::{serve, Config};
+pub(crate) use server::serve;
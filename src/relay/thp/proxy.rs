@@ -0,0 +1,355 @@
+//! Explicit HTTP proxy, for browsers and tools configured with
+//! `http_proxy` and `https_proxy`, so no iptables rule is needed.
+//!
+//! `CONNECT example.com:443` is answered with `200`, then the stream is
+//! tunneled. Plain requests with an absolute URI, e.g.
+//! `GET http://example.com/ HTTP/1.1`, are rewritten to the origin form
+//! before they are relayed. The stream is relayed to the host of the first
+//! request, so it's the only one of the connection, `Connection: close`
+//! makes the server close it after the response, and pipelined requests
+//! are dropped, clients send later requests on new connections.
+//!
+//! With `users`, the first request must carry one of them by
+//! `Proxy-Authorization: Basic`, otherwise it's answered with `407`.
+
+use std::io;
+
+use hyper::Uri;
+use memchr::memmem;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::relay::inbound::User;
+
+/// Requests with larger heads are refused
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const AUTH_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"roxy\"\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+const PROXY_AUTHORIZATION: &str = "proxy-authorization";
+const CONTENT_LENGTH: &str = "content-length";
+const TRANSFER_ENCODING: &str = "transfer-encoding";
+
+/// Headers for the proxy only, and `connection` which is replaced by
+/// `Connection: close`, they are removed from forwarded requests
+const HOP_HEADERS: [&str; 3] = ["proxy-connection", "connection", PROXY_AUTHORIZATION];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("request head is incomplete or too large")]
+    IncompleteHead,
+    #[error("invalid request line")]
+    InvalidRequestLine,
+    #[error("invalid request target {0:?}")]
+    InvalidTarget(String),
+    #[error("proxy authorization is missing or wrong")]
+    Unauthorized,
+}
+
+/// Destination of a request, and the bytes relayed before the rest of
+/// the stream
+#[derive(Debug, PartialEq)]
+struct Target {
+    connect: bool,
+    host: String,
+    port: u16,
+    head: Vec<u8>,
+    /// Value of `Proxy-Authorization`
+    authorization: Option<String>,
+}
+
+/// Read the first request, and returns its destination and the bytes to
+/// relay first. Clients get `400` if the request is invalid, and `407` if
+/// it's not authorized by any of `users`.
+pub async fn handshake(
+    stream: &mut TcpStream,
+    users: &[User],
+) -> Result<(String, u16, Vec<u8>), Error> {
+    let parsed = match read_head(stream).await {
+        Ok(head) => parse(head),
+        Err(err) => Err(err),
+    };
+
+    match parsed {
+        Ok(target) if !authorized(target.authorization.as_deref(), users) => {
+            let _ = stream.write_all(AUTH_REQUIRED).await;
+            Err(Error::Unauthorized)
+        }
+        Ok(target) => {
+            if target.connect {
+                stream.write_all(ESTABLISHED).await?;
+            }
+
+            Ok((target.host, target.port, target.head))
+        }
+        Err(err) => {
+            let _ = stream.write_all(BAD_REQUEST).await;
+            Err(err)
+        }
+    }
+}
+
+/// `Basic` credentials of one of `users`, anything goes without users
+fn authorized(authorization: Option<&str>, users: &[User]) -> bool {
+    if users.is_empty() {
+        return true;
+    }
+
+    let credentials = authorization
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, encoded)| base64::decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let (username, password) = match credentials.as_deref().and_then(|c| c.split_once(':')) {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+
+    users
+        .iter()
+        .any(|user| user.username == username && user.password == password)
+}
+
+/// Read until the end of the request head, bytes after it are returned too
+async fn read_head(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(Error::IncompleteHead);
+        }
+        head.extend_from_slice(&buf[..n]);
+
+        if memmem::find(&head, b"\r\n\r\n").is_some() {
+            return Ok(head);
+        }
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(Error::IncompleteHead);
+        }
+    }
+}
+
+fn parse(head: Vec<u8>) -> Result<Target, Error> {
+    let end = memmem::find(&head, b"\r\n\r\n").ok_or(Error::IncompleteHead)? + 4;
+    let line_end = memmem::find(&head, b"\r\n").ok_or(Error::IncompleteHead)?;
+    let line = std::str::from_utf8(&head[..line_end]).map_err(|_| Error::InvalidRequestLine)?;
+
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method, target, version)
+        }
+        _ => return Err(Error::InvalidRequestLine),
+    };
+    let invalid = || Error::InvalidTarget(target.to_string());
+    let uri = target.parse::<Uri>().map_err(|_| invalid())?;
+    let host = uri
+        .host()
+        .ok_or_else(invalid)?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let headers = || head[line_end + 2..end - 2].split_inclusive(|b| *b == b'\n');
+    let value = |name: &str| {
+        headers()
+            .find(|header| is_header(header, name))
+            .and_then(|header| std::str::from_utf8(&header[name.len() + 1..]).ok())
+            .map(str::trim)
+    };
+    let authorization = value(PROXY_AUTHORIZATION).map(str::to_string);
+
+    if method == "CONNECT" {
+        let port = uri.port_u16().ok_or_else(invalid)?;
+
+        // the client may send data before it gets the response
+        return Ok(Target {
+            connect: true,
+            host,
+            port,
+            head: head[end..].to_vec(),
+            authorization,
+        });
+    }
+
+    if uri.scheme_str() != Some("http") {
+        return Err(invalid());
+    }
+    let port = uri.port_u16().unwrap_or(80);
+    let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+
+    let mut rewritten = Vec::with_capacity(head.len());
+    rewritten.extend_from_slice(format!("{} {} {}\r\n", method, path, version).as_bytes());
+    for header in headers() {
+        if !HOP_HEADERS.iter().any(|name| is_header(header, name)) {
+            rewritten.extend_from_slice(header);
+        }
+    }
+    rewritten.extend_from_slice(b"Connection: close\r\n\r\n");
+
+    // bytes after the body are pipelined requests, which may be to other
+    // hosts, chunked bodies are relayed as they are
+    let read = head.len() - end;
+    let body = match value(CONTENT_LENGTH).map(str::parse::<usize>) {
+        _ if value(TRANSFER_ENCODING).is_some() => read,
+        Some(Ok(len)) => len.min(read),
+        _ => 0,
+    };
+    rewritten.extend_from_slice(&head[end..end + body]);
+
+    Ok(Target {
+        connect: false,
+        host,
+        port,
+        head: rewritten,
+        authorization,
+    })
+}
+
+/// `header` is a line of the header `name`
+fn is_header(header: &[u8], name: &str) -> bool {
+    header.len() > name.len()
+        && header[name.len()] == b':'
+        && header[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect() {
+        let target = parse(
+            b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n\x16\x03".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            target,
+            Target {
+                connect: true,
+                host: "example.com".to_string(),
+                port: 443,
+                head: b"\x16\x03".to_vec(),
+                authorization: None,
+            }
+        );
+
+        let target = parse(b"CONNECT [::1]:8443 HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+        assert_eq!(target.host, "::1");
+        assert_eq!(target.port, 8443);
+
+        // port is required
+        assert!(parse(b"CONNECT example.com HTTP/1.1\r\n\r\n".to_vec()).is_err());
+    }
+
+    #[test]
+    fn forward() {
+        let target = parse(
+            b"GET http://example.com:8080/index.html?q=1 HTTP/1.1\r\nHost: example.com:8080\r\nProxy-Connection: keep-alive\r\nproxy-authorization: Basic Zm9v\r\nContent-Length: 4\r\n\r\nbody"
+                .to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            target,
+            Target {
+                connect: false,
+                host: "example.com".to_string(),
+                port: 8080,
+                head: b"GET /index.html?q=1 HTTP/1.1\r\nHost: example.com:8080\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody"
+                    .to_vec(),
+                authorization: Some("Basic Zm9v".to_string()),
+            }
+        );
+
+        let target = parse(b"GET http://example.com HTTP/1.0\r\n\r\n".to_vec()).unwrap();
+        assert_eq!(target.port, 80);
+        assert_eq!(target.head, b"GET / HTTP/1.0\r\nConnection: close\r\n\r\n");
+
+        // origin form is for transparent proxies
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec()).is_err());
+        assert!(parse(b"GET https://example.com/ HTTP/1.1\r\n\r\n".to_vec()).is_err());
+        assert!(parse(b"GET http://example.com/ HTTP/1.1\r\nHost: exa".to_vec()).is_err());
+        assert!(parse(b"\x16\x03\x01\r\n\r\n".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn one_request_per_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(
+                    b"GET http://a.example/ HTTP/1.1\r\nHost: a.example\r\nProxy-Connection: keep-alive\r\n\r\n\
+                      GET http://b.example/ HTTP/1.1\r\nHost: b.example\r\nCookie: b=secret\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            stream
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (host, port, head) = handshake(&mut stream, &[]).await.unwrap();
+        assert_eq!((host.as_str(), port), ("a.example", 80));
+        // the request of b.example never reaches a.example
+        assert_eq!(
+            head,
+            b"GET / HTTP/1.1\r\nHost: a.example\r\nConnection: close\r\n\r\n"
+        );
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn established() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buf = vec![0u8; ESTABLISHED.len()];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (host, port, head) = handshake(&mut stream, &[]).await.unwrap();
+        assert_eq!((host.as_str(), port), ("example.com", 443));
+        assert!(head.is_empty());
+        assert_eq!(client.await.unwrap(), ESTABLISHED);
+    }
+
+    #[test]
+    fn authorize() {
+        let users = [User {
+            username: "user".to_string(),
+            password: "pa:ss".to_string(),
+        }];
+        assert!(authorized(None, &[]));
+        assert!(authorized(Some("Basic dXNlcjpwYTpzcw=="), &users));
+        assert!(authorized(Some("basic dXNlcjpwYTpzcw=="), &users));
+
+        // wrong password, scheme or encoding
+        assert!(!authorized(None, &users));
+        assert!(!authorized(Some("Basic dXNlcjpwYXNz"), &users));
+        assert!(!authorized(Some("Bearer dXNlcjpwYTpzcw=="), &users));
+        assert!(!authorized(Some("Basic ???"), &users));
+
+        let target = parse(
+            b"CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization:  Basic dXNlcjpwYTpzcw==\r\n\r\n"
+                .to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            target.authorization.as_deref(),
+            Some("Basic dXNlcjpwYTpzcw==")
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Destination of connections redirected by iptables REDIRECT or DNAT,
+//! for routers which can't use TPROXY. The original destination is kept
+//! by conntrack, and read with `SO_ORIGINAL_DST`.
+
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+use tokio::net::TcpStream;
+
+use crate::relay::tproxy::from_sockaddr;
+
+/// The destination before the redirection
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    // IPv4 connections of dual stack listeners are tracked as IPv4 ones
+    let (level, name) = match stream.local_addr()? {
+        SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_none() => {
+            (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+        }
+        _ => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+    };
+
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    from_sockaddr(&storage).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown address family of original destination",
+        )
+    })
+}
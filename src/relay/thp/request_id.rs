@@ -0,0 +1,173 @@
+//! Inject a request id header into plaintext HTTP requests, e.g.
+//! `X-Request-Id: 42`, the id is the connection's, so logs of upstream
+//! services can be joined with the access log and the `connection` span.
+//!
+//! Only the first request of a connection is modified, later requests of
+//! keep-alive connections are relayed as they are.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use memchr::memmem;
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Requests with larger heads are relayed as they are
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+pin_project! {
+    /// A stream which reads `buf` first, then the inner stream
+    pub struct Prepended<S> {
+        #[pin]
+        inner: S,
+        buf: Vec<u8>,
+        pos: usize,
+    }
+}
+
+impl<S> Prepended<S> {
+    pub fn with_buf(inner: S, buf: Vec<u8>) -> Self {
+        Self { inner, buf, pos: 0 }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Take the part of `buf` not read yet, the inner stream is read next
+    pub fn take_buf(&mut self) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.drain(..self.pos);
+        self.pos = 0;
+        buf
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Prepended<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if *this.pos < this.buf.len() {
+            let n = buf.remaining().min(this.buf.len() - *this.pos);
+            buf.put_slice(&this.buf[*this.pos..*this.pos + n]);
+            *this.pos += n;
+            if *this.pos == this.buf.len() {
+                *this.buf = vec![];
+                *this.pos = 0;
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        this.inner.poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Prepended<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Read the head of the first request, and replay it with the header
+/// added. It's skipped if the stream isn't plaintext HTTP/1, or the client
+/// sets the header already. A head read by the proxy handshake is
+/// modified in place.
+pub async fn inject(stream: &mut Prepended<TcpStream>, header: &str, id: u64) -> io::Result<()> {
+    if !stream.buf.is_empty() {
+        let head = std::mem::take(&mut stream.buf);
+        stream.buf = add_header(head, header, id);
+        return Ok(());
+    }
+
+    let mut first = [0u8; 1];
+    let n = stream.inner.peek(&mut first).await?;
+    if n == 0 || !first[0].is_ascii_uppercase() {
+        return Ok(());
+    }
+
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = stream.inner.read(&mut buf).await?;
+        head.extend_from_slice(&buf[..n]);
+
+        if n == 0 || head.len() >= MAX_HEAD_SIZE || memmem::find(&head, b"\r\n\r\n").is_some() {
+            break;
+        }
+    }
+
+    stream.buf = add_header(head, header, id);
+    stream.pos = 0;
+
+    Ok(())
+}
+
+/// Insert the header after the request line, the buffer is returned as it
+/// is if the head isn't complete
+fn add_header(mut head: Vec<u8>, header: &str, id: u64) -> Vec<u8> {
+    let end = match memmem::find(&head, b"\r\n\r\n") {
+        Some(end) => end + 2,
+        None => return head,
+    };
+    let line_end = match memmem::find(&head, b"\r\n") {
+        Some(pos) => pos,
+        None => return head,
+    };
+    if !head[..line_end].ends_with(b" HTTP/1.1") && !head[..line_end].ends_with(b" HTTP/1.0") {
+        return head;
+    }
+
+    // header names are case insensitive
+    let existed = head[line_end + 2..end].split(|b| *b == b'\n').any(|line| {
+        line.len() > header.len()
+            && line[header.len()] == b':'
+            && line[..header.len()].eq_ignore_ascii_case(header.as_bytes())
+    });
+    if existed {
+        return head;
+    }
+
+    let line = format!("{}: {}\r\n", header, id);
+    head.splice(line_end + 2..line_end + 2, line.into_bytes());
+
+    head
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add() {
+        let head = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\nbody".to_vec();
+        assert_eq!(
+            add_header(head, "X-Request-Id", 42),
+            b"GET / HTTP/1.1\r\nX-Request-Id: 42\r\nHost: example.com\r\n\r\nbody"
+        );
+
+        let head = b"GET / HTTP/1.1\r\nx-request-id: abc\r\n\r\n".to_vec();
+        assert_eq!(add_header(head.clone(), "X-Request-Id", 42), head);
+
+        // incomplete
+        let head = b"GET / HTTP/1.1\r\nHost: exa".to_vec();
+        assert_eq!(add_header(head.clone(), "X-Request-Id", 42), head);
+    }
+}
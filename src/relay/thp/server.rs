@@ -1,79 +1,537 @@
 use std::io;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
 
-use futures_util::future::join_all;
+use futures_util::future::try_join_all;
 use resolver::Resolver;
-use serde::Deserialize;
-use shadowsocks::{Address, ProxyStream};
-use tokio::net::TcpListener;
+use shadowsocks::{Address, ConnectOpts, ProxyStream};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::Instrument;
 
-use super::sniffing::destination_addr;
-use crate::Upstream;
+use super::proxy;
+use super::redirect;
+use super::request_id::{self, Prepended};
+use super::sniffing::{destination_addr, sniff_domain};
+use crate::net::KeepaliveConfig;
+use crate::relay::ban::Bans;
+use crate::relay::client_limit::ClientLimits;
+use crate::relay::connections::Tracked;
+use crate::relay::inbound::{self, Protocol, Route};
+use crate::relay::splice;
+use crate::relay::throttle::Throttled;
+use crate::relay::Connections;
+use crate::route::{Network, Outbound};
+use crate::upgrade::{self, Kind, Registration};
+use crate::{Router, Upstream};
 
-#[derive(Deserialize)]
-pub struct Config {
-    listen: Vec<SocketAddr>,
+pub async fn serve(
+    config: inbound::Config,
+    upstream: Upstream,
+    resolver: Resolver,
+    connections: Connections,
+    router: Router,
+) -> io::Result<()> {
+    let listeners = bind(config.listen, config.acceptors).await?;
+    if config.fast_open {
+        for (listener, _) in &listeners {
+            if let Err(err) = set_fast_open(listener) {
+                warn!(
+                    message = "enable TCP Fast Open failed",
+                    name = config.name.as_str(),
+                    ?err
+                );
+            }
+        }
+    }
+    info!(
+        message = "start inbound",
+        name = config.name.as_str(),
+        protocol = ?config.protocol,
+        listen = ?config.listen,
+        acceptors = listeners.len(),
+    );
+
+    // listeners are handed over on upgrade until the loops stop
+    let (listeners, _registrations): (Vec<_>, Vec<_>) = listeners.into_iter().unzip();
+    // shared by all acceptors
+    let bans = config.ban.clone().map(|ban| Arc::new(Bans::new(ban)));
+    let client_limits = config
+        .client_limit
+        .clone()
+        .map(|limit| Arc::new(ClientLimits::new(limit)));
+
+    // all loops stop if one of them fails
+    try_join_all(listeners.into_iter().map(|listener| {
+        accept(
+            listener,
+            config.clone(),
+            bans.clone(),
+            client_limits.clone(),
+            upstream.clone(),
+            resolver.clone(),
+            connections.clone(),
+            router.clone(),
+        )
+    }))
+    .await?;
+
+    Ok(())
 }
 
-pub async fn serve(config: Config, upstream: Upstream, resolver: Resolver) -> io::Result<()> {
-    let mut tasks = Vec::with_capacity(config.listen.len());
+/// Bind `acceptors` listeners to the same address with `SO_REUSEPORT`, or
+/// a plain one if there is only one acceptor. Listeners handed over by
+/// the old process are used first.
+async fn bind(addr: SocketAddr, acceptors: usize) -> io::Result<Vec<(TcpListener, Registration)>> {
+    if acceptors <= 1 {
+        let (listener, registration) = upgrade::tcp_listener(addr)?;
+        return Ok(vec![(TcpListener::from_std(listener)?, registration)]);
+    }
 
-    for addr in config.listen {
-        let listener = TcpListener::bind(addr).await?;
-        info!(
-            message = "start transparent http proxy server",
-            listen = ?addr,
-        );
+    let mut listeners: Vec<(TcpListener, Registration)> = Vec::with_capacity(acceptors);
+    for _ in 0..acceptors {
+        let listener = match upgrade::take(Kind::Tcp, addr) {
+            Some(fd) => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => {
+                // the rest are bound to the port of the first one, in case it's 0
+                let bind = match listeners.first() {
+                    Some((first, _)) => first.local_addr()?,
+                    None => addr,
+                };
+                let socket = if bind.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    TcpSocket::new_v6()?
+                };
+                socket.set_reuseaddr(true)?;
+                socket.set_reuseport(true)?;
+                socket.bind(bind)?;
+
+                socket.listen(1024)?
+            }
+        };
+
+        let registration = upgrade::register(Kind::Tcp, addr, listener.as_raw_fd());
+        listeners.push((listener, registration));
+    }
+
+    Ok(listeners)
+}
+
+/// Accept data in the SYN of clients which have a cookie
+fn set_fast_open(listener: &TcpListener) -> io::Result<()> {
+    // pending connections without a finished handshake
+    let queue: libc::c_int = 256;
+    let ret = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &queue as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Failures are only logged, the connection works without keepalive
+fn set_keepalive(stream: &TcpStream, keepalive: Option<&KeepaliveConfig>) {
+    if let Some(keepalive) = keepalive {
+        if let Err(err) = keepalive.apply(stream) {
+            debug!(message = "set keepalive failed", ?err);
+        }
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
+async fn accept(
+    listener: TcpListener,
+    config: inbound::Config,
+    bans: Option<Arc<Bans>>,
+    client_limits: Option<Arc<ClientLimits>>,
+    upstream: Upstream,
+    resolver: Resolver,
+    connections: Connections,
+    router: Router,
+) -> io::Result<()> {
+    let direct_opts = ConnectOpts {
+        bind_interface: config.interface.clone(),
+        fwmark: config.mark,
+        ..Default::default()
+    };
+    let users = Arc::new(config.users.clone());
+
+    loop {
+        let (mut local, src) = listener.accept().await?;
+        if !config.allowed(&src.ip()) {
+            debug!(
+                message = "source is not allowed",
+                inbound = config.name.as_str(),
+                ?src
+            );
+            continue;
+        }
+        if let Some(bans) = &bans {
+            if bans.banned(&src.ip()) {
+                debug!(
+                    message = "source is banned",
+                    inbound = config.name.as_str(),
+                    ?src
+                );
+                continue;
+            }
+        }
+        set_keepalive(&local, config.keepalive.as_ref());
+
+        let protocol = config.protocol;
+        let sniffing = config.sniffing.clone();
+        let users = users.clone();
+        let bans = bans.clone();
+        let client_limits = client_limits.clone();
+        let route = config.route;
+        let limits = config
+            .max_rate
+            .as_ref()
+            .map(|rate| rate.limits(&src.ip()))
+            .unwrap_or_default();
+        let name = config.name.clone();
+        let request_id = config.request_id.clone();
+        let upstream_tag = config.upstream_tag.clone();
+        let keepalive = config.keepalive.clone();
+        let direct_opts = direct_opts.clone();
         let balancer = upstream.clone();
         let resolver = resolver.clone();
-        tasks.push(tokio::spawn(async move {
-            loop {
-                let (mut local, src) = listener.accept().await.expect("listen success");
-                let balancer = balancer.clone();
-                let resolver = resolver.clone();
-
-                // handle the connect
-                tokio::spawn(async move {
-                    let (host, port) = match destination_addr(&mut local).await {
-                        Ok(dst) => dst,
-                        Err(err) => {
-                            warn!(message = "sniff hostname failed", ?err, ?src);
-                            return Err(io::Error::new(ErrorKind::Other, err));
-                        }
-                    };
-
-                    // Trying to connect 5 times
-                    for _i in 0..5 {
-                        let server = balancer.pick(&host).await;
-                        let target = Address::DomainNameAddress(host.clone(), port);
-
-                        debug!(message = "proxy connection", ?src, ?target, relay = ?server.remarks());
-
-                        match ProxyStream::connect(server.config(), target, &resolver, &Default::default()).await {
-                            Ok(proxy) => {
-                                if let Err(err) = proxy.proxy(local).await {
-                                    warn!(message = "proxy error", ?err, ?src, relay = ?server.remarks());
-                                    server.report_failure();
-                                }
-
-                                return Ok(());
-                            },
-                            Err(err) => {
-                                warn!(message = "connect proxy failed, try next", ?err, relay = server.remarks());
-                                server.report_failure()
-                            }
+        let connections = connections.clone();
+        let router = router.clone();
+
+        // handle the connect
+        tokio::spawn(async move {
+            // held until the connection is closed
+            let _permit = match &client_limits {
+                Some(client_limits) => match client_limits.acquire(src.ip()).await {
+                    Some(permit) => Some(permit),
+                    None => {
+                        debug!(
+                            message = "too many connections of source",
+                            inbound = name.as_str(),
+                            ?src
+                        );
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
+
+            let handshake = match protocol {
+                Protocol::Thp => destination_addr(&mut local, &sniffing)
+                    .await
+                    .map(|(host, port)| (host, port, vec![]))
+                    .map_err(|err| io::Error::new(ErrorKind::Other, err)),
+                Protocol::Http => proxy::handshake(&mut local, &users)
+                    .await
+                    .map_err(|err| io::Error::new(ErrorKind::Other, err)),
+                Protocol::Redirect => redirect::original_dst(&local)
+                    .map(|dst| (dst.ip().to_string(), dst.port(), vec![])),
+                Protocol::Tproxy => unreachable!("tproxy inbounds are served by tproxy"),
+            };
+            let (host, port, head) = match handshake {
+                Ok(dst) => dst,
+                Err(err) => {
+                    warn!(message = "handshake failed", ?protocol, ?err, ?src);
+                    if let Some(bans) = &bans {
+                        if bans.fail(src.ip()) {
+                            warn!(
+                                message = "ban source for failed handshakes",
+                                inbound = name.as_str(),
+                                ?src
+                            );
                         }
                     }
+                    return Err(err);
+                }
+            };
+
+            // sniffed domains are for rules, the original addresses are dialed
+            let sniffed = match (protocol, host.parse::<IpAddr>()) {
+                (Protocol::Redirect, Ok(ip)) if sniffing.redirect => {
+                    sniff_domain(&mut local, &sniffing)
+                        .await
+                        .map(|domain| (domain, SocketAddr::new(ip, port)))
+                }
+                _ => None,
+            };
+            let decision = match &sniffed {
+                Some((domain, dst)) => {
+                    debug!(message = "domain sniffed", domain = domain.as_str(), %dst);
+                    router
+                        .route_sniffed(Network::Tcp, src, domain, *dst, &resolver)
+                        .await
+                }
+                None => {
+                    router
+                        .route(Network::Tcp, src, &host, port, &resolver)
+                        .await
+                }
+            };
+            let (route, upstream_tag, mark) = match decision {
+                Some(decision) => {
+                    let (route, upstream_tag) = routed(decision.outbound, upstream_tag);
+                    (route, upstream_tag, decision.mark)
+                }
+                None => (route, upstream_tag, None),
+            };
+            // the mark of the rule takes precedence over the inbound's
+            let direct_opts = match mark {
+                Some(mark) => ConnectOpts {
+                    fwmark: Some(mark),
+                    ..direct_opts
+                },
+                None => direct_opts,
+            };
+
+            let local_addr = local.local_addr()?;
+            let tracked = connections.track(&name, route, src, local_addr, host.clone(), port);
+
+            // every event of this connection, in any module, is in the span
+            let span = info_span!(
+                "connection",
+                id = tracked.connection().id(),
+                inbound = name.as_str(),
+                %src,
+                host = host.as_str(),
+                port,
+                route = route.as_str(),
+            );
+
+            async move {
+                let mut local = Prepended::with_buf(local, head);
+                if let Some(header) = &request_id {
+                    let id = tracked.connection().id();
+                    if let Err(err) = request_id::inject(&mut local, header, id).await {
+                        debug!(message = "read request head failed", ?err);
+                        return Err(err);
+                    }
+                }
+
+                let local = Throttled::new(local, limits);
+                relay(
+                    tracked,
+                    local,
+                    host,
+                    port,
+                    balancer,
+                    upstream_tag,
+                    mark,
+                    keepalive,
+                    direct_opts,
+                    resolver,
+                )
+                .await
+            }
+            .instrument(span)
+            .await
+        });
+    }
+}
+
+/// Route and upstream tag of a connection matching a rule to `outbound`,
+/// `proxy` keeps the tag of the inbound
+fn routed(outbound: Outbound, upstream_tag: Option<String>) -> (Route, Option<String>) {
+    match outbound {
+        Outbound::Direct => (Route::Direct, None),
+        Outbound::Reject => (Route::Reject, None),
+        Outbound::Proxy => (Route::Proxy, upstream_tag),
+        Outbound::Tag(tag) => (Route::Proxy, Some(tag)),
+    }
+}
+
+/// Dial the destination or an upstream server, then copy until one side
+/// is closed. The shadowsocks handshake is sent with the first data, so
+/// it's in the `copy` phase. `mark` of the rule is set on the connection to
+/// the server.
+#[allow(clippy::too_many_arguments)]
+async fn relay(
+    tracked: Tracked,
+    local: Throttled<Prepended<TcpStream>>,
+    host: String,
+    port: u16,
+    balancer: Upstream,
+    upstream_tag: Option<String>,
+    mark: Option<u32>,
+    keepalive: Option<KeepaliveConfig>,
+    direct_opts: ConnectOpts,
+    resolver: Resolver,
+) -> io::Result<()> {
+    let conn = tracked.connection();
+
+    if conn.route() == Route::Reject {
+        debug!(message = "connection rejected");
+        conn.set_close_reason("rejected".to_string());
+        return Ok(());
+    }
+
+    if conn.route() == Route::Direct {
+        conn.set_upstream("direct".to_string());
+
+        let dial = async {
+            let addr = resolver
+                .resolve(&host, port)
+                .await
+                .map_err(|err| format!("resolve failed, {}", err))?;
+            conn.set_destination(addr.to_string());
+
+            ProxyStream::connect_server_addr(addr, &direct_opts)
+                .await
+                .map_err(|err| format!("connect failed, {}", err))
+        };
+        let mut remote = match dial.instrument(debug_span!("dial")).await {
+            Ok(remote) => {
+                set_keepalive(&remote, keepalive.as_ref());
+                conn.set_dialed();
+                conn.set_socket(remote.as_raw_fd());
+                remote
+            }
+            Err(reason) => {
+                debug!(message = "dial failed", %reason);
+                conn.set_close_reason(reason.clone());
+                return Err(io::Error::new(ErrorKind::Other, reason));
+            }
+        };
+
+        let mut local = tracked.count(local);
+        let copy = async {
+            // throttling needs the bytes to pass through userspace too
+            if local.captured() || local.get_mut().limited() {
+                return tokio::io::copy_bidirectional(&mut local, &mut remote)
+                    .await
+                    .map(|_| ());
+            }
+
+            // the head read by sniffing or the handshake goes first
+            let head = local.get_mut().get_mut().take_buf();
+            remote.write_all(&head).await?;
+            conn.add_upload(head.len() as u64);
+
+            splice::copy_bidirectional(
+                local.get_mut().get_ref().get_ref(),
+                &remote,
+                |n| conn.add_upload(n),
+                |n| conn.add_download(n),
+            )
+            .await
+            .map(|_| ())
+        };
+        match copy.instrument(debug_span!("copy")).await {
+            Ok(_) => conn.set_close_reason("done".to_string()),
+            Err(err) => {
+                debug!(message = "direct connection error", ?err);
+                conn.set_close_reason(err.to_string());
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Trying to connect 5 times
+    for attempt in 0..5 {
+        let (server, _permit) = match balancer.acquire(&host, upstream_tag.as_deref()).await {
+            Ok(picked) => picked,
+            Err(err) => {
+                warn!(message = "pick upstream server failed", %err);
+                conn.set_close_reason(err.to_string());
+                return Err(io::Error::new(ErrorKind::Other, err));
+            }
+        };
+        let target = match host.parse::<IpAddr>() {
+            Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, port)),
+            Err(_) => Address::DomainNameAddress(host.clone(), port),
+        };
+        let dial = debug_span!("dial", attempt, upstream = server.name().as_str());
+
+        debug!(
+            message = "proxy connection",
+            ?target,
+            upstream = server.name().as_str()
+        );
+
+        match balancer
+            .connect(&server, target, mark)
+            .instrument(dial)
+            .await
+        {
+            Ok(proxy) => {
+                conn.set_dialed();
+                conn.set_upstream(server.name());
+                conn.set_destination(server.config().addr().to_string());
+                conn.set_socket(proxy.as_raw_fd());
+
+                match proxy
+                    .proxy(server.meter(tracked.count(local)))
+                    .instrument(debug_span!("copy", upstream = server.name().as_str()))
+                    .await
+                {
+                    Ok(_) => conn.set_close_reason("done".to_string()),
+                    Err(err) => {
+                        warn!(
+                            message = "proxy error",
+                            ?err,
+                            connection_id = conn.id(),
+                            upstream = server.name().as_str()
+                        );
+                        server.report_failure();
+                        conn.set_close_reason(err.to_string());
+                    }
+                }
 
-                    Err(io::Error::new(ErrorKind::NotConnected, "no available proxy"))
-                });
+                return Ok(());
+            }
+            Err(err) => {
+                warn!(
+                    message = "connect proxy failed, try next",
+                    ?err,
+                    connection_id = conn.id(),
+                    upstream = server.name().as_str()
+                );
+                server.report_failure()
             }
-        }));
+        }
     }
 
-    join_all(tasks).await;
+    conn.set_close_reason("no available proxy".to_string());
+    Err(io::Error::new(
+        ErrorKind::NotConnected,
+        "no available proxy",
+    ))
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reuse_port() {
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), 4).await.unwrap();
+        assert_eq!(listeners.len(), 4);
+
+        let port = listeners[0].0.local_addr().unwrap().port();
+        assert!(listeners
+            .iter()
+            .all(|(listener, _)| listener.local_addr().unwrap().port() == port));
+    }
+
+    #[tokio::test]
+    async fn fast_open() {
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), 1).await.unwrap();
+        set_fast_open(&listeners[0].0).unwrap();
+    }
 }
@@ -1,7 +1,9 @@
 use std::io;
 use std::io::{Cursor, Read};
+use std::net::IpAddr;
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
+use std::time::Duration;
 
 use byteorder::ByteOrder;
 use byteorder::NetworkEndian;
@@ -10,8 +12,14 @@ use memchr::memchr;
 use tokio::net::TcpStream;
 use trust_dns_resolver::error::ResolveError;
 
+use crate::relay::inbound::Sniffing;
+
 const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
 
+/// Wait for the first data of redirected connections, protocols in which
+/// servers speak first are delayed by it once
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -48,7 +56,10 @@ impl From<Utf8Error> for Error {
     }
 }
 
-pub async fn destination_addr(stream: &mut TcpStream) -> Result<(String, u16), Error> {
+pub async fn destination_addr(
+    stream: &mut TcpStream,
+    sniffing: &Sniffing,
+) -> Result<(String, u16), Error> {
     let mut buf = [0; 1024];
 
     // TODO: something wrong might happened, retry this?
@@ -58,17 +69,49 @@ pub async fn destination_addr(stream: &mut TcpStream) -> Result<(String, u16), E
     let domain = match buf[0] {
         // 22 is Handshake
         // https://www.rfc-editor.org/rfc/rfc5246#section-6.2.1
-        22 => {
+        22 if sniffing.tls => {
             port = 443;
             tls_sni(&buf)
         }
-        b'G' | b'P' | b'D' | b'H' | b'C' | b'O' | b'T' => http_host(&buf).map(Into::into),
+        b'G' | b'P' | b'D' | b'H' | b'C' | b'O' | b'T' if sniffing.http => {
+            http_host(&buf).map(Into::into)
+        }
         _ => return Err(Error::UnknownProtocol),
     }?;
 
     Ok((domain, port))
 }
 
+/// Domain of a redirected connection from the TLS SNI or the Host header,
+/// None if the client sends neither in time, or the Host is an address
+pub async fn sniff_domain(stream: &mut TcpStream, sniffing: &Sniffing) -> Option<String> {
+    let sniffed = tokio::time::timeout(SNIFF_TIMEOUT, destination_addr(stream, sniffing)).await;
+    let (host, _) = match sniffed {
+        Ok(Ok(dst)) => dst,
+        Ok(Err(err)) => {
+            debug!(message = "sniff domain failed", ?err);
+            return None;
+        }
+        Err(_) => return None,
+    };
+
+    domain(&host).map(ToString::to_string)
+}
+
+/// `host` without the port, None for addresses
+fn domain(host: &str) -> Option<&str> {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    let addr = name.trim_start_matches('[').trim_end_matches(']');
+    if name.is_empty() || addr.parse::<IpAddr>().is_ok() || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+
+    Some(name)
+}
+
 fn http_host(buf: &[u8]) -> Result<&str, Error> {
     let mut start = 0;
 
@@ -262,6 +305,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_domain() {
+        assert_eq!(domain("www.example.com"), Some("www.example.com"));
+        assert_eq!(domain("www.example.com:8080"), Some("www.example.com"));
+        assert_eq!(domain("192.0.2.1:8080"), None);
+        assert_eq!(domain("[2001:db8::1]:443"), None);
+        assert_eq!(domain("2001:db8::1"), None);
+        assert_eq!(domain(""), None);
+    }
+
     #[test]
     fn parse_https() {
         let data = include_bytes!("../../../tests/https.bin");
@@ -0,0 +1,308 @@
+//! Bandwidth caps of connections by token buckets, e.g. so one client can't
+//! take the whole uplink
+//!
+//! ```yaml
+//! inbounds:
+//!   - name: lan
+//!     protocol: thp
+//!     listen: 0.0.0.0:1080
+//!     max_rate:
+//!       upload: 1MB
+//!       download: 10MB
+//!       clients:
+//!         - sources: 192.168.1.50
+//!           download: 100mbit
+//! ```
+//!
+//! Each connection has its own buckets, holding a second of traffic at
+//! most, so it may burst after being idle. `upload` is read from clients,
+//! `download` is written to them.
+
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::ready;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::net::Cidr;
+use crate::serde::size;
+
+/// Tokens are granted by 10ms of traffic at least, so slow buckets don't
+/// wake up for every few bytes
+const MIN_GRANT_PER_SECOND: u64 = 100;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    /// Bytes per second from clients, unlimited if it's not set
+    #[serde(default, with = "size::option")]
+    pub upload: Option<u64>,
+
+    /// Bytes per second to clients, unlimited if it's not set
+    #[serde(default, with = "size::option")]
+    pub download: Option<u64>,
+
+    /// Caps of some sources instead of the ones above, the first matching
+    /// one applies
+    #[serde(default)]
+    pub clients: Vec<ClientRate>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientRate {
+    #[serde(with = "crate::serde::networks")]
+    pub sources: Vec<Cidr>,
+
+    #[serde(default, with = "size::option")]
+    pub upload: Option<u64>,
+
+    #[serde(default, with = "size::option")]
+    pub download: Option<u64>,
+}
+
+/// Upload and download caps of a connection, in bytes per second
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl RateConfig {
+    pub fn limits(&self, ip: &IpAddr) -> Limits {
+        match self
+            .clients
+            .iter()
+            .find(|client| client.sources.iter().any(|cidr| cidr.contains(ip)))
+        {
+            Some(client) => Limits {
+                upload: client.upload,
+                download: client.download,
+            },
+            None => Limits {
+                upload: self.upload,
+                download: self.download,
+            },
+        }
+    }
+}
+
+struct Bucket {
+    /// Bytes per second, also the capacity
+    rate: u64,
+    tokens: u64,
+    /// When the tokens were refilled, fractions of a token are kept by
+    /// advancing it only by the time of whole tokens
+    refilled: Instant,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            rate,
+            tokens: rate,
+            refilled: now,
+            sleep: Box::pin(tokio::time::sleep_until(now)),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let added = elapsed.as_nanos() * self.rate as u128 / 1_000_000_000;
+        if self.tokens as u128 + added >= self.rate as u128 {
+            self.tokens = self.rate;
+            self.refilled = now;
+        } else if added > 0 {
+            self.tokens += added as u64;
+            self.refilled += nanos(added * 1_000_000_000 / self.rate as u128);
+        }
+    }
+
+    /// Wait for tokens, returns how many of `want` bytes can be moved now
+    fn poll_grant(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        let min = (self.rate / MIN_GRANT_PER_SECOND).max(1).min(want as u64);
+        loop {
+            let now = Instant::now();
+            self.refill(now);
+            if self.tokens >= min {
+                return Poll::Ready(self.tokens.min(want as u64) as usize);
+            }
+
+            let missing = (min - self.tokens) as u128;
+            // rounded up, the tokens are there when it wakes up
+            let wait = nanos(missing * 1_000_000_000 / self.rate as u128 + 1);
+            self.sleep.as_mut().reset(now + wait);
+            ready!(self.sleep.as_mut().poll(cx));
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens = self.tokens.saturating_sub(n as u64);
+    }
+}
+
+fn nanos(nanos: u128) -> Duration {
+    Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+}
+
+/// A client stream capped by `Limits`, reads are uploads and writes are
+/// downloads
+pub struct Throttled<S> {
+    inner: S,
+    upload: Option<Bucket>,
+    download: Option<Bucket>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, limits: Limits) -> Self {
+        Self {
+            inner,
+            upload: limits.upload.map(Bucket::new),
+            download: limits.download.map(Bucket::new),
+        }
+    }
+
+    /// Whether any direction is capped, streams without caps may be relayed
+    /// by other means than reads and writes, e.g. splice
+    pub fn limited(&self) -> bool {
+        self.upload.is_some() || self.download.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let bucket = match &mut this.upload {
+            Some(bucket) => bucket,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+
+        let granted = ready!(bucket.poll_grant(cx, buf.remaining()));
+        let mut limited = buf.take(granted);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        // the bytes are read into the unfilled part of `buf`
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        bucket.consume(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let bucket = match &mut this.download {
+            Some(bucket) => bucket,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+
+        let granted = ready!(bucket.poll_grant(cx, buf.len()));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]))?;
+        bucket.consume(n);
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn deserialize() {
+        let config: RateConfig = serde_yaml::from_str(
+            r#"
+upload: 1MB
+download: 10MB
+clients:
+  - sources: 192.168.1.50, 10.0.0.0/8
+    download: 80mbit
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.limits(&"192.168.1.1".parse().unwrap()),
+            Limits {
+                upload: Some(1_000_000),
+                download: Some(10_000_000),
+            }
+        );
+        assert_eq!(
+            config.limits(&"10.1.2.3".parse().unwrap()),
+            Limits {
+                upload: None,
+                download: Some(10_000_000),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn throttle() {
+        let data = vec![7u8; 30_000];
+
+        // a second of traffic passes at once, the rest at 20KB/s
+        let started = Instant::now();
+        let limits = Limits {
+            upload: None,
+            download: Some(20_000),
+        };
+        let mut written = Throttled::new(vec![], limits);
+        written.write_all(&data).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(450));
+        assert_eq!(written.get_ref(), &data);
+
+        let started = Instant::now();
+        let limits = Limits {
+            upload: Some(20_000),
+            download: None,
+        };
+        let mut read = vec![];
+        Throttled::new(&data[..], limits)
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(450));
+        assert_eq!(read, data);
+
+        let mut unlimited = Throttled::new(vec![], Limits::default());
+        assert!(!unlimited.limited());
+        unlimited.write_all(&data).await.unwrap();
+    }
+}
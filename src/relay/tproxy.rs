@@ -0,0 +1,319 @@
+//! Transparent proxy of UDP by TPROXY, Linux only, e.g. QUIC and games
+//!
+//! ```text
+//! ip rule add fwmark 1 lookup 100
+//! ip route add local 0.0.0.0/0 dev lo table 100
+//! iptables -t mangle -A PREROUTING -p udp -j TPROXY --on-port 1080 --tproxy-mark 1
+//! ```
+//!
+//! The socket is `IP_TRANSPARENT`, so redirected datagrams keep their
+//! original destination, which is received with `IP_RECVORIGDSTADDR`.
+//! Clients expect replies from the destination, so they are sent from
+//! transparent sockets bound to the address of the remote peer.
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+use resolver::Resolver;
+use shadowsocks::{Address, ConnectOpts, MAXIMUM_UDP_PAYLOAD_SIZE};
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+use super::inbound;
+use super::udp::{Reply, Sessions};
+use crate::upgrade;
+use crate::{Router, Upstream};
+
+pub async fn serve(
+    config: inbound::Config,
+    upstream: Upstream,
+    resolver: Resolver,
+    router: Router,
+) -> io::Result<()> {
+    let (socket, _registration) = upgrade::udp_socket(config.listen)?;
+    let v6 = config.listen.is_ipv6();
+    set_transparent(socket.as_raw_fd(), v6)?;
+    set_recv_orig_dst(socket.as_raw_fd(), v6)?;
+    let socket = UdpSocket::from_std(socket)?;
+    info!(
+        message = "start inbound",
+        name = config.name.as_str(),
+        protocol = ?config.protocol,
+        listen = ?config.listen,
+    );
+
+    let direct_opts = ConnectOpts {
+        bind_interface: config.interface.clone(),
+        fwmark: config.mark,
+        ..Default::default()
+    };
+    let sessions = Sessions::new(upstream, &config.udp).with_router(
+        router,
+        resolver,
+        config.upstream_tag.clone(),
+        direct_opts,
+    );
+    let mut buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+    loop {
+        socket.readable().await?;
+        let received = socket.try_io(Interest::READABLE, || {
+            recv_orig_dst(socket.as_raw_fd(), &mut buf)
+        });
+        let (n, src, dst) = match received {
+            Ok(received) => received,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        };
+
+        if !config.allowed(&src.ip()) {
+            debug!(
+                message = "source is not allowed",
+                inbound = config.name.as_str(),
+                ?src
+            );
+            continue;
+        }
+
+        let target = Address::SocketAddress(dst);
+        if let Err(err) = sessions
+            .send(src, &target, &buf[..n], &Reply::Transparent)
+            .await
+        {
+            debug!(
+                message = "relay datagram failed",
+                inbound = config.name.as_str(),
+                ?src,
+                ?dst,
+                ?err
+            );
+        }
+    }
+}
+
+/// A socket bound to `addr`, which is not a local one, to send replies
+/// from it
+pub(super) fn bind_transparent(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe {
+        libc::socket(
+            domain,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // owned now, it's closed on errors
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    set_transparent(fd, addr.is_ipv6())?;
+    let (storage, len) = to_sockaddr(addr);
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+            len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    UdpSocket::from_std(socket)
+}
+
+fn set_transparent(fd: RawFd, v6: bool) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1)?;
+    if v6 {
+        setsockopt(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1)?;
+    }
+
+    Ok(())
+}
+
+/// IPv4 datagrams of dual stack sockets carry `IP_ORIGDSTADDR` too
+fn set_recv_orig_dst(fd: RawFd, v6: bool) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1)?;
+    if v6 {
+        setsockopt(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1)?;
+    }
+
+    Ok(())
+}
+
+fn setsockopt(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Receive a datagram, returns its length, source and original
+/// destination
+fn recv_orig_dst(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut src: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // room for a sockaddr_in6 and the header, aligned
+    let mut control = [0u64; 8];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut src as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut dst = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let level = (*cmsg).cmsg_level;
+            let kind = (*cmsg).cmsg_type;
+            if (level == libc::SOL_IP && kind == libc::IP_ORIGDSTADDR)
+                || (level == libc::SOL_IPV6 && kind == libc::IPV6_ORIGDSTADDR)
+            {
+                let len = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    .min(mem::size_of::<libc::sockaddr_storage>());
+                let mut addr: libc::sockaddr_storage = mem::zeroed();
+                ptr::copy_nonoverlapping(
+                    libc::CMSG_DATA(cmsg),
+                    &mut addr as *mut libc::sockaddr_storage as *mut u8,
+                    len,
+                );
+                dst = from_sockaddr(&addr);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let src = from_sockaddr(&src).ok_or_else(|| invalid("unknown address family of source"))?;
+    let dst = dst.ok_or_else(|| invalid("no original destination of datagram"))?;
+
+    Ok((n as usize, src, dst))
+}
+
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// IPv4 mapped addresses are converted to IPv4 ones, replies to them are
+/// sent from IPv4 sockets
+pub(super) fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            Some(SocketAddrV4::new(ip, u16::from_be(sin.sin_port)).into())
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            let port = u16::from_be(sin6.sin6_port);
+            Some(match ip.to_ipv4_mapped() {
+                Some(ip) => SocketAddrV4::new(ip, port).into(),
+                None => SocketAddrV6::new(ip, port, sin6.sin6_flowinfo, sin6.sin6_scope_id).into(),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sockaddr() {
+        for addr in ["192.168.1.1:53", "[2001:db8::1]:443"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let (storage, _) = to_sockaddr(addr);
+            assert_eq!(from_sockaddr(&storage), Some(addr));
+        }
+
+        let (storage, _) = to_sockaddr("[::ffff:10.0.0.1]:80".parse().unwrap());
+        assert_eq!(
+            from_sockaddr(&storage),
+            Some("10.0.0.1:80".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn orig_dst() {
+        // not redirected, the original destination is the local address
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_recv_orig_dst(socket.as_raw_fd(), false).unwrap();
+        let local = socket.local_addr().unwrap();
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"ping", local).unwrap();
+
+        let mut buf = [0u8; 16];
+        let (n, src, dst) = recv_orig_dst(socket.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(src, client.local_addr().unwrap());
+        assert_eq!(dst, local);
+    }
+}
@@ -0,0 +1,605 @@
+//! Relay datagrams, e.g. DNS queries and QUIC, through the shadowsocks
+//! upstream servers.
+//!
+//! Each client address has a session per route, a socket to the server
+//! picked for its first datagram, so later datagrams of the flow keep going
+//! through the same server and replies are routed back to the client.
+//! Sessions are full-cone NAT, the client can send to any destination
+//! through its session, and replies of any remote peer are relayed back,
+//! which P2P traffic and games rely on. A session is closed if nothing is
+//! sent or received for `idle_timeout`.
+//!
+//! ```yaml
+//! inbounds:
+//!   - name: quic
+//!     protocol: tproxy
+//!     listen: 0.0.0.0:1080
+//!     udp:
+//!       idle_timeout: 1m
+//!       max_sessions: 1024
+//! ```
+//!
+//! Replies are sent from the socket the datagrams are received on, or for
+//! TPROXY, from the address of the remote peer, see `tproxy`. A session
+//! keeps at most `MAX_TRANSPARENT` sockets of remote peers, the ones idle
+//! for `idle_timeout` are closed, and the least recently used one is closed
+//! for a new peer if there are still too many.
+//!
+//! Each datagram of TPROXY is routed by the rules, e.g.
+//! `DST-PORT,443/udp,quic` sends QUIC through servers tagged `quic`, so a
+//! client has a session for each outbound it sends to. Datagrams routed to
+//! `direct` are sent from a local socket, with the `interface` and `mark`
+//! of the inbound, or the `mark` of the rule, and the ones routed to
+//! `reject` are dropped.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use resolver::Resolver;
+use serde::Deserialize;
+use shadowsocks::{
+    create_udp_socket, Address, ConnectOpts, ProxySocket, ProxySocketError,
+    MAXIMUM_UDP_PAYLOAD_SIZE,
+};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+use super::tproxy;
+use crate::route::{Network, Outbound};
+use crate::serde::duration;
+use crate::upstream::Server;
+use crate::{Router, Upstream};
+
+/// Transparent sockets a session keeps at most
+const MAX_TRANSPARENT: usize = 64;
+
+const fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_max_sessions() -> usize {
+    1024
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UdpConfig {
+    /// Sessions are closed if nothing is sent or received for this
+    #[serde(default = "default_idle_timeout", with = "duration")]
+    pub idle_timeout: Duration,
+
+    /// Datagrams of new clients are dropped while this many sessions are
+    /// open
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: default_idle_timeout(),
+            max_sessions: default_max_sessions(),
+        }
+    }
+}
+
+/// Where datagrams of a session go, with the `mark` of the rule
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Route {
+    Direct(Option<u32>),
+    /// Through an upstream server of the tag, any server if it's None
+    Proxy(Option<String>, Option<u32>),
+}
+
+enum Socket {
+    Proxy {
+        socket: ProxySocket,
+        server: Arc<Server>,
+    },
+    /// Not connected, so it sends to and receives from any peer
+    Direct(UdpSocket),
+}
+
+struct Session {
+    socket: Socket,
+    /// Datagrams of either direction keep the session open
+    last_active: Mutex<Instant>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_active.lock() = Instant::now();
+    }
+
+    fn upstream(&self) -> String {
+        match &self.socket {
+            Socket::Proxy { server, .. } => server.name(),
+            Socket::Direct(_) => "direct".to_string(),
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<(usize, Address), ProxySocketError> {
+        match &self.socket {
+            Socket::Proxy { socket, server } => {
+                let (n, from, _) = socket.recv(buf).await?;
+                server.add_download(n as u64);
+                Ok((n, from))
+            }
+            Socket::Direct(socket) => {
+                let (n, from) = socket.recv_from(buf).await?;
+                Ok((n, Address::SocketAddress(from)))
+            }
+        }
+    }
+}
+
+/// Sessions of a client by their routes
+type Table = Arc<Mutex<HashMap<(SocketAddr, Route), Arc<Session>>>>;
+
+/// How replies are sent back to clients
+#[derive(Clone)]
+pub enum Reply {
+    /// From the socket the datagram is received on
+    Socket(Arc<UdpSocket>),
+    /// From the address of the remote peer, clients of transparent proxies
+    /// expect replies from the destinations they sent to
+    Transparent,
+}
+
+/// Rules datagrams are routed by
+struct Routing {
+    router: Router,
+    resolver: Resolver,
+    /// Of the inbound, for datagrams to `proxy` or matching no rule
+    upstream_tag: Option<String>,
+    /// Interface and mark of direct sessions
+    direct_opts: ConnectOpts,
+}
+
+#[derive(Clone)]
+pub struct Sessions {
+    upstream: Upstream,
+    idle_timeout: Duration,
+    max_sessions: usize,
+    sessions: Table,
+    routing: Option<Arc<Routing>>,
+}
+
+impl Sessions {
+    pub fn new(upstream: Upstream, config: &UdpConfig) -> Self {
+        Self {
+            upstream,
+            idle_timeout: config.idle_timeout,
+            max_sessions: config.max_sessions,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            routing: None,
+        }
+    }
+
+    /// Route each datagram by the rules of `router`
+    pub fn with_router(
+        mut self,
+        router: Router,
+        resolver: Resolver,
+        upstream_tag: Option<String>,
+        direct_opts: ConnectOpts,
+    ) -> Self {
+        self.routing = Some(Arc::new(Routing {
+            router,
+            resolver,
+            upstream_tag,
+            direct_opts,
+        }));
+        self
+    }
+
+    /// Number of open sessions
+    pub fn len(&self) -> usize {
+        self.sessions.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Relay `payload` of `client` to `target`, replies are sent to the
+    /// client by `reply`
+    pub async fn send(
+        &self,
+        client: SocketAddr,
+        target: &Address,
+        payload: &[u8],
+        reply: &Reply,
+    ) -> io::Result<()> {
+        let key = (client, self.route(client, target).await?);
+        let existed = self.sessions.lock().get(&key).cloned();
+        let session = match existed {
+            Some(session) => session,
+            None => self.open(key, target, reply).await?,
+        };
+        session.touch();
+
+        match &session.socket {
+            Socket::Proxy { socket, server } => {
+                socket.send(target, payload, &Default::default()).await?;
+                server.add_upload(payload.len() as u64);
+            }
+            Socket::Direct(socket) => {
+                let addr = match (target, &self.routing) {
+                    (Address::SocketAddress(addr), _) => *addr,
+                    (Address::DomainNameAddress(domain, port), Some(routing)) => routing
+                        .resolver
+                        .resolve(domain, *port)
+                        .await
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?,
+                    (Address::DomainNameAddress(..), None) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "no resolver for direct datagrams",
+                        ))
+                    }
+                };
+                socket.send_to(payload, addr).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Route of a datagram of `client` to `target`
+    async fn route(&self, client: SocketAddr, target: &Address) -> io::Result<Route> {
+        let Routing {
+            router,
+            resolver,
+            upstream_tag,
+            ..
+        } = match &self.routing {
+            Some(routing) => routing.as_ref(),
+            None => return Ok(Route::Proxy(None, None)),
+        };
+
+        let (host, port) = match target {
+            Address::SocketAddress(addr) => (addr.ip().to_string(), addr.port()),
+            Address::DomainNameAddress(domain, port) => (domain.clone(), *port),
+        };
+        let decision = router
+            .route(Network::Udp, client, &host, port, resolver)
+            .await;
+        let mark = decision.as_ref().and_then(|decision| decision.mark);
+        let route = match decision.map(|decision| decision.outbound) {
+            Some(Outbound::Reject) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "rejected by rules"))
+            }
+            Some(Outbound::Direct) => Route::Direct(mark),
+            Some(Outbound::Tag(tag)) => Route::Proxy(Some(tag), mark),
+            Some(Outbound::Proxy) | None => Route::Proxy(upstream_tag.clone(), mark),
+        };
+
+        Ok(route)
+    }
+
+    async fn open(
+        &self,
+        key: (SocketAddr, Route),
+        target: &Address,
+        reply: &Reply,
+    ) -> io::Result<Arc<Session>> {
+        if self.len() >= self.max_sessions {
+            return Err(too_many_sessions());
+        }
+
+        let (client, route) = &key;
+        let socket = match route {
+            Route::Direct(mark) => {
+                let mut opts = match &self.routing {
+                    Some(routing) => routing.direct_opts.clone(),
+                    None => ConnectOpts::default(),
+                };
+                if mark.is_some() {
+                    opts.fwmark = *mark;
+                }
+                // clients of transparent proxies send to peers of their
+                // own family
+                Socket::Direct(create_udp_socket(client.into(), &opts).await?)
+            }
+            Route::Proxy(tag, mark) => {
+                let host = match target {
+                    Address::SocketAddress(addr) => addr.ip().to_string(),
+                    Address::DomainNameAddress(domain, _) => domain.clone(),
+                };
+                let server = self.upstream.pick(&host, tag.as_deref()).await;
+                let socket = self.upstream.udp_socket(&server, *mark).await?;
+                Socket::Proxy { socket, server }
+            }
+        };
+        let session = Arc::new(Session {
+            socket,
+            last_active: Mutex::new(Instant::now()),
+        });
+
+        {
+            // another datagram of the client may open one meanwhile
+            let mut sessions = self.sessions.lock();
+            if let Some(existed) = sessions.get(&key) {
+                return Ok(existed.clone());
+            }
+            if sessions.len() >= self.max_sessions {
+                return Err(too_many_sessions());
+            }
+            sessions.insert(key.clone(), session.clone());
+        }
+        if let Socket::Proxy { server, .. } = &session.socket {
+            server.add_session();
+        }
+
+        debug!(
+            message = "udp session opened",
+            ?client,
+            upstream = session.upstream().as_str()
+        );
+        tokio::spawn(relay_replies(
+            self.sessions.clone(),
+            key,
+            session.clone(),
+            reply.clone(),
+            self.idle_timeout,
+        ));
+
+        Ok(session)
+    }
+}
+
+/// Send replies of the session back to the client until it's idle
+async fn relay_replies(
+    sessions: Table,
+    key: (SocketAddr, Route),
+    session: Arc<Session>,
+    reply: Reply,
+    idle_timeout: Duration,
+) {
+    let client = key.0;
+    let mut buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+    // transparent sockets by the remote peer they are bound to
+    let mut transparent = HashMap::new();
+    loop {
+        let idle = session.last_active.lock().elapsed();
+        let wait = match idle_timeout.checked_sub(idle) {
+            Some(wait) if !wait.is_zero() => wait,
+            _ => break,
+        };
+
+        match time::timeout(wait, session.recv(&mut buf)).await {
+            Ok(Ok((n, from))) => {
+                session.touch();
+                let sent = match &reply {
+                    Reply::Socket(socket) => socket.send_to(&buf[..n], client).await,
+                    Reply::Transparent => {
+                        send_transparent(&mut transparent, &from, &buf[..n], client, idle_timeout)
+                            .await
+                    }
+                };
+                if let Err(err) = sent {
+                    debug!(message = "send reply to client failed", ?client, ?err);
+                    break;
+                }
+            }
+            Ok(Err(ProxySocketError::IoError(err))) => {
+                debug!(message = "receive from upstream failed", ?client, ?err);
+                break;
+            }
+            // e.g. a forged packet, the session is still usable
+            Ok(Err(err)) => {
+                debug!(message = "invalid packet from upstream", ?client, ?err);
+            }
+            // the client may have sent meanwhile
+            Err(_) => continue,
+        }
+    }
+
+    let mut sessions = sessions.lock();
+    if matches!(sessions.get(&key), Some(current) if Arc::ptr_eq(current, &session)) {
+        sessions.remove(&key);
+    }
+    debug!(
+        message = "udp session closed",
+        ?client,
+        upstream = session.upstream().as_str()
+    );
+}
+
+fn too_many_sessions() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "too many udp sessions")
+}
+
+/// Send `payload` to `client` from `from`, the remote peer, sockets idle
+/// for `idle_timeout` are closed
+async fn send_transparent(
+    sockets: &mut HashMap<SocketAddr, (UdpSocket, Instant)>,
+    from: &Address,
+    payload: &[u8],
+    client: SocketAddr,
+    idle_timeout: Duration,
+) -> io::Result<usize> {
+    let from = match from {
+        Address::SocketAddress(from) => *from,
+        Address::DomainNameAddress(..) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "reply from a domain name",
+            ))
+        }
+    };
+    if !sockets.contains_key(&from) {
+        evict(sockets, idle_timeout);
+    }
+    let (socket, last_used) = match sockets.entry(from) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert((tproxy::bind_transparent(from)?, Instant::now())),
+    };
+    *last_used = Instant::now();
+
+    socket.send_to(payload, client).await
+}
+
+/// Make room for a new socket, close the ones idle for `idle_timeout`, and
+/// the least recently used one if there are still `MAX_TRANSPARENT`
+fn evict<T>(sockets: &mut HashMap<SocketAddr, (T, Instant)>, idle_timeout: Duration) {
+    sockets.retain(|_, (_, last_used)| last_used.elapsed() < idle_timeout);
+    if sockets.len() < MAX_TRANSPARENT {
+        return;
+    }
+
+    let oldest = sockets
+        .iter()
+        .min_by_key(|(_, (_, last_used))| *last_used)
+        .map(|(addr, _)| *addr);
+    if let Some(oldest) = oldest {
+        sockets.remove(&oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use resolver::Resolver;
+
+    use super::*;
+    use crate::Config;
+
+    #[tokio::test]
+    async fn relay() {
+        // AEAD packets of both directions are in the same format, so the
+        // server can just echo them
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            loop {
+                let (n, from) = server.recv_from(&mut buf).await.unwrap();
+                server.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+
+        let config = Config::builder()
+            .resolver("127.0.0.1:53".parse().unwrap())
+            .nameserver("127.0.0.1:53".parse().unwrap())
+            .check(Duration::from_millis(100), Duration::from_secs(60))
+            .server(format!("ss://YWVzLTEyOC1nY206cGFzcw@{}", server_addr))
+            .build()
+            .unwrap();
+        let resolver = Resolver::new(vec!["127.0.0.1:53".parse().unwrap()]).unwrap();
+        let upstream = Upstream::new(config.upstream, resolver.clone())
+            .await
+            .unwrap();
+        let sessions = Sessions::new(
+            upstream,
+            &UdpConfig {
+                idle_timeout: Duration::from_millis(500),
+                max_sessions: 1,
+            },
+        );
+
+        let inbound = Reply::Socket(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        // any destination goes through the session of the client
+        let targets = [
+            Address::SocketAddress("8.8.8.8:53".parse().unwrap()),
+            Address::SocketAddress("1.1.1.1:443".parse().unwrap()),
+        ];
+
+        let mut buf = [0u8; 64];
+        for (target, payload) in targets.iter().zip([b"ping", b"pong"]) {
+            sessions
+                .send(client_addr, target, payload, &inbound)
+                .await
+                .unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], payload);
+        }
+        assert_eq!(sessions.len(), 1);
+
+        let other = "127.0.0.1:1".parse().unwrap();
+        assert!(sessions
+            .send(other, &targets[0], b"ping", &inbound)
+            .await
+            .is_err());
+
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(sessions.is_empty());
+
+        // every datagram is routed, a session of the client doesn't take
+        // datagrams of other routes
+        let direct = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let direct_addr = direct.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            loop {
+                let (n, from) = direct.recv_from(&mut buf).await.unwrap();
+                direct.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+        let router = Router::new(
+            vec![
+                "DST-PORT,443/udp,reject".parse().unwrap(),
+                "IP-CIDR,127.0.0.1/32,direct".parse().unwrap(),
+            ],
+            Default::default(),
+            Default::default(),
+        );
+        let sessions = Sessions::new(
+            sessions.upstream,
+            &UdpConfig {
+                idle_timeout: Duration::from_millis(500),
+                max_sessions: 2,
+            },
+        )
+        .with_router(router, resolver, None, Default::default());
+
+        sessions
+            .send(client_addr, &targets[0], b"ping", &inbound)
+            .await
+            .unwrap();
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert!(sessions
+            .send(client_addr, &targets[1], b"ping", &inbound)
+            .await
+            .is_err());
+        assert_eq!(sessions.len(), 1);
+
+        // sent from a local socket, not by the server
+        let target = Address::SocketAddress(direct_addr);
+        sessions
+            .send(client_addr, &target, b"direct", &inbound)
+            .await
+            .unwrap();
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"direct");
+        assert_eq!(sessions.len(), 2);
+    }
+
+    #[test]
+    fn evict_transparent() {
+        let now = Instant::now();
+        let idle = Duration::from_secs(60);
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+
+        let mut sockets: HashMap<_, _> = (0..MAX_TRANSPARENT as u16)
+            .map(|port| (addr(port), ((), now)))
+            .collect();
+        sockets.get_mut(&addr(0)).unwrap().1 = now - Duration::from_secs(1);
+        evict(&mut sockets, idle);
+        assert_eq!(sockets.len(), MAX_TRANSPARENT - 1);
+        assert!(!sockets.contains_key(&addr(0)));
+
+        sockets.get_mut(&addr(1)).unwrap().1 = now - idle;
+        sockets.get_mut(&addr(2)).unwrap().1 = now - idle;
+        evict(&mut sockets, idle);
+        assert_eq!(sockets.len(), MAX_TRANSPARENT - 3);
+    }
+}
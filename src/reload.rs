@@ -0,0 +1,404 @@
+//! Watch the config file and the files it includes, and apply changes to
+//! running services.
+//!
+//! Only the changed parts are rebuilt, e.g. changing an inbound restarts
+//! its listener but the relaying connections, upstream servers and DNS
+//! cache are kept.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use inotify::{Event, EventStream, Inotify, WatchDescriptor, WatchMask};
+use resolver::Resolver;
+use roxy::{
+    controller, dns, inbound, trace_filter, Config, Connections, LogHandle, Override, Profiles,
+    RemoteConfig, Router, Upstream,
+};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+/// Editors usually write the file more than once when saving
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// A spawned task which can be stopped and replaced
+pub struct Service {
+    stop: Arc<Notify>,
+    handle: JoinHandle<()>,
+}
+
+impl Service {
+    /// Spawn the task, if `fatal` is true, the process exits when it fails
+    pub fn spawn<F>(name: &'static str, fatal: bool, fut: F) -> Self
+    where
+        F: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let stop = Arc::new(Notify::new());
+        let notified = stop.clone();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                result = fut => {
+                    if let Err(err) = result {
+                        error!(message = "service failed", name, ?err);
+
+                        if fatal {
+                            exit(1);
+                        }
+                    }
+                }
+                _ = notified.notified() => {
+                    info!(message = "service stopped", name);
+                }
+            }
+        });
+
+        Self { stop, handle }
+    }
+
+    /// Stop the task and wait until it is dropped, so its listeners
+    /// are closed and the addresses can be bound again.
+    pub async fn stop(self) {
+        self.stop.notify_one();
+        let _ = self.handle.await;
+    }
+}
+
+pub struct Services {
+    pub config: Config,
+
+    pub resolver: Resolver,
+    pub upstream: Upstream,
+    pub logging: LogHandle,
+    pub dns: Arc<dns::Handler>,
+    pub connections: Connections,
+    pub profiles: Profiles,
+    pub router: Router,
+
+    pub dns_server: Option<Service>,
+    pub controller: Option<Service>,
+    /// Running inbounds by name
+    pub inbounds: HashMap<String, Service>,
+}
+
+impl Services {
+    pub fn dns_service(&self, listen: String, fatal: bool) -> Service {
+        let server = dns::Server::with_handler(listen, self.dns.clone());
+
+        Service::spawn("dns server", fatal, server.serve())
+    }
+
+    pub fn controller_service(
+        &self,
+        config: controller::Config,
+        fatal: bool,
+    ) -> Result<Service, controller::Error> {
+        let svr = controller::Server::new(
+            config,
+            self.upstream.clone(),
+            self.logging.clone(),
+            Some(self.dns.clone()),
+            self.connections.clone(),
+            self.profiles.clone(),
+        )?;
+
+        Ok(Service::spawn("controller", fatal, svr.serve()))
+    }
+
+    pub fn inbound_service(&self, config: inbound::Config, fatal: bool) -> Service {
+        Service::spawn(
+            "inbound",
+            fatal,
+            inbound::serve(
+                config,
+                self.upstream.clone(),
+                self.resolver.clone(),
+                self.connections.clone(),
+                self.router.clone(),
+            ),
+        )
+    }
+
+    /// Stop accepting, relaying connections are kept
+    async fn stop(self) {
+        if let Some(dns_server) = self.dns_server {
+            dns_server.stop().await;
+        }
+        if let Some(controller) = self.controller {
+            controller.stop().await;
+        }
+        for (_, inbound) in self.inbounds {
+            inbound.stop().await;
+        }
+    }
+
+    /// Compare with the running config and apply the changes, a section
+    /// keeps the running one if it can't be applied.
+    async fn apply(&mut self, mut new: Config) {
+        self.profiles.update(&new);
+
+        let diff = self.config.diff(&new);
+        if diff.is_empty() {
+            debug!(message = "config is not changed");
+            return;
+        }
+
+        info!(message = "config changed", ?diff);
+
+        if diff.restart {
+            warn!(
+                message =
+                    "changes of worker, resolvers, remote, upgrade and log sinks take effect after restart"
+            );
+        }
+
+        if diff.log {
+            match trace_filter(&new.log) {
+                Ok(filter) => self.logging.set(filter),
+                Err(err) => {
+                    warn!(message = "invalid log filter", ?err);
+                    new.log = self.config.log.clone();
+                }
+            }
+        }
+
+        if diff.upstream {
+            if let Err(err) = self.upstream.update(new.upstream.clone()).await {
+                warn!(message = "update upstream failed", ?err);
+                new.upstream = self.config.upstream.clone();
+            }
+        }
+
+        if diff.dns {
+            match self.dns.update(new.dns.clone()).await {
+                Ok(()) => {
+                    if self.config.dns.listen != new.dns.listen {
+                        if let Some(svc) = self.dns_server.take() {
+                            svc.stop().await;
+                        }
+
+                        self.dns_server = Some(self.dns_service(new.dns.listen.clone(), false));
+                    }
+                }
+                Err(err) => {
+                    warn!(message = "update dns rules failed", ?err);
+                    new.dns = self.config.dns.clone();
+                }
+            }
+        }
+
+        if diff.controller {
+            // build the new one first, so the running one is kept if
+            // the new config is invalid
+            let svr = new
+                .controller
+                .clone()
+                .map(|cc| self.controller_service(cc, false))
+                .transpose();
+
+            match svr {
+                Ok(svr) => {
+                    if let Some(svc) = std::mem::replace(&mut self.controller, svr) {
+                        svc.stop().await;
+                    }
+                }
+                Err(err) => {
+                    warn!(message = "invalid controller config", ?err);
+                    new.controller = self.config.controller.clone();
+                }
+            }
+        }
+
+        if diff.rules {
+            self.router.update(new.rules.clone());
+            self.router.update_script(new.script.as_ref());
+        }
+
+        if diff.inbounds {
+            // only the removed and changed ones are stopped, so the others
+            // keep accepting
+            for old in &self.config.inbounds {
+                if !new.inbounds.contains(old) {
+                    if let Some(svc) = self.inbounds.remove(&old.name) {
+                        svc.stop().await;
+                    }
+                }
+            }
+
+            for ic in &new.inbounds {
+                if !self.inbounds.contains_key(&ic.name) {
+                    let svc = self.inbound_service(ic.clone(), false);
+                    self.inbounds.insert(ic.name.clone(), svc);
+                }
+            }
+        }
+
+        self.config = new;
+    }
+}
+
+/// Fetch the remote config every `interval`, `changed` is notified when
+/// a new one is saved to the cache.
+pub async fn poll_remote(
+    remote: RemoteConfig,
+    interval: Duration,
+    resolver: Resolver,
+    changed: Arc<Notify>,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match remote.fetch(resolver.clone()).await {
+            Ok(true) => {
+                info!(message = "remote config changed");
+                changed.notify_one();
+            }
+            Ok(false) => debug!(message = "remote config is not changed"),
+            Err(err) => warn!(message = "fetch remote config failed", ?err),
+        }
+    }
+}
+
+/// Names of the config files in each watched directory, the files and
+/// include globs
+type Watched = HashMap<WatchDescriptor, Vec<glob::Pattern>>;
+
+/// Watch the directories of `sources` instead of the files, editors and
+/// ConfigMap replace files by renaming, which removes the watch of a file.
+/// Directories watched already are kept by inotify.
+fn watch_sources(events: &EventStream<[u8; 1024]>, sources: &[PathBuf]) -> Watched {
+    let mut watched = Watched::new();
+    for source in sources {
+        let (dir, name) = match split(source) {
+            Some(split) => split,
+            None => {
+                warn!(message = "invalid config path, it's not watched", ?source);
+                continue;
+            }
+        };
+        let name = name.to_string_lossy();
+        let wd = match events.watches().add(
+            &dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+        ) {
+            Ok(wd) => wd,
+            Err(err) => {
+                warn!(message = "watch config failed", ?err, ?dir);
+                continue;
+            }
+        };
+
+        // names of files matched by globs may have glob characters too
+        let patterns = watched.entry(wd).or_default();
+        patterns.push(glob::Pattern::new(&glob::Pattern::escape(&name)).expect("escaped name"));
+        if let Ok(pattern) = glob::Pattern::new(&name) {
+            patterns.push(pattern);
+        }
+    }
+
+    watched
+}
+
+/// The event is of a config file, or a file matching an include glob
+fn is_source(watched: &Watched, event: &Event<OsString>) -> bool {
+    match (watched.get(&event.wd), &event.name) {
+        (Some(patterns), Some(name)) => {
+            let name = name.to_string_lossy();
+            patterns.iter().any(|pattern| pattern.matches(&name))
+        }
+        _ => false,
+    }
+}
+
+/// Watch the config file at `path` and the files it includes, and reload
+/// when `remote` is notified or profiles are switched by the controller.
+/// `overrides` are applied to every reloaded config. It returns only when
+/// `upgraded` is notified, after services are stopped, if the watcher
+/// can't be created, changes of the files are just ignored.
+pub async fn watch(
+    path: PathBuf,
+    overrides: Vec<Override>,
+    remote: Arc<Notify>,
+    upgraded: Arc<Notify>,
+    mut services: Services,
+) {
+    let mut watcher =
+        match Inotify::init().and_then(|inotify| inotify.into_event_stream([0u8; 1024])) {
+            Ok(events) => {
+                let watched = watch_sources(&events, &services.config.sources);
+                info!(message = "watching config", sources = ?services.config.sources);
+                Some((events, watched))
+            }
+            Err(err) => {
+                warn!(
+                    message = "watch config failed, only remote and profile changes are reloaded",
+                    ?err
+                );
+                None
+            }
+        };
+
+    loop {
+        tokio::select! {
+            // pending forever without a watcher
+            result = async {
+                match &mut watcher {
+                    Some((events, _)) => events.next().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let (events, watched) = watcher.as_mut().expect("events of the watcher");
+                match result {
+                    Some(Ok(event)) if is_source(watched, &event) => {}
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => {
+                        warn!(message = "read inotify events failed", ?err);
+                        continue;
+                    }
+                    None => {
+                        warn!(message = "inotify event stream closed, config is not watched");
+                        watcher = None;
+                        continue;
+                    }
+                }
+
+                // drain the following events
+                while let Ok(Some(_)) = tokio::time::timeout(DEBOUNCE, events.next()).await {}
+            }
+            _ = remote.notified() => {}
+            _ = services.profiles.changed() => {}
+            _ = upgraded.notified() => return services.stop().await,
+        }
+
+        // profiles selected by the controller take precedence
+        let mut current = overrides.clone();
+        current.extend(services.profiles.selected());
+
+        match Config::load_with_overrides(&path, &current) {
+            Ok(new) => {
+                services.apply(new).await;
+                // includes may have changed
+                if let Some((events, watched)) = &mut watcher {
+                    *watched = watch_sources(events, &services.config.sources);
+                }
+            }
+            Err(err) => warn!(message = "load changed config failed", ?err),
+        }
+    }
+}
+
+fn split(path: &Path) -> Option<(PathBuf, OsString)> {
+    let name = path.file_name()?.to_os_string();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    Some((dir, name))
+}
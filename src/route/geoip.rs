@@ -0,0 +1,289 @@
+//! Countries of addresses for `GEOIP` rules, looked up in a MaxMind
+//! database, e.g. GeoLite2-Country or the `Country.mmdb` of Clash, and
+//! autonomous systems for `IP-ASN` rules, looked up in GeoLite2-ASN
+//!
+//! ```yaml
+//! geoip:
+//!   path: /var/lib/roxy/Country.mmdb
+//!   url: https://github.com/Dreamacro/maxmind-geoip/releases/latest/download/Country.mmdb
+//!   interval: 7d
+//! asn:
+//!   path: /var/lib/roxy/GeoLite2-ASN.mmdb
+//! ```
+//!
+//! With `url`, a database is downloaded if `path` doesn't exist, and
+//! again every `interval`. A downloaded one replaces the old one only if
+//! it can be read, and it's applied without restarting. `GEOIP` rules
+//! and `IP-ASN` rules don't match until their databases are loaded.
+
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{StatusCode, Uri};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use parking_lot::RwLock;
+use resolver::Resolver;
+use serde::Deserialize;
+
+use crate::http::HttpClient;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("read {path:?} failed, {err}")]
+    Read { path: PathBuf, err: io::Error },
+    #[error("write {path:?} failed, {err}")]
+    Write { path: PathBuf, err: io::Error },
+    #[error("invalid database, {0}")]
+    Database(MaxMindDBError),
+    #[error("invalid uri \"{0}\"")]
+    InvalidUri(String),
+    #[error(transparent)]
+    Http(#[from] hyper::Error),
+    #[error("unexpected status code {0}")]
+    UnexpectedStatusCode(StatusCode),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// The mmdb file, it's written if `url` is set
+    pub path: PathBuf,
+
+    /// Download the database from it
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// Download the database periodically, it's downloaded only if `path`
+    /// doesn't exist if it's not set
+    #[serde(default, with = "crate::serde::duration::option")]
+    pub interval: Option<Duration>,
+}
+
+type Database = Arc<Reader<Vec<u8>>>;
+
+/// The loaded database, it's shared by routers and replaced by downloads
+#[derive(Clone, Default)]
+pub struct GeoIp {
+    reader: Arc<RwLock<Option<Database>>>,
+}
+
+impl GeoIp {
+    /// Load the database of `config`, nothing is loaded if it doesn't
+    /// exist but it's going to be downloaded
+    pub fn open(config: &GeoIpConfig) -> Result<Self, Error> {
+        let geoip = GeoIp::default();
+        match std::fs::read(&config.path) {
+            Ok(content) => geoip.replace(content)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && config.url.is_some() => {}
+            Err(err) => {
+                return Err(Error::Read {
+                    path: config.path.clone(),
+                    err,
+                })
+            }
+        }
+
+        Ok(geoip)
+    }
+
+    fn replace(&self, content: Vec<u8>) -> Result<(), Error> {
+        let reader = Reader::from_source(content).map_err(Error::Database)?;
+        *self.reader.write() = Some(Arc::new(reader));
+
+        Ok(())
+    }
+
+    /// ISO code of the country of `ip`, e.g. `CN`
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.read().clone()?;
+        let country = reader.lookup::<geoip2::Country>(ip).ok()?;
+        let code = country.country.or(country.registered_country)?.iso_code?;
+
+        Some(code.to_ascii_uppercase())
+    }
+
+    /// Number of the autonomous system of `ip`, e.g. 15169
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        let reader = self.reader.read().clone()?;
+        let asn = reader.lookup::<geoip2::Asn>(ip).ok()?;
+
+        asn.autonomous_system_number
+    }
+
+    /// Download the database if it's missing, then every `interval`,
+    /// until the process exits
+    pub async fn update(self, config: GeoIpConfig, resolver: Resolver) {
+        let url = match &config.url {
+            Some(url) => url.clone(),
+            None => return,
+        };
+        let client = HttpClient::new(resolver);
+
+        let path = &config.path;
+        if !path.exists() {
+            match self.download(&client, &url, path).await {
+                Ok(()) => info!(message = "geoip database downloaded", ?path, %url),
+                Err(err) => warn!(message = "download geoip database failed", %err, %url),
+            }
+        }
+
+        let interval = match config.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        loop {
+            tokio::time::sleep(interval).await;
+
+            match self.download(&client, &url, path).await {
+                Ok(()) => info!(message = "geoip database updated", ?path, %url),
+                Err(err) => warn!(message = "update geoip database failed", %err, %url),
+            }
+        }
+    }
+
+    async fn download(&self, client: &HttpClient, url: &str, path: &Path) -> Result<(), Error> {
+        let uri = Uri::from_str(url).map_err(|_| Error::InvalidUri(url.to_string()))?;
+        let (parts, body) = client.get(uri).await?.into_parts();
+        if parts.status != StatusCode::OK {
+            return Err(Error::UnexpectedStatusCode(parts.status));
+        }
+        let content = hyper::body::to_bytes(body).await?.to_vec();
+
+        // a broken download should not replace the file
+        Reader::from_source(&content[..]).map_err(Error::Database)?;
+        write(path, &content)?;
+
+        self.replace(content)
+    }
+}
+
+fn write(path: &Path, content: &[u8]) -> Result<(), Error> {
+    crate::fs::write_atomic(path, content).map_err(|err| Error::Write {
+        path: path.to_path_buf(),
+        err,
+    })
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Encode a field of the MaxMind DB data section
+    fn field(kind: u8, size: usize, payload: &[u8]) -> Vec<u8> {
+        let mut buf = if kind > 7 {
+            vec![size as u8, kind - 7]
+        } else {
+            vec![(kind << 5) | size as u8]
+        };
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        field(2, s.len(), s.as_bytes())
+    }
+
+    fn map(pairs: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = field(7, pairs.len(), &[]);
+        for (key, value) in pairs {
+            buf.extend(string(key));
+            buf.extend_from_slice(value);
+        }
+        buf
+    }
+
+    /// An IPv4 database of one node, the record of `0.0.0.0/1` is `low`
+    /// and the one of `128.0.0.0/1` is `high`
+    pub fn database(kind: &str, low: Vec<u8>, high: Vec<u8>) -> Vec<u8> {
+        // records point to the data section after the 16 bytes separator
+        let node_count = 1u32;
+        let record = |offset: usize| {
+            let value = node_count as usize + 16 + offset;
+            [(value >> 16) as u8, (value >> 8) as u8, value as u8]
+        };
+        let mut buf = vec![];
+        buf.extend_from_slice(&record(0));
+        buf.extend_from_slice(&record(low.len()));
+        buf.extend_from_slice(&[0; 16]);
+        buf.extend(low);
+        buf.extend(high);
+
+        buf.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        buf.extend(map(&[
+            ("binary_format_major_version", field(5, 1, &[2])),
+            ("binary_format_minor_version", field(5, 0, &[])),
+            ("build_epoch", field(9, 0, &[])),
+            ("database_type", string(kind)),
+            ("description", map(&[])),
+            ("ip_version", field(5, 1, &[4])),
+            ("languages", field(11, 0, &[])),
+            ("node_count", field(6, 1, &[node_count as u8])),
+            ("record_size", field(5, 1, &[24])),
+        ]));
+
+        buf
+    }
+
+    pub fn country_database() -> Vec<u8> {
+        let country = |code: &str| map(&[("country", map(&[("iso_code", string(code))]))]);
+        database("GeoLite2-Country", country("cn"), country("US"))
+    }
+
+    pub fn asn_database() -> Vec<u8> {
+        let asn = |number: &[u8]| map(&[("autonomous_system_number", field(6, 2, number))]);
+        // 15169 and 13335
+        database("GeoLite2-ASN", asn(&[0x3b, 0x41]), asn(&[0x34, 0x17]))
+    }
+
+    #[test]
+    fn country() {
+        let geoip = GeoIp::default();
+        assert_eq!(geoip.country("1.2.3.4".parse().unwrap()), None);
+
+        geoip.replace(country_database()).unwrap();
+        assert_eq!(
+            geoip.country("1.2.3.4".parse().unwrap()),
+            Some("CN".to_string())
+        );
+        assert_eq!(
+            geoip.country("200.1.1.1".parse().unwrap()),
+            Some("US".to_string())
+        );
+        assert!(geoip.replace(b"not a database".to_vec()).is_err());
+        assert_eq!(geoip.asn("1.2.3.4".parse().unwrap()), None);
+
+        geoip.replace(asn_database()).unwrap();
+        assert_eq!(geoip.asn("8.8.8.8".parse().unwrap()), Some(15169));
+        assert_eq!(geoip.asn("200.1.1.1".parse().unwrap()), Some(13335));
+        assert_eq!(geoip.country("8.8.8.8".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn open() {
+        let dir = std::env::temp_dir().join(format!("roxy-geoip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = GeoIpConfig {
+            path: dir.join("Country.mmdb"),
+            url: None,
+            interval: None,
+        };
+
+        // it's going to be downloaded
+        assert!(GeoIp::open(&config).is_err());
+        config.url = Some("https://example.com/Country.mmdb".to_string());
+        assert!(GeoIp::open(&config).is_ok());
+
+        write(&config.path, &country_database()).unwrap();
+        let geoip = GeoIp::open(&config).unwrap();
+        assert_eq!(
+            geoip.country("8.8.8.8".parse().unwrap()),
+            Some("CN".to_string())
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+//! Routing rules pick where connections go by their destinations and
+//! sources, they are shared by all inbounds, and the DNS server rejects queries of
+//! domains routed to `reject`.
+//!
+//! ```yaml
+//! rules:
+//!   - DOMAIN-SUFFIX,ads.example.com,reject
+//!   - DOMAIN-KEYWORD,google,proxy
+//!   - DOMAIN,intranet.example.com,direct
+//!   - IP-CIDR,192.168.0.0/16,direct,no-resolve
+//!   - GEOIP,CN,direct
+//!   - DST-PORT,25,reject
+//!   - PROCESS-NAME,steam,direct
+//!   - SRC-IP,192.168.1.20,direct
+//!   - SRC-CIDR,192.168.2.0/24,office
+//!   - MATCH,asia
+//! ```
+//!
+//! Rules are matched in order, the first matching one decides, and the
+//! `route` and `upstream_tag` of the inbound apply if none matches.
+//! Outbounds are `direct`, `reject`, `proxy` or a tag of upstream servers.
+//! Domains are resolved once for the first rule of addresses unless it
+//! has `no-resolve`. `GEOIP` rules look up countries in the database of
+//! `geoip`, `IP-ASN` rules look up autonomous systems in the database of
+//! `asn`, and `PROCESS-NAME` and `PROCESS-PATH` rules look up the local
+//! process of the connection once. Connections to addresses may have
+//! domains sniffed, e.g. by `redirect` inbounds, domain rules match them
+//! and the others match the addresses. A `route` function of `script` is
+//! asked before all rules, see `script`. Rules ending with a time window,
+//! e.g. `time=mon-fri/09:00-17:00`, are skipped outside of it, see `time`.
+//! Rules ending with `mark=N` set `SO_MARK` of the outbound socket, see
+//! `Rule::mark`.
+
+mod geoip;
+mod process;
+mod rule;
+mod script;
+mod time;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use resolver::Resolver;
+
+use crate::DateTime;
+
+pub use geoip::{GeoIp, GeoIpConfig};
+pub use rule::{Destination, Matcher, Network, Outbound, Rule, Source};
+#[cfg(feature = "script")]
+pub use script::Script;
+pub use script::ScriptConfig;
+
+/// Where a connection goes, by a rule or the script
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decision {
+    pub outbound: Outbound,
+    /// `mark` of the rule, see `Rule::mark`
+    pub mark: Option<u32>,
+}
+
+/// Rules of the running config, it follows hot reloads
+#[derive(Clone, Default)]
+pub struct Router {
+    rules: Arc<RwLock<Arc<Vec<Rule>>>>,
+    geoip: GeoIp,
+    asn: GeoIp,
+    #[cfg(feature = "script")]
+    script: Arc<RwLock<Option<Arc<Script>>>>,
+}
+
+impl Router {
+    pub fn new(rules: Vec<Rule>, geoip: GeoIp, asn: GeoIp) -> Self {
+        Self {
+            rules: Arc::new(RwLock::new(Arc::new(rules))),
+            geoip,
+            asn,
+            #[cfg(feature = "script")]
+            script: Arc::default(),
+        }
+    }
+
+    pub fn update(&self, rules: Vec<Rule>) {
+        *self.rules.write() = Arc::new(rules);
+    }
+
+    /// Load the script of `config`, or remove it if it's None. A script
+    /// failing to load is removed too, so connections are left to rules.
+    #[cfg(feature = "script")]
+    pub fn update_script(&self, config: Option<&ScriptConfig>) {
+        let script = config.and_then(|config| match Script::load(config) {
+            Ok(script) => Some(Arc::new(script)),
+            Err(err) => {
+                warn!(message = "load routing script failed", %err);
+                None
+            }
+        });
+        *self.script.write() = script;
+    }
+
+    /// `check` reports scripts of builds without the feature
+    #[cfg(not(feature = "script"))]
+    pub fn update_script(&self, _config: Option<&ScriptConfig>) {}
+
+    /// Decision of the first rule a connection from `src` to `host:port`
+    /// matches, `None` if no rule matches
+    pub async fn route(
+        &self,
+        network: Network,
+        src: SocketAddr,
+        host: &str,
+        port: u16,
+        resolver: &Resolver,
+    ) -> Option<Decision> {
+        let ip = host.parse::<IpAddr>().ok();
+        let domain = match ip {
+            Some(_) => None,
+            None => Some(normalize(host)),
+        };
+
+        self.route_to(network, src, domain, ip, port, resolver)
+            .await
+    }
+
+    /// Decision of a connection to `dst` whose domain is sniffed, domain
+    /// rules match `domain` and address rules match `dst` without
+    /// resolving
+    pub async fn route_sniffed(
+        &self,
+        network: Network,
+        src: SocketAddr,
+        domain: &str,
+        dst: SocketAddr,
+        resolver: &Resolver,
+    ) -> Option<Decision> {
+        let domain = Some(normalize(domain));
+        self.route_to(network, src, domain, Some(dst.ip()), dst.port(), resolver)
+            .await
+    }
+
+    /// `ip` is resolved from `domain` for address rules if it's unknown
+    async fn route_to(
+        &self,
+        network: Network,
+        src: SocketAddr,
+        domain: Option<String>,
+        mut ip: Option<IpAddr>,
+        port: u16,
+        resolver: &Resolver,
+    ) -> Option<Decision> {
+        let rules = self.rules.read().clone();
+        let known = ip.is_some();
+        let mut resolved = known;
+        let mut country = None;
+        let mut asn = None;
+        let mut process = None;
+        let mut now = None;
+        let src_ip = match src.ip() {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => IpAddr::V6(ip),
+            },
+            ip => ip,
+        };
+
+        #[cfg(feature = "script")]
+        let script = self.script.read().clone();
+        #[cfg(feature = "script")]
+        if let Some(script) = script {
+            if script.needs_process() {
+                process = Some(lookup_process(network, src).await);
+            }
+            let found = process.as_ref().and_then(Option::as_ref);
+            if let Some(outbound) = script.route(domain.as_deref(), ip, port, src_ip, found) {
+                debug!(message = "route by script", %outbound);
+                return Some(Decision {
+                    outbound,
+                    mark: None,
+                });
+            }
+        }
+
+        for rule in rules.iter() {
+            if rule.time.is_some() && !rule.active(now.get_or_insert_with(DateTime::now_local)) {
+                continue;
+            }
+
+            if rule.matcher.needs_ip() && !rule.no_resolve && !resolved {
+                resolved = true;
+                if let Some(host) = &domain {
+                    match resolver.resolve(host, port).await {
+                        Ok(addr) => ip = Some(addr.ip()),
+                        Err(err) => debug!(message = "resolve for rules failed", ?err, host),
+                    }
+                }
+            }
+
+            let ip = if rule.no_resolve && !known { None } else { ip };
+            if let (Matcher::GeoIp(_), Some(ip), None) = (&rule.matcher, ip, &country) {
+                country = Some(self.geoip.country(ip));
+            }
+            if let (Matcher::IpAsn(_), Some(ip), None) = (&rule.matcher, ip, &asn) {
+                asn = Some(self.asn.asn(ip));
+            }
+
+            if rule.matcher.needs_process() && process.is_none() {
+                process = Some(lookup_process(network, src).await);
+            }
+
+            let src = Source {
+                ip: Some(src_ip),
+                process: process.as_ref().and_then(Option::as_ref),
+            };
+            let dst = Destination {
+                domain: domain.as_deref(),
+                ip,
+                country: country.as_ref().and_then(Option::as_deref),
+                asn: asn.flatten(),
+                port,
+                network,
+            };
+            if rule.matcher.matches(&src, &dst) {
+                debug!(message = "match rule", rule = %rule);
+                return Some(Decision {
+                    outbound: rule.outbound.clone(),
+                    mark: rule.mark,
+                });
+            }
+        }
+
+        None
+    }
+
+    /// Outbound of the first domain rule `domain` matches, other rules
+    /// depend on connections, so they are skipped
+    pub fn route_domain(&self, domain: &str) -> Option<Outbound> {
+        let domain = normalize(domain);
+        let dst = Destination {
+            domain: Some(&domain),
+            ip: None,
+            country: None,
+            asn: None,
+            port: 0,
+            network: Network::Tcp,
+        };
+        let now = DateTime::now_local();
+
+        self.rules
+            .read()
+            .iter()
+            .find(|rule| {
+                rule.matcher.is_domain()
+                    && rule.active(&now)
+                    && rule.matcher.matches(&Source::default(), &dst)
+            })
+            .map(|rule| rule.outbound.clone())
+    }
+}
+
+/// The local process of a connection, off the runtime since `/proc` is
+/// scanned
+async fn lookup_process(network: Network, src: SocketAddr) -> Option<process::Process> {
+    let found = tokio::task::spawn_blocking(move || process::lookup(network, src))
+        .await
+        .unwrap_or_default();
+    debug!(message = "lookup process", ?found, %src);
+
+    found
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<Rule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    fn src() -> SocketAddr {
+        "192.168.1.10:50000".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn route() {
+        let router = Router::new(
+            rules(&[
+                "DOMAIN-SUFFIX,ads.example.com,reject",
+                "IP-CIDR,10.0.0.0/8,direct,no-resolve,mark=255",
+                "DST-PORT,25,reject",
+                "DST-PORT,443/udp,reject",
+                "DOMAIN-KEYWORD,example,asia",
+                "SRC-IP,192.168.1.20,direct",
+                "SRC-CIDR,192.168.1.0/24,tv",
+                "SRC-CIDR,192.168.3.0/24,reject,time=00:00-24:00",
+            ]),
+            GeoIp::default(),
+            GeoIp::default(),
+        );
+        let resolver = Resolver::new(vec!["127.0.0.1:53".parse().unwrap()]).unwrap();
+
+        let route = |host: &'static str, port| {
+            let router = router.clone();
+            let resolver = resolver.clone();
+            async move {
+                router
+                    .route(Network::Tcp, src(), host, port, &resolver)
+                    .await
+                    .map(|decision| decision.outbound)
+            }
+        };
+        assert_eq!(
+            route("x.ads.example.com", 443).await,
+            Some(Outbound::Reject)
+        );
+        assert_eq!(route("10.1.2.3", 443).await, Some(Outbound::Direct));
+        assert_eq!(route("10.1.2.3", 25).await, Some(Outbound::Direct));
+        // the mark of the rule goes with its outbound
+        assert_eq!(
+            router
+                .route(Network::Tcp, src(), "10.1.2.3", 443, &resolver)
+                .await,
+            Some(Decision {
+                outbound: Outbound::Direct,
+                mark: Some(255),
+            })
+        );
+        assert_eq!(route("192.0.2.1", 25).await, Some(Outbound::Reject));
+        assert_eq!(
+            route("www.example.com", 443).await,
+            Some(Outbound::Tag("asia".to_string()))
+        );
+        // other clients and destinations
+        assert_eq!(
+            route("192.0.2.1", 443).await,
+            Some(Outbound::Tag("tv".to_string()))
+        );
+        let mapped = "[::ffff:192.168.1.20]:50000".parse().unwrap();
+        assert_eq!(
+            router
+                .route(Network::Tcp, mapped, "192.0.2.1", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Direct)
+        );
+        let other = "192.168.2.10:50000".parse().unwrap();
+        assert_eq!(
+            router
+                .route(Network::Tcp, other, "192.0.2.1", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            None
+        );
+        assert_eq!(
+            router
+                .route(Network::Udp, other, "192.0.2.1", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Reject)
+        );
+        // always inside of the window
+        let late = "192.168.3.10:50000".parse().unwrap();
+        assert_eq!(
+            router
+                .route(Network::Tcp, late, "192.0.2.1", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Reject)
+        );
+        // sniffed domains of addresses match both kinds of rules
+        let dst = "10.1.2.3:443".parse().unwrap();
+        assert_eq!(
+            router
+                .route_sniffed(Network::Tcp, other, "X.Ads.Example.com", dst, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Reject)
+        );
+        assert_eq!(
+            router
+                .route_sniffed(Network::Tcp, other, "www.example.org", dst, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Direct)
+        );
+
+        assert_eq!(
+            router.route_domain("ads.example.com."),
+            Some(Outbound::Reject)
+        );
+        assert_eq!(router.route_domain("other.org"), None);
+
+        router.update(vec![]);
+        assert_eq!(route("x.ads.example.com", 443).await, None);
+    }
+
+    fn open(name: &str, content: Vec<u8>) -> GeoIp {
+        let dir = std::env::temp_dir().join(format!("roxy-route-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        let geoip = GeoIp::open(&GeoIpConfig {
+            path: path.clone(),
+            url: None,
+            interval: None,
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        geoip
+    }
+
+    #[tokio::test]
+    async fn geoip() {
+        let rules = rules(&[
+            "GEOIP,CN,direct,no-resolve",
+            "IP-ASN,13335,asia",
+            "GEOIP,US,reject",
+        ]);
+        let router = Router::new(rules.clone(), GeoIp::default(), GeoIp::default());
+        let resolver = Resolver::new(vec!["127.0.0.1:53".parse().unwrap()]).unwrap();
+
+        // no database is loaded yet
+        assert_eq!(
+            router
+                .route(Network::Tcp, src(), "1.2.3.4", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            None
+        );
+
+        let geoip = open("Country.mmdb", geoip::tests::country_database());
+        let asn = open("GeoLite2-ASN.mmdb", geoip::tests::asn_database());
+        let router = Router::new(rules, geoip, asn);
+        assert_eq!(
+            router
+                .route(Network::Tcp, src(), "1.2.3.4", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Direct)
+        );
+        assert_eq!(
+            router
+                .route(Network::Tcp, src(), "200.1.1.1", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Tag("asia".to_string()))
+        );
+    }
+
+    #[cfg(feature = "script")]
+    #[tokio::test]
+    async fn script() {
+        let router = Router::new(
+            rules(&["DOMAIN-SUFFIX,example.com,reject"]),
+            GeoIp::default(),
+            GeoIp::default(),
+        );
+        let resolver = Resolver::new(vec!["127.0.0.1:53".parse().unwrap()]).unwrap();
+
+        router.update_script(Some(&ScriptConfig {
+            path: None,
+            code: Some(
+                r#"fn route(domain, dst_ip, dst_port, src_ip, process) {
+                    if domain == "www.example.com" { "direct" }
+                }"#
+                .to_string(),
+            ),
+            process: false,
+        }));
+        assert_eq!(
+            router
+                .route(Network::Tcp, src(), "www.example.com", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Direct)
+        );
+        // nothing returned leaves it to rules
+        assert_eq!(
+            router
+                .route(Network::Tcp, src(), "api.example.com", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Reject)
+        );
+
+        router.update_script(None);
+        assert_eq!(
+            router
+                .route(Network::Tcp, src(), "www.example.com", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Reject)
+        );
+    }
+
+    #[tokio::test]
+    async fn process() {
+        let exe = std::env::current_exe().unwrap();
+        let router = Router::new(
+            rules(&[&format!("PROCESS-PATH,{},direct", exe.display())]),
+            GeoIp::default(),
+            GeoIp::default(),
+        );
+        let resolver = Resolver::new(vec!["127.0.0.1:53".parse().unwrap()]).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let local = stream.local_addr().unwrap();
+        assert_eq!(
+            router
+                .route(Network::Tcp, local, "example.com", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            Some(Outbound::Direct)
+        );
+        assert_eq!(
+            router
+                .route(Network::Tcp, src(), "example.com", 443, &resolver)
+                .await
+                .map(|decision| decision.outbound),
+            None
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! The local process of a connection, for `PROCESS-NAME` and
+//! `PROCESS-PATH` rules. The socket of the source address is found in
+//! `/proc/net/tcp` and `/proc/net/tcp6`, or the `udp` ones, then the
+//! process having its inode is searched in `/proc/*/fd`.
+//!
+//! Only connections from the host itself, e.g. redirected by the `OUTPUT`
+//! chain, have processes. Sockets of other users can't be seen once
+//! privileges are dropped, unless roxy has `CAP_SYS_PTRACE`. Other
+//! platforms have no `/proc`, `roxy check` rejects process rules there.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Network;
+
+/// Owners of sockets found recently, UDP sessions and retries look up the
+/// same socket again, and scanning `/proc/*/fd` is not cheap. Inodes may be
+/// reused once sockets are closed, so owners are kept only for a while
+static OWNERS: Mutex<Vec<(u64, u32, Instant)>> = Mutex::new(Vec::new());
+
+const OWNER_TTL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Process {
+    /// File name of the executable, e.g. `steam`
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// The process owning the socket bound to `src`, blocking
+pub fn lookup(network: Network, src: SocketAddr) -> Option<Process> {
+    let inode = socket_inode(network, src)?;
+    let pid = cached_owner(inode)?;
+
+    match fs::read_link(format!("/proc/{}/exe", pid)) {
+        Ok(path) => Some(Process {
+            name: path.file_name()?.to_string_lossy().into_owned(),
+            path,
+        }),
+        // kernel threads and exited processes
+        Err(_) => {
+            let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+            Some(Process {
+                name: comm.trim_end().to_string(),
+                path: PathBuf::new(),
+            })
+        }
+    }
+}
+
+/// Inode of the socket bound to `src`, IPv4 sources may be bound by IPv6
+/// sockets too
+fn socket_inode(network: Network, src: SocketAddr) -> Option<u64> {
+    let (v4, v6) = match network {
+        Network::Tcp => ("/proc/net/tcp", "/proc/net/tcp6"),
+        Network::Udp => ("/proc/net/udp", "/proc/net/udp6"),
+    };
+    let mapped = match src {
+        SocketAddr::V4(v4) => Some(SocketAddr::new(
+            IpAddr::V6(v4.ip().to_ipv6_mapped()),
+            v4.port(),
+        )),
+        SocketAddr::V6(_) => None,
+    };
+    let tables: &[(&str, SocketAddr)] = match mapped {
+        Some(mapped) => &[(v4, src), (v6, mapped)],
+        None => &[(v6, src)],
+    };
+
+    tables.iter().find_map(|(table, addr)| {
+        let content = fs::read_to_string(table).ok()?;
+        content.lines().skip(1).find_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match (fields.get(1), fields.get(9)) {
+                (Some(local), Some(inode)) if parse_addr(local) == Some(*addr) => {
+                    inode.parse().ok().filter(|inode| *inode != 0)
+                }
+                _ => None,
+            }
+        })
+    })
+}
+
+/// `0100007F:0050`, addresses are 32 bits words in the host byte order
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    let (ip, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let mut octets = Vec::with_capacity(16);
+    for i in (0..ip.len()).step_by(8) {
+        let word = u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?;
+        octets.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match octets.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])),
+        16 => {
+            let mut buf = [0u8; 16];
+            buf.copy_from_slice(&octets);
+            IpAddr::V6(Ipv6Addr::from(buf))
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+/// [`owner`], cached for [`OWNER_TTL`]
+fn cached_owner(inode: u64) -> Option<u32> {
+    let now = Instant::now();
+    {
+        let mut owners = OWNERS.lock().unwrap();
+        owners.retain(|(_, _, found)| now.duration_since(*found) < OWNER_TTL);
+        if let Some((_, pid, _)) = owners.iter().find(|(cached, _, _)| *cached == inode) {
+            return Some(*pid);
+        }
+    }
+
+    let pid = owner(inode)?;
+    OWNERS.lock().unwrap().push((inode, pid, now));
+    Some(pid)
+}
+
+/// Pid of the process having the socket open
+fn owner(inode: u64) -> Option<u32> {
+    let link = PathBuf::from(format!("socket:[{}]", inode));
+
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+        // processes of other users are not readable
+        let fds = fs::read_dir(entry.path().join("fd")).ok()?;
+        fds.flatten()
+            .any(|fd| fs::read_link(fd.path()).ok().as_ref() == Some(&link))
+            .then_some(pid)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let addr = if cfg!(target_endian = "little") {
+            "0100007F:1F90"
+        } else {
+            "7F000001:1F90"
+        };
+        assert_eq!(parse_addr(addr), Some("127.0.0.1:8080".parse().unwrap()));
+        assert_eq!(parse_addr("0100007F"), None);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn lookup_self() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let process = lookup(Network::Tcp, stream.local_addr().unwrap()).unwrap();
+        assert_eq!(process.path, std::env::current_exe().unwrap());
+        // the cached owner
+        let process = lookup(Network::Tcp, stream.local_addr().unwrap()).unwrap();
+        assert_eq!(process.path, std::env::current_exe().unwrap());
+        assert_eq!(lookup(Network::Tcp, "127.0.0.1:1".parse().unwrap()), None);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let process = lookup(Network::Udp, socket.local_addr().unwrap()).unwrap();
+        assert_eq!(process.path, std::env::current_exe().unwrap());
+    }
+}
@@ -0,0 +1,575 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use super::process::Process;
+use super::time::Window;
+use crate::net::{self, Cidr};
+use crate::DateTime;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ParseError {
+    #[error("rule \"{0}\" must be TYPE,VALUE,OUTBOUND")]
+    Format(String),
+    #[error("unknown rule type \"{0}\"")]
+    UnknownType(String),
+    #[error("invalid port \"{0}\"")]
+    InvalidPort(String),
+    #[error("invalid address \"{0}\"")]
+    InvalidAddress(String),
+    #[error("invalid autonomous system number \"{0}\"")]
+    InvalidAsn(String),
+    #[error("invalid time window \"{0}\", e.g. mon-fri/09:00-17:00")]
+    InvalidTime(String),
+    #[error("invalid mark \"{0}\"")]
+    InvalidMark(String),
+    #[error("unknown option \"{0}\"")]
+    UnknownOption(String),
+    #[error(transparent)]
+    Cidr(#[from] net::ParseError),
+}
+
+/// Where connections matching a rule go
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outbound {
+    /// Connect to the destination directly
+    Direct,
+    /// Close the connection
+    Reject,
+    /// Relay by the upstream servers, with the `upstream_tag` of the inbound
+    Proxy,
+    /// Relay by the upstream servers with the tag, see `upstream.tags`
+    Tag(String),
+}
+
+impl FromStr for Outbound {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let outbound = match s.to_ascii_lowercase().as_str() {
+            "" => return Err(ParseError::Format(s.to_string())),
+            "direct" => Outbound::Direct,
+            "reject" => Outbound::Reject,
+            "proxy" => Outbound::Proxy,
+            _ => Outbound::Tag(s.to_string()),
+        };
+
+        Ok(outbound)
+    }
+}
+
+impl Display for Outbound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outbound::Direct => f.write_str("direct"),
+            Outbound::Reject => f.write_str("reject"),
+            Outbound::Proxy => f.write_str("proxy"),
+            Outbound::Tag(tag) => f.write_str(tag),
+        }
+    }
+}
+
+/// Transport protocol of a connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Network {
+    Tcp,
+    /// Sessions of `tproxy` inbounds
+    Udp,
+}
+
+/// Ports of `DST-PORT` rules, `25`, `8000-9000`, or either of them with
+/// `/tcp` or `/udp`, e.g. `443/udp`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ports {
+    pub start: u16,
+    pub end: u16,
+    /// Both if it's not set
+    pub network: Option<Network>,
+}
+
+impl Ports {
+    fn contains(&self, port: u16, network: Network) -> bool {
+        (self.start..=self.end).contains(&port)
+            && match self.network {
+                Some(expected) => expected == network,
+                None => true,
+            }
+    }
+}
+
+impl FromStr for Ports {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidPort(s.to_string());
+        let (range, network) = match s.split_once('/') {
+            Some((range, network)) => match network.to_ascii_lowercase().as_str() {
+                "tcp" => (range, Some(Network::Tcp)),
+                "udp" => (range, Some(Network::Udp)),
+                _ => return Err(invalid()),
+            },
+            None => (s, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (range, range),
+        };
+        let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+        let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+
+        Ok(Ports {
+            start,
+            end,
+            network,
+        })
+    }
+}
+
+impl Display for Ports {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-{}", self.end)?;
+        }
+        match self.network {
+            Some(Network::Tcp) => f.write_str("/tcp"),
+            Some(Network::Udp) => f.write_str("/udp"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What is known about the destination of a connection, `ip` is set if
+/// it's an address or it's resolved for rules of addresses
+pub struct Destination<'a> {
+    /// Lowercase, without the trailing dot
+    pub domain: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+    /// ISO code of the country of `ip`, it's looked up for `GEOIP` rules
+    pub country: Option<&'a str>,
+    /// Autonomous system of `ip`, it's looked up for `IP-ASN` rules
+    pub asn: Option<u32>,
+    pub port: u16,
+    pub network: Network,
+}
+
+/// What is known about where a connection comes from
+#[derive(Default)]
+pub struct Source<'a> {
+    /// IPv4-mapped addresses are IPv4 ones
+    pub ip: Option<IpAddr>,
+    /// The local process, it's looked up for `PROCESS-*` rules
+    pub process: Option<&'a Process>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Matcher {
+    Domain(String),
+    /// The domain and its subdomains
+    DomainSuffix(String),
+    /// Domains containing it
+    DomainKeyword(String),
+    IpCidr(Cidr),
+    /// Addresses in the country, an uppercase ISO code
+    GeoIp(String),
+    /// Addresses in the autonomous system
+    IpAsn(u32),
+    DstPort(Ports),
+    /// The address of the client
+    SrcIp(IpAddr),
+    /// Clients in the network
+    SrcCidr(Cidr),
+    /// File name of the executable of the local process
+    ProcessName(String),
+    /// Executable of the local process
+    ProcessPath(String),
+    /// Every connection
+    Match,
+}
+
+impl Matcher {
+    pub fn matches(&self, src: &Source<'_>, dst: &Destination<'_>) -> bool {
+        match (self, dst.domain, dst.ip) {
+            (Matcher::Domain(expected), Some(domain), _) => domain == expected,
+            (Matcher::DomainSuffix(suffix), Some(domain), _) => {
+                match domain.strip_suffix(suffix.as_str()) {
+                    Some(rest) => rest.is_empty() || rest.ends_with('.'),
+                    None => false,
+                }
+            }
+            (Matcher::DomainKeyword(keyword), Some(domain), _) => domain.contains(keyword.as_str()),
+            (Matcher::IpCidr(cidr), _, Some(ip)) => cidr.contains(&ip),
+            (Matcher::GeoIp(code), _, Some(_)) => dst.country == Some(code.as_str()),
+            (Matcher::IpAsn(asn), _, Some(_)) => dst.asn == Some(*asn),
+            (Matcher::DstPort(ports), _, _) => ports.contains(dst.port, dst.network),
+            (Matcher::SrcIp(ip), _, _) => src.ip == Some(*ip),
+            (Matcher::SrcCidr(cidr), _, _) => match &src.ip {
+                Some(ip) => cidr.contains(ip),
+                None => false,
+            },
+            (Matcher::ProcessName(name), _, _) => match src.process {
+                Some(process) => process.name == *name,
+                None => false,
+            },
+            (Matcher::ProcessPath(path), _, _) => match src.process {
+                Some(process) => process.path.as_os_str() == path.as_str(),
+                None => false,
+            },
+            (Matcher::Match, _, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether it matches domains only
+    pub fn is_domain(&self) -> bool {
+        matches!(
+            self,
+            Matcher::Domain(_) | Matcher::DomainSuffix(_) | Matcher::DomainKeyword(_)
+        )
+    }
+
+    /// Whether it matches addresses, domains are resolved for it
+    pub fn needs_ip(&self) -> bool {
+        matches!(
+            self,
+            Matcher::IpCidr(_) | Matcher::GeoIp(_) | Matcher::IpAsn(_)
+        )
+    }
+
+    /// Whether it matches local processes, they are looked up for it
+    pub fn needs_process(&self) -> bool {
+        matches!(self, Matcher::ProcessName(_) | Matcher::ProcessPath(_))
+    }
+}
+
+/// A rule in the Clash notation, `TYPE,VALUE,OUTBOUND`, e.g.
+/// `DOMAIN-SUFFIX,google.com,proxy`, or `MATCH,OUTBOUND`. Rules of
+/// addresses may end with `no-resolve`, so domains don't match them
+/// instead of being resolved, and any rule may end with a time window,
+/// e.g. `time=mon-fri/09:00-17:00`, or a `SO_MARK` of the outbound
+/// connection, e.g. `mark=255`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub matcher: Matcher,
+    pub outbound: Outbound,
+    pub no_resolve: bool,
+    /// The rule matches nothing outside of it
+    pub time: Option<Window>,
+    /// `SO_MARK` of the destination socket of direct connections, or of
+    /// the socket to the server, instead of `upstream.mark`
+    pub mark: Option<u32>,
+}
+
+impl Rule {
+    /// The rule applies at `now`, it's outside of its time window otherwise
+    pub fn active(&self, now: &DateTime) -> bool {
+        match &self.time {
+            Some(window) => window.contains(now),
+            None => true,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(',').map(str::trim).collect::<Vec<_>>();
+        let kind = parts[0].to_ascii_uppercase();
+        let (value, outbound, options) = match parts[..] {
+            [_, outbound, ref options @ ..] if kind == "MATCH" => ("", outbound, options),
+            [_, value, outbound, ref options @ ..] if !value.is_empty() => {
+                (value, outbound, options)
+            }
+            _ => return Err(ParseError::Format(s.to_string())),
+        };
+        let matcher = match kind.as_str() {
+            "MATCH" => Matcher::Match,
+            "DOMAIN" => Matcher::Domain(domain(value)),
+            "DOMAIN-SUFFIX" => Matcher::DomainSuffix(domain(value)),
+            "DOMAIN-KEYWORD" => Matcher::DomainKeyword(value.to_ascii_lowercase()),
+            "IP-CIDR" | "IP-CIDR6" => Matcher::IpCidr(value.parse()?),
+            "GEOIP" => Matcher::GeoIp(value.to_ascii_uppercase()),
+            "IP-ASN" => Matcher::IpAsn(asn(value)?),
+            "DST-PORT" => Matcher::DstPort(value.parse()?),
+            "SRC-IP" => Matcher::SrcIp(
+                value
+                    .parse()
+                    .map_err(|_| ParseError::InvalidAddress(value.to_string()))?,
+            ),
+            "SRC-CIDR" | "SRC-IP-CIDR" => Matcher::SrcCidr(value.parse()?),
+            "PROCESS-NAME" => Matcher::ProcessName(value.to_string()),
+            "PROCESS-PATH" => Matcher::ProcessPath(value.to_string()),
+            _ => return Err(ParseError::UnknownType(parts[0].to_string())),
+        };
+
+        let mut no_resolve = false;
+        let mut time = None;
+        let mut mark = None;
+        for option in options {
+            match option.split_once('=') {
+                None if *option == "no-resolve" && matcher.needs_ip() => no_resolve = true,
+                Some(("time", window)) => time = Some(window.parse()?),
+                Some(("mark", value)) => {
+                    let parsed = value
+                        .parse()
+                        .map_err(|_| ParseError::InvalidMark(value.to_string()))?;
+                    mark = Some(parsed);
+                }
+                _ => return Err(ParseError::UnknownOption(option.to_string())),
+            }
+        }
+
+        Ok(Rule {
+            matcher,
+            outbound: outbound.parse()?,
+            no_resolve,
+            time,
+            mark,
+        })
+    }
+}
+
+/// `15169` or `AS15169`
+fn asn(value: &str) -> Result<u32, ParseError> {
+    let number = match value.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("AS") => &value[2..],
+        _ => value,
+    };
+
+    number
+        .parse()
+        .map_err(|_| ParseError::InvalidAsn(value.to_string()))
+}
+
+fn domain(value: &str) -> String {
+    value.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.matcher {
+            Matcher::Domain(domain) => write!(f, "DOMAIN,{}", domain)?,
+            Matcher::DomainSuffix(suffix) => write!(f, "DOMAIN-SUFFIX,{}", suffix)?,
+            Matcher::DomainKeyword(keyword) => write!(f, "DOMAIN-KEYWORD,{}", keyword)?,
+            Matcher::IpCidr(cidr) => write!(f, "IP-CIDR,{}", cidr)?,
+            Matcher::GeoIp(code) => write!(f, "GEOIP,{}", code)?,
+            Matcher::IpAsn(asn) => write!(f, "IP-ASN,{}", asn)?,
+            Matcher::DstPort(ports) => write!(f, "DST-PORT,{}", ports)?,
+            Matcher::SrcIp(ip) => write!(f, "SRC-IP,{}", ip)?,
+            Matcher::SrcCidr(cidr) => write!(f, "SRC-CIDR,{}", cidr)?,
+            Matcher::ProcessName(name) => write!(f, "PROCESS-NAME,{}", name)?,
+            Matcher::ProcessPath(path) => write!(f, "PROCESS-PATH,{}", path)?,
+            Matcher::Match => f.write_str("MATCH")?,
+        }
+        write!(f, ",{}", self.outbound)?;
+        if self.no_resolve {
+            f.write_str(",no-resolve")?;
+        }
+        if let Some(window) = &self.time {
+            write!(f, ",time={}", window)?;
+        }
+        if let Some(mark) = self.mark {
+            write!(f, ",mark={}", mark)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Rule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let rule: Rule = "DOMAIN-SUFFIX,Google.com.,PROXY".parse().unwrap();
+        assert_eq!(
+            rule.matcher,
+            Matcher::DomainSuffix("google.com".to_string())
+        );
+        assert_eq!(rule.outbound, Outbound::Proxy);
+        assert_eq!(rule.to_string(), "DOMAIN-SUFFIX,google.com,proxy");
+
+        let rule: Rule = "ip-cidr, 10.0.0.0/8, direct, no-resolve".parse().unwrap();
+        assert_eq!(rule.matcher, Matcher::IpCidr("10.0.0.0/8".parse().unwrap()));
+        assert!(rule.no_resolve);
+
+        let rule: Rule = "GEOIP,cn,DIRECT,no-resolve".parse().unwrap();
+        assert_eq!(rule.matcher, Matcher::GeoIp("CN".to_string()));
+        assert_eq!(rule.to_string(), "GEOIP,CN,direct,no-resolve");
+
+        let rule: Rule = "IP-ASN,AS15169,google".parse().unwrap();
+        assert_eq!(rule.matcher, Matcher::IpAsn(15169));
+        assert_eq!(rule.to_string(), "IP-ASN,15169,google");
+
+        let rule: Rule = "PROCESS-NAME,Steam,direct".parse().unwrap();
+        assert_eq!(rule.matcher, Matcher::ProcessName("Steam".to_string()));
+        assert_eq!(rule.to_string(), "PROCESS-NAME,Steam,direct");
+
+        let rule: Rule = "SRC-IP-CIDR,192.168.1.0/24,asia".parse().unwrap();
+        assert_eq!(rule.to_string(), "SRC-CIDR,192.168.1.0/24,asia");
+
+        let rule: Rule = "MATCH,asia".parse().unwrap();
+        assert_eq!(rule.outbound, Outbound::Tag("asia".to_string()));
+
+        let rule: Rule = "DOMAIN-SUFFIX,facebook.com,reject,time=Mon-Fri/9:00-17:00"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.time, Some("mon-fri/09:00-17:00".parse().unwrap()));
+        assert_eq!(
+            rule.to_string(),
+            "DOMAIN-SUFFIX,facebook.com,reject,time=mon-fri/09:00-17:00"
+        );
+        let rule: Rule = "MATCH,reject,time=00:00-06:00".parse().unwrap();
+        assert_eq!(rule.to_string(), "MATCH,reject,time=00:00-06:00");
+        assert_eq!(
+            "MATCH,direct,time=noon".parse::<Rule>(),
+            Err(ParseError::InvalidTime("noon".to_string()))
+        );
+
+        let rule: Rule = "GEOIP,CN,direct,no-resolve,mark=255".parse().unwrap();
+        assert_eq!(rule.mark, Some(255));
+        assert_eq!(rule.to_string(), "GEOIP,CN,direct,no-resolve,mark=255");
+        assert_eq!(
+            "MATCH,direct,mark=-1".parse::<Rule>(),
+            Err(ParseError::InvalidMark("-1".to_string()))
+        );
+
+        assert_eq!(
+            "DOMAIN,example.com".parse::<Rule>(),
+            Err(ParseError::Format("DOMAIN,example.com".to_string()))
+        );
+        assert_eq!(
+            "GEOSITE,cn,direct".parse::<Rule>(),
+            Err(ParseError::UnknownType("GEOSITE".to_string()))
+        );
+        assert_eq!(
+            "DST-PORT,smtp,reject".parse::<Rule>(),
+            Err(ParseError::InvalidPort("smtp".to_string()))
+        );
+        assert_eq!(
+            "SRC-IP,tv,direct".parse::<Rule>(),
+            Err(ParseError::InvalidAddress("tv".to_string()))
+        );
+        assert_eq!(
+            "IP-ASN,google,proxy".parse::<Rule>(),
+            Err(ParseError::InvalidAsn("google".to_string()))
+        );
+        assert_eq!(
+            "DOMAIN,example.com,direct,no-resolve".parse::<Rule>(),
+            Err(ParseError::UnknownOption("no-resolve".to_string()))
+        );
+    }
+
+    #[test]
+    fn ports() {
+        let ports: Ports = "8000-9000/UDP".parse().unwrap();
+        assert_eq!(
+            ports,
+            Ports {
+                start: 8000,
+                end: 9000,
+                network: Some(Network::Udp),
+            }
+        );
+        assert_eq!(ports.to_string(), "8000-9000/udp");
+        assert!(ports.contains(8000, Network::Udp));
+        assert!(ports.contains(9000, Network::Udp));
+        assert!(!ports.contains(9001, Network::Udp));
+        assert!(!ports.contains(8080, Network::Tcp));
+
+        let ports: Ports = "25".parse().unwrap();
+        assert_eq!(ports.to_string(), "25");
+        assert!(ports.contains(25, Network::Tcp));
+        assert!(ports.contains(25, Network::Udp));
+
+        for invalid in ["9000-8000", "443/quic", "1-70000", "-"] {
+            assert_eq!(
+                invalid.parse::<Ports>(),
+                Err(ParseError::InvalidPort(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let dst = Destination {
+            domain: Some("www.google.com"),
+            ip: None,
+            country: None,
+            asn: None,
+            port: 443,
+            network: Network::Tcp,
+        };
+        let matched = |rule: &str| {
+            let rule = rule.parse::<Rule>().unwrap();
+            rule.matcher.matches(&Source::default(), &dst)
+        };
+
+        assert!(matched("DOMAIN-SUFFIX,google.com,proxy"));
+        assert!(matched("DOMAIN-SUFFIX,www.google.com,proxy"));
+        assert!(!matched("DOMAIN-SUFFIX,gle.com,proxy"));
+        assert!(matched("DOMAIN,www.google.com,proxy"));
+        assert!(!matched("DOMAIN,google.com,proxy"));
+        assert!(matched("DOMAIN-KEYWORD,goog,proxy"));
+        assert!(matched("DST-PORT,443,proxy"));
+        assert!(matched("DST-PORT,400-500,proxy"));
+        assert!(matched("DST-PORT,443/tcp,proxy"));
+        assert!(!matched("DST-PORT,443/udp,proxy"));
+        assert!(!matched("DST-PORT,80-442,proxy"));
+        assert!(!matched("IP-CIDR,0.0.0.0/0,proxy"));
+        assert!(!matched("GEOIP,US,proxy"));
+        assert!(matched("MATCH,proxy"));
+
+        let dst = Destination {
+            domain: None,
+            ip: Some("1.2.3.4".parse().unwrap()),
+            country: Some("CN"),
+            asn: Some(4134),
+            port: 443,
+            network: Network::Tcp,
+        };
+        let matched = |rule: &str| {
+            let rule = rule.parse::<Rule>().unwrap();
+            rule.matcher.matches(&Source::default(), &dst)
+        };
+        assert!(matched("GEOIP,cn,direct"));
+        assert!(!matched("GEOIP,US,direct"));
+        assert!(matched("IP-ASN,4134,direct"));
+        assert!(!matched("IP-ASN,15169,direct"));
+
+        let process = Process {
+            name: "steam".to_string(),
+            path: "/usr/bin/steam".into(),
+        };
+        let src = Source {
+            ip: Some("192.168.1.20".parse().unwrap()),
+            process: Some(&process),
+        };
+        let matched = |rule: &str| {
+            let rule = rule.parse::<Rule>().unwrap();
+            rule.matcher.matches(&src, &dst)
+        };
+        assert!(matched("PROCESS-NAME,steam,direct"));
+        assert!(!matched("PROCESS-NAME,Steam,direct"));
+        assert!(matched("PROCESS-PATH,/usr/bin/steam,direct"));
+        assert!(!matched("PROCESS-PATH,steam,direct"));
+        assert!(matched("SRC-IP,192.168.1.20,direct"));
+        assert!(!matched("SRC-IP,192.168.1.21,direct"));
+        assert!(matched("SRC-CIDR,192.168.1.0/24,direct"));
+        assert!(!matched("SRC-IP-CIDR,10.0.0.0/8,direct"));
+    }
+}
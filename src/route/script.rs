@@ -0,0 +1,226 @@
+//! Routing by a rhai script, for logic rules can't express, e.g. depending
+//! on several properties of connections at once
+//!
+//! ```yaml
+//! script:
+//!   path: /etc/roxy/route.rhai
+//!   process: true
+//! ```
+//!
+//! ```rhai
+//! fn route(domain, dst_ip, dst_port, src_ip, process) {
+//!     if src_ip.starts_with("192.168.3.") && dst_port == 22 {
+//!         return "office";
+//!     }
+//!     if process == "steam" && !domain.ends_with(".steampowered.com") {
+//!         return "reject";
+//!     }
+//! }
+//! ```
+//!
+//! `route` is called before rules, a returned outbound decides, returning
+//! nothing leaves the connection to them. Unknown values are empty strings,
+//! domains are not resolved for `dst_ip`, and `process` is looked up only
+//! with `process: true`. Scripts can't touch files or the network, and they
+//! are stopped after `MAX_OPERATIONS`.
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptConfig {
+    /// The script file, it's read again when `script` or `rules` changes
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+
+    /// The script itself, instead of `path`
+    #[serde(default)]
+    pub code: Option<String>,
+
+    /// Look up local processes of connections for the script, which scans
+    /// `/proc` for each connection
+    #[serde(default)]
+    pub process: bool,
+}
+
+#[cfg(feature = "script")]
+pub use engine::Script;
+
+#[cfg(feature = "script")]
+mod engine {
+    use std::io;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+
+    use rhai::{Dynamic, Engine, Scope, AST};
+
+    use super::ScriptConfig;
+    use crate::route::process::Process;
+    use crate::route::Outbound;
+
+    /// Operations of a call, so a loop can't block the runtime
+    const MAX_OPERATIONS: u64 = 100_000;
+
+    const ROUTE: &str = "route";
+    const ROUTE_PARAMS: usize = 5;
+
+    #[derive(Debug, thiserror::Error)]
+    pub enum Error {
+        #[error("read {path:?} failed, {err}")]
+        Read { path: PathBuf, err: io::Error },
+        #[error("path or code is required")]
+        Missing,
+        #[error("compile failed, {0}")]
+        Compile(String),
+        #[error("fn route(domain, dst_ip, dst_port, src_ip, process) is not defined")]
+        NoRoute,
+    }
+
+    pub struct Script {
+        engine: Engine,
+        ast: AST,
+        process: bool,
+    }
+
+    impl Script {
+        pub fn load(config: &ScriptConfig) -> Result<Self, Error> {
+            let code = match (&config.code, &config.path) {
+                (Some(code), _) => code.clone(),
+                (None, Some(path)) => std::fs::read_to_string(path).map_err(|err| Error::Read {
+                    path: path.clone(),
+                    err,
+                })?,
+                (None, None) => return Err(Error::Missing),
+            };
+
+            let mut engine = Engine::new();
+            engine.set_max_operations(MAX_OPERATIONS);
+            engine.on_print(|text| debug!(message = "script print", text));
+            engine.on_debug(|text, _, pos| debug!(message = "script debug", text, %pos));
+
+            let ast = engine
+                .compile(&code)
+                .map_err(|err| Error::Compile(err.to_string()))?;
+            let defined = ast
+                .iter_functions()
+                .any(|f| f.name == ROUTE && f.params.len() == ROUTE_PARAMS);
+            if !defined {
+                return Err(Error::NoRoute);
+            }
+
+            Ok(Self {
+                engine,
+                ast,
+                process: config.process,
+            })
+        }
+
+        /// The script wants processes of connections
+        pub fn needs_process(&self) -> bool {
+            self.process
+        }
+
+        /// Outbound returned by `route`, None if it returns nothing or fails
+        pub fn route(
+            &self,
+            domain: Option<&str>,
+            dst_ip: Option<IpAddr>,
+            dst_port: u16,
+            src_ip: IpAddr,
+            process: Option<&Process>,
+        ) -> Option<Outbound> {
+            let args = (
+                domain.unwrap_or_default().to_string(),
+                dst_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                dst_port as rhai::INT,
+                src_ip.to_string(),
+                process.map(|p| p.name.clone()).unwrap_or_default(),
+            );
+            let returned =
+                match self
+                    .engine
+                    .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, ROUTE, args)
+                {
+                    Ok(returned) => returned,
+                    Err(err) => {
+                        debug!(message = "script failed", %err);
+                        return None;
+                    }
+                };
+            if returned.is_unit() {
+                return None;
+            }
+
+            match returned.into_string() {
+                Ok(outbound) => outbound.parse().ok(),
+                Err(kind) => {
+                    debug!(message = "script returned no outbound", kind);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn script(code: &str) -> Result<Script, Error> {
+            Script::load(&ScriptConfig {
+                path: None,
+                code: Some(code.to_string()),
+                process: false,
+            })
+        }
+
+        #[test]
+        fn route() {
+            let script = script(
+                r#"
+fn route(domain, dst_ip, dst_port, src_ip, process) {
+    if src_ip == "192.168.1.20" && dst_port == 22 {
+        return "office";
+    }
+    if domain.ends_with(".example.com") || dst_ip == "10.0.0.1" {
+        return "direct";
+    }
+}
+"#,
+            )
+            .unwrap();
+            let src = "192.168.1.20".parse().unwrap();
+
+            assert_eq!(
+                script.route(None, None, 22, src, None),
+                Some(Outbound::Tag("office".to_string()))
+            );
+            assert_eq!(
+                script.route(Some("www.example.com"), None, 443, src, None),
+                Some(Outbound::Direct)
+            );
+            assert_eq!(
+                script.route(None, Some("10.0.0.1".parse().unwrap()), 443, src, None),
+                Some(Outbound::Direct)
+            );
+            assert_eq!(
+                script.route(Some("example.org"), None, 443, src, None),
+                None
+            );
+        }
+
+        #[test]
+        fn invalid() {
+            assert!(matches!(script("fn route("), Err(Error::Compile(_))));
+            assert!(matches!(script("fn route(domain) {}"), Err(Error::NoRoute)));
+
+            // stopped by the limit, and values which aren't strings
+            let src = "192.168.1.20".parse().unwrap();
+            let looping = script("fn route(a, b, c, d, e) { loop {} }").unwrap();
+            assert_eq!(looping.route(None, None, 443, src, None), None);
+            let number = script("fn route(a, b, c, d, e) { 1 }").unwrap();
+            assert_eq!(number.route(None, None, 443, src, None), None);
+        }
+    }
+}
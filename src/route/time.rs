@@ -0,0 +1,176 @@
+//! Time windows of rules, e.g. `time=mon-fri/09:00-17:00`, a rule with one
+//! matches only inside it, in the local time of the system. Days are
+//! optional, and both days and times may wrap, e.g. `fri-mon` or
+//! `22:00-06:00`, which ends on the next morning.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use super::rule::ParseError;
+use crate::DateTime;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Window {
+    /// First and last day, 0 is Monday, every day if it's None
+    days: Option<(u8, u8)>,
+    /// Minutes of the day, the end is excluded
+    start: u16,
+    end: u16,
+}
+
+impl Window {
+    pub fn contains(&self, now: &DateTime) -> bool {
+        let minute = now.hour() as u16 * 60 + now.minute() as u16;
+        let today = now.weekday();
+
+        if self.start < self.end {
+            self.on(today) && self.start <= minute && minute < self.end
+        } else {
+            // the part after midnight belongs to the day before
+            (self.on(today) && minute >= self.start)
+                || (self.on((today + 6) % 7) && minute < self.end)
+        }
+    }
+
+    fn on(&self, day: u8) -> bool {
+        match self.days {
+            Some((first, last)) if first <= last => first <= day && day <= last,
+            Some((first, last)) => day >= first || day <= last,
+            None => true,
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidTime(s.to_string());
+
+        let (days, times) = match s.split_once('/') {
+            Some((days, times)) => {
+                let days = match days.split_once('-') {
+                    Some((first, last)) => (day(first), day(last)),
+                    None => (day(days), day(days)),
+                };
+                match days {
+                    (Some(first), Some(last)) => (Some((first, last)), times),
+                    _ => return Err(invalid()),
+                }
+            }
+            None => (None, s),
+        };
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let (start, end) = match (minutes(start), minutes(end)) {
+            (Some(start), Some(end)) if start != end && start < MINUTES_PER_DAY => (start, end),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Window { days, start, end })
+    }
+}
+
+fn day(s: &str) -> Option<u8> {
+    DAYS.iter()
+        .position(|day| day.eq_ignore_ascii_case(s))
+        .map(|day| day as u8)
+}
+
+/// `09:30`, `24:00` ends at midnight
+fn minutes(s: &str) -> Option<u16> {
+    let (hour, minute) = s.split_once(':')?;
+    let (hour, minute) = (hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?);
+    if minute >= 60 || hour > 24 || hour * 60 + minute > MINUTES_PER_DAY {
+        return None;
+    }
+
+    Some(hour * 60 + minute)
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.days {
+            Some((first, last)) if first == last => write!(f, "{}/", DAYS[first as usize])?,
+            Some((first, last)) => write!(f, "{}-{}/", DAYS[first as usize], DAYS[last as usize])?,
+            None => {}
+        }
+
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    /// 2026-10-19 is a Monday
+    fn at(day: u64, hour: u64, minute: u64) -> DateTime {
+        let secs = 1_792_368_000 + day * 86_400 + hour * 3600 + minute * 60;
+        DateTime::from(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn parse() {
+        let window: Window = "Mon-Fri/9:00-17:30".parse().unwrap();
+        assert_eq!(window.to_string(), "mon-fri/09:00-17:30");
+        assert_eq!(
+            "sat/22:00-06:00".parse::<Window>().unwrap().to_string(),
+            "sat/22:00-06:00"
+        );
+        assert_eq!(
+            "00:00-24:00".parse::<Window>().unwrap().to_string(),
+            "00:00-24:00"
+        );
+
+        for invalid in [
+            "",
+            "9-17",
+            "mon-fri",
+            "xyz/09:00-17:00",
+            "09:00-09:00",
+            "09:60-10:00",
+            "9999:00-10:00",
+        ] {
+            assert_eq!(
+                invalid.parse::<Window>(),
+                Err(ParseError::InvalidTime(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let work: Window = "mon-fri/09:00-17:00".parse().unwrap();
+        assert!(work.contains(&at(0, 9, 0)));
+        assert!(work.contains(&at(4, 16, 59)));
+        assert!(!work.contains(&at(0, 17, 0)));
+        assert!(!work.contains(&at(0, 8, 59)));
+        assert!(!work.contains(&at(5, 12, 0)));
+
+        // friday night to saturday morning, and the weekend wraps
+        let night: Window = "fri/22:00-06:00".parse().unwrap();
+        assert!(night.contains(&at(4, 23, 0)));
+        assert!(night.contains(&at(5, 5, 59)));
+        assert!(!night.contains(&at(4, 5, 0)));
+        assert!(!night.contains(&at(5, 22, 0)));
+        let weekend: Window = "sat-sun/00:00-24:00".parse().unwrap();
+        assert!(weekend.contains(&at(6, 12, 0)));
+        assert!(!weekend.contains(&at(7, 0, 0)));
+        let wrapped: Window = "sun-mon/10:00-11:00".parse().unwrap();
+        assert!(wrapped.contains(&at(0, 10, 30)));
+        assert!(wrapped.contains(&at(6, 10, 30)));
+        assert!(!wrapped.contains(&at(1, 10, 30)));
+    }
+}
@@ -0,0 +1,675 @@
+//! Restrict what a compromised roxy could do, e.g.
+//!
+//! ```yaml
+//! sandbox:
+//!   seccomp: true
+//!   landlock: true
+//!   writable:
+//!     - /var/lib/roxy/plugins
+//! ```
+//!
+//! seccomp allows only the system calls roxy uses, others fail with
+//! `EPERM`. Landlock makes the filesystem read only, except directories
+//! of files roxy writes, e.g. log files, caches and `upgrade.socket`, and
+//! `writable`. Both are applied before any service starts, and inherited
+//! by the new process of an upgrade. They are skipped with a warning if
+//! the kernel or architecture doesn't support them.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::Config;
+
+const fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// Allow only the system calls roxy uses
+    #[serde(default = "default_enabled")]
+    pub seccomp: bool,
+
+    /// Make the filesystem read only, except the directories roxy writes
+    #[serde(default = "default_enabled")]
+    pub landlock: bool,
+
+    /// More writable directories, besides the ones roxy writes itself
+    #[serde(default)]
+    pub writable: Vec<PathBuf>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            seccomp: true,
+            landlock: true,
+            writable: vec![],
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0} failed, {1}")]
+    Syscall(&'static str, io::Error),
+}
+
+/// Apply the sandbox, it must be called while the process has only one
+/// thread, threads spawned later inherit it.
+pub fn apply(config: &Config) -> Result<(), Error> {
+    let sandbox = &config.sandbox;
+    if !sandbox.seccomp && !sandbox.landlock {
+        return Ok(());
+    }
+
+    // required by both to be applied without privileges
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(Error::Syscall("prctl", io::Error::last_os_error()));
+    }
+
+    if sandbox.landlock {
+        let mut writable = writable(config);
+        writable.extend(sandbox.writable.iter().cloned());
+
+        match landlock::restrict(&writable)? {
+            Some(abi) => info!(message = "landlock applied", abi, ?writable),
+            None => warn!(message = "landlock is not supported by the kernel, skip it"),
+        }
+    }
+
+    if sandbox.seccomp {
+        match seccomp::filter() {
+            Some(filter) => {
+                seccomp::install(&filter)?;
+                info!(message = "seccomp applied", rules = filter.len());
+            }
+            None => warn!(message = "seccomp is not supported on this architecture, skip it"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Directories of files written at runtime
+fn writable(config: &Config) -> Vec<PathBuf> {
+    let mut files: Vec<&Path> = vec![];
+    if let Some(file) = &config.log.file {
+        files.push(&file.path);
+    }
+    if let Some(file) = config.log.access.as_ref().and_then(|a| a.file.as_ref()) {
+        files.push(&file.path);
+    }
+    if let Some(path) = config
+        .controller
+        .as_ref()
+        .and_then(|c| c.audit_log.as_ref())
+    {
+        files.push(path);
+    }
+    if let Some(path) = config
+        .upstream
+        .provider
+        .as_ref()
+        .and_then(|p| p.cache.as_ref())
+    {
+        files.push(path);
+    }
+    if let Some(path) = &config.upstream.history {
+        files.push(path);
+    }
+    if let Some(remote) = &config.remote {
+        files.push(&remote.cache);
+    }
+    for database in [&config.geoip, &config.asn].into_iter().flatten() {
+        if database.url.is_some() {
+            files.push(&database.path);
+        }
+    }
+    if let Some(upgrade) = &config.upgrade {
+        files.push(&upgrade.socket);
+    }
+
+    let mut dirs = files
+        .into_iter()
+        .map(|file| match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        })
+        .collect::<Vec<_>>();
+    if let Some(dir) = config
+        .controller
+        .as_ref()
+        .and_then(|c| c.capture_dir.as_ref())
+    {
+        dirs.push(dir.clone());
+    }
+    dirs.sort();
+    dirs.dedup();
+
+    dirs
+}
+
+mod landlock {
+    use super::*;
+
+    /// `linux/landlock.h`
+    const CREATE_RULESET_VERSION: u32 = 1;
+    const RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    /// Added in ABI 3
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    const READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    const WRITE: u64 = ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_REG
+        | ACCESS_FS_MAKE_SOCK
+        | ACCESS_FS_TRUNCATE;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Rights handled by the ABI, newer ones are not restricted
+    fn handled(abi: i64) -> u64 {
+        match abi {
+            1 => (1 << 13) - 1,
+            2 => (1 << 14) - 1,
+            _ => (1 << 15) - 1,
+        }
+    }
+
+    /// Everything is readable, `writable` directories and their
+    /// subdirectories are writable too. The ABI version is returned, or
+    /// `None` if Landlock is not supported.
+    pub fn restrict(writable: &[PathBuf]) -> Result<Option<i64>, Error> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Ok(None),
+                _ => Err(Error::Syscall("landlock_create_ruleset", err)),
+            };
+        }
+
+        let handled = handled(abi);
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::Syscall(
+                "landlock_create_ruleset",
+                io::Error::last_os_error(),
+            ));
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        add_rule(&ruleset, Path::new("/"), READ & handled)?;
+        add_rule(&ruleset, Path::new("/dev/null"), ACCESS_FS_WRITE_FILE)?;
+        for dir in writable {
+            if let Err(err) = add_rule(&ruleset, dir, (READ | WRITE) & handled) {
+                warn!(message = "directory is not writable in landlock", ?dir, %err);
+            }
+        }
+
+        let ret =
+            unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) };
+        if ret != 0 {
+            return Err(Error::Syscall(
+                "landlock_restrict_self",
+                io::Error::last_os_error(),
+            ));
+        }
+
+        Ok(Some(abi))
+    }
+
+    fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<(), Error> {
+        let file = File::open(path).map_err(|err| Error::Syscall("open", err))?;
+
+        // rights of directories can't be granted to files
+        let access = if file.metadata().map(|m| m.is_dir()).unwrap_or(false) {
+            access
+        } else {
+            access & (ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE)
+        };
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Syscall(
+                "landlock_add_rule",
+                io::Error::last_os_error(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+mod seccomp {
+    use super::*;
+
+    /// `linux/seccomp.h` and `linux/audit.h`
+    const SET_MODE_FILTER: libc::c_ulong = 1;
+    const RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const RET_ERRNO: u32 = 0x0005_0000;
+    const RET_ALLOW: u32 = 0x7fff_0000;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Offsets in `struct seccomp_data`
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    /// `BPF_LD | BPF_W | BPF_ABS`
+    const BPF_LD_W_ABS: u16 = 0x20;
+    /// `BPF_JMP | BPF_JEQ | BPF_K`
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    /// `BPF_RET | BPF_K`
+    const BPF_RET_K: u16 = 0x06;
+
+    /// System calls of roxy, tokio, the standard library and glibc,
+    /// including spawning the new process of an upgrade
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const ALLOWED: &[libc::c_long] = &[
+        // files
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_close,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_statfs,
+        libc::SYS_fstatfs,
+        libc::SYS_openat,
+        libc::SYS_readlinkat,
+        libc::SYS_getdents64,
+        libc::SYS_getcwd,
+        libc::SYS_mkdirat,
+        libc::SYS_unlinkat,
+        libc::SYS_renameat2,
+        libc::SYS_faccessat,
+        libc::SYS_faccessat2,
+        libc::SYS_ftruncate,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_fallocate,
+        libc::SYS_flock,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_inotify_init1,
+        libc::SYS_inotify_add_watch,
+        libc::SYS_inotify_rm_watch,
+        libc::SYS_memfd_create,
+        // memory
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mprotect,
+        libc::SYS_mremap,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_membarrier,
+        // signals
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_tgkill,
+        libc::SYS_kill,
+        // processes and threads
+        libc::SYS_clone,
+        libc::SYS_clone3,
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+        libc::SYS_set_tid_address,
+        libc::SYS_set_robust_list,
+        libc::SYS_get_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_futex,
+        libc::SYS_gettid,
+        libc::SYS_getpid,
+        libc::SYS_getppid,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_capget,
+        libc::SYS_prctl,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_sched_yield,
+        libc::SYS_getrlimit,
+        libc::SYS_prlimit64,
+        libc::SYS_getrusage,
+        libc::SYS_times,
+        libc::SYS_uname,
+        libc::SYS_sysinfo,
+        libc::SYS_getrandom,
+        // the new process of an upgrade applies the sandbox again
+        libc::SYS_seccomp,
+        libc::SYS_landlock_create_ruleset,
+        libc::SYS_landlock_add_rule,
+        libc::SYS_landlock_restrict_self,
+        // time
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_gettimeofday,
+        // network
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_connect,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_getsockopt,
+        libc::SYS_setsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_shutdown,
+        // polling
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_eventfd2,
+        libc::SYS_pipe2,
+        // direct connections are relayed by splice
+        libc::SYS_splice,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+    ];
+
+    /// Legacy system calls, which have `*at` replacements on newer
+    /// architectures
+    #[cfg(target_arch = "x86_64")]
+    const LEGACY: &[libc::c_long] = &[
+        libc::SYS_open,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_access,
+        libc::SYS_readlink,
+        libc::SYS_rename,
+        libc::SYS_unlink,
+        libc::SYS_mkdir,
+        libc::SYS_getdents,
+        libc::SYS_pipe,
+        libc::SYS_dup2,
+        libc::SYS_poll,
+        libc::SYS_select,
+        libc::SYS_epoll_wait,
+        libc::SYS_epoll_create,
+        libc::SYS_eventfd,
+        libc::SYS_inotify_init,
+        libc::SYS_fork,
+        libc::SYS_vfork,
+        libc::SYS_arch_prctl,
+        libc::SYS_time,
+    ];
+
+    #[cfg(target_arch = "aarch64")]
+    const LEGACY: &[libc::c_long] = &[];
+
+    fn statement(code: u16, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: BPF_JMP_JEQ_K,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// The BPF program, or `None` if the architecture is not supported
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn filter() -> Option<Vec<libc::sock_filter>> {
+        let mut filter = vec![
+            // system calls of other ABIs, e.g. 32 bits ones, are not
+            // expected at all
+            statement(BPF_LD_W_ABS, ARCH_OFFSET),
+            jump(AUDIT_ARCH, 1, 0),
+            statement(BPF_RET_K, RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, NR_OFFSET),
+        ];
+        for nr in ALLOWED.iter().chain(LEGACY) {
+            filter.push(jump(*nr as u32, 0, 1));
+            filter.push(statement(BPF_RET_K, RET_ALLOW));
+        }
+        filter.push(statement(BPF_RET_K, RET_ERRNO | libc::EPERM as u32));
+
+        Some(filter)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn filter() -> Option<Vec<libc::sock_filter>> {
+        None
+    }
+
+    /// A filter refusing only `nr`, stacked on [`filter`] by tests
+    #[cfg(test)]
+    pub fn deny(nr: libc::c_long) -> Vec<libc::sock_filter> {
+        vec![
+            statement(BPF_LD_W_ABS, NR_OFFSET),
+            jump(nr as u32, 0, 1),
+            statement(BPF_RET_K, RET_ERRNO | libc::EPERM as u32),
+            statement(BPF_RET_K, RET_ALLOW),
+        ]
+    }
+
+    pub fn install(filter: &[libc::sock_filter]) -> Result<(), Error> {
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+        };
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SET_MODE_FILTER,
+                0,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Syscall("seccomp", io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writable_dirs() {
+        let mut config = Config::builder()
+            .resolver("1.1.1.1:53".parse().unwrap())
+            .nameserver("8.8.8.8:53".parse().unwrap())
+            .server("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388#local")
+            .build()
+            .unwrap();
+        config.upstream.history = Some(PathBuf::from("/var/lib/roxy/history.json"));
+        config.upgrade = Some(crate::upgrade::UpgradeConfig {
+            socket: PathBuf::from("/var/lib/roxy/upgrade.sock"),
+            drain: std::time::Duration::from_secs(1),
+        });
+
+        assert_eq!(writable(&config), vec![PathBuf::from("/var/lib/roxy")]);
+
+        let mut controller = crate::controller::Config::new("127.0.0.1:9000", None);
+        controller.capture_dir = Some(PathBuf::from("/var/lib/roxy/captures"));
+        config.controller = Some(controller);
+        assert_eq!(
+            writable(&config),
+            vec![
+                PathBuf::from("/var/lib/roxy"),
+                PathBuf::from("/var/lib/roxy/captures")
+            ]
+        );
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn filter() {
+        let filter = seccomp::filter().unwrap();
+
+        // arch check, a pair for each allowed call and the default action
+        assert_eq!(filter.len() % 2, 1);
+        assert!(filter.len() < u16::MAX as usize);
+    }
+
+    /// Relay a direct connection by splice in a thread with `filters`
+    /// installed, seccomp filters apply to the calling thread only
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn relay_filtered(filters: Vec<Vec<libc::sock_filter>>) {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        async fn pair() -> (TcpStream, TcpStream) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+
+            (connected.unwrap(), accepted.unwrap().0)
+        }
+
+        let relayed = std::thread::spawn(move || {
+            assert_eq!(
+                unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) },
+                0
+            );
+            for filter in &filters {
+                seccomp::install(filter).unwrap();
+            }
+
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let (mut client, local) = pair().await;
+                let (remote, mut server) = pair().await;
+
+                let relay =
+                    crate::relay::splice::copy_bidirectional(&local, &remote, |_| {}, |_| {});
+                let peers = async {
+                    client.write_all(b"ping").await.unwrap();
+                    client.shutdown().await.unwrap();
+
+                    let mut received = vec![];
+                    server.read_to_end(&mut received).await.unwrap();
+                    server.write_all(b"pong").await.unwrap();
+                    server.shutdown().await.unwrap();
+
+                    let mut reply = vec![];
+                    client.read_to_end(&mut reply).await.unwrap();
+                    (received, reply)
+                };
+
+                // peers wait forever if the relay fails
+                let relayed = tokio::time::timeout(Duration::from_secs(5), async {
+                    tokio::join!(relay, peers)
+                });
+                let (copied, (received, reply)) = relayed.await.unwrap();
+                assert_eq!(copied.unwrap(), (4, 4));
+                assert_eq!(received, b"ping");
+                assert_eq!(reply, b"pong");
+            })
+        });
+
+        relayed.join().unwrap();
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn relay_sandboxed() {
+        relay_filtered(vec![seccomp::filter().unwrap()]);
+        // splice refused, copied in userspace instead
+        relay_filtered(vec![
+            seccomp::filter().unwrap(),
+            seccomp::deny(libc::SYS_splice),
+        ]);
+    }
+}
@@ -1,5 +1,4 @@
 use serde::{Deserializer, Serializer};
-use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
 use std::time::Duration;
 
@@ -12,10 +11,33 @@ const HOUR: u64 = 60 * MINUTE;
 const DAY: u64 = 24 * HOUR;
 const WEEK: u64 = 7 * DAY;
 
-// for serde
+// for serde, a bare integer is seconds, e.g. `interval: 30`
 pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
-    let s: Cow<str> = serde::__private::de::borrow_cow_str(deserializer)?;
-    parse_duration(&s).map_err(serde::de::Error::custom)
+    deserializer.deserialize_any(Visitor)
+}
+
+struct Visitor;
+
+impl<'de> serde::de::Visitor<'de> for Visitor {
+    type Value = Duration;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a duration like 300ms, 30s, 1h30m, or seconds")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Duration::from_secs(v))
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        u64::try_from(v)
+            .map(Duration::from_secs)
+            .map_err(|_| E::custom(ParseError::InvalidDuration))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        parse_duration(v).map_err(E::custom)
+    }
 }
 
 pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
@@ -295,7 +317,16 @@ pub fn duration(d: &Duration) -> String {
         }
     }
 
-    return String::from_utf8_lossy(&buf[w..]).to_string();
+    let mut s = String::from_utf8_lossy(&buf[w..]).to_string();
+    // "5m0s" is written as "5m", and "1h0m0s" as "1h"
+    if s.ends_with("m0s") {
+        s.truncate(s.len() - 2);
+    }
+    if s.ends_with("h0m") {
+        s.truncate(s.len() - 2);
+    }
+
+    s
 }
 
 // fmt_frac formats the fraction of v / 10 ** prec (e.g., ".12345") into the
@@ -347,18 +378,20 @@ pub mod option {
     use super::*;
     use serde::Deserialize;
 
+    struct Wrapper(Duration);
+
+    impl<'de> Deserialize<'de> for Wrapper {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::deserialize(deserializer).map(Wrapper)
+        }
+    }
+
     pub fn deserialize<'de, D: Deserializer<'de>>(
         deserializer: D,
     ) -> Result<Option<Duration>, D::Error> {
-        let s: Option<String> = Option::deserialize(deserializer)?;
+        let wrapper: Option<Wrapper> = Option::deserialize(deserializer)?;
 
-        match s {
-            Some(text) => {
-                let duration = parse_duration(&text).map_err(serde::de::Error::custom)?;
-                Ok(Some(duration))
-            }
-            None => Ok(None),
-        }
+        Ok(wrapper.map(|Wrapper(d)| d))
     }
 
     pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
@@ -593,6 +626,10 @@ mod tests {
             ("4m5.001s", 4 * MINUTE + 5001 * MILLISECOND),
             ("5h6m7.001s", 5 * HOUR + 6 * MINUTE + 7001 * MILLISECOND),
             ("8m0.000000001s", 8 * MINUTE + NANOSECOND),
+            ("5m", 5 * MINUTE),
+            ("1h", HOUR),
+            ("1h0m5s", HOUR + 5 * SECOND),
+            ("2h30m", 2 * HOUR + 30 * MINUTE),
             // ("2562047h47m16.854775807s", u64::MAX),
             // ("-2562047h47m16.854775808s", u64::MIN),
         ];
@@ -602,4 +639,21 @@ mod tests {
             assert_eq!(duration(&d), want)
         }
     }
+
+    #[test]
+    fn deserialize_seconds() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            #[serde(with = "super")]
+            interval: Duration,
+            #[serde(default, with = "super::option")]
+            timeout: Option<Duration>,
+        }
+
+        let config: Config = serde_yaml::from_str("interval: 30\ntimeout: 1m30s").unwrap();
+        assert_eq!(config.interval, Duration::from_secs(30));
+        assert_eq!(config.timeout, Some(Duration::from_secs(90)));
+
+        assert!(serde_yaml::from_str::<Config>("interval: -1").is_err());
+    }
 }
@@ -1 +1,27 @@
 pub mod duration;
+pub mod networks;
+pub mod size;
+pub mod socket_addrs;
+
+use serde::{Deserialize, Deserializer};
+
+/// A list, or a string separated by commas or whitespace, the latter is
+/// handy when the value comes from an environment variable, e.g.
+/// `resolvers: ${RESOLVERS}`
+pub fn list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match List::deserialize(deserializer)? {
+        List::One(s) => s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(ToString::to_string)
+            .collect(),
+        List::Many(list) => list,
+    })
+}
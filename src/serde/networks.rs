@@ -0,0 +1,78 @@
+//! Network lists, entries are networks, addresses or ranges, e.g.
+//!
+//! ```yaml
+//! - 10.0.0.0/8
+//! - 192.168.1.1
+//! - 172.16.0.10-172.16.0.20
+//! ```
+//!
+//! Ranges are split into networks.
+
+use serde::Deserializer;
+
+use crate::net::{Cidr, Range};
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Cidr>, D::Error> {
+    let mut cidrs = vec![];
+
+    for entry in super::list(deserializer)? {
+        if entry.contains('-') {
+            let range = entry.parse::<Range>().map_err(serde::de::Error::custom)?;
+            cidrs.extend(range.cidrs());
+        } else {
+            cidrs.push(entry.parse().map_err(serde::de::Error::custom)?);
+        }
+    }
+
+    Ok(cidrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Config {
+        #[serde(with = "super")]
+        networks: Vec<Cidr>,
+        #[serde(with = "crate::serde::socket_addrs")]
+        addrs: Vec<SocketAddr>,
+    }
+
+    #[test]
+    fn deserialize_lists() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+networks:
+  - 10.0.0.0/8
+  - fd00::1
+  - 192.168.1.1-192.168.1.2
+addrs: 1.1.1.1:53, 8.8.8.8:53
+"#,
+        )
+        .unwrap();
+
+        let networks = config
+            .networks
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            networks,
+            [
+                "10.0.0.0/8",
+                "fd00::1/128",
+                "192.168.1.1/32",
+                "192.168.1.2/32"
+            ]
+        );
+        assert_eq!(config.addrs.len(), 2);
+
+        assert!(serde_yaml::from_str::<Config>("networks: [10.0.0.0/33]\naddrs: []").is_err());
+        assert!(serde_yaml::from_str::<Config>("networks: []\naddrs: 1.1.1.1").is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Sizes in bytes, e.g. "512B", "10MiB", "1.5GB", and bandwidths in bits
+//! per second, e.g. "100mbit", "1gbit", which are converted to bytes.
+//! A bare integer is bytes, e.g. `10` or "10". Units are case insensitive.
+
+use serde::Deserializer;
+use std::fmt::{Display, Formatter};
+
+const KB: u64 = 1000;
+const MB: u64 = 1000 * KB;
+const GB: u64 = 1000 * MB;
+const TB: u64 = 1000 * GB;
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+const TIB: u64 = 1024 * GIB;
+
+// for serde
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    deserializer.deserialize_any(Visitor)
+}
+
+struct Visitor;
+
+impl<'de> serde::de::Visitor<'de> for Visitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a size like 512B, 10MiB, 1gbit, or bytes")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(v)
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        u64::try_from(v).map_err(|_| E::custom(ParseError::InvalidSize))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        parse_size(v).map_err(E::custom)
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum ParseError {
+    InvalidSize,
+    UnknownUnit,
+    Overflow,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            ParseError::InvalidSize => "invalid size",
+            ParseError::UnknownUnit => "unknown unit",
+            ParseError::Overflow => "size is too large",
+        };
+
+        write!(f, "{}", msg)
+    }
+}
+
+/// Parse a size like "10MiB", "1gbit" or "512" to bytes, fractions are
+/// allowed, e.g. "1.5GB", and rounded down to bytes.
+pub fn parse_size(text: &str) -> Result<u64, ParseError> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    if number.is_empty() {
+        return Err(ParseError::InvalidSize);
+    }
+
+    let unit = unit.trim().to_ascii_lowercase();
+    let (bytes, bits) = match unit.as_str() {
+        "" | "b" => (1, false),
+        "k" | "kb" => (KB, false),
+        "m" | "mb" => (MB, false),
+        "g" | "gb" => (GB, false),
+        "t" | "tb" => (TB, false),
+        "ki" | "kib" => (KIB, false),
+        "mi" | "mib" => (MIB, false),
+        "gi" | "gib" => (GIB, false),
+        "ti" | "tib" => (TIB, false),
+        "bit" => (1, true),
+        "kbit" => (KB, true),
+        "mbit" => (MB, true),
+        "gbit" => (GB, true),
+        _ => return Err(ParseError::UnknownUnit),
+    };
+
+    let size = match number.split_once('.') {
+        None => number
+            .parse::<u64>()
+            .map_err(|_| ParseError::InvalidSize)?
+            .checked_mul(bytes)
+            .ok_or(ParseError::Overflow)?,
+        Some(_) => {
+            let number = number.parse::<f64>().map_err(|_| ParseError::InvalidSize)?;
+            let size = number * bytes as f64;
+            if size >= u64::MAX as f64 {
+                return Err(ParseError::Overflow);
+            }
+            size as u64
+        }
+    };
+
+    Ok(if bits { size / 8 } else { size })
+}
+
+pub mod option {
+    use super::*;
+    use serde::Deserialize;
+
+    struct Wrapper(u64);
+
+    impl<'de> Deserialize<'de> for Wrapper {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::deserialize(deserializer).map(Wrapper)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        let wrapper: Option<Wrapper> = Option::deserialize(deserializer)?;
+
+        Ok(wrapper.map(|Wrapper(size)| size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        let tests = [
+            ("512B", 512),
+            ("10KB", 10 * KB),
+            ("10k", 10 * KB),
+            ("10MiB", 10 * MIB),
+            ("10 mib", 10 * MIB),
+            ("1.5GB", 1500 * MB),
+            ("1TiB", TIB),
+            ("8bit", 1),
+            ("100mbit", 100 * MB / 8),
+            ("1gbit", GB / 8),
+        ];
+
+        for (input, want) in tests {
+            assert_eq!(parse_size(input), Ok(want), "{}", input);
+        }
+
+        assert_eq!(parse_size("10"), Ok(10));
+        assert_eq!(parse_size("1.5"), Ok(1));
+        assert_eq!(parse_size("MiB"), Err(ParseError::InvalidSize));
+        assert_eq!(parse_size("10PiB"), Err(ParseError::UnknownUnit));
+        assert_eq!(parse_size("99999999999TiB"), Err(ParseError::Overflow));
+    }
+
+    #[test]
+    fn deserialize_bytes() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            #[serde(with = "super::option")]
+            size: Option<u64>,
+        }
+
+        for text in ["size: 10", "size: \"10\"", "size: 10B"] {
+            let config: Config = serde_yaml::from_str(text).unwrap();
+            assert_eq!(config.size, Some(10), "{}", text);
+        }
+    }
+}
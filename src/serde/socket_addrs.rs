@@ -0,0 +1,19 @@
+//! Socket address lists, e.g. `[1.1.1.1:53, 8.8.8.8:53]` or
+//! `"1.1.1.1:53, 8.8.8.8:53"`
+
+use std::net::SocketAddr;
+
+use serde::Deserializer;
+
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error> {
+    super::list(deserializer)?
+        .iter()
+        .map(|addr| {
+            addr.parse().map_err(|err| {
+                serde::de::Error::custom(format!("invalid address \"{}\", {}", addr, err))
+            })
+        })
+        .collect()
+}
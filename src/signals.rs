@@ -1,4 +1,4 @@
-use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::{Signal, SignalKind};
 
 pub async fn shutdown() {
     let mut sigterm = tokio::signal::unix::signal(SignalKind::terminate())
@@ -11,3 +11,9 @@ pub async fn shutdown() {
         _ = sigint.recv() => {}
     };
 }
+
+/// `SIGUSR2` asks for a binary upgrade, see `roxy::upgrade`
+pub fn upgrade() -> Signal {
+    tokio::signal::unix::signal(SignalKind::user_defined2())
+        .expect("Failed to register signal handler")
+}
@@ -1,17 +1,80 @@
-use tracing::{Dispatch, Level};
+use std::io;
+use std::path::PathBuf;
 
-use crate::log::Logger;
+use tracing::level_filters::LevelFilter;
+use tracing::Dispatch;
 
-pub fn init(level: Level, timestamp: bool) {
-    let logger = Logger::new(level, timestamp);
+use crate::config::Log;
+use crate::log::{
+    AccessLog, Exporter, Filter, Handle, Journald, Logger, ParseError, RotatingFile, Sink, Syslog,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid filter, {0}")]
+    Filter(#[from] ParseError),
+    #[error("open log file {path:?} failed, {err}")]
+    File { path: PathBuf, err: io::Error },
+    #[error("connect syslog {address} failed, {err}")]
+    Syslog { address: String, err: io::Error },
+    #[error("connect journald failed, {0}")]
+    Journald(io::Error),
+    #[error("open access log failed, {0}")]
+    Access(io::Error),
+}
+
+/// Build the filter described by `level` and `filter` of the config
+pub fn filter(config: &Log) -> Result<Filter, ParseError> {
+    match &config.filter {
+        Some(directives) => Filter::parse(LevelFilter::from_level(config.level), directives),
+        None => Ok(Filter::new(config.level)),
+    }
+}
+
+/// Install the global logger, spans and metrics are exported if `otlp` is
+/// set, the exporter of the returned handle must be spawned, and its access
+/// log passed to `Connections`.
+pub fn init(config: &Log) -> Result<Handle, Error> {
+    let filter = filter(config)?;
+    let sink =
+        match (&config.file, &config.syslog) {
+            (Some(file), _) => {
+                Sink::File(RotatingFile::open(file.clone()).map_err(|err| Error::File {
+                    path: file.path.clone(),
+                    err,
+                })?)
+            }
+            (None, Some(syslog)) => Sink::Syslog(Syslog::connect(syslog.clone()).map_err(
+                |err| Error::Syslog {
+                    address: syslog.address.to_string(),
+                    err,
+                },
+            )?),
+            (None, None) if config.journald => {
+                Sink::Journald(Journald::connect().map_err(Error::Journald)?)
+            }
+            (None, None) => Sink::Stdout,
+        };
+
+    let mut logger =
+        Logger::new(filter, sink, config.format, config.timestamp).with_sampling(&config.sampling);
+    if let Some(otlp) = &config.otlp {
+        logger = logger.with_exporter(Exporter::new(otlp.clone()));
+    }
+    if let Some(access) = &config.access {
+        logger = logger.with_access_log(AccessLog::open(access).map_err(Error::Access)?);
+    }
+    let handle = logger.handle();
     let dispatcher = Dispatch::new(logger);
 
     tracing::dispatcher::set_global_default(dispatcher).expect("set global logger failed");
+
+    Ok(handle)
 }
 
 #[cfg(test)]
 pub fn test_init() {
-    init(Level::INFO, true)
+    init(&Log::default()).expect("init logger");
 }
 
 #[test]
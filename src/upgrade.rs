@@ -0,0 +1,468 @@
+//! Hand listening sockets over to a new roxy binary, so it can be
+//! upgraded without refusing connections, e.g.
+//!
+//! ```yaml
+//! upgrade:
+//!   socket: /run/roxy/upgrade.sock
+//!   drain: 1m
+//! ```
+//!
+//! On `SIGUSR2`, the running process execs its binary again with the same
+//! arguments, and sends the listeners of inbounds, the DNS server and the
+//! controller over `socket`. The new process serves on them instead of
+//! binding again, so connections queued in the backlog are not refused.
+//! Once it's ready, the old one stops accepting, and exits when relayed
+//! connections are closed or `drain` is elapsed.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixListener;
+
+use crate::relay::Connections;
+use crate::serde::duration;
+
+/// Path of the handover socket, it's set for the new process only
+const SOCKET_ENV: &str = "ROXY_UPGRADE_SOCKET";
+
+/// Max file descriptors of one message, `SCM_MAX_FD` of the kernel
+const MAX_FDS: usize = 253;
+
+/// How long the new process may take to connect and get ready
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Services bind asynchronously, listeners not taken after this are
+/// closed, e.g. the inbound is removed from the config
+const READY_DELAY: Duration = Duration::from_secs(1);
+
+const fn default_drain() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UpgradeConfig {
+    /// Unix socket the listeners are sent over
+    pub socket: PathBuf,
+
+    /// The old process exits after this, even if some connections are
+    /// still relaying
+    #[serde(default = "default_drain", with = "duration")]
+    pub drain: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("bind {path:?} failed, {err}")]
+    Bind { path: PathBuf, err: io::Error },
+    #[error("exec new process failed, {0}")]
+    Exec(io::Error),
+    #[error("new process didn't connect in time")]
+    Timeout,
+    #[error("too many listeners, at most 253 can be handed over")]
+    TooMany,
+    #[error("send listeners failed, {0}")]
+    Send(io::Error),
+    #[error("new process is not ready, {0}")]
+    NotReady(io::Error),
+    #[error("receive listeners failed, {0}")]
+    Receive(io::Error),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Tcp,
+    Udp,
+}
+
+/// Description of a sent descriptor, in the same order
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    kind: Kind,
+    addr: SocketAddr,
+}
+
+struct Listener {
+    id: u64,
+    kind: Kind,
+    addr: SocketAddr,
+    fd: RawFd,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Listeners of running services, they are handed over on upgrade
+static LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+
+/// Listeners received from the old process, not taken by services yet
+static INHERITED: Mutex<Vec<(Kind, SocketAddr, OwnedFd)>> = Mutex::new(Vec::new());
+
+/// Connection to the old process, it's told when this one is ready
+static OLD_PROCESS: Mutex<Option<UnixStream>> = Mutex::new(None);
+
+/// A listener is handed over on upgrade until this is dropped, so it
+/// must live as long as the listener
+pub struct Registration {
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        LISTENERS
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .retain(|listener| listener.id != self.id);
+    }
+}
+
+/// Register a listener of `addr`, the address services are configured
+/// with, so the new process takes it by the same address
+pub fn register(kind: Kind, addr: SocketAddr, fd: RawFd) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    LISTENERS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .push(Listener { id, kind, addr, fd });
+
+    Registration { id }
+}
+
+/// Take a listener of `addr` received from the old process
+pub fn take(kind: Kind, addr: SocketAddr) -> Option<OwnedFd> {
+    let mut inherited = INHERITED.lock().unwrap_or_else(|err| err.into_inner());
+    let index = inherited
+        .iter()
+        .position(|(k, a, _)| *k == kind && *a == addr)?;
+
+    Some(inherited.remove(index).2)
+}
+
+/// A TCP listener of `addr`, inherited from the old process or bound now
+pub fn tcp_listener(addr: SocketAddr) -> io::Result<(std::net::TcpListener, Registration)> {
+    let listener = match take(Kind::Tcp, addr) {
+        Some(fd) => std::net::TcpListener::from(fd),
+        None => std::net::TcpListener::bind(addr)?,
+    };
+    listener.set_nonblocking(true)?;
+    let registration = register(Kind::Tcp, addr, listener.as_raw_fd());
+
+    Ok((listener, registration))
+}
+
+/// A UDP socket of `addr`, inherited from the old process or bound now
+pub fn udp_socket(addr: SocketAddr) -> io::Result<(std::net::UdpSocket, Registration)> {
+    let socket = match take(Kind::Udp, addr) {
+        Some(fd) => std::net::UdpSocket::from(fd),
+        None => std::net::UdpSocket::bind(addr)?,
+    };
+    socket.set_nonblocking(true)?;
+    let registration = register(Kind::Udp, addr, socket.as_raw_fd());
+
+    Ok((socket, registration))
+}
+
+/// Receive listeners from the old process if this one is started by an
+/// upgrade. It must be called before any thread is spawned.
+pub fn inherit() -> Result<usize, Error> {
+    let path = match std::env::var_os(SOCKET_ENV) {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(0),
+    };
+    std::env::remove_var(SOCKET_ENV);
+
+    let stream = UnixStream::connect(&path).map_err(Error::Receive)?;
+    stream
+        .set_read_timeout(Some(HANDOVER_TIMEOUT))
+        .map_err(Error::Receive)?;
+
+    let (data, fds) = recv_fds(&stream).map_err(Error::Receive)?;
+    let entries = serde_json::from_slice::<Vec<Entry>>(&data)
+        .map_err(|err| Error::Receive(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+    if entries.len() != fds.len() {
+        return Err(Error::Receive(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "descriptors don't match their descriptions",
+        )));
+    }
+
+    let received = entries.len();
+    INHERITED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .extend(
+            entries
+                .into_iter()
+                .zip(fds)
+                .map(|(entry, fd)| (entry.kind, entry.addr, fd)),
+        );
+
+    // the old process waits for this
+    let _ = stream.set_read_timeout(None);
+    *OLD_PROCESS.lock().unwrap_or_else(|err| err.into_inner()) = Some(stream);
+
+    Ok(received)
+}
+
+/// Tell the old process to stop accepting, once services have taken
+/// their listeners. Listeners nobody takes are closed.
+pub async fn ready() {
+    let stream = OLD_PROCESS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .take();
+    let mut stream = match stream {
+        Some(stream) => stream,
+        None => return,
+    };
+
+    tokio::time::sleep(READY_DELAY).await;
+
+    for (kind, addr, _fd) in INHERITED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .drain(..)
+    {
+        warn!(
+            message = "inherited listener is not used, close it",
+            ?kind,
+            ?addr
+        );
+    }
+
+    match stream.write_all(b"r") {
+        Ok(()) => info!(message = "listeners taken over from the old process"),
+        Err(err) => warn!(message = "notify the old process failed", ?err),
+    }
+}
+
+/// Exec the binary again, and send registered listeners to it. It returns
+/// once the new process is ready, then this one should stop accepting.
+pub async fn handover(config: &UpgradeConfig) -> Result<(), Error> {
+    let listener = bind(&config.socket)?;
+
+    let exe = std::env::current_exe().map_err(Error::Exec)?;
+    let child = std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .env(SOCKET_ENV, &config.socket)
+        .spawn()
+        .map_err(Error::Exec)?;
+    info!(message = "new process started", pid = child.id());
+
+    let accepted = tokio::time::timeout(HANDOVER_TIMEOUT, listener.accept()).await;
+    let _ = std::fs::remove_file(&config.socket);
+    let stream = match accepted {
+        Ok(Ok((stream, _))) => stream.into_std().map_err(Error::Send)?,
+        Ok(Err(err)) => return Err(Error::Send(err)),
+        Err(_) => return Err(Error::Timeout),
+    };
+
+    // descriptors are duplicated, so they are still valid if services
+    // are stopped meanwhile
+    let (entries, fds) = {
+        let listeners = LISTENERS.lock().unwrap_or_else(|err| err.into_inner());
+        if listeners.len() > MAX_FDS {
+            return Err(Error::TooMany);
+        }
+
+        let mut entries = Vec::with_capacity(listeners.len());
+        let mut fds = Vec::with_capacity(listeners.len());
+        for listener in listeners.iter() {
+            fds.push(dup(listener.fd).map_err(Error::Send)?);
+            entries.push(Entry {
+                kind: listener.kind,
+                addr: listener.addr,
+            });
+        }
+
+        (entries, fds)
+    };
+
+    let data = serde_json::to_vec(&entries).expect("serialize listeners");
+    tokio::task::spawn_blocking(move || {
+        stream.set_nonblocking(false).map_err(Error::Send)?;
+        stream
+            .set_read_timeout(Some(HANDOVER_TIMEOUT))
+            .map_err(Error::Send)?;
+
+        let raw = fds.iter().map(|fd| fd.as_raw_fd()).collect::<Vec<_>>();
+        send_fds(&stream, &data, &raw).map_err(Error::Send)?;
+        info!(
+            message = "listeners sent to the new process",
+            count = raw.len()
+        );
+
+        let mut buf = [0u8; 1];
+        match (&stream).read(&mut buf) {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(Error::NotReady(io::ErrorKind::UnexpectedEof.into())),
+            Err(err) => Err(Error::NotReady(err)),
+        }
+    })
+    .await
+    .map_err(|err| Error::NotReady(io::Error::new(io::ErrorKind::Other, err)))?
+}
+
+/// Wait until relayed connections are closed, or `timeout` is elapsed
+pub async fn drain(connections: &Connections, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let active = connections.traffic().connections;
+        if active == 0 {
+            info!(message = "all connections are closed");
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!(message = "drain timeout, close connections", active);
+            return;
+        }
+
+        debug!(message = "waiting for connections", active);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn bind(path: &Path) -> Result<UnixListener, Error> {
+    // left by a crashed upgrade
+    let _ = std::fs::remove_file(path);
+
+    UnixListener::bind(path).map_err(|err| Error::Bind {
+        path: path.to_path_buf(),
+        err,
+    })
+}
+
+fn dup(fd: RawFd) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Control message buffer for `n` descriptors and its length, `u64`
+/// keeps it aligned for `cmsghdr`
+fn control_buf(n: usize) -> (Vec<u64>, usize) {
+    let space = unsafe { libc::CMSG_SPACE((n * mem::size_of::<RawFd>()) as u32) } as usize;
+    (vec![0u64; space / mem::size_of::<u64>() + 1], space)
+}
+
+fn send_fds(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let (mut control, space) = control_buf(fds.len());
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN((mem::size_of_val(fds)) as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+    }
+
+    let n = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if (n as usize) < data.len() {
+        return Err(io::ErrorKind::WriteZero.into());
+    }
+
+    Ok(())
+}
+
+fn recv_fds(stream: &UnixStream) -> io::Result<(Vec<u8>, Vec<OwnedFd>)> {
+    let mut data = vec![0u8; 64 * 1024];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let (mut control, space) = control_buf(MAX_FDS);
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let n = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    data.truncate(n as usize);
+
+    let mut fds = vec![];
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "descriptors are truncated",
+        ));
+    }
+
+    Ok((data, fds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_listeners() {
+        let (old, new) = UnixStream::pair().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        send_fds(&old, b"listeners", &[listener.as_raw_fd()]).unwrap();
+        let (data, fds) = recv_fds(&new).unwrap();
+        assert_eq!(data, b"listeners");
+        assert_eq!(fds.len(), 1);
+
+        // the received one is the same socket
+        drop(listener);
+        let received = std::net::TcpListener::from(fds.into_iter().next().unwrap());
+        assert_eq!(received.local_addr().unwrap(), addr);
+        std::net::TcpStream::connect(addr).unwrap();
+        received.accept().unwrap();
+
+        let registered = |addr| LISTENERS.lock().unwrap().iter().any(|l| l.addr == addr);
+        let registration = register(Kind::Tcp, addr, received.as_raw_fd());
+        assert!(registered(addr));
+        drop(registration);
+        assert!(!registered(addr));
+    }
+}
@@ -4,26 +4,30 @@ use std::time::Duration;
 
 use byte_string::ByteStr;
 use resolver::Resolver;
-use shadowsocks::{Address, ConnectOpts, ProxyStream};
+use shadowsocks::{Address, ConnectOpts};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::time;
 use tokio::time::Instant;
 
-use super::Server;
+use super::{CheckConfig, Probe, ProbeMethod, Server};
 
 pub struct Checker {
     server: Arc<Server>,
     resolver: Resolver,
     timeout: Duration,
+    probe: Probe,
+    method: ProbeMethod,
     connect_opts: ConnectOpts,
 }
 
 impl Checker {
-    pub fn new(server: Arc<Server>, resolver: Resolver, timeout: Duration) -> Self {
+    pub fn new(server: Arc<Server>, resolver: Resolver, check: &CheckConfig) -> Self {
         Self {
             server,
             resolver,
-            timeout,
+            timeout: check.timeout,
+            probe: check.probe.clone(),
+            method: check.method,
             connect_opts: Default::default(),
         }
     }
@@ -41,43 +45,75 @@ impl Checker {
     }
 
     async fn check_request(&self) -> io::Result<()> {
-        self.check_request_tcp_firefox().await
+        match &self.probe {
+            Probe::Http { host, port, path } => self.check_request_http(host, *port, path).await,
+            Probe::Tcp => self.check_request_tcp().await,
+        }
     }
 
-    /// Detect TCP connectivity with Firefox's http://detectportal.firefox.com/success.txt
-    async fn check_request_tcp_firefox(&self) -> io::Result<()> {
-        static GET_BODY: &[u8] = b"GET /success.txt HTTP/1.1\r\nHost: detectportal.firefox.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+    /// Connect to the server only, chained ones through the server they
+    /// are reached by
+    async fn check_request_tcp(&self) -> io::Result<()> {
+        match self.server.via() {
+            Some(via) => {
+                let stream = via.dial(&self.resolver, &self.connect_opts).await?;
+                let addr = self.server.config().addr().clone();
+                via.tunnel(stream, addr).await.map(|_| ())
+            }
+            None => self
+                .server
+                .dial(&self.resolver, &self.connect_opts)
+                .await
+                .map(|_| ()),
+        }
+    }
 
-        let addr = Address::DomainNameAddress("detectportal.firefox.com".to_owned(), 80);
-        let mut stream = ProxyStream::connect(
-            self.server.config(),
-            addr,
-            &self.resolver,
-            &self.connect_opts,
-        )
-        .await?;
+    /// Request the url through the tunnel, any 2xx status passes, e.g.
+    /// Firefox's http://detectportal.firefox.com/success.txt
+    async fn check_request_http(&self, host: &str, port: u16, path: &str) -> io::Result<()> {
+        use std::io::{Error, ErrorKind};
 
-        stream.write_all(GET_BODY).await?;
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept: */*\r\n\r\n",
+            self.method.as_str(),
+            path,
+            host
+        );
+
+        let addr = Address::DomainNameAddress(host.to_owned(), port);
+        let mut stream = match self.server.via() {
+            Some(via) => {
+                self.server
+                    .tunnel_via(&via, &self.resolver, &self.connect_opts, addr)
+                    .await?
+            }
+            None => {
+                let stream = self.server.dial(&self.resolver, &self.connect_opts).await?;
+                self.server.tunnel(stream, addr).await?
+            }
+        };
+
+        stream.write_all(request.as_bytes()).await?;
 
         let mut reader = BufReader::new(stream);
 
         let mut buf = Vec::new();
         reader.read_until(b'\n', &mut buf).await?;
 
-        static EXPECTED_HTTP_STATUS_LINE: &[u8] = b"HTTP/1.1 200 OK\r\n";
-        if buf != EXPECTED_HTTP_STATUS_LINE {
-            use std::io::{Error, ErrorKind};
-
+        // e.g. `HTTP/1.1 204 No Content`
+        let passed = buf.starts_with(b"HTTP/1.") && buf.get(9) == Some(&b'2');
+        if !passed {
             debug!(
-                "unexpected response from http://detectportal.firefox.com/success.txt, {:?}",
-                ByteStr::new(&buf)
+                message = "unexpected response of the probe",
+                host,
+                path,
+                response = ?ByteStr::new(&buf)
             );
 
-            let err = Error::new(
+            return Err(Error::new(
                 ErrorKind::InvalidData,
-                "unexpected response from http://detectportal.firefox.com/success.txt",
-            );
-            return Err(err);
+                "unexpected response of the probe",
+            ));
         }
 
         Ok(())
@@ -86,7 +122,7 @@ impl Checker {
     async fn check_delay(&self) -> io::Result<u32> {
         let start = Instant::now();
 
-        // Send HTTP GET and read the first byte
+        // Send the probe and read the status line
         let result = time::timeout(self.timeout, self.check_request()).await;
 
         let elapsed = Instant::now() - start;
@@ -1,6 +1,15 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
+use super::resolve::{ReResolveConfig, ResolveConfig};
+use super::shadow_tls::ShadowTlsConfig;
+use super::tls::TlsConfig;
+use crate::net::KeepaliveConfig;
 use crate::serde::duration;
+use hyper::Uri;
 use serde::Deserialize;
 
 /// Interval between each check
@@ -16,36 +25,529 @@ const fn default_check_interval() -> Duration {
     DEFAULT_CHECK_INTERVAL
 }
 
-#[derive(Clone, Deserialize, Default)]
+const fn default_threshold() -> u32 {
+    1
+}
+
+/// Requested through the tunnel by default
+pub const DEFAULT_PROBE_URL: &str = "http://detectportal.firefox.com/success.txt";
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum ProbeError {
+    #[error("invalid url, {0}")]
+    InvalidUrl(String),
+    #[error("only http urls are supported")]
+    UnsupportedScheme,
+}
+
+/// How servers are checked
+#[derive(Clone, Debug, PartialEq)]
+pub enum Probe {
+    /// Request the url through the tunnel, any 2xx response passes
+    Http {
+        host: String,
+        port: u16,
+        path: String,
+    },
+    /// Connect to the server only, it's cheap but the tunnel is not verified
+    Tcp,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        DEFAULT_PROBE_URL
+            .parse()
+            .expect("default probe url is valid")
+    }
+}
+
+impl FromStr for Probe {
+    type Err = ProbeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "tcp" {
+            return Ok(Probe::Tcp);
+        }
+
+        let uri = s
+            .parse::<Uri>()
+            .map_err(|err| ProbeError::InvalidUrl(err.to_string()))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(ProbeError::UnsupportedScheme);
+        }
+        let host = uri
+            .host()
+            .ok_or_else(|| ProbeError::InvalidUrl("host is required".to_string()))?;
+
+        Ok(Probe::Http {
+            host: host.to_string(),
+            port: uri.port_u16().unwrap_or(80),
+            path: uri
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/")
+                .to_string(),
+        })
+    }
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::Http {
+                host,
+                port: 80,
+                path,
+            } => write!(f, "http://{}{}", host, path),
+            Probe::Http { host, port, path } => write!(f, "http://{}:{}{}", host, port, path),
+            Probe::Tcp => f.write_str("tcp"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Probe {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum LoadBalanceType {
     #[default]
     Best,
+    /// Consistent hash of the eTLD+1 of requests, so a site sticks to a
+    /// server
+    #[serde(alias = "consistent_hash")]
     Etld,
+    /// New connections are distributed by `weights`
+    Weighted,
+    /// New connections go to alive servers in turn
+    RoundRobin,
+    /// The alive server with the fewest connections in flight
+    LeastConnections,
+    /// The first alive server by priority, the preferred one is used again
+    /// once it's been alive for `recover`
+    Fallback,
 }
 
-#[derive(Deserialize)]
+const fn default_recover() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Servers with a tag, picked by their own load balance
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub load_balance: LoadBalanceType,
+
+    /// Server names by priority for `fallback`, tagged servers in the order
+    /// of the pool if it's empty
+    #[serde(default)]
+    pub servers: Vec<String>,
+
+    /// How long a preferred server is alive before `fallback` switches back
+    /// to it, so a flapping server isn't switched to and from
+    #[serde(default = "default_recover", with = "duration")]
+    pub recover: Duration,
+
+    /// Weights of servers by name for `weighted`, instead of the ones of
+    /// `upstream.weights`
+    #[serde(default)]
+    pub weights: BTreeMap<String, u32>,
+
+    /// Network interface of servers with the tag, instead of
+    /// `upstream.interface`
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        Self {
+            load_balance: LoadBalanceType::default(),
+            servers: vec![],
+            recover: default_recover(),
+            weights: BTreeMap::new(),
+            interface: None,
+        }
+    }
+}
+
+/// Method of `http://` probes
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeMethod {
+    #[default]
+    Get,
+    /// The response has no body, so it's cheaper for both ends
+    Head,
+}
+
+impl ProbeMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeMethod::Get => "GET",
+            ProbeMethod::Head => "HEAD",
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct CheckConfig {
     #[serde(with = "duration", default = "default_check_timeout")]
     pub timeout: Duration,
     #[serde(with = "duration", default = "default_check_interval")]
     pub interval: Duration,
+
+    /// An `http://` url requested through the tunnel, or `tcp`
+    #[serde(default)]
+    pub probe: Probe,
+
+    #[serde(default)]
+    pub method: ProbeMethod,
+
+    /// Consecutive passed checks to mark an unhealthy server healthy
+    #[serde(default = "default_threshold")]
+    pub healthy_threshold: u32,
+
+    /// Consecutive failures, of checks or relayed connections, to mark a
+    /// healthy server unhealthy
+    #[serde(default = "default_threshold")]
+    pub unhealthy_threshold: u32,
+
+    /// The best server is replaced only by one faster by more than this,
+    /// so it doesn't change with every check
+    #[serde(default, with = "duration")]
+    pub tolerance: Duration,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_CHECK_TIMEOUT,
+            interval: DEFAULT_CHECK_INTERVAL,
+            probe: Probe::default(),
+            method: ProbeMethod::default(),
+            healthy_threshold: default_threshold(),
+            unhealthy_threshold: default_threshold(),
+            tolerance: Duration::ZERO,
+        }
+    }
+}
+
+/// What to do with a new connection when the picked server is full
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Use the next alive server which isn't full
+    #[default]
+    Spill,
+    /// Wait for `queue_timeout` until a connection of the server closed
+    Queue,
+    /// Close the connection
+    Reject,
+}
+
+const fn default_queue_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// Caps of in-flight connections per server, to protect small servers
+/// from overload
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LimitConfig {
+    /// Of each server, unlimited if it's not set
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+
+    /// Overrides of `max_connections` by server name
+    #[serde(default)]
+    pub servers: BTreeMap<String, usize>,
+
+    #[serde(default)]
+    pub overflow: Overflow,
+
+    #[serde(default = "default_queue_timeout", with = "duration")]
+    pub queue_timeout: Duration,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            servers: BTreeMap::new(),
+            overflow: Overflow::default(),
+            queue_timeout: default_queue_timeout(),
+        }
+    }
+}
+
+impl LimitConfig {
+    /// 0 means unlimited
+    pub fn max_connections(&self, name: &str) -> usize {
+        self.servers
+            .get(name)
+            .copied()
+            .or(self.max_connections)
+            .unwrap_or(0)
+    }
+}
+
+const fn default_failure_rate() -> f64 {
+    0.5
+}
+
+const fn default_min_requests() -> usize {
+    5
 }
 
-#[derive(Deserialize)]
+const fn default_breaker_window() -> usize {
+    20
+}
+
+const fn default_cooldown() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Stop selecting a server whose dials keep failing for `cooldown`, then
+/// let one connection through to try it, so a dying server doesn't add
+/// connect timeouts to every connection until the next health check
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// Open the circuit when failed dials of the last `window` ones reach
+    /// this ratio
+    #[serde(default = "default_failure_rate")]
+    pub failure_rate: f64,
+
+    /// Dials required before the rate is considered
+    #[serde(default = "default_min_requests")]
+    pub min_requests: usize,
+
+    /// Number of recent dials the rate is calculated over
+    #[serde(default = "default_breaker_window")]
+    pub window: usize,
+
+    #[serde(default = "default_cooldown", with = "duration")]
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_rate: default_failure_rate(),
+            min_requests: default_min_requests(),
+            window: default_breaker_window(),
+            cooldown: default_cooldown(),
+        }
+    }
+}
+
+const fn default_warm_idle_timeout() -> Duration {
+    Duration::from_secs(15)
+}
+
+/// Connections to servers established ahead, new relayed connections
+/// take them, so the TCP handshake is skipped. Nothing is sent before
+/// they are taken, since the target is in the first packet.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WarmConfig {
+    /// Idle connections kept for each alive server, 0 disables it
+    #[serde(default)]
+    pub connections: usize,
+
+    /// Idle connections older than this are closed, it should be shorter
+    /// than the timeout of servers waiting for the first packet
+    #[serde(default = "default_warm_idle_timeout", with = "duration")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for WarmConfig {
+    fn default() -> Self {
+        Self {
+            connections: 0,
+            idle_timeout: default_warm_idle_timeout(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct ProviderConfig {
     pub endpoint: String,
 
     #[serde(with = "duration")]
     pub interval: Duration,
+
+    /// File to keep the last fetched subscription, servers in it are used
+    /// when fetching fails on startup
+    #[serde(default)]
+    pub cache: Option<PathBuf>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct Config {
     #[serde(default)]
     pub load_balance: LoadBalanceType,
 
     pub check: CheckConfig,
 
-    pub provider: ProviderConfig,
+    /// Servers in `ss://` url format, they are used along with servers
+    /// from the provider
+    #[serde(default)]
+    pub servers: Vec<String>,
+
+    #[serde(default)]
+    pub provider: Option<ProviderConfig>,
+
+    /// Weights of servers by name for `weighted`, servers not listed have
+    /// weight 1, and ones of weight 0 are used only if others are down
+    #[serde(default)]
+    pub weights: BTreeMap<String, u32>,
+
+    /// Tags of servers by name, inbounds select servers by tag, so they
+    /// don't depend on concrete servers
+    #[serde(default)]
+    pub tags: BTreeMap<String, Vec<String>>,
+
+    /// Groups by tag, inbounds with the tag as `upstream_tag` pick servers
+    /// by the group's load balance instead of `load_balance`
+    #[serde(default)]
+    pub groups: BTreeMap<String, GroupConfig>,
+
+    /// Servers reached through another server, by server name, e.g. a
+    /// shadowsocks server over an HTTP proxy. Only shadowsocks servers can
+    /// be chained, and by one hop.
+    #[serde(default)]
+    pub via: BTreeMap<String, String>,
+
+    /// How hostnames of servers are resolved, by server name
+    #[serde(default)]
+    pub resolve: BTreeMap<String, ResolveConfig>,
+
+    /// Keep resolved addresses of servers for a while, instead of
+    /// resolving hostnames by every connection
+    #[serde(default)]
+    pub re_resolve: Option<ReResolveConfig>,
+
+    /// shadow-tls v3 transports of shadowsocks servers, by server name
+    #[serde(default)]
+    pub shadow_tls: BTreeMap<String, ShadowTlsConfig>,
+
+    /// Trust settings of `https://` proxies and VLESS servers over TLS, by
+    /// server name, the system roots verify the others
+    #[serde(default)]
+    pub tls: BTreeMap<String, TlsConfig>,
+
+    /// File to keep latency history of servers across restarts
+    #[serde(default)]
+    pub history: Option<PathBuf>,
+
+    #[serde(default)]
+    pub limit: LimitConfig,
+
+    #[serde(default)]
+    pub warm: WarmConfig,
+
+    /// Circuit breaker of each server, disabled if it's not set
+    #[serde(default)]
+    pub breaker: Option<BreakerConfig>,
+
+    /// TCP Fast Open on connections to servers, the request goes in the
+    /// SYN once the server has handed out a cookie
+    #[serde(default)]
+    pub fast_open: bool,
+
+    /// TCP keepalive of connections to servers, disabled if it's not set
+    #[serde(default)]
+    pub keepalive: Option<KeepaliveConfig>,
+
+    /// Connect to servers through this network interface, by
+    /// `SO_BINDTODEVICE`, e.g. the WAN interface of a router
+    #[serde(default)]
+    pub interface: Option<String>,
+
+    /// Network interfaces of servers by name, instead of the one of their
+    /// group or `interface`
+    #[serde(default)]
+    pub interfaces: BTreeMap<String, String>,
+
+    /// `SO_MARK` of connections to servers, so policy routing, e.g.
+    /// `ip rule add fwmark 255 lookup main`, keeps them out of TPROXY rules
+    #[serde(default)]
+    pub mark: Option<u32>,
+
+    /// `SO_MARK` of connections to servers by name, instead of `mark`
+    #[serde(default)]
+    pub marks: BTreeMap<String, u32>,
+}
+
+impl Config {
+    /// Network interface of the server named `name` with `tags`, its own
+    /// one, the one of its first group having one, or `interface`
+    pub fn interface_of(&self, name: &str, tags: &[String]) -> Option<String> {
+        self.interfaces
+            .get(name)
+            .or_else(|| {
+                tags.iter()
+                    .filter_map(|tag| self.groups.get(tag))
+                    .find_map(|group| group.interface.as_ref())
+            })
+            .or(self.interface.as_ref())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe() {
+        assert_eq!("tcp".parse(), Ok(Probe::Tcp));
+        assert_eq!(
+            "http://example.com:8080/generate_204?a=1".parse(),
+            Ok(Probe::Http {
+                host: "example.com".to_string(),
+                port: 8080,
+                path: "/generate_204?a=1".to_string(),
+            })
+        );
+        assert_eq!(
+            "https://example.com".parse::<Probe>(),
+            Err(ProbeError::UnsupportedScheme)
+        );
+        assert_eq!(Probe::default().to_string(), DEFAULT_PROBE_URL);
+
+        let check: CheckConfig =
+            serde_yaml::from_str("{probe: http://example.com/generate_204, method: head}").unwrap();
+        assert_eq!(check.method.as_str(), "HEAD");
+    }
+
+    #[test]
+    fn interface_of() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+check: {}
+interface: eth0
+interfaces:
+  hk-01: wg0
+tags:
+  jp-01: [asia]
+groups:
+  asia:
+    load_balance: best
+    interface: eth1
+"#,
+        )
+        .unwrap();
+        let tags = ["asia".to_string()];
+
+        assert_eq!(config.interface_of("hk-01", &tags).as_deref(), Some("wg0"));
+        assert_eq!(config.interface_of("jp-01", &tags).as_deref(), Some("eth1"));
+        assert_eq!(config.interface_of("us-01", &[]).as_deref(), Some("eth0"));
+    }
 }
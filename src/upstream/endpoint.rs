@@ -0,0 +1,204 @@
+//! Upstream servers are shadowsocks servers of `ss://` urls, HTTP proxies
+//! of `http://` and `https://` urls, see `http`, VMess servers of
+//! `vmess://` urls, see `vmess`, or VLESS servers of `vless://` urls, see
+//! `vless`.
+
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use shadowsocks::{Address, ProxyStream, ServerConfig, UrlParseError};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::http::HttpProxyConfig;
+use super::shadow_tls::ShadowTlsStream;
+use super::tls::MaybeTlsStream;
+use super::vless::{VlessConfig, VlessStream};
+use super::vmess::{VmessConfig, VmessStream};
+
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    Shadowsocks(ServerConfig),
+    Http(HttpProxyConfig),
+    Vmess(VmessConfig),
+    Vless(VlessConfig),
+}
+
+impl From<ServerConfig> for Endpoint {
+    fn from(config: ServerConfig) -> Self {
+        Endpoint::Shadowsocks(config)
+    }
+}
+
+impl Endpoint {
+    pub fn from_url(url: &str) -> Result<Self, UrlParseError> {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            HttpProxyConfig::from_url(url).map(Endpoint::Http)
+        } else if url.starts_with("vmess://") {
+            VmessConfig::from_url(url).map(Endpoint::Vmess)
+        } else if url.starts_with("vless://") {
+            VlessConfig::from_url(url).map(Endpoint::Vless)
+        } else {
+            ServerConfig::from_url(url).map(Endpoint::Shadowsocks)
+        }
+    }
+
+    pub fn addr(&self) -> &Address {
+        match self {
+            Endpoint::Shadowsocks(config) => config.addr(),
+            Endpoint::Http(config) => config.addr(),
+            Endpoint::Vmess(config) => config.addr(),
+            Endpoint::Vless(config) => config.addr(),
+        }
+    }
+
+    pub fn remarks(&self) -> Option<&String> {
+        match self {
+            Endpoint::Shadowsocks(config) => config.remarks(),
+            Endpoint::Http(config) => config.remarks(),
+            Endpoint::Vmess(config) => config.remarks(),
+            Endpoint::Vless(config) => config.remarks(),
+        }
+    }
+}
+
+/// Parse `host[:port]` or `[ipv6][:port]` of urls
+pub(super) fn parse_authority(
+    authority: &str,
+    default_port: u16,
+) -> Result<Address, UrlParseError> {
+    let (host, port) = match authority.strip_prefix('[') {
+        // IPv6
+        Some(rest) => match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => (host, Some(port.strip_prefix(':').unwrap_or(port))),
+            None => return Err(UrlParseError::InvalidServerAddr),
+        },
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(UrlParseError::MissingHost);
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| UrlParseError::InvalidServerAddr)?,
+        None => default_port,
+    };
+
+    Ok(match host.parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddress((ip, port).into()),
+        Err(_) => Address::DomainNameAddress(host.to_string(), port),
+    })
+}
+
+/// A stream to the destination through an upstream server
+// most are shadowsocks ones, boxing them costs an allocation each
+#[allow(clippy::large_enum_variant)]
+pub enum Tunnel {
+    Shadowsocks(ProxyStream),
+    /// Shadowsocks over shadow-tls
+    ShadowTls(ProxyStream<ShadowTlsStream>),
+    Http(MaybeTlsStream),
+    Vmess(VmessStream),
+    Vless(VlessStream),
+    /// Shadowsocks over a tunnel of another server
+    Chained(Box<ProxyStream<Tunnel>>),
+}
+
+impl Tunnel {
+    /// Relay data between `local` and the server until one side is closed
+    pub async fn proxy<L>(self, mut local: L) -> io::Result<()>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            Tunnel::Shadowsocks(stream) => stream.proxy(local).await,
+            Tunnel::ShadowTls(stream) => stream.proxy(local).await,
+            Tunnel::Http(mut stream) => tokio::io::copy_bidirectional(&mut local, &mut stream)
+                .await
+                .map(|_| ()),
+            Tunnel::Vmess(mut stream) => tokio::io::copy_bidirectional(&mut local, &mut stream)
+                .await
+                .map(|_| ()),
+            Tunnel::Vless(mut stream) => tokio::io::copy_bidirectional(&mut local, &mut stream)
+                .await
+                .map(|_| ()),
+            Tunnel::Chained(stream) => stream.proxy(local).await,
+        }
+    }
+}
+
+impl AsRawFd for Tunnel {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Tunnel::Shadowsocks(stream) => stream.as_raw_fd(),
+            Tunnel::ShadowTls(stream) => stream.as_raw_fd(),
+            Tunnel::Http(stream) => stream.as_raw_fd(),
+            Tunnel::Vmess(stream) => stream.as_raw_fd(),
+            Tunnel::Vless(stream) => stream.as_raw_fd(),
+            Tunnel::Chained(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Tunnel::Shadowsocks(stream) => Pin::new(stream).poll_read(cx, buf),
+            Tunnel::ShadowTls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Tunnel::Http(stream) => Pin::new(stream).poll_read(cx, buf),
+            Tunnel::Vmess(stream) => Pin::new(stream).poll_read(cx, buf),
+            Tunnel::Vless(stream) => Pin::new(stream).poll_read(cx, buf),
+            Tunnel::Chained(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Tunnel::Shadowsocks(stream) => Pin::new(stream).poll_write(cx, buf),
+            Tunnel::ShadowTls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Tunnel::Http(stream) => Pin::new(stream).poll_write(cx, buf),
+            Tunnel::Vmess(stream) => Pin::new(stream).poll_write(cx, buf),
+            Tunnel::Vless(stream) => Pin::new(stream).poll_write(cx, buf),
+            Tunnel::Chained(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Tunnel::Shadowsocks(stream) => Pin::new(stream).poll_flush(cx),
+            Tunnel::ShadowTls(stream) => Pin::new(stream).poll_flush(cx),
+            Tunnel::Http(stream) => Pin::new(stream).poll_flush(cx),
+            Tunnel::Vmess(stream) => Pin::new(stream).poll_flush(cx),
+            Tunnel::Vless(stream) => Pin::new(stream).poll_flush(cx),
+            Tunnel::Chained(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Tunnel::Shadowsocks(stream) => Pin::new(stream).poll_shutdown(cx),
+            Tunnel::ShadowTls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Tunnel::Http(stream) => Pin::new(stream).poll_shutdown(cx),
+            Tunnel::Vmess(stream) => Pin::new(stream).poll_shutdown(cx),
+            Tunnel::Vless(stream) => Pin::new(stream).poll_shutdown(cx),
+            Tunnel::Chained(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Happy Eyeballs v2 (RFC 8305) connects to servers which have both IPv6
+//! and IPv4 addresses. Addresses are tried alternating the families, IPv6
+//! first, and a new attempt starts if the last one hasn't succeeded in
+//! `ATTEMPT_DELAY` or as soon as it fails, while earlier ones go on. The
+//! first established connection wins, so a broken IPv6 network costs a
+//! quarter of a second instead of a connect timeout.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use tokio::time;
+
+/// The recommended "Connection Attempt Delay"
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Interleave the families of `addrs`, starting with IPv6, the order within
+/// a family is kept
+pub fn sort(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+
+    let mut sorted = Vec::with_capacity(v6.len() + v4.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return sorted,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Race `connect` to `addrs`, returns the first connection established, or
+/// the last error if all of them failed
+pub async fn connect<F, Fut, S>(addrs: Vec<SocketAddr>, connect: F) -> io::Result<S>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let mut addrs = sort(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(connect(addr)),
+                None => {
+                    return Err(last_err.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no address to connect")
+                    }))
+                }
+            }
+        }
+
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                // the next one starts now, instead of after the delay
+                Err(err) => {
+                    last_err = Some(err);
+                    if let Some(addr) = addrs.next() {
+                        attempts.push(connect(addr));
+                    }
+                }
+            },
+            _ = time::sleep(ATTEMPT_DELAY), if addrs.len() > 0 => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(connect(addr));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleave() {
+        let sorted = sort(addrs(&[
+            "192.0.2.1:443",
+            "192.0.2.2:443",
+            "192.0.2.3:443",
+            "[2001:db8::1]:443",
+        ]));
+
+        assert_eq!(
+            sorted,
+            addrs(&[
+                "[2001:db8::1]:443",
+                "192.0.2.1:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443",
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn race() {
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+
+        // IPv6 is black-holed, IPv4 wins after the delay
+        let started = Instant::now();
+        let won = connect(vec![v4, v6], |addr| async move {
+            if addr.is_ipv6() {
+                time::sleep(Duration::from_secs(10)).await;
+            }
+            Ok(addr)
+        })
+        .await
+        .unwrap();
+        assert_eq!(won, v4);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // IPv6 is refused, IPv4 starts at once
+        let started = Instant::now();
+        let won = connect(vec![v4, v6], |addr| async move {
+            if addr.is_ipv6() {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            Ok(addr)
+        })
+        .await
+        .unwrap();
+        assert_eq!(won, v4);
+        assert!(started.elapsed() < ATTEMPT_DELAY);
+
+        let err = connect(vec![v4, v6], |_| async {
+            Err::<SocketAddr, _>(io::Error::from(io::ErrorKind::ConnectionRefused))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,92 @@
+//! Latency history of servers is saved to `upstream.history` after each
+//! round of checks, and restored on startup, so the best server can be
+//! chosen by smoothed latency right after restarts.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::server::Server;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Record {
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    /// Latency in milliseconds, 0 means failed
+    pub value: u32,
+}
+
+/// History by server name, missing or invalid files are ignored
+pub fn load(path: &Path) -> BTreeMap<String, Vec<Record>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(err) => {
+            warn!(message = "read latency history failed", ?path, ?err);
+            return BTreeMap::new();
+        }
+    };
+
+    match serde_json::from_slice(&data) {
+        Ok(history) => history,
+        Err(err) => {
+            warn!(message = "invalid latency history", ?path, ?err);
+            BTreeMap::new()
+        }
+    }
+}
+
+/// Write to a temporary file first, so the history is never truncated
+pub fn save(path: &Path, servers: &[Arc<Server>]) -> io::Result<()> {
+    let history = servers
+        .iter()
+        .map(|server| (server.name(), server.history()))
+        .collect::<BTreeMap<_, _>>();
+    let data = serde_json::to_vec(&history)?;
+
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Restore history of servers by name
+pub fn restore(servers: &[Arc<Server>], history: &BTreeMap<String, Vec<Record>>) {
+    for server in servers {
+        if let Some(records) = history.get(&server.name()) {
+            server.restore(records);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shadowsocks::ServerConfig;
+
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("roxy-history-{}.json", std::process::id()));
+        let server = Arc::new(Server::new(
+            ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388#hk").unwrap(),
+        ));
+        server.push_latency(100);
+        server.report_failure();
+        server.push_latency(200);
+
+        save(&path, &[server.clone()]).unwrap();
+        let history = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(history["hk"].len(), 3);
+
+        let restored = Arc::new(Server::new(server.config().clone()));
+        restored.push_latency(300);
+        restore(&[restored.clone()], &history);
+        assert_eq!(restored.history().len(), 4);
+        assert_eq!(restored.latency(), 300);
+        assert_eq!(restored.smoothed_latency(), 200);
+    }
+}
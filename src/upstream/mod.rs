@@ -1,39 +1,150 @@
 // mod balancer;
 mod checker;
 mod config;
+mod endpoint;
 mod error;
+mod eyeballs;
 mod hash;
+mod history;
+mod http;
+mod plugin;
 mod provider;
+mod resolve;
 mod server;
+mod shadow_tls;
+mod tls;
+mod vless;
+mod vmess;
 
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
 use std::net::AddrParseError;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub use config::Config;
+pub use config::{
+    BreakerConfig, CheckConfig, Config, GroupConfig, LimitConfig, LoadBalanceType, Overflow, Probe,
+    ProbeMethod, ProviderConfig, WarmConfig,
+};
+pub use endpoint::{Endpoint, Tunnel};
 use futures_util::stream::FuturesUnordered;
 use futures_util::StreamExt;
 use hash::{fnv, jumphash};
 use publicsuffix::effective_tld_plus_one;
 use resolver::Resolver;
-use server::{Server, Stat};
-use tokio::sync::RwLock;
+use serde::Deserialize;
+pub use server::Server;
+use server::{name_of, Permit, Stat};
+use shadowsocks::{Address, ConnectOpts, ProxySocket, UrlParseError};
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, RwLock};
+use tokio::task::JoinHandle;
 use tokio::time;
 
-use crate::upstream::config::LoadBalanceType;
-use crate::upstream::provider::Provider;
+use crate::net::KeepaliveConfig;
+use crate::upstream::provider::{Provider, Usage};
+use crate::upstream::resolve::ServerResolver;
+
+/// How often warm connections are topped up
+const WARM_INTERVAL: Duration = Duration::from_secs(1);
 
 struct Peers {
     servers: Vec<Arc<Server>>,
     best: AtomicUsize,
+    /// The best servers of tags
+    bests: parking_lot::Mutex<HashMap<String, usize>>,
+    /// Milliseconds a server must be faster by to replace the best one
+    tolerance: u32,
+
+    /// Weights of `servers`, and current weights of the smooth weighted
+    /// round robin
+    weights: Vec<u32>,
+    current: parking_lot::Mutex<Vec<i64>>,
+    /// Current weights of groups with their own weights, by tag
+    group_current: parking_lot::Mutex<HashMap<String, Vec<i64>>>,
+
+    /// Turns of the round robin
+    next: AtomicUsize,
+    /// Servers used by `fallback`, by tag
+    primaries: parking_lot::Mutex<HashMap<String, Primary>>,
+}
+
+/// The server of a `fallback` group, and a preferred one since it's alive
+struct Primary {
+    index: usize,
+    recovering: Option<(usize, Instant)>,
 }
 
 impl Peers {
-    fn new(servers: Vec<Arc<Server>>) -> Self {
+    fn new(servers: Vec<Arc<Server>>, config: &Config) -> Self {
+        let check = &config.check;
+        for server in &servers {
+            server.set_thresholds(check.healthy_threshold, check.unhealthy_threshold);
+            server.set_max_connections(config.limit.max_connections(&server.name()));
+            server.set_breaker(config.breaker.clone());
+            let tags = config.tags.get(&server.name()).cloned().unwrap_or_default();
+            server.set_interface(config.interface_of(&server.name(), &tags));
+            server.set_tags(tags);
+
+            let resolver =
+                config
+                    .resolve
+                    .get(&server.name())
+                    .and_then(|rc| match ServerResolver::new(rc) {
+                        Ok(resolver) => Some(Arc::new(resolver)),
+                        Err(err) => {
+                            warn!(
+                                message = "create resolver of server failed",
+                                ?err,
+                                name = server.name()
+                            );
+                            None
+                        }
+                    });
+            server.set_resolver(resolver);
+            server.set_re_resolve(config.re_resolve.clone());
+            server.set_shadow_tls(config.shadow_tls.get(&server.name()).cloned().map(Arc::new));
+            server.set_tls(config.tls.get(&server.name()).cloned());
+            server.set_mark(config.marks.get(&server.name()).copied().or(config.mark));
+        }
+        for server in &servers {
+            let via = config.via.get(&server.name()).and_then(|name| {
+                let via = servers.iter().find(|svr| svr.name() == *name);
+                // one hop only, so there is no loop
+                match via {
+                    Some(via) if !Arc::ptr_eq(via, server) && !config.via.contains_key(name) => {
+                        Some(via.clone())
+                    }
+                    _ => {
+                        warn!(
+                            message = "server to chain through is not usable",
+                            name = server.name(),
+                            via = name.as_str()
+                        );
+                        None
+                    }
+                }
+            });
+            server.set_via(via);
+        }
+
+        let weights = servers
+            .iter()
+            .map(|server| config.weights.get(&server.name()).copied().unwrap_or(1))
+            .collect::<Vec<_>>();
+        let current = parking_lot::Mutex::new(vec![0; servers.len()]);
+
         Self {
             servers,
             best: AtomicUsize::new(0),
+            bests: Default::default(),
+            tolerance: u32::try_from(check.tolerance.as_millis()).unwrap_or(u32::MAX),
+            weights,
+            current,
+            group_current: Default::default(),
+            next: AtomicUsize::new(0),
+            primaries: Default::default(),
         }
     }
 
@@ -41,62 +152,383 @@ impl Peers {
         self.servers.clone()
     }
 
-    fn best(&self) -> Arc<Server> {
+    /// The best server is chosen over all servers, it's chosen among
+    /// tagged ones by latency if `tag` is set
+    fn best(&self, tag: Option<&str>) -> Arc<Server> {
+        if let Some(name) = tag {
+            let fastest = self
+                .servers
+                .iter()
+                .enumerate()
+                .filter(|(_, svr)| svr.tagged(tag) && svr.alive())
+                .min_by_key(|(_, svr)| svr.smoothed_latency());
+            let (fastest, latency) = match fastest {
+                Some((index, svr)) => (index, svr.smoothed_latency()),
+                None => return self.fallback(tag),
+            };
+
+            let mut bests = self.bests.lock();
+            let best = bests.entry(name.to_string()).or_insert(fastest);
+            if !self.keeps(*best, tag, latency) {
+                *best = fastest;
+            }
+            return self.servers[*best].clone();
+        }
+
         let best = &self.servers[self.best.load(Ordering::Relaxed)];
         if best.alive() {
             return best.clone();
         }
 
-        self.fallback()
+        self.fallback(tag)
     }
 
-    fn by_etld(&self, host: &str) -> Arc<Server> {
-        let servers = &self.servers;
+    /// Consistent hash of the eTLD+1 of `host`, so connections to a site
+    /// go through the same server, and sites don't see rotating exit IPs
+    fn by_etld(&self, host: &str, tag: Option<&str>) -> Arc<Server> {
+        let servers = self
+            .servers
+            .iter()
+            .filter(|svr| svr.tagged(tag))
+            .collect::<Vec<_>>();
+        if servers.is_empty() {
+            return self.fallback(tag);
+        }
 
         let etld = effective_tld_plus_one(host).unwrap_or(host);
-        let mut key = fnv(etld.as_bytes());
+        let key = fnv(etld.as_bytes());
         let buckets = servers.len();
 
-        for _i in 0..5 {
-            let index = jumphash(key, buckets as i64);
-            let svr = &servers[index as usize];
+        for attempt in 0..5 {
+            let index = jumphash(key.wrapping_add(attempt), buckets as i64);
+            let svr = servers[index as usize];
             if svr.alive() {
                 return svr.clone();
             }
-
-            key += 1;
         }
 
-        warn!(
-            message = "pick tcp server by etld+1 failed, use first alive peer",
+        // hashed among alive ones, so the site still sticks to one server,
+        // instead of all of them going to the first alive one
+        let alive = servers
+            .into_iter()
+            .filter(|svr| svr.alive())
+            .collect::<Vec<_>>();
+        if alive.is_empty() {
+            return self.fallback(tag);
+        }
+        debug!(
+            message = "servers of the host by etld+1 are down, hash among alive ones",
             host
         );
 
-        self.fallback()
+        alive[jumphash(key, alive.len() as i64) as usize].clone()
+    }
+
+    /// Smooth weighted round robin over alive servers, like nginx, so
+    /// servers are interleaved instead of picked in bursts
+    fn weighted(&self, tag: Option<&str>) -> Arc<Server> {
+        let mut current = self.current.lock();
+        match self.pick_weighted(tag, &self.weights, &mut current) {
+            Some(index) => self.servers[index].clone(),
+            // backups of weight 0
+            None => self.fallback(tag),
+        }
+    }
+
+    /// `weighted` among servers with `tag`, by the group's own `weights`,
+    /// servers not listed have their weight of `upstream.weights`
+    fn weighted_group(&self, tag: &str, weights: &BTreeMap<String, u32>) -> Arc<Server> {
+        let weights = self
+            .servers
+            .iter()
+            .zip(&self.weights)
+            .map(|(server, weight)| weights.get(&server.name()).copied().unwrap_or(*weight))
+            .collect::<Vec<_>>();
+
+        let mut groups = self.group_current.lock();
+        let current = groups
+            .entry(tag.to_string())
+            .or_insert_with(|| vec![0; self.servers.len()]);
+        match self.pick_weighted(Some(tag), &weights, current) {
+            Some(index) => self.servers[index].clone(),
+            None => self.fallback(Some(tag)),
+        }
     }
 
-    fn fallback(&self) -> Arc<Server> {
+    fn pick_weighted(
+        &self,
+        tag: Option<&str>,
+        weights: &[u32],
+        current: &mut [i64],
+    ) -> Option<usize> {
+        let mut total = 0;
+        let mut picked = None;
+
+        for (index, server) in self.servers.iter().enumerate() {
+            let weight = weights[index] as i64;
+            if weight == 0 || !server.alive() || !server.tagged(tag) {
+                continue;
+            }
+
+            current[index] += weight;
+            total += weight;
+            match picked {
+                Some(picked) if current[picked] >= current[index] => {}
+                _ => picked = Some(index),
+            }
+        }
+
+        if let Some(index) = picked {
+            current[index] -= total;
+        }
+        picked
+    }
+
+    /// Alive servers in turn, the order is the one of `servers`
+    fn round_robin(&self, tag: Option<&str>) -> Arc<Server> {
+        let alive = self
+            .servers
+            .iter()
+            .filter(|svr| svr.tagged(tag) && svr.alive())
+            .collect::<Vec<_>>();
+        if alive.is_empty() {
+            return self.fallback(tag);
+        }
+
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        alive[next % alive.len()].clone()
+    }
+
+    /// The alive server with the fewest connections in flight, the faster
+    /// one of a tie
+    fn least_connections(&self, tag: Option<&str>) -> Arc<Server> {
+        let least = self
+            .servers
+            .iter()
+            .filter(|svr| svr.tagged(tag) && svr.alive())
+            .min_by_key(|svr| (svr.connections(), svr.smoothed_latency()));
+        match least {
+            Some(least) => least.clone(),
+            None => self.fallback(tag),
+        }
+    }
+
+    /// The first alive server of `priority`, or of tagged ones. Once a
+    /// server is used, a preferred one replaces it after being alive for
+    /// `recover`, or at once if the used one is down.
+    fn by_priority(
+        &self,
+        tag: Option<&str>,
+        priority: &[String],
+        recover: Duration,
+    ) -> Arc<Server> {
+        let candidates = if priority.is_empty() {
+            (0..self.servers.len())
+                .filter(|&index| self.servers[index].tagged(tag))
+                .collect::<Vec<_>>()
+        } else {
+            priority
+                .iter()
+                .filter_map(|name| self.servers.iter().position(|svr| svr.name() == *name))
+                .collect()
+        };
+        let first = match candidates
+            .iter()
+            .copied()
+            .find(|&index| self.servers[index].alive())
+        {
+            Some(first) => first,
+            None => return self.fallback(tag),
+        };
+
+        let mut primaries = self.primaries.lock();
+        let primary = primaries
+            .entry(tag.unwrap_or_default().to_string())
+            .or_insert(Primary {
+                index: first,
+                recovering: None,
+            });
+        if !self.servers[primary.index].alive() || !candidates.contains(&primary.index) {
+            primary.index = first;
+        }
+        if primary.index == first {
+            primary.recovering = None;
+            return self.servers[first].clone();
+        }
+
+        // a preferred server is alive again
+        let since = match primary.recovering {
+            Some((index, since)) if index == first => since,
+            _ => Instant::now(),
+        };
+        if since.elapsed() >= recover {
+            info!(
+                message = "switch back to the preferred server",
+                name = self.servers[first].name()
+            );
+            *primary = Primary {
+                index: first,
+                recovering: None,
+            };
+        } else {
+            primary.recovering = Some((first, since));
+        }
+
+        self.servers[primary.index].clone()
+    }
+
+    /// Keep the latency history of servers which are in `old` too
+    fn inherit(&self, old: &Peers) {
+        for server in &self.servers {
+            if let Some(previous) = old.find(&server.name()) {
+                server.restore(&previous.history());
+            }
+        }
+    }
+
+    /// Keep the balancing state of servers which are in `old` too, by
+    /// address, so changing servers doesn't restart weighted and plain
+    /// round robins, or switch fallback groups and best servers
+    fn carry(&self, old: &Peers) {
+        let indexes = self
+            .servers
+            .iter()
+            .enumerate()
+            .map(|(index, svr)| (svr.config().addr().to_string(), index))
+            .rev()
+            .collect::<HashMap<_, _>>();
+        let remap = |index: usize| {
+            old.servers
+                .get(index)
+                .and_then(|svr| indexes.get(&svr.config().addr().to_string()).copied())
+        };
+        let remap_current = |old: &[i64]| {
+            let mut current = vec![0; self.servers.len()];
+            for (index, weight) in old.iter().enumerate() {
+                if let Some(index) = remap(index) {
+                    current[index] = *weight;
+                }
+            }
+            current
+        };
+
+        if let Some(best) = remap(old.best.load(Ordering::Relaxed)) {
+            self.best.store(best, Ordering::Relaxed);
+        }
+        *self.bests.lock() = old
+            .bests
+            .lock()
+            .iter()
+            .filter_map(|(tag, index)| Some((tag.clone(), remap(*index)?)))
+            .collect();
+        *self.current.lock() = remap_current(&old.current.lock());
+        *self.group_current.lock() = old
+            .group_current
+            .lock()
+            .iter()
+            .map(|(tag, current)| (tag.clone(), remap_current(current)))
+            .collect();
+        self.next
+            .store(old.next.load(Ordering::Relaxed), Ordering::Relaxed);
+        *self.primaries.lock() = old
+            .primaries
+            .lock()
+            .iter()
+            .filter_map(|(tag, primary)| {
+                let recovering = primary
+                    .recovering
+                    .and_then(|(index, since)| Some((remap(index)?, since)));
+                Some((
+                    tag.clone(),
+                    Primary {
+                        index: remap(primary.index)?,
+                        recovering,
+                    },
+                ))
+            })
+            .collect();
+    }
+
+    fn find(&self, name: &str) -> Option<Arc<Server>> {
+        self.servers.iter().find(|svr| svr.name() == name).cloned()
+    }
+
+    /// The first alive server with `tag`, or any alive one if there is
+    /// no such server
+    fn fallback(&self, tag: Option<&str>) -> Arc<Server> {
         for svr in &self.servers {
-            if svr.alive() {
+            if svr.alive() && svr.tagged(tag) {
                 return svr.clone();
             }
         }
+        if tag.is_some() {
+            warn!(message = "no alive proxy with the tag, use others", ?tag);
+            return self.fallback(None);
+        }
 
         warn!("no alive proxy, return the first one");
 
         self.servers[0].clone()
     }
 
-    async fn check_all(self: Arc<Self>, interval: Duration, timeout: Duration, resolver: Resolver) {
+    /// Top up warm connections of alive servers, and close expired ones
+    /// Dial warm connections with `opts` and `keepalive`, like connections
+    /// of `Upstream::connect`
+    async fn fill_warm(
+        &self,
+        warm: &WarmConfig,
+        timeout: Duration,
+        resolver: &Resolver,
+        opts: &ConnectOpts,
+        keepalive: Option<&KeepaliveConfig>,
+    ) {
+        let tasks = FuturesUnordered::new();
+        for server in &self.servers {
+            let kept = server.expire_warm(warm.idle_timeout);
+            // chained servers are reached by tunnels, not connections
+            if !server.alive() || server.via().is_some() {
+                continue;
+            }
+
+            for _ in kept..warm.connections {
+                let server = server.clone();
+                tasks.push(async move {
+                    match time::timeout(timeout, server.dial(resolver, opts)).await {
+                        Ok(Ok(stream)) => {
+                            set_keepalive(keepalive, &server, &stream);
+                            server.put_warm(stream)
+                        }
+                        Ok(Err(err)) => {
+                            debug!(
+                                message = "warm connection failed",
+                                ?err,
+                                upstream = server.name().as_str()
+                            )
+                        }
+                        Err(_) => {
+                            debug!(
+                                message = "warm connection timeout",
+                                upstream = server.name().as_str()
+                            )
+                        }
+                    }
+                });
+            }
+        }
+
+        let _n = tasks.collect::<Vec<_>>().await;
+    }
+
+    async fn check_all(self: Arc<Self>, check: CheckConfig, resolver: Resolver) {
         loop {
-            time::sleep(interval).await;
+            time::sleep(check.interval).await;
 
-            self.check_once(timeout, false, resolver.clone()).await;
+            self.check_once(&check, false, resolver.clone()).await;
             trace!(message = "finished initializing server scores");
         }
     }
 
-    async fn check_once(&self, timeout: Duration, first_run: bool, resolver: Resolver) {
+    async fn check_once(&self, check: &CheckConfig, first_run: bool, resolver: Resolver) {
         let servers = &self.servers;
         if servers.is_empty() {
             return;
@@ -105,35 +537,52 @@ impl Peers {
         let tasks = FuturesUnordered::new();
         for server in servers {
             tasks.push(
-                checker::Checker::new(server.clone(), resolver.clone(), timeout)
-                    .check_update_score(),
+                checker::Checker::new(server.clone(), resolver.clone(), check).check_update_score(),
             );
         }
 
         let _n = tasks.collect::<Vec<_>>().await;
 
+        self.update_best(first_run);
+    }
+
+    /// Whether the best server at `index` is kept, it's kept while it's
+    /// alive and not slower than `fastest` by more than the tolerance
+    fn keeps(&self, index: usize, tag: Option<&str>, fastest: u32) -> bool {
+        let best = &self.servers[index];
+        best.alive()
+            && best.tagged(tag)
+            && best.smoothed_latency() <= fastest.saturating_add(self.tolerance)
+    }
+
+    fn update_best(&self, first_run: bool) {
+        let servers = &self.servers;
         let mut best_index = 0;
         let mut best_latency = u32::MAX;
         for (index, server) in servers.iter().enumerate() {
-            let latency = server.latency();
-            if latency == 0 {
-                // not alive
+            if !server.alive() {
                 continue;
             }
 
+            let latency = server.smoothed_latency();
+
             if latency < best_latency {
                 best_index = index;
                 best_latency = latency;
             }
         }
 
+        let current = self.best.load(Ordering::Relaxed);
+        if !first_run && best_latency != u32::MAX && self.keeps(current, None, best_latency) {
+            return;
+        }
         self.best.store(best_index, Ordering::Relaxed);
 
         let best = &self.servers[best_index];
         let addr = best.config().addr().to_string();
         if first_run {
             info!(message = "choose best server", addr,);
-        } else {
+        } else if best_index != current {
             info!(message = "switch best server", addr,);
         }
     }
@@ -146,97 +595,1130 @@ pub enum Error {
 
     #[error(transparent)]
     Provider(#[from] provider::Error),
+
+    #[error("invalid server url \"{0}\", {1:?}")]
+    InvalidServer(String, UrlParseError),
+
+    #[error("no server, either servers or provider is required")]
+    NoServers,
+
+    #[error("server \"{0}\" reaches its connection limit")]
+    Full(String),
+
+    #[error("server \"{0}\" not found")]
+    UnknownServer(String),
+
+    #[error("server \"{0}\" exists already")]
+    DuplicateServer(String),
+}
+
+/// Servers added, replaced or removed through the controller, they are
+/// applied to servers fetched by the provider too, until the config is
+/// updated
+#[derive(Default)]
+struct Overrides {
+    added: Vec<Endpoint>,
+    removed: BTreeSet<String>,
+    /// Tags and weights changed, by server name
+    edited: BTreeMap<String, ServerEdit>,
+}
+
+/// Settings of a server changed through the controller, unset ones are
+/// kept
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerEdit {
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub weight: Option<u32>,
+}
+
+impl Overrides {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.edited.is_empty()
+    }
+
+    /// `config` with the edited tags and weights
+    fn config(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        for (name, edit) in &self.edited {
+            if let Some(tags) = &edit.tags {
+                config.tags.insert(name.clone(), tags.clone());
+            }
+            if let Some(weight) = edit.weight {
+                config.weights.insert(name.clone(), weight);
+            }
+        }
+
+        config
+    }
+
+    fn edit(&mut self, name: &str, edit: ServerEdit) {
+        let edited = self.edited.entry(name.to_string()).or_default();
+        if edit.tags.is_some() {
+            edited.tags = edit.tags;
+        }
+        if edit.weight.is_some() {
+            edited.weight = edit.weight;
+        }
+    }
+
+    /// Drop removed servers and ones replaced by added ones, then append
+    /// the added ones
+    fn apply(&self, servers: Vec<Arc<Server>>) -> Vec<Arc<Server>> {
+        let replaced = self.added.iter().map(name_of).collect::<BTreeSet<_>>();
+
+        servers
+            .into_iter()
+            .filter(|server| {
+                let name = server.name();
+                !self.removed.contains(&name) && !replaced.contains(&name)
+            })
+            .chain(
+                self.added
+                    .iter()
+                    .map(|sc| Arc::new(Server::new(sc.clone()))),
+            )
+            .collect()
+    }
+
+    fn add(&mut self, config: Endpoint) {
+        let name = name_of(&config);
+        self.removed.remove(&name);
+        self.added.retain(|sc| name_of(sc) != name);
+        self.added.push(config);
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.added.retain(|sc| name_of(sc) != name);
+        self.edited.remove(name);
+        self.removed.insert(name.to_string());
+    }
+}
+
+/// The replaced server must exist, and the new name must not be taken by
+/// others
+fn validate_put(peers: &Peers, replaced: Option<&str>, name: &str) -> Result<(), Error> {
+    if let Some(replaced) = replaced {
+        if peers.find(replaced).is_none() {
+            return Err(Error::UnknownServer(replaced.to_string()));
+        }
+    }
+    if replaced != Some(name) && peers.find(name).is_some() {
+        return Err(Error::DuplicateServer(name.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Parse `ss://`, `http://` and `https://` urls of static servers
+fn parse_servers(urls: &[String]) -> Result<Vec<Endpoint>, Error> {
+    urls.iter()
+        .map(|url| Endpoint::from_url(url).map_err(|err| Error::InvalidServer(url.clone(), err)))
+        .collect()
+}
+
+/// Options of connections to servers, `mark` is the one of a rule, the
+/// interface and mark of servers are added by `Server::dial`
+fn dial_opts(config: &Config, mark: Option<u32>) -> ConnectOpts {
+    let mut opts = ConnectOpts::default();
+    opts.tcp.fastopen = config.fast_open;
+    opts.fwmark = mark;
+
+    opts
+}
+
+/// Failures are only logged, the connection works without keepalive
+fn set_keepalive(keepalive: Option<&KeepaliveConfig>, server: &Server, stream: &TcpStream) {
+    if let Some(keepalive) = keepalive {
+        if let Err(err) = keepalive.apply(stream) {
+            debug!(
+                message = "set keepalive failed",
+                ?err,
+                upstream = server.name().as_str()
+            );
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Upstream {
     peers: Arc<RwLock<Arc<Peers>>>,
-    lb_type: LoadBalanceType,
+    lb_type: Arc<parking_lot::RwLock<LoadBalanceType>>,
+    limit: Arc<parking_lot::RwLock<LimitConfig>>,
+    warm: Arc<parking_lot::RwLock<WarmConfig>>,
+    resolver: Resolver,
+
+    /// The current config, servers changed through the controller are
+    /// built with it
+    config: Arc<parking_lot::RwLock<Config>>,
+    overrides: Arc<parking_lot::Mutex<Overrides>>,
+
+    /// Name of the server selected manually, it overrides `lb_type`
+    /// while the server is alive
+    selected: Arc<parking_lot::RwLock<Option<String>>>,
+    /// Wake up the provider task to fetch servers immediately
+    reload: Arc<Notify>,
+    /// Traffic of the subscription, if the provider reports it
+    usage: Arc<parking_lot::Mutex<Option<Usage>>>,
+    /// Health check and provider tasks, they are restarted when config changed
+    tasks: Arc<parking_lot::Mutex<Vec<JoinHandle<()>>>>,
 }
 
 impl Upstream {
     pub async fn new(config: Config, resolver: Resolver) -> Result<Self, Error> {
-        let check = config.check;
-        let provider = Provider::new(config.provider.endpoint, resolver.clone());
-        let servers = provider.load().await?;
+        let statics = parse_servers(&config.servers)?;
+        let usage = Arc::new(parking_lot::Mutex::new(None));
+        let provider = config
+            .provider
+            .as_ref()
+            .map(|pc| Provider::new(pc, resolver.clone(), usage.clone()));
+
+        let mut servers = statics
+            .iter()
+            .cloned()
+            .map(|sc| Arc::new(Server::new(sc)))
+            .collect::<Vec<_>>();
+        if let Some(provider) = &provider {
+            match provider.load().await {
+                Ok(fetched) => servers.extend(fetched),
+                Err(err) => match provider.cached() {
+                    Some(cached) => {
+                        warn!(message = "load servers failed, use cached ones", ?err);
+                        servers.extend(cached);
+                    }
+                    None => return Err(err.into()),
+                },
+            }
+        }
+        if servers.is_empty() {
+            return Err(Error::NoServers);
+        }
 
         info!(
             message = "load proxy servers success",
             total = servers.len()
         );
 
-        let peers = Arc::new(RwLock::new(Arc::new(Peers::new(servers))));
+        if let Some(path) = &config.history {
+            history::restore(&servers, &history::load(path));
+        }
+
+        let peers = Arc::new(RwLock::new(Arc::new(Peers::new(servers, &config))));
         {
             let cp = peers.read().await;
 
             // first check
-            cp.check_once(check.timeout, true, resolver.clone()).await;
+            cp.check_once(&config.check, true, resolver.clone()).await;
+        }
+
+        let upstream = Self {
+            peers,
+            lb_type: Arc::new(parking_lot::RwLock::new(config.load_balance)),
+            limit: Arc::new(parking_lot::RwLock::new(config.limit.clone())),
+            warm: Arc::new(parking_lot::RwLock::new(config.warm.clone())),
+            resolver,
+            config: Arc::new(parking_lot::RwLock::new(config.clone())),
+            overrides: Default::default(),
+            selected: Default::default(),
+            reload: Arc::new(Notify::new()),
+            usage,
+            tasks: Default::default(),
+        };
+        upstream.spawn(config, provider, statics);
+
+        Ok(upstream)
+    }
+
+    /// Apply the new config, servers are kept until the new ones are
+    /// loaded, so relaying connections are not affected.
+    pub async fn update(&self, config: Config) -> Result<(), Error> {
+        let statics = parse_servers(&config.servers)?;
+        if statics.is_empty() && config.provider.is_none() {
+            return Err(Error::NoServers);
         }
 
-        let cp = peers.clone();
-        let cr = resolver.clone();
-        tokio::spawn(async move {
+        *self.lb_type.write() = config.load_balance;
+        *self.limit.write() = config.limit.clone();
+        *self.warm.write() = config.warm.clone();
+        *self.config.write() = config.clone();
+        {
+            let mut overrides = self.overrides.lock();
+            if !overrides.is_empty() {
+                info!(message = "servers changed at runtime are dropped by the new config");
+                *overrides = Overrides::default();
+            }
+        }
+
+        let provider = config
+            .provider
+            .as_ref()
+            .map(|pc| Provider::new(pc, self.resolver.clone(), self.usage.clone()));
+        if provider.is_none() {
+            *self.usage.lock() = None;
+        }
+
+        if provider.is_some() {
+            // the provider task merges static servers and fetched ones
+            self.spawn(config, provider, statics);
+            self.reload();
+        } else {
+            let new = Peers::new(
+                statics
+                    .iter()
+                    .cloned()
+                    .map(|sc| Arc::new(Server::new(sc)))
+                    .collect(),
+                &config,
+            );
+            let old = self.peers.read().await.clone();
+            new.inherit(&old);
+            new.carry(&old);
+            new.check_once(&config.check, true, self.resolver.clone())
+                .await;
+            *self.peers.write().await = Arc::new(new);
+
+            self.spawn(config, provider, statics);
+        }
+
+        info!(message = "upstream config updated");
+
+        Ok(())
+    }
+
+    fn spawn(&self, config: Config, provider: Option<Provider>, statics: Vec<Endpoint>) {
+        let check = config.check.clone();
+        let mut tasks = self.tasks.lock();
+        tasks.drain(..).for_each(|task| task.abort());
+
+        let cp = self.peers.clone();
+        let cr = self.resolver.clone();
+        let history = config.history.clone();
+        tasks.push(tokio::spawn(async move {
             loop {
                 time::sleep(check.interval).await;
 
-                cp.read()
-                    .await
-                    .check_once(check.timeout, false, cr.clone())
-                    .await;
-            }
-        });
+                let peers = cp.read().await.clone();
+                peers.check_once(&check, false, cr.clone()).await;
 
-        // update servers periodically
-        {
-            let peers = peers.clone();
-            let interval = config.provider.interval;
-            let timeout = check.timeout;
+                if let Some(path) = &history {
+                    if let Err(err) = history::save(path, &peers.servers) {
+                        warn!(message = "save latency history failed", ?path, ?err);
+                    }
+                }
+            }
+        }));
 
-            tokio::spawn(async move {
+        let warm = config.warm.clone();
+        if warm.connections > 0 {
+            let peers = self.peers.clone();
+            let resolver = self.resolver.clone();
+            let timeout = config.check.timeout;
+            let opts = dial_opts(&config, None);
+            let keepalive = config.keepalive.clone();
+            tasks.push(tokio::spawn(async move {
                 loop {
-                    time::sleep(interval).await;
+                    time::sleep(WARM_INTERVAL).await;
 
-                    match provider.load().await {
-                        Ok(servers) => {
-                            let new = Peers::new(servers);
-                            new.check_once(timeout, true, resolver.clone()).await;
+                    let cp = peers.read().await.clone();
+                    cp.fill_warm(&warm, timeout, &resolver, &opts, keepalive.as_ref())
+                        .await;
+                }
+            }));
+        }
 
-                            let mut p = peers.write().await;
-                            *p = Arc::new(new);
+        let (provider, interval) = match (provider, &config.provider) {
+            (Some(provider), Some(pc)) => (provider, pc.interval),
+            _ => return,
+        };
 
-                            info!(message = "update servers success", total = p.servers.len());
-                        }
-                        Err(err) => {
-                            warn!(message = "reload servers failed", ?err);
+        // update servers periodically
+        let peers = self.peers.clone();
+        let overrides = self.overrides.clone();
+        let reload = self.reload.clone();
+        let resolver = self.resolver.clone();
+
+        tasks.push(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = time::sleep(interval) => {},
+                    _ = reload.notified() => {
+                        info!(message = "reload servers requested");
+                    }
+                }
+
+                match provider.load().await {
+                    Ok(fetched) => {
+                        let mut servers = statics
+                            .iter()
+                            .cloned()
+                            .map(|sc| Arc::new(Server::new(sc)))
+                            .collect::<Vec<_>>();
+                        servers.extend(fetched);
+                        let (servers, edited) = {
+                            let overrides = overrides.lock();
+                            (overrides.apply(servers), overrides.config(&config))
+                        };
+                        if servers.is_empty() {
+                            warn!(message = "no servers are fetched, keep the current ones");
+                            continue;
                         }
+
+                        let new = Peers::new(servers, &edited);
+                        let old = peers.read().await.clone();
+                        new.inherit(&old);
+                        new.carry(&old);
+                        new.check_once(&config.check, true, resolver.clone()).await;
+
+                        let mut p = peers.write().await;
+                        *p = Arc::new(new);
+
+                        info!(message = "update servers success", total = p.servers.len());
+                    }
+                    Err(err) => {
+                        // keep the current servers until the next successful fetch
+                        warn!(message = "reload servers failed", ?err);
                     }
                 }
-            });
+            }
+        }));
+    }
+
+    /// Pick a server by the load balance, among servers with `tag` if
+    /// it's set
+    pub async fn pick(&self, host: &str, tag: Option<&str>) -> Arc<Server> {
+        let peers = self.peers.read().await;
+
+        let selected = self.selected.read().clone();
+        if let Some(name) = selected {
+            match peers.find(&name) {
+                Some(svr) if svr.alive() && svr.tagged(tag) => return svr,
+                _ => {
+                    debug!(message = "selected server is not available", name);
+                }
+            }
         }
 
-        Ok(Self {
-            peers,
-            lb_type: config.load_balance,
-        })
+        // a group of the tag has its own load balance
+        let group = tag.and_then(|tag| self.config.read().groups.get(tag).cloned());
+        let lb_type = match &group {
+            Some(group) => group.load_balance,
+            None => *self.lb_type.read(),
+        };
+        let group = group.unwrap_or_default();
+        match lb_type {
+            LoadBalanceType::Best => peers.best(tag),
+            LoadBalanceType::Etld => peers.by_etld(host, tag),
+            LoadBalanceType::Weighted => match tag {
+                Some(tag) if !group.weights.is_empty() => peers.weighted_group(tag, &group.weights),
+                _ => peers.weighted(tag),
+            },
+            LoadBalanceType::RoundRobin => peers.round_robin(tag),
+            LoadBalanceType::LeastConnections => peers.least_connections(tag),
+            LoadBalanceType::Fallback => peers.by_priority(tag, &group.servers, group.recover),
+        }
     }
 
-    pub async fn pick(&self, host: &str) -> Arc<Server> {
-        let peers = self.peers.read().await;
+    /// Pick a server for a new connection, and take a slot of it. The
+    /// connection is counted until the permit is dropped.
+    pub async fn acquire(
+        &self,
+        host: &str,
+        tag: Option<&str>,
+    ) -> Result<(Arc<Server>, Permit), Error> {
+        let server = self.pick(host, tag).await;
+        if let Some(permit) = server.try_acquire() {
+            return Ok((server, permit));
+        }
+
+        let (overflow, queue_timeout) = {
+            let limit = self.limit.read();
+            (limit.overflow, limit.queue_timeout)
+        };
+        match overflow {
+            Overflow::Spill => {
+                let peers = self.peers.read().await;
+                for other in &peers.servers {
+                    if !other.alive() || !other.tagged(tag) || Arc::ptr_eq(other, &server) {
+                        continue;
+                    }
+                    if let Some(permit) = other.try_acquire() {
+                        debug!(
+                            message = "server is full, spill to another one",
+                            full = server.name(),
+                            spilled = other.name()
+                        );
+                        return Ok((other.clone(), permit));
+                    }
+                }
+            }
+            Overflow::Queue => {
+                let deadline = time::Instant::now() + queue_timeout;
+                while time::timeout_at(deadline, server.released()).await.is_ok() {
+                    if let Some(permit) = server.try_acquire() {
+                        return Ok((server, permit));
+                    }
+                }
+            }
+            Overflow::Reject => {}
+        }
+
+        Err(Error::Full(server.name()))
+    }
+
+    /// Tunnel to `target` through the server, over a warm connection if
+    /// there is one. `mark` is the `SO_MARK` of the connection instead of
+    /// the server's, warm connections are not used with it.
+    pub async fn connect(
+        &self,
+        server: &Server,
+        target: Address,
+        mark: Option<u32>,
+    ) -> io::Result<Tunnel> {
+        let max_idle = self.warm.read().idle_timeout;
+        let warm = match mark {
+            Some(_) => None,
+            None => server.take_warm(max_idle),
+        };
+        if let Some(stream) = warm {
+            trace!(
+                message = "use warm connection",
+                upstream = server.name().as_str()
+            );
+            self.set_keepalive(server, &stream);
+            return server.tunnel(stream, target).await;
+        }
+
+        // refused `CONNECT` of HTTP proxies counts as a failed dial
+        server.begin_dial();
+        let opts = dial_opts(&self.config.read(), mark);
+        let result = match server.via() {
+            Some(via) => server.tunnel_via(&via, &self.resolver, &opts, target).await,
+            None => match server.dial(&self.resolver, &opts).await {
+                Ok(stream) => {
+                    self.set_keepalive(server, &stream);
+                    server.tunnel(stream, target).await
+                }
+                Err(err) => Err(err),
+            },
+        };
+        server.record_dial(result.is_ok());
+
+        result
+    }
+
+    fn set_keepalive(&self, server: &Server, stream: &TcpStream) {
+        let keepalive = self.config.read().keepalive.clone();
+        set_keepalive(keepalive.as_ref(), server, stream);
+    }
+
+    /// A UDP socket relaying datagrams through the server
+    pub async fn udp_socket(&self, server: &Server, mark: Option<u32>) -> io::Result<ProxySocket> {
+        let opts = ConnectOpts {
+            fwmark: mark,
+            ..Default::default()
+        };
+        server.udp_socket(&self.resolver, &opts).await
+    }
+
+    /// Add the server of the `ss://` or `http://` url, it's checked before
+    /// it's used
+    pub async fn add_server(&self, url: &str) -> Result<(), Error> {
+        self.put_server(None, url).await
+    }
+
+    /// Replace the server named `name` by the one of the url
+    pub async fn replace_server(&self, name: &str, url: &str) -> Result<(), Error> {
+        self.put_server(Some(name), url).await
+    }
+
+    async fn put_server(&self, replaced: Option<&str>, url: &str) -> Result<(), Error> {
+        let sc =
+            Endpoint::from_url(url).map_err(|err| Error::InvalidServer(url.to_string(), err))?;
+        let name = name_of(&sc);
+        {
+            let peers = self.peers.read().await;
+            validate_put(&peers, replaced, &name)?;
+        }
+
+        let config = self.config.read().clone();
+        let server = Arc::new(Server::new(sc.clone()));
+        checker::Checker::new(server.clone(), self.resolver.clone(), &config.check)
+            .check_update_score()
+            .await;
+
+        // it may be changed while checking
+        let mut peers = self.peers.write().await;
+        validate_put(&peers, replaced, &name)?;
+
+        let mut servers = peers.servers();
+        match replaced {
+            Some(replaced) => {
+                for svr in servers.iter_mut() {
+                    if svr.name() == replaced {
+                        *svr = server.clone();
+                    }
+                }
+            }
+            None => servers.push(server),
+        }
+
+        let config = {
+            let mut overrides = self.overrides.lock();
+            if let Some(replaced) = replaced {
+                overrides.remove(replaced);
+            }
+            overrides.add(sc);
+            overrides.config(&config)
+        };
+
+        let new = Peers::new(servers, &config);
+        new.carry(&peers);
+        new.update_best(false);
+        *peers = Arc::new(new);
+
+        info!(message = "server updated at runtime", name, ?replaced);
+
+        Ok(())
+    }
+
+    /// Remove the server named `name`, the last one can't be removed
+    pub async fn remove_server(&self, name: &str) -> Result<(), Error> {
+        let mut peers = self.peers.write().await;
+        if peers.find(name).is_none() {
+            return Err(Error::UnknownServer(name.to_string()));
+        }
+        if peers.servers.len() == 1 {
+            return Err(Error::NoServers);
+        }
+
+        let config = {
+            let mut overrides = self.overrides.lock();
+            overrides.remove(name);
+            overrides.config(&self.config.read())
+        };
+
+        let servers = peers
+            .servers()
+            .into_iter()
+            .filter(|svr| svr.name() != name)
+            .collect();
+        let new = Peers::new(servers, &config);
+        new.carry(&peers);
+        new.update_best(false);
+        *peers = Arc::new(new);
+
+        info!(message = "server removed at runtime", name);
+
+        Ok(())
+    }
+
+    /// Change tags or the weight of the server named `name`, they are
+    /// kept until the config is updated
+    pub async fn edit_server(&self, name: &str, edit: ServerEdit) -> Result<(), Error> {
+        let mut peers = self.peers.write().await;
+        if peers.find(name).is_none() {
+            return Err(Error::UnknownServer(name.to_string()));
+        }
+
+        let config = {
+            let mut overrides = self.overrides.lock();
+            overrides.edit(name, edit);
+            overrides.config(&self.config.read())
+        };
+        let new = Peers::new(peers.servers(), &config);
+        new.carry(&peers);
+        new.update_best(false);
+        *peers = Arc::new(new);
+
+        info!(message = "server edited at runtime", name);
 
-        match self.lb_type {
-            LoadBalanceType::Best => peers.best(),
-            LoadBalanceType::Etld => peers.by_etld(host),
+        Ok(())
+    }
+
+    /// Always use the server named `name` while it is alive, `None` restores
+    /// the configured load balance. Returns false if there is no such server.
+    pub async fn select(&self, name: Option<String>) -> bool {
+        if let Some(name) = &name {
+            if self.peers.read().await.find(name).is_none() {
+                return false;
+            }
         }
+
+        info!(message = "select server", ?name);
+        *self.selected.write() = name;
+
+        true
+    }
+
+    /// Fetch servers from the provider now, instead of waiting for the
+    /// next interval
+    pub fn reload(&self) {
+        self.reload.notify_one();
+    }
+
+    /// Traffic of the subscription, reported by SIP008 providers
+    pub fn usage(&self) -> Option<Usage> {
+        *self.usage.lock()
+    }
+
+    /// Stat of the server named `name`, with its latency history
+    pub async fn stat(&self, name: &str) -> Option<Stat> {
+        self.peers.read().await.find(name).map(|svr| svr.stat())
     }
 
-    pub async fn stats(&self) -> Vec<Stat> {
+    /// Stats of all servers, or the ones with `tag`
+    pub async fn stats(&self, tag: Option<&str>) -> Vec<Stat> {
         let mut stats = vec![];
         let peers = self.peers.read().await;
 
         for svr in &peers.servers {
-            stats.push(svr.stat());
+            if svr.tagged(tag) {
+                stats.push(svr.stat());
+            }
         }
 
         stats
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted() {
+        let config = Config {
+            load_balance: LoadBalanceType::Weighted,
+            check: CheckConfig::default(),
+            servers: vec![],
+            provider: None,
+            weights: [("a".to_string(), 3), ("c".to_string(), 0)].into(),
+            tags: [("b".to_string(), vec!["asia".to_string()])].into(),
+            groups: Default::default(),
+            via: Default::default(),
+            resolve: Default::default(),
+            re_resolve: None,
+            shadow_tls: Default::default(),
+            tls: Default::default(),
+            history: None,
+            limit: LimitConfig::default(),
+            warm: WarmConfig::default(),
+            breaker: None,
+            fast_open: false,
+            keepalive: None,
+            interface: None,
+            interfaces: BTreeMap::new(),
+            mark: None,
+            marks: BTreeMap::new(),
+        };
+        let servers = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388#{}", name);
+                let server = Server::new(Endpoint::from_url(&url).unwrap());
+                server.push_latency(100);
+                Arc::new(server)
+            })
+            .collect();
+        let peers = Peers::new(servers, &config);
+
+        let picked = (0..8)
+            .map(|_| peers.weighted(None).name())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["a", "a", "b", "a", "a", "a", "b", "a"]);
+
+        // backups are used if others are down
+        peers.servers[0].report_failure();
+        peers.servers[1].report_failure();
+        assert_eq!(peers.weighted(None).name(), "c");
+
+        // only tagged ones, or others if they are down
+        peers.servers[1].push_latency(100);
+        assert_eq!(peers.weighted(Some("asia")).name(), "b");
+        assert_eq!(peers.by_etld("example.com", Some("asia")).name(), "b");
+        peers.servers[1].report_failure();
+        assert_eq!(peers.best(Some("asia")).name(), "c");
+    }
+
+    #[test]
+    fn weighted_group() {
+        let config = Config {
+            load_balance: LoadBalanceType::Best,
+            check: CheckConfig::default(),
+            servers: vec![],
+            provider: None,
+            weights: Default::default(),
+            tags: [
+                ("a".to_string(), vec!["x".to_string()]),
+                ("b".to_string(), vec!["x".to_string()]),
+            ]
+            .into(),
+            groups: Default::default(),
+            via: Default::default(),
+            resolve: Default::default(),
+            re_resolve: None,
+            shadow_tls: Default::default(),
+            tls: Default::default(),
+            history: None,
+            limit: LimitConfig::default(),
+            warm: WarmConfig::default(),
+            breaker: None,
+            fast_open: false,
+            keepalive: None,
+            interface: None,
+            interfaces: BTreeMap::new(),
+            mark: None,
+            marks: BTreeMap::new(),
+        };
+        let servers = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388#{}", name);
+                let server = Server::new(Endpoint::from_url(&url).unwrap());
+                server.push_latency(100);
+                Arc::new(server)
+            })
+            .collect();
+        let peers = Peers::new(servers, &config);
+
+        // 70/30 between the tagged ones
+        let weights = [("a".to_string(), 7), ("b".to_string(), 3)].into();
+        let picked = (0..10)
+            .map(|_| peers.weighted_group("x", &weights).name())
+            .collect::<Vec<_>>();
+        assert_eq!(picked.iter().filter(|name| *name == "a").count(), 7);
+        assert_eq!(picked.iter().filter(|name| *name == "b").count(), 3);
+
+        // the weights of the pool are not affected
+        let picked = (0..3)
+            .map(|_| peers.weighted(None).name())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn by_etld() {
+        let config = Config {
+            load_balance: LoadBalanceType::Etld,
+            check: CheckConfig::default(),
+            servers: vec![],
+            provider: None,
+            weights: Default::default(),
+            tags: Default::default(),
+            groups: Default::default(),
+            via: Default::default(),
+            resolve: Default::default(),
+            re_resolve: None,
+            shadow_tls: Default::default(),
+            tls: Default::default(),
+            history: None,
+            limit: LimitConfig::default(),
+            warm: WarmConfig::default(),
+            breaker: None,
+            fast_open: false,
+            keepalive: None,
+            interface: None,
+            interfaces: BTreeMap::new(),
+            mark: None,
+            marks: BTreeMap::new(),
+        };
+        let servers = (0..8)
+            .map(|index| {
+                let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388#{}", index);
+                let server = Server::new(Endpoint::from_url(&url).unwrap());
+                server.push_latency(100);
+                Arc::new(server)
+            })
+            .collect();
+        let peers = Peers::new(servers, &config);
+
+        // a site goes through the same server
+        let picked = peers.by_etld("example.co.uk", None).name();
+        for _ in 0..4 {
+            assert_eq!(peers.by_etld("example.co.uk", None).name(), picked);
+        }
+
+        // and sticks to another one while it's down
+        let index = peers.servers.iter().position(|svr| svr.name() == picked);
+        peers.servers[index.unwrap()].report_failure();
+        let other = peers.by_etld("example.co.uk", None).name();
+        assert_ne!(other, picked);
+        for _ in 0..4 {
+            assert_eq!(peers.by_etld("example.co.uk", None).name(), other);
+        }
+
+        // all but one down
+        for svr in &peers.servers[1..] {
+            svr.report_failure();
+        }
+        peers.servers[0].push_latency(100);
+        assert_eq!(peers.by_etld("example.co.uk", None).name(), "0");
+    }
+
+    #[test]
+    fn round_robin() {
+        let config = Config {
+            load_balance: LoadBalanceType::RoundRobin,
+            check: CheckConfig::default(),
+            servers: vec![],
+            provider: None,
+            weights: Default::default(),
+            tags: Default::default(),
+            groups: Default::default(),
+            via: Default::default(),
+            resolve: Default::default(),
+            re_resolve: None,
+            shadow_tls: Default::default(),
+            tls: Default::default(),
+            history: None,
+            limit: LimitConfig::default(),
+            warm: WarmConfig::default(),
+            breaker: None,
+            fast_open: false,
+            keepalive: None,
+            interface: None,
+            interfaces: BTreeMap::new(),
+            mark: None,
+            marks: BTreeMap::new(),
+        };
+        let servers = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388#{}", name);
+                let server = Server::new(Endpoint::from_url(&url).unwrap());
+                server.push_latency(100);
+                Arc::new(server)
+            })
+            .collect();
+        let peers = Peers::new(servers, &config);
+
+        let picked = (0..4)
+            .map(|_| peers.round_robin(None).name())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["a", "b", "c", "a"]);
+
+        // the server with fewer connections, the first one of a tie
+        let _a = peers.servers[0].try_acquire().unwrap();
+        let _c = peers.servers[2].try_acquire().unwrap();
+        assert_eq!(peers.least_connections(None).name(), "b");
+        let _b = peers.servers[1].try_acquire().unwrap();
+        assert_eq!(peers.least_connections(None).name(), "a");
+
+        // dead servers are skipped
+        peers.servers[0].report_failure();
+        assert_eq!(peers.least_connections(None).name(), "b");
+        let picked = (0..2)
+            .map(|_| peers.round_robin(None).name())
+            .collect::<Vec<_>>();
+        assert!(picked.contains(&"b".to_string()) && picked.contains(&"c".to_string()));
+    }
+
+    #[test]
+    fn carry() {
+        let config: Config = serde_yaml::from_str("{check: {}, weights: {a: 2}}").unwrap();
+        let servers = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| {
+                    let port = 8380 + (name.as_bytes()[0] - b'a') as u16;
+                    let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:{}#{}", port, name);
+                    let server = Server::new(Endpoint::from_url(&url).unwrap());
+                    server.push_latency(100);
+                    Arc::new(server)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let old = Peers::new(servers(&["a", "b", "c"]), &config);
+        assert_eq!(old.round_robin(None).name(), "a");
+        assert_eq!(old.weighted(None).name(), "a");
+        assert_eq!(old.weighted(None).name(), "b");
+        old.best.store(2, Ordering::Relaxed);
+
+        // "d" is added, the turns go on
+        let new = Peers::new(servers(&["a", "b", "c", "d"]), &config);
+        new.carry(&old);
+        assert_eq!(new.round_robin(None).name(), "b");
+        assert_eq!(new.best(None).name(), "c");
+        let picked = (0..5)
+            .map(|_| new.weighted(None).name())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["c", "a", "d", "a", "b"]);
+    }
+
+    #[test]
+    fn by_priority() {
+        let config = Config {
+            load_balance: LoadBalanceType::Fallback,
+            check: CheckConfig::default(),
+            servers: vec![],
+            provider: None,
+            weights: Default::default(),
+            tags: [("b".to_string(), vec!["x".to_string()])].into(),
+            groups: Default::default(),
+            via: Default::default(),
+            resolve: Default::default(),
+            re_resolve: None,
+            shadow_tls: Default::default(),
+            tls: Default::default(),
+            history: None,
+            limit: LimitConfig::default(),
+            warm: WarmConfig::default(),
+            breaker: None,
+            fast_open: false,
+            keepalive: None,
+            interface: None,
+            interfaces: BTreeMap::new(),
+            mark: None,
+            marks: BTreeMap::new(),
+        };
+        let servers = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388#{}", name);
+                let server = Server::new(Endpoint::from_url(&url).unwrap());
+                server.push_latency(100);
+                Arc::new(server)
+            })
+            .collect();
+        let peers = Peers::new(servers, &config);
+        let priority = ["c".to_string(), "a".to_string()];
+        let hour = Duration::from_secs(3600);
+
+        assert_eq!(peers.by_priority(None, &priority, hour).name(), "c");
+        peers.servers[2].report_failure();
+        assert_eq!(peers.by_priority(None, &priority, hour).name(), "a");
+
+        // it's kept until the preferred one has recovered for a while
+        peers.servers[2].push_latency(100);
+        assert_eq!(peers.by_priority(None, &priority, hour).name(), "a");
+        assert_eq!(
+            peers.by_priority(None, &priority, Duration::ZERO).name(),
+            "c"
+        );
+
+        // tagged ones in the order of the pool without priority
+        assert_eq!(peers.by_priority(Some("x"), &[], hour).name(), "b");
+    }
+
+    #[test]
+    fn tolerance() {
+        let config = Config {
+            load_balance: LoadBalanceType::Best,
+            check: CheckConfig {
+                tolerance: Duration::from_millis(50),
+                ..CheckConfig::default()
+            },
+            servers: vec![],
+            provider: None,
+            weights: Default::default(),
+            tags: [
+                ("a".to_string(), vec!["x".to_string()]),
+                ("b".to_string(), vec!["x".to_string()]),
+            ]
+            .into(),
+            groups: Default::default(),
+            via: Default::default(),
+            resolve: Default::default(),
+            re_resolve: None,
+            shadow_tls: Default::default(),
+            tls: Default::default(),
+            history: None,
+            limit: LimitConfig::default(),
+            warm: WarmConfig::default(),
+            breaker: None,
+            fast_open: false,
+            keepalive: None,
+            interface: None,
+            interfaces: BTreeMap::new(),
+            mark: None,
+            marks: BTreeMap::new(),
+        };
+        let servers = [("a", 100), ("b", 200)]
+            .iter()
+            .map(|(name, latency)| {
+                let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388#{}", name);
+                let server = Server::new(Endpoint::from_url(&url).unwrap());
+                server.push_latency(*latency);
+                Arc::new(server)
+            })
+            .collect();
+        let peers = Peers::new(servers, &config);
+        peers.update_best(true);
+        assert_eq!(peers.best(None).name(), "a");
+        assert_eq!(peers.best(Some("x")).name(), "a");
+
+        // b is faster by less than the tolerance, mean 63 against 100
+        for _ in 0..9 {
+            peers.servers[1].push_latency(48);
+        }
+        peers.update_best(false);
+        assert_eq!(peers.best(None).name(), "a");
+        assert_eq!(peers.best(Some("x")).name(), "a");
+
+        // by more than it, mean 63 against 250
+        peers.servers[0].push_latency(400);
+        peers.update_best(false);
+        assert_eq!(peers.best(None).name(), "b");
+        assert_eq!(peers.best(Some("x")).name(), "b");
+    }
+
+    #[test]
+    fn overrides() {
+        let config = |name: &str, port: u16| {
+            let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:{}#{}", port, name);
+            Endpoint::from_url(&url).unwrap()
+        };
+        let fetched = || {
+            ["a", "b", "c"]
+                .iter()
+                .map(|name| Arc::new(Server::new(config(name, 8388))))
+                .collect::<Vec<_>>()
+        };
+
+        let mut overrides = Overrides::default();
+        overrides.add(config("d", 8388));
+        overrides.add(config("b", 9000));
+        overrides.remove("c");
+
+        let servers = overrides.apply(fetched());
+        let names = servers.iter().map(|svr| svr.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "d", "b"]);
+        assert_eq!(servers[2].config().addr().to_string(), "127.0.0.1:9000");
+
+        // removing an added one drops it
+        overrides.remove("d");
+        let names = overrides
+            .apply(fetched())
+            .iter()
+            .map(|svr| svr.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b"]);
+
+        // edits are merged, and dropped with the server
+        let config: Config = serde_yaml::from_str("{check: {}, weights: {a: 2}}").unwrap();
+        overrides.edit(
+            "a",
+            ServerEdit {
+                tags: Some(vec!["asia".to_string()]),
+                weight: None,
+            },
+        );
+        overrides.edit(
+            "b",
+            ServerEdit {
+                tags: None,
+                weight: Some(5),
+            },
+        );
+        let edited = overrides.config(&config);
+        assert_eq!(edited.tags["a"], ["asia"]);
+        assert_eq!(edited.weights["a"], 2);
+        assert_eq!(edited.weights["b"], 5);
+
+        overrides.remove("b");
+        assert!(!overrides.config(&config).weights.contains_key("b"));
+    }
+}
@@ -0,0 +1,179 @@
+//! SIP003 plugins, e.g. `obfs-local` and `v2ray-plugin`. A server with
+//! `plugin` in its url is reached through a plugin process, which listens
+//! on a local port and forwards to the server, e.g.
+//!
+//! ```text
+//! ss://YWVzLTEyOC1nY206cGFzcw@example.com:443?plugin=obfs-local%3Bobfs%3Dtls%3Bobfs-host%3Dexample.com#hk
+//! ```
+//!
+//! The plugin is started by the first connection to the server, started
+//! again if it exits, and killed when the server is dropped, e.g. removed
+//! by a reload. Only TCP goes through plugins, UDP is relayed to the
+//! server directly.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use shadowsocks::{Address, ConnectOpts, PluginConfig, ProxyStream};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// How long a started plugin has to listen on its local port
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Plugin {
+    child: Child,
+    local: SocketAddr,
+}
+
+impl Plugin {
+    /// Spawn the plugin forwarding a free local port to `remote`
+    pub fn start(config: &PluginConfig, remote: &Address) -> io::Result<Self> {
+        let (host, port) = match remote {
+            Address::SocketAddress(addr) => (addr.ip().to_string(), addr.port()),
+            Address::DomainNameAddress(domain, port) => (domain.clone(), *port),
+        };
+        // the port is free when it's probed, plugins can't be given a
+        // listener
+        let local = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+
+        let mut command = Command::new(&config.plugin);
+        command
+            .env("SS_REMOTE_HOST", host)
+            .env("SS_REMOTE_PORT", port.to_string())
+            .env("SS_LOCAL_HOST", local.ip().to_string())
+            .env("SS_LOCAL_PORT", local.port().to_string())
+            .stdin(Stdio::null());
+        if let Some(opts) = &config.plugin_opts {
+            command.env("SS_PLUGIN_OPTIONS", opts);
+        }
+        // servers are not dropped when the process exits, e.g. after an
+        // upgrade, the plugin must not outlive it
+        unsafe {
+            command.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+
+        info!(
+            message = "plugin started",
+            plugin = config.plugin.as_str(),
+            pid = child.id(),
+            ?local
+        );
+
+        Ok(Self { child, local })
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Connect to the local port of a plugin just started, which may not be
+/// listening yet
+pub async fn connect_started(addr: SocketAddr) -> io::Result<TcpStream> {
+    let deadline = time::Instant::now() + START_TIMEOUT;
+    loop {
+        match ProxyStream::connect_server_addr(addr, &ConnectOpts::default()).await {
+            Err(err)
+                if err.kind() == io::ErrorKind::ConnectionRefused
+                    && time::Instant::now() < deadline =>
+            {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = shadowsocks::ServerConfig::from_url(
+            "ss://YWVzLTEyOC1nY206cGFzcw@example.com:443?plugin=obfs-local%3Bobfs%3Dtls%3Bobfs-host%3Dexample.com#hk",
+        )
+        .unwrap();
+        assert_eq!(
+            config.plugin(),
+            Some(&PluginConfig {
+                plugin: "obfs-local".to_string(),
+                plugin_opts: Some("obfs=tls;obfs-host=example.com".to_string()),
+            })
+        );
+
+        let config = shadowsocks::ServerConfig::from_url(
+            "ss://YWVzLTEyOC1nY206cGFzcw@example.com:443?plugin=v2ray-plugin",
+        )
+        .unwrap();
+        assert_eq!(config.plugin().unwrap().plugin_opts, None);
+        assert!(shadowsocks::ServerConfig::from_url(
+            "ss://YWVzLTEyOC1nY206cGFzcw@example.com:443?plugin="
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn lifecycle() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("roxy-plugin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("plugin");
+        let env = dir.join("env");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$SS_REMOTE_HOST:$SS_REMOTE_PORT $SS_PLUGIN_OPTIONS\" > {}\nexec sleep 10\n",
+                env.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = PluginConfig {
+            plugin: script.display().to_string(),
+            plugin_opts: Some("obfs=http".to_string()),
+        };
+        let remote = Address::DomainNameAddress("example.com".to_string(), 443);
+        let mut plugin = Plugin::start(&config, &remote).unwrap();
+        assert!(plugin.local_addr().ip().is_loopback());
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(plugin.running());
+        assert_eq!(
+            std::fs::read_to_string(&env).unwrap(),
+            "example.com:443 obfs=http\n"
+        );
+        drop(plugin);
+
+        let config = PluginConfig {
+            plugin: dir.join("missing").display().to_string(),
+            plugin_opts: None,
+        };
+        assert!(Plugin::start(&config, &remote).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
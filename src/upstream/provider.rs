@@ -1,26 +1,29 @@
-use std::io::BufRead;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 
+use crate::upstream::config::ProviderConfig;
 use crate::upstream::server::Server;
-use base64::DecodeError;
+use crate::upstream::Endpoint;
 use hyper::http::uri::InvalidUri;
 use hyper::{StatusCode, Uri};
+use parking_lot::Mutex;
 use resolver::Resolver;
-use shadowsocks::{ServerConfig, UrlParseError};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     InvalidUri(#[from] InvalidUri),
     #[error(transparent)]
-    Decode(#[from] DecodeError),
-    #[error(transparent)]
     Http(#[from] hyper::Error),
     #[error("unexpected status code {0}")]
     Unexpected(StatusCode),
-    #[error("parse server url failed, {0:?}")]
-    ServerUrl(UrlParseError),
+    #[error("unknown subscription format, neither share links, SIP008 nor Clash proxies")]
+    UnknownFormat,
+    #[error("no valid server in the subscription")]
+    Empty,
 }
 
 impl From<StatusCode> for Error {
@@ -29,32 +32,72 @@ impl From<StatusCode> for Error {
     }
 }
 
-impl From<UrlParseError> for Error {
-    fn from(err: UrlParseError) -> Self {
-        Self::ServerUrl(err)
-    }
+/// Traffic of the subscription, SIP008 documents report it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Usage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_used: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_remaining: Option<u64>,
 }
 
 pub struct Provider {
     endpoint: String,
+    /// The last fetched subscription is saved here
+    cache: Option<PathBuf>,
     resolver: Resolver,
+    /// Of the last loaded subscription
+    usage: Arc<Mutex<Option<Usage>>>,
 }
 
 impl Provider {
-    pub fn new(endpoint: String, resolver: Resolver) -> Self {
-        Self { endpoint, resolver }
+    pub fn new(
+        config: &ProviderConfig,
+        resolver: Resolver,
+        usage: Arc<Mutex<Option<Usage>>>,
+    ) -> Self {
+        Self {
+            endpoint: config.endpoint.clone(),
+            cache: config.cache.clone(),
+            resolver,
+            usage,
+        }
     }
 
+    /// Fetch the subscription, and save it to the cache file if it is valid
     pub async fn load(&self) -> Result<Vec<Arc<Server>>, Error> {
-        let servers = self.fetch().await?;
+        let data = self.fetch().await?;
+        let (servers, usage) = parse(&data)?;
+        *self.usage.lock() = usage;
 
-        Ok(servers
-            .into_iter()
-            .map(|config| Arc::new(Server::new(config)))
-            .collect())
+        if let Some(path) = &self.cache {
+            if let Err(err) = std::fs::write(path, &data) {
+                warn!(message = "save subscription cache failed", ?path, ?err);
+            }
+        }
+
+        Ok(into_servers(servers))
     }
 
-    async fn fetch(&self) -> Result<Vec<ServerConfig>, Error> {
+    /// Servers of the last successful fetch, it's used when fetching
+    /// fails on startup, e.g. the endpoint is blocked.
+    pub fn cached(&self) -> Option<Vec<Arc<Server>>> {
+        let path = self.cache.as_ref()?;
+        let data = std::fs::read(path).ok()?;
+
+        match parse(&data) {
+            Ok((servers, usage)) => {
+                *self.usage.lock() = usage;
+                Some(into_servers(servers))
+            }
+            Err(err) => {
+                warn!(message = "invalid subscription cache", ?path, ?err);
+                None
+            }
+        }
+    }
+
+    async fn fetch(&self) -> Result<Vec<u8>, Error> {
         let client = crate::http::HttpClient::new(self.resolver.clone());
         let uri = Uri::from_str(&self.endpoint)?;
         let resp = client.get(uri).await?;
@@ -66,10 +109,229 @@ impl Provider {
 
         let data = hyper::body::to_bytes(body).await?;
 
-        base64::decode(data.as_ref())?
+        Ok(data.to_vec())
+    }
+}
+
+fn into_servers(configs: Vec<Endpoint>) -> Vec<Arc<Server>> {
+    configs
+        .into_iter()
+        .map(|config| Arc::new(Server::new(config)))
+        .collect()
+}
+
+/// Parse a subscription, it's either share links, e.g. `ss://` and
+/// `vmess://` urls, one per line, which may be base64 encoded, a SIP008
+/// JSON document, or a Clash provider YAML with `proxies`. Servers can't
+/// be parsed are skipped.
+fn parse(data: &[u8]) -> Result<(Vec<Endpoint>, Option<Usage>), Error> {
+    let trimmed = String::from_utf8_lossy(data);
+    let trimmed = trimmed.trim();
+
+    // providers often strip the padding, and wrap lines
+    let joined = trimmed.split_whitespace().collect::<String>();
+    let decoded = [
+        base64::STANDARD,
+        base64::URL_SAFE,
+        base64::STANDARD_NO_PAD,
+        base64::URL_SAFE_NO_PAD,
+    ]
+    .iter()
+    .find_map(|config| base64::decode_config(&joined, *config).ok())
+    .unwrap_or_else(|| trimmed.as_bytes().to_vec());
+
+    let mut usage = None;
+    let servers = if is_links(&decoded) {
+        String::from_utf8_lossy(&decoded)
             .lines()
-            .flatten()
-            .map(|url| ServerConfig::from_url(&url).map_err(Into::into))
-            .collect::<Result<Vec<ServerConfig>, Error>>()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .filter_map(|url| match Endpoint::from_url(url) {
+                Ok(config) => Some(config),
+                Err(err) => {
+                    warn!(message = "skip invalid server url", url, ?err);
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+    } else if let Ok(sip008) = serde_json::from_slice::<Sip008>(&decoded) {
+        usage = Some(Usage {
+            bytes_used: sip008.bytes_used,
+            bytes_remaining: sip008.bytes_remaining,
+        });
+        sip008
+            .servers
+            .iter()
+            .filter_map(Sip008Server::endpoint)
+            .collect()
+    } else {
+        parse_clash(&decoded)?
+    };
+
+    if servers.is_empty() {
+        return Err(Error::Empty);
+    }
+
+    Ok((servers, usage))
+}
+
+/// SIP008 online configuration, fields not used are ignored
+#[derive(Deserialize)]
+struct Sip008 {
+    servers: Vec<Sip008Server>,
+    #[serde(default)]
+    bytes_used: Option<u64>,
+    #[serde(default)]
+    bytes_remaining: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Sip008Server {
+    #[serde(default)]
+    remarks: Option<String>,
+    server: String,
+    server_port: u16,
+    password: String,
+    method: String,
+    #[serde(default)]
+    plugin: Option<String>,
+    #[serde(default)]
+    plugin_opts: Option<String>,
+}
+
+impl Sip008Server {
+    fn endpoint(&self) -> Option<Endpoint> {
+        let plugin = match (&self.plugin, &self.plugin_opts) {
+            (Some(plugin), _) if plugin.is_empty() => None,
+            (Some(plugin), Some(opts)) if !opts.is_empty() => Some(format!("{};{}", plugin, opts)),
+            (Some(plugin), _) => Some(plugin.clone()),
+            (None, _) => None,
+        };
+        let url = crate::config::ss_url(
+            &self.method,
+            &self.password,
+            &self.server,
+            self.server_port as u64,
+            self.remarks.as_deref().unwrap_or_default(),
+            plugin.as_deref(),
+        );
+
+        match Endpoint::from_url(&url) {
+            Ok(endpoint) => Some(endpoint),
+            Err(err) => {
+                warn!(
+                    message = "skip invalid server",
+                    server = self.server.as_str(),
+                    ?err
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Whether the subscription is share links, by the scheme of the first one
+fn is_links(data: &[u8]) -> bool {
+    ["ss://", "vmess://", "vless://", "http://", "https://"]
+        .iter()
+        .any(|scheme| data.starts_with(scheme.as_bytes()))
+}
+
+fn parse_clash(data: &[u8]) -> Result<Vec<Endpoint>, Error> {
+    let value: Value = serde_yaml::from_slice(data).map_err(|_| Error::UnknownFormat)?;
+    let proxies = match value.get("proxies") {
+        Some(Value::Sequence(proxies)) => proxies,
+        _ => return Err(Error::UnknownFormat),
+    };
+
+    let servers = proxies
+        .iter()
+        .filter_map(|proxy| {
+            let url = match crate::config::clash_proxy(proxy) {
+                Ok(url) => url,
+                Err(reason) => {
+                    let name = proxy.get("name").and_then(Value::as_str);
+                    warn!(message = "skip unsupported proxy", ?name, reason);
+                    return None;
+                }
+            };
+
+            Endpoint::from_url(&url).ok()
+        })
+        .collect();
+
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_subscription() {
+        let urls = "ss://YWVzLTEyOC1nY206cGFzcw@a.example.com:8388#a\n\
+                    ss://YWVzLTEyOC1nY206cGFzcw@b.example.com:8388#b\n";
+        assert_eq!(parse(urls.as_bytes()).unwrap().0.len(), 2);
+        assert_eq!(parse(base64::encode(urls).as_bytes()).unwrap().0.len(), 2);
+        let unpadded = base64::encode_config(urls, base64::URL_SAFE_NO_PAD);
+        assert_eq!(parse(unpadded.as_bytes()).unwrap().0.len(), 2);
+
+        let links = "vless://b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4@c.example.com:443#c\n\
+                     ss://YWVzLTEyOC1nY206cGFzcw@a.example.com:8388#a\n";
+        let (servers, _) = parse(base64::encode(links).as_bytes()).unwrap();
+        assert!(matches!(servers[0], Endpoint::Vless(_)));
+        assert_eq!(servers.len(), 2);
+
+        let clash = r#"
+proxies:
+  - name: a
+    type: ss
+    server: a.example.com
+    port: 8388
+    cipher: aes-128-gcm
+    password: pass
+  - name: b
+    type: vmess
+    server: b.example.com
+    port: 443
+"#;
+        assert_eq!(parse(clash.as_bytes()).unwrap().0.len(), 1);
+
+        let sip008 = r#"{
+  "version": 1,
+  "servers": [
+    {
+      "id": "27b8a625-4f4b-4428-9f0f-8a2317db7c79",
+      "remarks": "a",
+      "server": "a.example.com",
+      "server_port": 8388,
+      "password": "pass",
+      "method": "aes-128-gcm",
+      "plugin": "obfs-local",
+      "plugin_opts": "obfs=http;obfs-host=example.com"
+    },
+    {
+      "server": "b.example.com",
+      "server_port": 8388,
+      "password": "pass",
+      "method": "unknown"
+    }
+  ],
+  "bytes_used": 274877906944,
+  "bytes_remaining": 824633720832
+}"#;
+        let (servers, usage) = parse(sip008.as_bytes()).unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].remarks().unwrap(), "a");
+        assert_eq!(
+            usage,
+            Some(Usage {
+                bytes_used: Some(274877906944),
+                bytes_remaining: Some(824633720832),
+            })
+        );
+
+        assert!(matches!(parse(b"<html></html>"), Err(Error::UnknownFormat)));
+        assert!(matches!(parse(b"proxies: []"), Err(Error::Empty)));
     }
 }
@@ -0,0 +1,212 @@
+//! How hostnames of servers are resolved, each server can have its own
+//! address family preference, nameservers or static addresses, e.g.
+//!
+//! ```yaml
+//! upstream:
+//!   resolve:
+//!     hk-01:
+//!       strategy: ipv4_only
+//!       nameservers: 223.5.5.5:53
+//!     jp-01:
+//!       addresses: [203.0.113.10, 203.0.113.11]
+//! ```
+//!
+//! Servers not listed are resolved by `resolvers`, and all addresses are
+//! raced by Happy Eyeballs, see `eyeballs`, like `happy_eyeballs`. UDP
+//! goes to the first address of them.
+//!
+//! Hostnames are resolved by every connection, unless `re_resolve` keeps
+//! the addresses for its `interval`, they are resolved again earlier after
+//! `failures` connects to them failed in a row, e.g. the provider moved
+//! the server to other addresses.
+//!
+//! ```yaml
+//! upstream:
+//!   re_resolve:
+//!     interval: 10m
+//!     failures: 3
+//! ```
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use resolver::{ResolveError, Resolver};
+use serde::Deserialize;
+
+use crate::serde::duration;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// IPv6 addresses are used if there is no IPv4 one
+    #[default]
+    Ipv4First,
+    /// IPv4 addresses are used if there is no IPv6 one
+    Ipv6First,
+    Ipv4Only,
+    Ipv6Only,
+    /// Race connects to all addresses, IPv6 ones first, UDP prefers IPv4
+    HappyEyeballs,
+}
+
+impl Strategy {
+    /// The preferred address of `ips`
+    pub fn pick(&self, ips: &[IpAddr]) -> Option<IpAddr> {
+        let v4 = ips.iter().find(|ip| ip.is_ipv4()).copied();
+        let v6 = ips.iter().find(|ip| ip.is_ipv6()).copied();
+
+        match self {
+            Strategy::Ipv4First | Strategy::HappyEyeballs => v4.or(v6),
+            Strategy::Ipv6First => v6.or(v4),
+            Strategy::Ipv4Only => v4,
+            Strategy::Ipv6Only => v6,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ResolveConfig {
+    #[serde(default)]
+    pub strategy: Strategy,
+
+    /// Resolve the hostname by these instead of `resolvers`, e.g. to avoid
+    /// resolving it by a nameserver which is reached through the server
+    #[serde(default, with = "crate::serde::socket_addrs")]
+    pub nameservers: Vec<SocketAddr>,
+
+    /// Connect to these addresses, the hostname is not resolved at all
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+}
+
+const fn default_interval() -> Duration {
+    Duration::from_secs(600)
+}
+
+const fn default_failures() -> u32 {
+    3
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReResolveConfig {
+    /// How long resolved addresses of a server are used
+    #[serde(default = "default_interval", with = "duration")]
+    pub interval: Duration,
+
+    /// Failed connects in a row to resolve the hostname again before the
+    /// interval, 0 disables it
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+}
+
+impl Default for ReResolveConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            failures: default_failures(),
+        }
+    }
+}
+
+pub struct ServerResolver {
+    strategy: Strategy,
+    resolver: Option<Resolver>,
+    addresses: Vec<IpAddr>,
+}
+
+impl ServerResolver {
+    pub fn new(config: &ResolveConfig) -> Result<Self, ResolveError> {
+        let resolver = if config.nameservers.is_empty() {
+            None
+        } else {
+            Some(Resolver::new(config.nameservers.clone())?)
+        };
+
+        Ok(Self {
+            strategy: config.strategy,
+            resolver,
+            addresses: config.addresses.clone(),
+        })
+    }
+
+    /// Resolve `host` of the server, by its own nameservers if they are
+    /// set, or `default`
+    pub async fn resolve(
+        &self,
+        default: &Resolver,
+        host: &str,
+        port: u16,
+    ) -> io::Result<SocketAddr> {
+        let ips = self.lookup(default, host).await?;
+
+        match self.strategy.pick(&ips) {
+            Some(ip) => Ok(SocketAddr::new(ip, port)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address of {} matches {:?}", host, self.strategy),
+            )),
+        }
+    }
+
+    /// Addresses to connect to, all of them for `HappyEyeballs`, otherwise
+    /// the one `resolve` returns
+    pub async fn resolve_all(
+        &self,
+        default: &Resolver,
+        host: &str,
+        port: u16,
+    ) -> io::Result<Vec<SocketAddr>> {
+        if self.strategy != Strategy::HappyEyeballs {
+            return Ok(vec![self.resolve(default, host, port).await?]);
+        }
+
+        let ips = self.lookup(default, host).await?;
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Drop cached answers of the nameservers `lookup` uses
+    pub fn clear_cache(&self, default: &Resolver) {
+        if self.addresses.is_empty() {
+            self.resolver.as_ref().unwrap_or(default).clear_cache();
+        }
+    }
+
+    async fn lookup(&self, default: &Resolver, host: &str) -> io::Result<Vec<IpAddr>> {
+        if !self.addresses.is_empty() {
+            return Ok(self.addresses.clone());
+        }
+
+        Ok(self
+            .resolver
+            .as_ref()
+            .unwrap_or(default)
+            .lookup_ip(host)
+            .await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick() {
+        let ips = ["2001:db8::1".parse().unwrap(), "192.0.2.1".parse().unwrap()];
+
+        assert_eq!(Strategy::Ipv4First.pick(&ips), Some(ips[1]));
+        assert_eq!(Strategy::Ipv6First.pick(&ips), Some(ips[0]));
+        assert_eq!(Strategy::Ipv6Only.pick(&ips[1..]), None);
+        assert_eq!(Strategy::Ipv6First.pick(&ips[1..]), Some(ips[1]));
+        assert_eq!(Strategy::HappyEyeballs.pick(&ips), Some(ips[1]));
+    }
+}
@@ -1,18 +1,39 @@
 use std::collections::VecDeque;
-use std::time::{Instant, SystemTime};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use parking_lot::Mutex;
+use resolver::Resolver;
 use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
-use shadowsocks::ServerConfig;
+use shadowsocks::{Address, ConnectOpts, ProxySocket, ProxyStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_rustls::rustls::ClientConfig;
 
+use crate::upstream::endpoint::{Endpoint, Tunnel};
+use crate::upstream::eyeballs;
+use crate::upstream::history::Record;
+use crate::upstream::http;
+use crate::upstream::plugin::{self, Plugin};
+use crate::upstream::resolve::{ReResolveConfig, ServerResolver};
+use crate::upstream::shadow_tls::{self, ShadowTlsConfig};
+use crate::upstream::tls::{self, TlsConfig};
+use crate::upstream::BreakerConfig;
+use crate::upstream::{vless, vmess};
 use crate::DateTime;
 
 const MAX_HISTORY: usize = 10;
 
 #[derive(Clone)]
 struct Latency {
-    timestamp: Instant,
+    timestamp: SystemTime,
     value: u32,
 }
 
@@ -23,10 +44,7 @@ impl Serialize for Latency {
     {
         let mut s = serializer.serialize_struct("Latency", 2)?;
 
-        let system_now = SystemTime::now();
-        let instant_now = Instant::now();
-        let approx = system_now - (instant_now - self.timestamp);
-        let datetime = DateTime::from(approx);
+        let datetime = DateTime::from(self.timestamp);
 
         s.serialize_field("timestamp", &datetime.to_string())?;
         s.serialize_field("value", &self.value)?;
@@ -35,15 +53,185 @@ impl Serialize for Latency {
     }
 }
 
+/// Health with hysteresis, it flips only after enough consecutive results
+struct Health {
+    /// `None` until the first result, which decides it directly
+    healthy: Option<bool>,
+    passes: u32,
+    failures: u32,
+
+    healthy_threshold: u32,
+    unhealthy_threshold: u32,
+}
+
+impl Health {
+    fn record(&mut self, passed: bool) {
+        if passed {
+            self.passes += 1;
+            self.failures = 0;
+        } else {
+            self.failures += 1;
+            self.passes = 0;
+        }
+
+        self.healthy = match self.healthy {
+            None => Some(passed),
+            Some(false) if self.passes >= self.healthy_threshold => Some(true),
+            Some(true) if self.failures >= self.unhealthy_threshold => Some(false),
+            keep => keep,
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Circuit {
+    Closed,
+    /// Not selected until the instant
+    Open(Instant),
+    /// A trial connection is dialing
+    HalfOpen,
+}
+
+impl Serialize for Circuit {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let state = match self {
+            Circuit::Closed => "closed",
+            Circuit::Open(until) if Instant::now() >= *until => "half_open",
+            Circuit::Open(_) => "open",
+            Circuit::HalfOpen => "half_open",
+        };
+
+        serializer.serialize_str(state)
+    }
+}
+
+/// Dial results of the server and the circuit decided by them
+struct Breaker {
+    config: BreakerConfig,
+    /// true means failed
+    results: VecDeque<bool>,
+    circuit: Circuit,
+}
+
+impl Breaker {
+    fn allows(&self) -> bool {
+        match self.circuit {
+            Circuit::Closed => true,
+            Circuit::Open(until) => Instant::now() >= until,
+            Circuit::HalfOpen => false,
+        }
+    }
+
+    fn record(&mut self, failed: bool) {
+        let cooldown = self.config.cooldown;
+        match self.circuit {
+            Circuit::Closed => {
+                if self.results.len() >= self.config.window {
+                    self.results.pop_front();
+                }
+                self.results.push_back(failed);
+
+                let failures = self.results.iter().filter(|failed| **failed).count();
+                if self.results.len() >= self.config.min_requests
+                    && failures as f64 >= self.config.failure_rate * self.results.len() as f64
+                {
+                    self.circuit = Circuit::Open(Instant::now() + cooldown);
+                }
+            }
+            // the trial decides, dials started before opening are ignored
+            Circuit::HalfOpen | Circuit::Open(_) => {
+                if failed {
+                    self.circuit = Circuit::Open(Instant::now() + cooldown);
+                } else {
+                    self.results.clear();
+                    self.circuit = Circuit::Closed;
+                }
+            }
+        }
+    }
+}
+
+/// Name of the server of `config`, see `Server::name`
+pub fn name_of(config: &Endpoint) -> String {
+    match config.remarks() {
+        Some(remarks) => remarks.clone(),
+        None => config.addr().to_string(),
+    }
+}
+
 pub struct Server {
-    config: ServerConfig,
+    config: Endpoint,
 
     latencies: Mutex<VecDeque<Latency>>,
+    health: Mutex<Health>,
+
+    /// In-flight connections, and the cap of them, 0 means unlimited
+    active: AtomicUsize,
+    max_connections: AtomicUsize,
+    /// Notified when a connection closed
+    released: Notify,
+
+    /// Connections and UDP sessions relayed since the server is added, and
+    /// their payload bytes, upload is from clients to the server
+    relayed: AtomicU64,
+    upload: AtomicU64,
+    download: AtomicU64,
+
+    /// Connections established ahead, and when they are established
+    warm: Mutex<VecDeque<(Instant, TcpStream)>>,
+
+    breaker: Mutex<Option<Breaker>>,
+
+    tags: Mutex<Vec<String>>,
+
+    /// Resolves the hostname of the server, `resolvers` are used if it's
+    /// not set
+    resolver: Mutex<Option<Arc<ServerResolver>>>,
+    /// Resolved addresses are kept if it's set
+    re_resolve: Mutex<Option<ReResolveConfig>>,
+    /// Addresses of the hostname, and when they are resolved
+    resolved: Mutex<Option<(Instant, Vec<SocketAddr>)>>,
+    /// Connects failed in a row
+    connect_failures: AtomicU32,
+
+    /// The SIP003 plugin process, started by the first connection
+    plugin: Mutex<Option<Plugin>>,
+
+    /// Wraps the shadowsocks stream if it's set
+    shadow_tls: Mutex<Option<Arc<ShadowTlsConfig>>>,
+
+    /// Trust settings of TLS to the server, see `tls`
+    tls: Mutex<Option<TlsConfig>>,
+    /// Built from `tls` by the first TLS connection
+    tls_client: Mutex<Option<Arc<ClientConfig>>>,
+
+    /// Network interface connections to the server go through
+    interface: Mutex<Option<String>>,
+    /// `SO_MARK` of connections to the server
+    mark: Mutex<Option<u32>>,
+
+    /// The server is reached by a tunnel of this one
+    via: Mutex<Option<Arc<Server>>>,
+}
+
+/// A slot of the server's connections, it's released when dropped
+pub struct Permit {
+    server: Arc<Server>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.server.active.fetch_sub(1, Ordering::AcqRel);
+        self.server.released.notify_one();
+    }
 }
 
 impl Server {
     #[inline]
-    pub fn config(&self) -> &ServerConfig {
+    pub fn config(&self) -> &Endpoint {
         &self.config
     }
 
@@ -51,18 +239,533 @@ impl Server {
         self.config.remarks()
     }
 
-    pub fn new(config: ServerConfig) -> Self {
+    /// Remarks of the server, or it's address if remarks is not set
+    pub fn name(&self) -> String {
+        name_of(&self.config)
+    }
+
+    pub fn new(config: impl Into<Endpoint>) -> Self {
         Self {
-            config,
+            config: config.into(),
             latencies: Mutex::new(VecDeque::with_capacity(MAX_HISTORY)),
+            health: Mutex::new(Health {
+                healthy: None,
+                passes: 0,
+                failures: 0,
+                healthy_threshold: 1,
+                unhealthy_threshold: 1,
+            }),
+            active: AtomicUsize::new(0),
+            max_connections: AtomicUsize::new(0),
+            released: Notify::new(),
+            relayed: AtomicU64::new(0),
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            warm: Mutex::new(VecDeque::new()),
+            breaker: Mutex::new(None),
+            tags: Mutex::new(vec![]),
+            resolver: Mutex::new(None),
+            re_resolve: Mutex::new(None),
+            resolved: Mutex::new(None),
+            connect_failures: AtomicU32::new(0),
+            plugin: Mutex::new(None),
+            shadow_tls: Mutex::new(None),
+            tls: Mutex::new(None),
+            tls_client: Mutex::new(None),
+            interface: Mutex::new(None),
+            mark: Mutex::new(None),
+            via: Mutex::new(None),
+        }
+    }
+
+    pub fn set_max_connections(&self, max: usize) {
+        self.max_connections.store(max, Ordering::Relaxed);
+    }
+
+    /// Connections in flight
+    pub fn connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Take a slot if the server isn't full
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let max = self.max_connections.load(Ordering::Relaxed);
+        let mut active = self.active.load(Ordering::Acquire);
+        loop {
+            if max != 0 && active >= max {
+                return None;
+            }
+
+            match self.active.compare_exchange_weak(
+                active,
+                active + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    return Some(Permit {
+                        server: self.clone(),
+                    })
+                }
+                Err(current) => active = current,
+            }
+        }
+    }
+
+    /// Wrap the client side stream of a connection relayed by the server,
+    /// so the traffic is added to the server's
+    pub fn meter<S>(self: &Arc<Self>, stream: S) -> Metered<S> {
+        self.relayed.fetch_add(1, Ordering::Relaxed);
+        Metered {
+            inner: stream,
+            server: self.clone(),
+        }
+    }
+
+    /// A UDP session relayed by the server is opened
+    pub fn add_session(&self) {
+        self.relayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_upload(&self, n: u64) {
+        self.upload.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_download(&self, n: u64) {
+        self.download.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Wait until a connection of the server closed
+    pub async fn released(&self) {
+        self.released.notified().await
+    }
+
+    /// Take the newest warm connection which is not closed by the server
+    /// and not older than `max_idle`
+    pub fn take_warm(&self, max_idle: Duration) -> Option<TcpStream> {
+        let mut warm = self.warm.lock();
+        while let Some((established, stream)) = warm.pop_back() {
+            if established.elapsed() >= max_idle {
+                // the rest are older
+                warm.clear();
+                return None;
+            }
+
+            // servers send nothing before the first packet, so anything
+            // readable means it's closed or broken
+            match stream.try_read(&mut [0u8; 1]) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Some(stream),
+                _ => continue,
+            }
+        }
+
+        None
+    }
+
+    pub fn put_warm(&self, stream: TcpStream) {
+        self.warm.lock().push_back((Instant::now(), stream));
+    }
+
+    /// Close warm connections older than `max_idle`, returns the number of
+    /// the rest
+    pub fn expire_warm(&self, max_idle: Duration) -> usize {
+        let mut warm = self.warm.lock();
+        while let Some((established, _)) = warm.front() {
+            if established.elapsed() < max_idle {
+                break;
+            }
+            warm.pop_front();
         }
+
+        warm.len()
+    }
+
+    /// Consecutive results required to flip the health, 1 by default
+    pub fn set_thresholds(&self, healthy: u32, unhealthy: u32) {
+        let mut health = self.health.lock();
+        health.healthy_threshold = healthy.max(1);
+        health.unhealthy_threshold = unhealthy.max(1);
     }
 
+    pub fn set_resolver(&self, resolver: Option<Arc<ServerResolver>>) {
+        *self.resolver.lock() = resolver;
+    }
+
+    pub fn set_re_resolve(&self, config: Option<ReResolveConfig>) {
+        *self.re_resolve.lock() = config;
+    }
+
+    pub fn set_shadow_tls(&self, config: Option<Arc<ShadowTlsConfig>>) {
+        *self.shadow_tls.lock() = config;
+    }
+
+    pub fn set_tls(&self, config: Option<TlsConfig>) {
+        let mut tls = self.tls.lock();
+        if *tls != config {
+            *tls = config;
+            *self.tls_client.lock() = None;
+        }
+    }
+
+    /// Client config of TLS to the server, an invalid `tls` fails
+    /// connections instead of falling back to the system roots
+    fn tls_client(&self) -> io::Result<Arc<ClientConfig>> {
+        let mut client = self.tls_client.lock();
+        if let Some(client) = &*client {
+            return Ok(client.clone());
+        }
+
+        let built = tls::client_config(self.tls.lock().as_ref())?;
+        *client = Some(built.clone());
+
+        Ok(built)
+    }
+
+    pub fn set_interface(&self, interface: Option<String>) {
+        *self.interface.lock() = interface;
+    }
+
+    pub fn set_mark(&self, mark: Option<u32>) {
+        *self.mark.lock() = mark;
+    }
+
+    pub fn set_via(&self, via: Option<Arc<Server>>) {
+        *self.via.lock() = via;
+    }
+
+    pub fn via(&self) -> Option<Arc<Server>> {
+        self.via.lock().clone()
+    }
+
+    /// `opts` with the settings of the server
+    fn connect_opts(&self, opts: &ConnectOpts) -> ConnectOpts {
+        let mut opts = opts.clone();
+        if let Some(interface) = &*self.interface.lock() {
+            opts.bind_interface = Some(interface.clone());
+        }
+        // the mark of a rule goes first
+        if opts.fwmark.is_none() {
+            opts.fwmark = *self.mark.lock();
+        }
+
+        opts
+    }
+
+    /// Connect to the server, the hostname is resolved by the server's
+    /// resolver, or `default`. Servers with a plugin are connected through
+    /// the plugin's local port.
+    pub async fn dial(&self, default: &Resolver, opts: &ConnectOpts) -> io::Result<TcpStream> {
+        if let Some((local, started)) = self.plugin()? {
+            if started {
+                return plugin::connect_started(local).await;
+            }
+            return ProxyStream::connect_server_addr(local, &ConnectOpts::default()).await;
+        }
+
+        let addrs = self.resolve_all(default).await?;
+        let opts = self.connect_opts(opts);
+
+        let result =
+            eyeballs::connect(addrs, |addr| ProxyStream::connect_server_addr(addr, &opts)).await;
+        if result.is_ok() {
+            self.connect_failures.store(0, Ordering::Relaxed);
+        } else {
+            self.connect_failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    /// Tunnel to `target` over a stream connected by `dial`, HTTP proxies
+    /// are asked by `CONNECT` and VMess and VLESS servers get the request
+    /// header now, shadowsocks servers get the target with the first data,
+    /// after the shadow-tls handshake if it's set
+    pub async fn tunnel(&self, stream: TcpStream, target: Address) -> io::Result<Tunnel> {
+        match &self.config {
+            Endpoint::Shadowsocks(config) => {
+                let shadow_tls = self.shadow_tls.lock().clone();
+                match shadow_tls {
+                    Some(shadow_tls) => {
+                        let stream = shadow_tls::connect(stream, &shadow_tls).await?;
+                        Ok(Tunnel::ShadowTls(ProxyStream::from_stream(
+                            stream, config, target,
+                        )))
+                    }
+                    None => Ok(Tunnel::Shadowsocks(ProxyStream::from_stream(
+                        stream, config, target,
+                    ))),
+                }
+            }
+            Endpoint::Http(config) => {
+                let tls = config.tls().then(|| self.tls_client()).transpose()?;
+                http::connect(stream, config, &target, tls)
+                    .await
+                    .map(Tunnel::Http)
+            }
+            Endpoint::Vmess(config) => vmess::connect(stream, config, &target)
+                .await
+                .map(Tunnel::Vmess),
+            Endpoint::Vless(config) => {
+                let tls = config.tls().then(|| self.tls_client()).transpose()?;
+                vless::connect(stream, config, &target, tls)
+                    .await
+                    .map(Tunnel::Vless)
+            }
+        }
+    }
+
+    /// Tunnel to `target` through the server, which is reached by a tunnel
+    /// of `via` to it. Only shadowsocks servers without a plugin or
+    /// shadow-tls can be chained.
+    pub async fn tunnel_via(
+        &self,
+        via: &Server,
+        default: &Resolver,
+        opts: &ConnectOpts,
+        target: Address,
+    ) -> io::Result<Tunnel> {
+        let config = match &self.config {
+            Endpoint::Shadowsocks(config)
+                if config.plugin().is_none() && self.shadow_tls.lock().is_none() =>
+            {
+                config
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only shadowsocks servers can be chained",
+                ))
+            }
+        };
+
+        let stream = via.dial(default, opts).await?;
+        let hop = via.tunnel(stream, self.config.addr().clone()).await?;
+
+        Ok(Tunnel::Chained(Box::new(ProxyStream::from_stream(
+            hop, config, target,
+        ))))
+    }
+
+    /// Like `dial`, but a UDP socket for relaying datagrams through the
+    /// server, only shadowsocks servers are supported
+    pub async fn udp_socket(
+        &self,
+        default: &Resolver,
+        opts: &ConnectOpts,
+    ) -> io::Result<ProxySocket> {
+        if self.via.lock().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "udp through chained servers is not supported",
+            ));
+        }
+        let config = match &self.config {
+            Endpoint::Shadowsocks(config) => config,
+            Endpoint::Http(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "http proxies can't relay udp",
+                ))
+            }
+            Endpoint::Vmess(_) | Endpoint::Vless(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "udp through vmess and vless servers is not supported",
+                ))
+            }
+        };
+        let addr = self.resolve(default).await?;
+
+        ProxySocket::connect_server_addr(config, addr, &self.connect_opts(opts))
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Local address of the plugin, and whether it's just started. It's
+    /// started again if it exited.
+    fn plugin(&self) -> io::Result<Option<(SocketAddr, bool)>> {
+        let config = match &self.config {
+            Endpoint::Shadowsocks(config) => match config.plugin() {
+                Some(config) => config,
+                None => return Ok(None),
+            },
+            Endpoint::Http(_) | Endpoint::Vmess(_) | Endpoint::Vless(_) => return Ok(None),
+        };
+
+        let mut plugin = self.plugin.lock();
+        if let Some(running) = plugin.as_mut() {
+            if running.running() {
+                return Ok(Some((running.local_addr(), false)));
+            }
+            warn!(
+                message = "plugin exited, start it again",
+                upstream = self.name().as_str()
+            );
+        }
+
+        let started = Plugin::start(config, self.config.addr())?;
+        let local = started.local_addr();
+        *plugin = Some(started);
+
+        Ok(Some((local, true)))
+    }
+
+    async fn resolve(&self, default: &Resolver) -> io::Result<SocketAddr> {
+        let kept = self.re_resolve.lock().is_some();
+        let addr = match self.config.addr() {
+            Address::SocketAddress(addr) => *addr,
+            // kept addresses are picked like `resolve` of the resolver does
+            Address::DomainNameAddress(domain, _) if kept => {
+                let addrs = self.resolve_all(default).await?;
+                let ips = addrs.iter().map(SocketAddr::ip).collect::<Vec<_>>();
+                let picked = match &*self.resolver.lock() {
+                    Some(resolver) => resolver.strategy().pick(&ips),
+                    None => ips.first().copied(),
+                };
+                match picked {
+                    Some(ip) => SocketAddr::new(ip, addrs[0].port()),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("no address of {}", domain),
+                        ))
+                    }
+                }
+            }
+            Address::DomainNameAddress(domain, port) => {
+                let resolver = self.resolver.lock().clone();
+                match resolver {
+                    Some(resolver) => resolver.resolve(default, domain, *port).await?,
+                    None => default.resolve(domain, *port).await?,
+                }
+            }
+        };
+
+        Ok(addr)
+    }
+
+    /// Addresses `dial` races, see `eyeballs`
+    async fn resolve_all(&self, default: &Resolver) -> io::Result<Vec<SocketAddr>> {
+        let (domain, port) = match self.config.addr() {
+            Address::SocketAddress(addr) => return Ok(vec![*addr]),
+            Address::DomainNameAddress(domain, port) => (domain, *port),
+        };
+        let re_resolve = self.re_resolve.lock().clone();
+        let re_resolve = match re_resolve {
+            Some(re_resolve) => re_resolve,
+            None => return self.lookup(default, domain, port).await,
+        };
+
+        let failures = self.connect_failures.load(Ordering::Relaxed);
+        if re_resolve.failures > 0 && failures >= re_resolve.failures {
+            // the stale addresses may be cached by the resolver too
+            debug!(
+                message = "resolve server again after failed connects",
+                name = self.name(),
+                failures
+            );
+            self.connect_failures.store(0, Ordering::Relaxed);
+            if let Some(resolver) = &*self.resolver.lock() {
+                resolver.clear_cache(default);
+            } else {
+                default.clear_cache();
+            }
+        } else if let Some((resolved, addrs)) = &*self.resolved.lock() {
+            if resolved.elapsed() < re_resolve.interval {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let addrs = self.lookup(default, domain, port).await?;
+        let mut resolved = self.resolved.lock();
+        if let Some((_, old)) = &*resolved {
+            if *old != addrs {
+                info!(
+                    message = "addresses of server changed",
+                    name = self.name(),
+                    ?addrs
+                );
+            }
+        }
+        *resolved = Some((Instant::now(), addrs.clone()));
+
+        Ok(addrs)
+    }
+
+    async fn lookup(
+        &self,
+        default: &Resolver,
+        domain: &str,
+        port: u16,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let resolver = self.resolver.lock().clone();
+        let addrs = match resolver {
+            Some(resolver) => resolver.resolve_all(default, domain, port).await?,
+            None => default.lookup(domain, port).await?,
+        };
+
+        Ok(addrs)
+    }
+
+    pub fn set_tags(&self, tags: Vec<String>) {
+        *self.tags.lock() = tags;
+    }
+
+    /// Whether the server has `tag`, every server matches `None`
+    pub fn tagged(&self, tag: Option<&str>) -> bool {
+        match tag {
+            Some(tag) => self.tags.lock().iter().any(|t| t == tag),
+            None => true,
+        }
+    }
+
+    pub fn set_breaker(&self, config: Option<BreakerConfig>) {
+        *self.breaker.lock() = config.map(|config| Breaker {
+            config,
+            results: VecDeque::new(),
+            circuit: Circuit::Closed,
+        });
+    }
+
+    /// Healthy, and not stopped by the circuit breaker
     #[inline]
     pub fn alive(&self) -> bool {
-        let history = self.latencies.lock();
-        let latency = history.back().expect("at least on latency saved");
-        latency.value > 0
+        let allowed = match &*self.breaker.lock() {
+            Some(breaker) => breaker.allows(),
+            None => true,
+        };
+
+        allowed && self.health.lock().healthy.unwrap_or(false)
+    }
+
+    /// Called before dialing, the first dial after the cooldown is the
+    /// trial, others are not selected until it's done
+    pub fn begin_dial(&self) {
+        if let Some(breaker) = &mut *self.breaker.lock() {
+            if let Circuit::Open(until) = breaker.circuit {
+                if Instant::now() >= until {
+                    breaker.circuit = Circuit::HalfOpen;
+                }
+            }
+        }
+    }
+
+    /// Feed the circuit breaker with a dial result
+    pub fn record_dial(&self, ok: bool) {
+        if let Some(breaker) = &mut *self.breaker.lock() {
+            let before = breaker.circuit;
+            breaker.record(!ok);
+
+            match (before, breaker.circuit) {
+                (Circuit::Closed, Circuit::Open(_)) | (Circuit::HalfOpen, Circuit::Open(_)) => {
+                    warn!(message = "circuit opened", upstream = self.name().as_str())
+                }
+                (Circuit::HalfOpen, Circuit::Closed) | (Circuit::Open(_), Circuit::Closed) => {
+                    info!(message = "circuit closed", upstream = self.name().as_str())
+                }
+                _ => {}
+            }
+        }
     }
 
     #[inline]
@@ -70,7 +773,10 @@ impl Server {
         self.push_latency(0);
     }
 
+    /// Record a check, or a relayed connection failed, 0 means failed
     pub fn push_latency(&self, value: u32) {
+        self.health.lock().record(value > 0);
+
         let mut history = self.latencies.lock();
 
         if history.len() == MAX_HISTORY {
@@ -78,7 +784,7 @@ impl Server {
         }
 
         history.push_back(Latency {
-            timestamp: Instant::now(),
+            timestamp: SystemTime::now(),
             value,
         });
     }
@@ -86,17 +792,127 @@ impl Server {
     pub fn stat(&self) -> Stat {
         let config = &self.config;
         let latencies = { self.latencies.lock().clone() };
+        let latency = self.smoothed_latency();
+        let health = self.health.lock();
 
         Stat {
             remarks: config.remarks().cloned(),
             address: config.addr().to_string(),
+            tags: self.tags.lock().clone(),
+            healthy: health.healthy.unwrap_or(false),
+            connections: self.active.load(Ordering::Relaxed),
+            relayed: self.relayed.load(Ordering::Relaxed),
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+            warm: self.warm.lock().len(),
+            circuit: self.breaker.lock().as_ref().map(|breaker| breaker.circuit),
+            passes: health.passes,
+            failures: health.failures,
+            latency,
             latencies,
         }
     }
 
+    /// The last passed check, 0 if there is none
     pub fn latency(&self) -> u32 {
         let history = self.latencies.lock();
-        history.back().expect("not empty").value
+        history
+            .iter()
+            .rev()
+            .map(|latency| latency.value)
+            .find(|value| *value > 0)
+            .unwrap_or(0)
+    }
+
+    /// Mean of passed checks in the history, so one noisy check doesn't
+    /// switch the best server, 0 if there is none
+    pub fn smoothed_latency(&self) -> u32 {
+        let history = self.latencies.lock();
+        let (sum, count) = history
+            .iter()
+            .filter(|latency| latency.value > 0)
+            .fold((0u64, 0u64), |(sum, count), latency| {
+                (sum + latency.value as u64, count + 1)
+            });
+
+        sum.checked_div(count).unwrap_or(0) as u32
+    }
+
+    /// Checks in the history, to be persisted
+    pub fn history(&self) -> Vec<Record> {
+        self.latencies
+            .lock()
+            .iter()
+            .map(|latency| Record {
+                timestamp: latency
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                value: latency.value,
+            })
+            .collect()
+    }
+
+    /// Restore persisted checks before new ones, the health is not changed
+    pub fn restore(&self, records: &[Record]) {
+        let mut history = self.latencies.lock();
+        let restored = records.iter().map(|record| Latency {
+            timestamp: UNIX_EPOCH + Duration::from_secs(record.timestamp),
+            value: record.value,
+        });
+
+        let mut merged = restored.chain(history.drain(..)).collect::<VecDeque<_>>();
+        while merged.len() > MAX_HISTORY {
+            merged.pop_front();
+        }
+        *history = merged;
+    }
+}
+
+/// Count bytes read from(upload) and written to(download) the client for
+/// the server, see `Server::meter`
+pub struct Metered<S> {
+    inner: S,
+    server: Arc<Server>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.server.add_upload((buf.filled().len() - before) as u64);
+
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.server.add_download(n as u64);
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
     }
 }
 
@@ -104,5 +920,261 @@ impl Server {
 pub struct Stat {
     remarks: Option<String>,
     address: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    healthy: bool,
+    /// In-flight relayed connections
+    connections: usize,
+    /// Connections and UDP sessions relayed in total
+    relayed: u64,
+    /// Payload bytes from clients to the server, and back
+    upload: u64,
+    download: u64,
+    /// Idle connections established ahead
+    warm: usize,
+    /// Of the circuit breaker, if it's enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit: Option<Circuit>,
+    /// Consecutive passed checks
+    passes: u32,
+    /// Consecutive failures
+    failures: u32,
+    /// Mean of passed checks, which the best server is chosen by
+    latency: u32,
     latencies: VecDeque<Latency>,
 }
+
+#[cfg(test)]
+mod tests {
+    use shadowsocks::ServerConfig;
+
+    use super::*;
+
+    #[test]
+    fn health() {
+        let server = Server::new(
+            ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388").unwrap(),
+        );
+        server.set_thresholds(2, 3);
+        assert!(!server.alive());
+
+        // the first result decides
+        server.push_latency(100);
+        assert!(server.alive());
+
+        server.report_failure();
+        server.report_failure();
+        assert!(server.alive());
+        assert_eq!(server.latency(), 100);
+        server.report_failure();
+        assert!(!server.alive());
+
+        server.push_latency(50);
+        assert!(!server.alive());
+        server.push_latency(60);
+        assert!(server.alive());
+        assert_eq!(server.latency(), 60);
+    }
+
+    #[test]
+    fn permit() {
+        let server = Arc::new(Server::new(
+            ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388").unwrap(),
+        ));
+        server.set_max_connections(2);
+
+        let first = server.try_acquire().unwrap();
+        let _second = server.try_acquire().unwrap();
+        assert!(server.try_acquire().is_none());
+        assert_eq!(server.stat().connections, 2);
+
+        drop(first);
+        assert!(server.try_acquire().is_some());
+    }
+
+    #[test]
+    fn connect_opts() {
+        let server = Server::new(
+            ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388").unwrap(),
+        );
+        let mut opts = ConnectOpts::default();
+        opts.tcp.fastopen = true;
+
+        server.set_interface(Some("eth0".to_string()));
+        server.set_mark(Some(255));
+        let merged = server.connect_opts(&opts);
+        assert!(merged.tcp.fastopen);
+        assert_eq!(merged.bind_interface.as_deref(), Some("eth0"));
+        assert_eq!(merged.fwmark, Some(255));
+        opts.fwmark = Some(1);
+        assert_eq!(server.connect_opts(&opts).fwmark, Some(1));
+    }
+
+    #[tokio::test]
+    async fn meter() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = Arc::new(Server::new(
+            ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388").unwrap(),
+        ));
+
+        let mut read = vec![];
+        server
+            .meter(&b"request"[..])
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+        server.meter(vec![]).write_all(b"reply!").await.unwrap();
+        server.add_session();
+        server.add_download(10);
+
+        let stat = server.stat();
+        assert_eq!((stat.relayed, stat.upload, stat.download), (3, 7, 16));
+    }
+
+    #[test]
+    fn breaker() {
+        let server = Server::new(
+            ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388").unwrap(),
+        );
+        server.push_latency(100);
+        server.set_breaker(Some(BreakerConfig {
+            failure_rate: 0.5,
+            min_requests: 4,
+            window: 4,
+            cooldown: Duration::from_millis(20),
+        }));
+
+        server.record_dial(false);
+        server.record_dial(false);
+        server.record_dial(true);
+        assert!(server.alive());
+        server.record_dial(false);
+        assert!(!server.alive());
+
+        // half open after the cooldown, only the trial is let through
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(server.alive());
+        server.begin_dial();
+        assert!(!server.alive());
+        server.record_dial(false);
+        assert!(!server.alive());
+
+        std::thread::sleep(Duration::from_millis(30));
+        server.begin_dial();
+        server.record_dial(true);
+        assert!(server.alive());
+        assert_eq!(server.stat().circuit, Some(Circuit::Closed));
+    }
+
+    #[tokio::test]
+    async fn chain() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+
+            // the shadowsocks request comes through the tunnel
+            let n = stream.read(&mut buf).await.unwrap();
+            (request, n)
+        });
+
+        let via = Server::new(Endpoint::from_url(&format!("http://{}", addr)).unwrap());
+        let server = Server::new(
+            ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@10.1.2.3:8388").unwrap(),
+        );
+        let resolver = Resolver::new(vec!["127.0.0.1:53".parse().unwrap()]).unwrap();
+        let target = Address::DomainNameAddress("example.com".to_string(), 443);
+        let mut tunnel = server
+            .tunnel_via(&via, &resolver, &Default::default(), target)
+            .await
+            .unwrap();
+        assert!(matches!(tunnel, Tunnel::Chained(_)));
+        tunnel.write_all(b"hello").await.unwrap();
+
+        let (request, n) = proxy.await.unwrap();
+        assert!(request.starts_with("CONNECT 10.1.2.3:8388 HTTP/1.1\r\n"));
+        // salt, the encrypted target and data at least
+        assert!(n > 16 + 5);
+
+        // http proxies can't be chained
+        let err = via
+            .tunnel_via(
+                &server,
+                &resolver,
+                &Default::default(),
+                "1.1.1.1:53".parse().unwrap(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn warm() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(
+            ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@127.0.0.1:8388").unwrap(),
+        );
+
+        server.put_warm(TcpStream::connect(addr).await.unwrap());
+        let (_kept, _) = listener.accept().await.unwrap();
+        assert!(server.take_warm(Duration::from_secs(10)).is_some());
+
+        // closed by the server
+        server.put_warm(TcpStream::connect(addr).await.unwrap());
+        let (closed, _) = listener.accept().await.unwrap();
+        drop(closed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(server.take_warm(Duration::from_secs(10)).is_none());
+
+        // expired
+        server.put_warm(TcpStream::connect(addr).await.unwrap());
+        assert_eq!(server.expire_warm(Duration::from_secs(10)), 1);
+        assert_eq!(server.expire_warm(Duration::ZERO), 0);
+    }
+
+    #[tokio::test]
+    async fn re_resolve() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // nothing listens on it after the listener is dropped
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let url = format!("ss://YWVzLTEyOC1nY206cGFzcw@localhost:{}", port);
+        let server = Server::new(ServerConfig::from_url(&url).unwrap());
+        server.set_re_resolve(Some(ReResolveConfig {
+            interval: Duration::from_secs(3600),
+            failures: 2,
+        }));
+        let resolver = Resolver::new(vec!["127.0.0.1:53".parse().unwrap()]).unwrap();
+        let opts = ConnectOpts::default();
+
+        // kept addresses are used until connects to them failed twice
+        *server.resolved.lock() = Some((Instant::now(), vec![closed]));
+        assert!(server.dial(&resolver, &opts).await.is_err());
+        assert!(server.dial(&resolver, &opts).await.is_err());
+        assert_eq!(server.connect_failures.load(Ordering::Relaxed), 2);
+
+        let (dialed, accepted) = tokio::join!(server.dial(&resolver, &opts), listener.accept());
+        assert_eq!(dialed.unwrap().local_addr().unwrap(), accepted.unwrap().1);
+        assert_eq!(server.connect_failures.load(Ordering::Relaxed), 0);
+        let (_, addrs) = server.resolved.lock().clone().unwrap();
+        assert!(addrs.iter().all(|addr| addr.port() == port));
+    }
+}
@@ -0,0 +1,528 @@
+//! shadow-tls v3, a transport wrapping the shadowsocks stream of a server
+//! in a TLS handshake borrowed from a real server, e.g.
+//!
+//! ```yaml
+//! upstream:
+//!   shadow_tls:
+//!     hk-01:
+//!       server_name: www.microsoft.com
+//!       password: secret
+//! ```
+//!
+//! The ClientHello carries an HMAC of the password in its session id, so
+//! the shadow-tls server relays the handshake to `server_name`, and
+//! observers see a genuine certificate. Once the ServerHello is received,
+//! data is sent in TLS application data records, each one prefixed by an
+//! HMAC over the server random and the data so far. Records of the real
+//! server, which don't carry the HMAC, are dropped. The handshake is not
+//! finished with the real server, its keys are never needed.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use rand::{thread_rng, Rng};
+use ring::hmac;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+const CHANGE_CIPHER_SPEC: u8 = 0x14;
+const ALERT: u8 = 0x15;
+const HANDSHAKE: u8 = 0x16;
+const APPLICATION_DATA: u8 = 0x17;
+
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_HELLO: u8 = 0x02;
+
+const HEADER_LEN: usize = 5;
+const HMAC_LEN: usize = 4;
+/// Largest data of a record
+const MAX_DATA: usize = 16 * 1024 - HMAC_LEN;
+/// Records before the ServerHello are not expected, but a few are
+/// tolerated
+const MAX_HANDSHAKE_LEN: usize = 64 * 1024;
+/// Bytes read from the server at a time
+const READ_SIZE: usize = 4 * 1024;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowTlsConfig {
+    /// Sent as SNI, the shadow-tls server borrows the handshake of it
+    pub server_name: String,
+
+    pub password: String,
+}
+
+/// Handshake with the shadow-tls server over `stream`, data can be sent
+/// once it's returned
+pub async fn connect(
+    mut stream: TcpStream,
+    config: &ShadowTlsConfig,
+) -> io::Result<ShadowTlsStream> {
+    let key = hmac::Key::new(
+        hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        config.password.as_bytes(),
+    );
+
+    let hello = client_hello(&key, &config.server_name)?;
+    stream.write_all(&hello).await?;
+
+    let server_random = read_server_hello(&mut stream).await?;
+
+    Ok(ShadowTlsStream::new(stream, &key, &server_random))
+}
+
+/// A ClientHello like the ones of browsers, the session id is 28 random
+/// bytes and the HMAC of the whole message with the HMAC zeroed
+fn client_hello(key: &hmac::Key, server_name: &str) -> io::Result<Vec<u8>> {
+    if server_name.is_empty() || server_name.len() > u8::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid server name of shadow-tls",
+        ));
+    }
+
+    let mut rng = thread_rng();
+    let mut extensions = Vec::with_capacity(512);
+    let mut extension = |kind: u16, data: &[u8]| {
+        extensions.extend_from_slice(&kind.to_be_bytes());
+        extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(data);
+    };
+
+    // server_name
+    let name = server_name.as_bytes();
+    let mut sni = Vec::with_capacity(name.len() + 5);
+    sni.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+    sni.push(0);
+    sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    sni.extend_from_slice(name);
+    extension(0x0000, &sni);
+    // extended_master_secret, renegotiation_info
+    extension(0x0017, &[]);
+    extension(0xff01, &[0]);
+    // supported_groups, x25519, secp256r1 and secp384r1
+    extension(0x000a, &[0, 6, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18]);
+    // ec_point_formats, session_ticket
+    extension(0x000b, &[1, 0]);
+    extension(0x0023, &[]);
+    // application_layer_protocol_negotiation, h2 and http/1.1
+    extension(0x0010, b"\x00\x0c\x02h2\x08http/1.1");
+    // status_request
+    extension(0x0005, &[1, 0, 0, 0, 0]);
+    // signature_algorithms
+    extension(
+        0x000d,
+        &[
+            0, 16, 0x04, 0x03, 0x08, 0x04, 0x04, 0x01, 0x05, 0x03, 0x08, 0x05, 0x05, 0x01, 0x08,
+            0x06, 0x06, 0x01,
+        ],
+    );
+    // signed_certificate_timestamp
+    extension(0x0012, &[]);
+    // key_share of x25519, any 32 bytes are a valid public key, the
+    // shared secret is never used
+    let mut key_share = vec![0, 36, 0x00, 0x1d, 0, 32];
+    key_share.extend_from_slice(&rng.gen::<[u8; 32]>());
+    extension(0x0033, &key_share);
+    // psk_key_exchange_modes, supported_versions of TLS 1.3 and 1.2
+    extension(0x002d, &[1, 1]);
+    extension(0x002b, &[4, 0x03, 0x04, 0x03, 0x03]);
+
+    let mut body = Vec::with_capacity(extensions.len() + 128);
+    body.extend_from_slice(&[0x03, 0x03]);
+    body.extend_from_slice(&rng.gen::<[u8; 32]>());
+    body.push(32);
+    let session_id = body.len();
+    body.extend_from_slice(&rng.gen::<[u8; 28]>());
+    body.extend_from_slice(&[0; HMAC_LEN]);
+    // TLS 1.3 suites, then ECDHE ones of TLS 1.2
+    body.extend_from_slice(&[
+        0, 18, 0x13, 0x01, 0x13, 0x02, 0x13, 0x03, 0xc0, 0x2b, 0xc0, 0x2f, 0xc0, 0x2c, 0xc0, 0x30,
+        0xcc, 0xa9, 0xcc, 0xa8,
+    ]);
+    // no compression
+    body.extend_from_slice(&[1, 0]);
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+
+    let mut message = Vec::with_capacity(body.len() + 4);
+    message.push(CLIENT_HELLO);
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    message.extend_from_slice(&body);
+
+    let tag = hmac::sign(key, &message);
+    let offset = 4 + session_id + 28;
+    message[offset..offset + HMAC_LEN].copy_from_slice(&tag.as_ref()[..HMAC_LEN]);
+
+    let mut record = Vec::with_capacity(message.len() + HEADER_LEN);
+    record.extend_from_slice(&[HANDSHAKE, 0x03, 0x01]);
+    record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    record.extend_from_slice(&message);
+
+    Ok(record)
+}
+
+/// Read records until the ServerHello, returns the server random
+async fn read_server_hello(stream: &mut TcpStream) -> io::Result<[u8; 32]> {
+    let mut read = 0;
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+
+        read += HEADER_LEN + len;
+        if read > MAX_HANDSHAKE_LEN {
+            break;
+        }
+
+        match header[0] {
+            // type, length, version and random
+            HANDSHAKE if payload.first() == Some(&SERVER_HELLO) && payload.len() >= 38 => {
+                let mut random = [0u8; 32];
+                random.copy_from_slice(&payload[6..38]);
+                return Ok(random);
+            }
+            HANDSHAKE | CHANGE_CIPHER_SPEC => {}
+            ALERT => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "shadow-tls handshake is refused",
+                ))
+            }
+            _ => break,
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no ServerHello from the shadow-tls server",
+    ))
+}
+
+/// HMAC of everything so far, the context is not consumed
+fn hmac_of(context: &hmac::Context) -> [u8; HMAC_LEN] {
+    let mut tag = [0u8; HMAC_LEN];
+    tag.copy_from_slice(&context.clone().sign().as_ref()[..HMAC_LEN]);
+    tag
+}
+
+/// A stream tunneled through a shadow-tls server
+pub struct ShadowTlsStream {
+    stream: TcpStream,
+    /// Rolling HMACs of data sent and received
+    client_hmac: hmac::Context,
+    server_hmac: hmac::Context,
+    /// Whether data of the server is received, records without the HMAC
+    /// are invalid after it
+    authenticated: bool,
+
+    /// Received but not parsed
+    received: Vec<u8>,
+    /// Data of the last record, but not read
+    plain: Vec<u8>,
+    plain_pos: usize,
+    eof: bool,
+
+    /// Framed but not sent
+    framed: Vec<u8>,
+    framed_pos: usize,
+    /// A ChangeCipherSpec goes before the first data, like the one before
+    /// the Finished of real clients
+    change_cipher_spec_sent: bool,
+}
+
+impl ShadowTlsStream {
+    fn new(stream: TcpStream, key: &hmac::Key, server_random: &[u8; 32]) -> Self {
+        let hmac_with = |suffix: &[u8]| {
+            let mut context = hmac::Context::with_key(key);
+            context.update(server_random);
+            context.update(suffix);
+            context
+        };
+
+        Self {
+            stream,
+            client_hmac: hmac_with(b"C"),
+            server_hmac: hmac_with(b"S"),
+            authenticated: false,
+            received: Vec::with_capacity(READ_SIZE),
+            plain: Vec::new(),
+            plain_pos: 0,
+            eof: false,
+            framed: Vec::new(),
+            framed_pos: 0,
+            change_cipher_spec_sent: false,
+        }
+    }
+
+    /// Read until `needed` bytes are received, false if the server closed
+    /// the stream before
+    fn poll_receive(&mut self, cx: &mut Context<'_>, needed: usize) -> Poll<io::Result<bool>> {
+        while self.received.len() < needed {
+            let filled = self.received.len();
+            self.received.resize(needed.max(filled + READ_SIZE), 0);
+            let mut buf = ReadBuf::new(&mut self.received[filled..]);
+            let result = Pin::new(&mut self.stream).poll_read(cx, &mut buf);
+            let n = buf.filled().len();
+            self.received.truncate(filled + n);
+
+            ready!(result)?;
+            if n == 0 {
+                return Poll::Ready(Ok(false));
+            }
+        }
+
+        Poll::Ready(Ok(true))
+    }
+
+    /// Parse the first record received, data of the server is kept in
+    /// `plain`
+    fn parse(&mut self, len: usize) -> io::Result<()> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let kind = self.received[0];
+        let payload = &self.received[HEADER_LEN..HEADER_LEN + len];
+
+        match kind {
+            APPLICATION_DATA if payload.len() >= HMAC_LEN => {
+                let (tag, data) = payload.split_at(HMAC_LEN);
+                let mut context = self.server_hmac.clone();
+                context.update(data);
+                let expected = hmac_of(&context);
+
+                if tag == expected {
+                    context.update(&expected);
+                    self.server_hmac = context;
+                    self.authenticated = true;
+                    self.plain.clear();
+                    self.plain.extend_from_slice(data);
+                    self.plain_pos = 0;
+                } else if self.authenticated {
+                    return Err(invalid("invalid hmac of shadow-tls record"));
+                }
+            }
+            ALERT if self.authenticated => self.eof = true,
+            // of the real server
+            _ if !self.authenticated => {}
+            _ => return Err(invalid("unexpected record of shadow-tls")),
+        }
+        self.received.drain(..HEADER_LEN + len);
+
+        Ok(())
+    }
+
+    /// Frame `data` in an application data record
+    fn frame(&mut self, data: &[u8]) {
+        if !self.change_cipher_spec_sent {
+            self.framed
+                .extend_from_slice(&[CHANGE_CIPHER_SPEC, 0x03, 0x03, 0, 1, 1]);
+            self.change_cipher_spec_sent = true;
+        }
+
+        self.client_hmac.update(data);
+        let tag = hmac_of(&self.client_hmac);
+        self.client_hmac.update(&tag);
+
+        self.framed
+            .extend_from_slice(&[APPLICATION_DATA, 0x03, 0x03]);
+        self.framed
+            .extend_from_slice(&((HMAC_LEN + data.len()) as u16).to_be_bytes());
+        self.framed.extend_from_slice(&tag);
+        self.framed.extend_from_slice(data);
+    }
+
+    /// Send what's framed
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.framed_pos < self.framed.len() {
+            let n =
+                ready!(Pin::new(&mut self.stream).poll_write(cx, &self.framed[self.framed_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.framed_pos += n;
+        }
+        self.framed.clear();
+        self.framed_pos = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsRawFd for ShadowTlsStream {
+    /// The socket connected to the server
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl AsyncRead for ShadowTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            if !ready!(this.poll_receive(cx, HEADER_LEN))? {
+                if this.received.is_empty() {
+                    this.eof = true;
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            let len = u16::from_be_bytes([this.received[3], this.received[4]]) as usize;
+            if !ready!(this.poll_receive(cx, HEADER_LEN + len))? {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.parse(len)?;
+        }
+    }
+}
+
+impl AsyncWrite for ShadowTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_DATA);
+        this.frame(&buf[..n]);
+        // it's accepted, what can't be sent now is sent by the next write
+        // or flush
+        if let Poll::Ready(Err(err)) = this.poll_send(cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "secret";
+
+    fn key() -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, PASSWORD.as_bytes())
+    }
+
+    #[test]
+    fn hello() {
+        let record = client_hello(&key(), "www.example.com").unwrap();
+        assert_eq!(record[..3], [HANDSHAKE, 0x03, 0x01]);
+        assert_eq!(
+            u16::from_be_bytes([record[3], record[4]]) as usize,
+            record.len() - HEADER_LEN
+        );
+
+        // verified like the server does
+        let mut message = record[HEADER_LEN..].to_vec();
+        assert_eq!(message[0], CLIENT_HELLO);
+        assert_eq!(message[38], 32);
+        let tag = message[67..71].to_vec();
+        message[67..71].fill(0);
+        assert_eq!(hmac::sign(&key(), &message).as_ref()[..HMAC_LEN], tag);
+        assert!(record
+            .windows("www.example.com".len())
+            .any(|window| window == b"www.example.com"));
+
+        assert!(client_hello(&key(), "").is_err());
+    }
+
+    #[tokio::test]
+    async fn tunnel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0u8; HEADER_LEN];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut hello = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+            stream.read_exact(&mut hello).await.unwrap();
+
+            // ServerHello, then a record of the real server and data
+            let random = [7u8; 32];
+            let mut response = vec![HANDSHAKE, 0x03, 0x03, 0, 38, SERVER_HELLO, 0, 0, 34, 3, 3];
+            response.extend_from_slice(&random);
+            response.extend_from_slice(&[CHANGE_CIPHER_SPEC, 3, 3, 0, 1, 1]);
+            response.extend_from_slice(&[APPLICATION_DATA, 3, 3, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+            let mut server_hmac = hmac::Context::with_key(&key());
+            server_hmac.update(&random);
+            server_hmac.update(b"S");
+            server_hmac.update(b"hello");
+            let tag = hmac_of(&server_hmac);
+            response.extend_from_slice(&[APPLICATION_DATA, 3, 3, 0, 9]);
+            response.extend_from_slice(&tag);
+            response.extend_from_slice(b"hello");
+            stream.write_all(&response).await.unwrap();
+
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let config = ShadowTlsConfig {
+            server_name: "www.example.com".to_string(),
+            password: PASSWORD.to_string(),
+        };
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connect(stream, &config).await.unwrap();
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"ping").await.unwrap();
+        stream.write_all(b"pong").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut client_hmac = hmac::Context::with_key(&key());
+        client_hmac.update(&[7u8; 32]);
+        client_hmac.update(b"C");
+        let mut expected = vec![CHANGE_CIPHER_SPEC, 3, 3, 0, 1, 1];
+        for data in [b"ping", b"pong"] {
+            client_hmac.update(data);
+            let tag = hmac_of(&client_hmac);
+            client_hmac.update(&tag);
+            expected.extend_from_slice(&[APPLICATION_DATA, 3, 3, 0, 8]);
+            expected.extend_from_slice(&tag);
+            expected.extend_from_slice(data);
+        }
+        assert_eq!(server.await.unwrap(), expected);
+    }
+}
@@ -0,0 +1,559 @@
+//! TLS to upstream servers, e.g. `https://` proxies and VLESS servers.
+//! Certificates are verified by the system roots, unless the server has
+//! settings in `upstream.tls`, by server name, e.g.
+//!
+//! ```yaml
+//! upstream:
+//!   tls:
+//!     office:
+//!       ca: /etc/roxy/office-ca.pem
+//!       cert: /etc/roxy/client.pem
+//!       key: /etc/roxy/client.key
+//!     hk-01:
+//!       fingerprint: 3f:1a:...:9c
+//!     jp-01:
+//!       spki: 8e:07:...:d2
+//! ```
+//!
+//! `ca` replaces the system roots, a `fingerprint`, the SHA-256 of the
+//! server's certificate, trusts that certificate alone, e.g. a self-signed
+//! one, `spki`, the SHA-256 of its SubjectPublicKeyInfo, trusts any
+//! certificate of that key, so the pin survives renewals, and `insecure`
+//! skips verification. Only one of them may be set. `cert` and `key` are
+//! sent to servers asking for client certificates.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use ring::digest;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use tokio_rustls::rustls::{
+    self, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+use tokio_rustls::TlsConnector;
+
+/// Loaded by the first TLS server, loading system roots is not cheap
+static SYSTEM_ROOTS: Mutex<Option<Arc<RootCertStore>>> = Mutex::new(None);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("read {path:?} failed, {err}")]
+    Read { path: PathBuf, err: io::Error },
+    #[error("no certificate in {0:?}")]
+    NoCertificate(PathBuf),
+    #[error("no private key in {0:?}")]
+    NoKey(PathBuf),
+    #[error("cert and key must be set together")]
+    HalfClientAuth,
+    #[error("fingerprint must be 64 hex digits, colons are allowed")]
+    InvalidFingerprint,
+    #[error("spki must be 64 hex digits, colons are allowed")]
+    InvalidSpki,
+    #[error("only one of ca, fingerprint, spki and insecure is allowed")]
+    ConflictingTrust,
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM bundle of CAs the certificate must chain to, instead of the
+    /// system roots
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+
+    /// SHA-256 of the server's certificate, in hex
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+
+    /// SHA-256 of the SubjectPublicKeyInfo of the server's certificate, in
+    /// hex, it stays the same when the certificate is renewed with the same
+    /// key
+    #[serde(default)]
+    pub spki: Option<String>,
+
+    /// PEM certificate chain sent to servers asking for one, with `key`
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+
+    /// PEM private key of `cert`
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+
+    /// Don't verify the certificate at all
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+impl TlsConfig {
+    /// Files and the fingerprint are valid, for `roxy check`
+    pub fn check(&self) -> Result<(), Error> {
+        client_config(Some(self)).map(drop)
+    }
+}
+
+/// Build the config of a server, `None` is verified by the system roots
+pub fn client_config(config: Option<&TlsConfig>) -> Result<Arc<ClientConfig>, Error> {
+    let default = TlsConfig::default();
+    let config = config.unwrap_or(&default);
+
+    let trusts = [
+        config.ca.is_some(),
+        config.fingerprint.is_some(),
+        config.spki.is_some(),
+        config.insecure,
+    ];
+    if trusts.iter().filter(|set| **set).count() > 1 {
+        return Err(Error::ConflictingTrust);
+    }
+
+    let verifier: Arc<dyn ServerCertVerifier> = if config.insecure {
+        Arc::new(Insecure)
+    } else if let Some(fingerprint) = &config.fingerprint {
+        Arc::new(Pinned(parse_fingerprint(fingerprint)?))
+    } else if let Some(spki) = &config.spki {
+        let digest = parse_fingerprint(spki).map_err(|_| Error::InvalidSpki)?;
+        Arc::new(PinnedKey(digest))
+    } else if let Some(ca) = &config.ca {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(ca)? {
+            roots.add(&cert).map_err(|err| {
+                rustls::Error::InvalidCertificateData(format!("{} in {:?}", err, ca))
+            })?;
+        }
+        Arc::new(WebPkiVerifier::new(roots, None))
+    } else {
+        Arc::new(WebPkiVerifier::new(system_roots()?.as_ref().clone(), None))
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+
+    let config = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => builder.with_single_cert(read_certs(cert)?, read_key(key)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(Error::HalfClientAuth),
+    };
+
+    Ok(Arc::new(config))
+}
+
+fn system_roots() -> Result<Arc<RootCertStore>, Error> {
+    let mut roots = SYSTEM_ROOTS.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(roots) = &*roots {
+        return Ok(roots.clone());
+    }
+
+    let mut store = RootCertStore::empty();
+    let certs = rustls_native_certs::load_native_certs().map_err(|err| Error::Read {
+        path: PathBuf::from("system roots"),
+        err,
+    })?;
+    for cert in certs {
+        // invalid ones are skipped, like browsers do
+        let _ = store.add(&Certificate(cert.0));
+    }
+    let store = Arc::new(store);
+    *roots = Some(store.clone());
+
+    Ok(store)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, Error> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| Error::Read {
+            path: path.to_path_buf(),
+            err,
+        })
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).map_err(|err| Error::Read {
+        path: path.to_path_buf(),
+        err,
+    })?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificate(path.to_path_buf()));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, Error> {
+    let items = rustls_pemfile::read_all(&mut open(path)?).map_err(|err| Error::Read {
+        path: path.to_path_buf(),
+        err,
+    })?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::NoKey(path.to_path_buf()))
+}
+
+/// `3F:1A:...` or `3f1a...`
+fn parse_fingerprint(s: &str) -> Result<Vec<u8>, Error> {
+    let digits = s.bytes().filter(|b| *b != b':').collect::<Vec<_>>();
+    if digits.len() != 64 {
+        return Err(Error::InvalidFingerprint);
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(Error::InvalidFingerprint)
+        })
+        .collect()
+}
+
+/// Trusts the certificate of the SHA-256 only, chains and names are not
+/// checked
+struct Pinned(Vec<u8>);
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if digest::digest(&digest::SHA256, &end_entity.0).as_ref() == self.0.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificateData(
+                "fingerprint mismatch".to_string(),
+            ))
+        }
+    }
+}
+
+/// Trusts certificates of the key whose SubjectPublicKeyInfo has the
+/// SHA-256 only, chains and names are not checked
+struct PinnedKey(Vec<u8>);
+
+impl ServerCertVerifier for PinnedKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let spki = spki(&end_entity.0).ok_or_else(|| {
+            rustls::Error::InvalidCertificateData("no SubjectPublicKeyInfo".to_string())
+        })?;
+        if digest::digest(&digest::SHA256, spki).as_ref() == self.0.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificateData(
+                "spki mismatch".to_string(),
+            ))
+        }
+    }
+}
+
+/// The first DER element of some bytes
+struct Der<'a> {
+    tag: u8,
+    /// The element with its header
+    element: &'a [u8],
+    content: &'a [u8],
+    /// Bytes after the element
+    rest: &'a [u8],
+}
+
+fn der_element(input: &[u8]) -> Option<Der<'_>> {
+    let tag = *input.first()?;
+    let first = *input.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        // long form, the number of length bytes follows
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |len, b| len << 8 | *b as usize);
+        (len, 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    let element = input.get(..end)?;
+
+    Some(Der {
+        tag,
+        element,
+        content: &element[header..],
+        rest: &input[end..],
+    })
+}
+
+/// The DER SubjectPublicKeyInfo of a DER certificate, the 7th field of
+/// TBSCertificate, counting the optional version
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let cert = der_element(cert)?.content;
+    let mut rest = der_element(cert)?.content;
+
+    // [0] version
+    if rest.first() == Some(&0xa0) {
+        rest = der_element(rest)?.rest;
+    }
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.rest;
+    }
+    let spki = der_element(rest)?;
+
+    (spki.tag == 0x30).then_some(spki.element)
+}
+
+struct Insecure;
+
+impl ServerCertVerifier for Insecure {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A stream to an upstream server, over TLS or not
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsRawFd for MaybeTlsStream {
+    /// The socket connected to the server
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            MaybeTlsStream::Plain(stream) => stream.as_raw_fd(),
+            MaybeTlsStream::Tls(stream) => stream.get_ref().0.as_raw_fd(),
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// TLS handshake over `stream` by `config`, the certificate must be valid
+/// for `name`
+pub async fn connect(
+    stream: TcpStream,
+    name: &str,
+    config: Arc<ClientConfig>,
+) -> io::Result<MaybeTlsStream> {
+    let name = ServerName::try_from(name)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let stream = TlsConnector::from(config).connect(name, stream).await?;
+
+    Ok(MaybeTlsStream::Tls(Box::new(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint() {
+        let hex = "3f1a".repeat(16);
+        let parsed = parse_fingerprint(&hex).unwrap();
+        assert_eq!(parsed.len(), 32);
+        assert_eq!(&parsed[..2], &[0x3f, 0x1a]);
+
+        let colons = parsed
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), parsed);
+
+        assert!(parse_fingerprint("3f1a").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let config = TlsConfig {
+            cert: Some(PathBuf::from("/nonexistent/client.pem")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            client_config(Some(&config)),
+            Err(Error::HalfClientAuth)
+        ));
+
+        let config = TlsConfig {
+            ca: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            client_config(Some(&config)),
+            Err(Error::Read { .. })
+        ));
+
+        let config = TlsConfig {
+            insecure: true,
+            ..Default::default()
+        };
+        assert!(client_config(Some(&config)).is_ok());
+
+        let config = TlsConfig {
+            spki: Some("3f1a".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            client_config(Some(&config)),
+            Err(Error::InvalidSpki)
+        ));
+
+        // the fingerprint would win over the CA silently
+        let config = TlsConfig {
+            ca: Some(PathBuf::from("/etc/roxy/ca.pem")),
+            fingerprint: Some("3f1a".repeat(16)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            client_config(Some(&config)),
+            Err(Error::ConflictingTrust)
+        ));
+        let config = TlsConfig {
+            fingerprint: Some("3f1a".repeat(16)),
+            spki: Some("3f1a".repeat(16)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            client_config(Some(&config)),
+            Err(Error::ConflictingTrust)
+        ));
+    }
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut element = vec![tag];
+        if content.len() < 0x80 {
+            element.push(content.len() as u8);
+        } else {
+            element.push(0x82);
+            element.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        }
+        element.extend_from_slice(content);
+        element
+    }
+
+    /// A certificate of `key` with `serial`, only its structure is valid
+    fn certificate(serial: u8, key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let algorithm = tlv(
+            0x30,
+            &tlv(0x06, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01]),
+        );
+        let spki = tlv(0x30, &[algorithm.clone(), tlv(0x03, key)].concat());
+        let name = tlv(0x30, &tlv(0x31, &tlv(0x30, b"example")));
+        let tbs = tlv(
+            0x30,
+            &[
+                tlv(0xa0, &tlv(0x02, &[2])),
+                tlv(0x02, &[serial]),
+                algorithm.clone(),
+                name.clone(),
+                tlv(0x30, &[0; 30]),
+                name,
+                spki.clone(),
+            ]
+            .concat(),
+        );
+        let cert = tlv(0x30, &[tbs, algorithm, tlv(0x03, &[0; 200])].concat());
+
+        (cert, spki)
+    }
+
+    #[test]
+    fn subject_public_key_info() {
+        let key = [4u8; 65];
+        let (cert, expected) = certificate(1, &key);
+        assert_eq!(spki(&cert), Some(expected.as_slice()));
+
+        // renewed with the same key
+        let (renewed, _) = certificate(2, &key);
+        assert_ne!(cert, renewed);
+        assert_eq!(spki(&renewed), spki(&cert));
+
+        assert_eq!(spki(&cert[..cert.len() / 2]), None);
+        assert_eq!(spki(&[0x30, 0x00]), None);
+    }
+}
@@ -0,0 +1,310 @@
+//! VLESS servers as upstream servers, many commercial endpoints only
+//! expose them, e.g.
+//!
+//! ```text
+//! vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@example.com:443?encryption=none&security=tls&sni=cdn.example.com&type=tcp#hk
+//! ```
+//!
+//! VLESS authenticates by the user id and has no encryption layer, it's
+//! meant to run over TLS, `security=tls`, verified by the system roots or
+//! `upstream.tls` of the server.
+//! Other transports, REALITY and XTLS flows are not supported, and UDP
+//! can't be relayed through them.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use percent_encoding::percent_decode_str;
+use shadowsocks::{Address, UrlParseError};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ClientConfig;
+
+use super::endpoint::parse_authority;
+use super::tls::{self, MaybeTlsStream};
+use super::vmess::{parse_uuid, put_target};
+
+const VERSION: u8 = 0;
+const COMMAND_TCP: u8 = 0x01;
+
+#[derive(Clone, Debug)]
+pub struct VlessConfig {
+    addr: Address,
+    id: [u8; 16],
+    /// Server name of the certificate, if it's over TLS
+    tls: Option<String>,
+    remarks: Option<String>,
+}
+
+impl VlessConfig {
+    /// Parse `vless://id@host[:port][?params][#remarks]`, the port is 443
+    /// by default
+    pub fn from_url(url: &str) -> Result<Self, UrlParseError> {
+        let rest = url
+            .strip_prefix("vless://")
+            .ok_or(UrlParseError::InvalidScheme)?;
+        let (rest, remarks) = match rest.split_once('#') {
+            Some((rest, fragment)) => (
+                rest,
+                Some(
+                    percent_decode_str(fragment)
+                        .decode_utf8_lossy()
+                        .into_owned(),
+                ),
+            ),
+            None => (rest, None),
+        };
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+
+        let (id, authority) = rest
+            .rsplit_once('@')
+            .ok_or(UrlParseError::InvalidUserInfo)?;
+        let id = parse_uuid(&percent_decode_str(id).decode_utf8_lossy())
+            .ok_or(UrlParseError::InvalidAuthInfo)?;
+        let addr = parse_authority(authority, 443)?;
+
+        let mut tls = false;
+        let mut sni = None;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode_str(value).decode_utf8_lossy();
+            // REALITY, XTLS flows, other transports and obfuscation are
+            // not supported
+            let supported = match key {
+                "encryption" => value == "none",
+                "security" => {
+                    tls = value == "tls";
+                    matches!(&*value, "tls" | "none" | "")
+                }
+                "sni" | "peer" => {
+                    sni = Some(value.into_owned()).filter(|sni| !sni.is_empty());
+                    true
+                }
+                "type" => matches!(&*value, "tcp" | ""),
+                "headerType" => matches!(&*value, "none" | ""),
+                "flow" => value.is_empty(),
+                _ => true,
+            };
+            if !supported {
+                return Err(UrlParseError::InvalidQueryString);
+            }
+        }
+        let tls = tls.then(|| {
+            sni.unwrap_or_else(|| match &addr {
+                Address::DomainNameAddress(domain, _) => domain.clone(),
+                Address::SocketAddress(addr) => addr.ip().to_string(),
+            })
+        });
+
+        Ok(Self {
+            addr,
+            id,
+            tls,
+            remarks,
+        })
+    }
+
+    #[inline]
+    pub fn addr(&self) -> &Address {
+        &self.addr
+    }
+
+    /// `security=tls`
+    #[inline]
+    pub fn tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    #[inline]
+    pub fn remarks(&self) -> Option<&String> {
+        self.remarks.as_ref()
+    }
+}
+
+/// Tunnel to `target` over a stream connected to the server, over TLS by
+/// `tls` with `security=tls`. The request header is sent now, the response
+/// header is skipped with the first data.
+pub async fn connect(
+    stream: TcpStream,
+    config: &VlessConfig,
+    target: &Address,
+    tls: Option<Arc<ClientConfig>>,
+) -> io::Result<VlessStream> {
+    let mut stream = match (&config.tls, tls) {
+        (Some(name), Some(tls)) => tls::connect(stream, name, tls).await?,
+        _ => MaybeTlsStream::Plain(stream),
+    };
+
+    let mut request = Vec::with_capacity(64);
+    request.push(VERSION);
+    request.extend_from_slice(&config.id);
+    // no addons
+    request.push(0);
+    request.push(COMMAND_TCP);
+    put_target(&mut request, target)?;
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    Ok(VlessStream {
+        stream,
+        header_read: 0,
+        header_len: 2,
+    })
+}
+
+/// A stream tunneled through a VLESS server
+pub struct VlessStream {
+    stream: MaybeTlsStream,
+    /// The response header is the version, the length of addons and the
+    /// addons
+    header_read: usize,
+    header_len: usize,
+}
+
+impl AsRawFd for VlessStream {
+    /// The socket connected to the server
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl AsyncRead for VlessStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        // it's a few bytes, and data follows it, so it's read byte by byte
+        while this.header_read < this.header_len {
+            let mut byte = [0u8; 1];
+            let mut byte_buf = ReadBuf::new(&mut byte);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut byte_buf))?;
+            if byte_buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            match this.header_read {
+                0 if byte[0] != VERSION => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unknown version of vless response",
+                    )))
+                }
+                1 => this.header_len += byte[0] as usize,
+                _ => {}
+            }
+            this.header_read += 1;
+        }
+
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for VlessStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = VlessConfig::from_url(
+            "vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@example.com:8443?encryption=none&security=tls&sni=cdn.example.com&type=tcp#hk%20vless",
+        )
+        .unwrap();
+        assert_eq!(config.addr().to_string(), "example.com:8443");
+        assert_eq!(config.id[..4], [0xb8, 0x31, 0xb5, 0xe4]);
+        assert_eq!(config.tls.as_deref(), Some("cdn.example.com"));
+        assert_eq!(config.remarks().unwrap(), "hk vless");
+
+        let config = VlessConfig::from_url(
+            "vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@example.com?security=tls",
+        )
+        .unwrap();
+        assert_eq!(config.addr().to_string(), "example.com:443");
+        assert_eq!(config.tls.as_deref(), Some("example.com"));
+
+        let config =
+            VlessConfig::from_url("vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@[::1]:10086")
+                .unwrap();
+        assert_eq!(config.addr().to_string(), "[::1]:10086");
+        assert_eq!(config.tls, None);
+        assert_eq!(config.remarks(), None);
+
+        for url in [
+            "vless://example.com:443",
+            "vless://b831b5e4@example.com:443",
+            "vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@:443",
+            "vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@example.com:443?security=reality",
+            "vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@example.com:443?type=ws",
+            "vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@example.com:443?security=tls&flow=xtls-rprx-vision",
+            "vmess://b831b5e4-63b6-4105-a6eb-1e47389151bf@example.com:443",
+        ] {
+            assert!(VlessConfig::from_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn tunnel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 34];
+            stream.read_exact(&mut request).await.unwrap();
+
+            // with an addon of 2 bytes, data follows the header
+            stream.write_all(b"\x00\x02abhello").await.unwrap();
+
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.unwrap();
+            (request, data)
+        });
+
+        let config = VlessConfig::from_url(&format!(
+            "vless://b831b5e4-63b6-4105-a6eb-1e47389151bf@{}",
+            addr
+        ))
+        .unwrap();
+        let target = Address::DomainNameAddress("example.com".to_string(), 443);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connect(stream, &config, &target, None).await.unwrap();
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let (request, data) = server.await.unwrap();
+        assert_eq!(request[0], VERSION);
+        assert_eq!(request[1..17], config.id);
+        assert_eq!(request[17..], *b"\x00\x01\x01\xbb\x02\x0bexample.com");
+        assert_eq!(data, b"ping");
+    }
+}
@@ -0,0 +1,886 @@
+//! VMess servers as upstream servers, so v2ray servers users already have
+//! can be used without a second client. They are given by the `vmess://`
+//! share links of v2rayN, i.e. base64 encoded JSON, e.g.
+//!
+//! ```text
+//! vmess://eyJ2IjoiMiIsInBzIjoiaGsiLCJhZGQiOiJleGFtcGxlLmNvbSIsInBvcnQiOiIxMDA4NiIsImlkIjoiYjgzMWI1ZTQtNjNiNi00MTA1LWE2ZWItMWU0NzM4OTE1MWJmIiwiYWlkIjoiMCIsIm5ldCI6InRjcCIsInNjeSI6ImNoYWNoYTIwLXBvbHkxMzA1In0=
+//! ```
+//!
+//! Only the AEAD header (`aid` 0) over plain TCP is supported, bodies are
+//! `aes-128-gcm` or `chacha20-poly1305` chunks, `auto` is `aes-128-gcm`.
+//! Chunk lengths are not masked. UDP can't be relayed through them.
+
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use futures::ready;
+use md5::{Digest, Md5};
+use rand::{thread_rng, Rng};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, CHACHA20_POLY1305};
+use ring::digest;
+use serde_json::Value;
+use shadowsocks::{Address, UrlParseError};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Appended to the user id, the command key is the MD5 of them
+const CMD_KEY_SALT: &[u8] = b"c48619fe-8f02-49e0-b9e9-edf763e17e21";
+const KDF_SALT: &[u8] = b"VMess AEAD KDF";
+
+const VERSION: u8 = 1;
+const OPTION_CHUNK_STREAM: u8 = 0x01;
+const COMMAND_TCP: u8 = 0x01;
+
+const TAG_LEN: usize = 16;
+/// Largest payload of a body chunk
+const MAX_PAYLOAD: usize = 16 * 1024 - TAG_LEN;
+/// Bytes read from the server at a time
+const READ_SIZE: usize = 4 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Security {
+    Aes128Gcm,
+    Chacha20Poly1305,
+}
+
+impl Security {
+    /// Value in the request header
+    fn id(self) -> u8 {
+        match self {
+            Security::Aes128Gcm => 0x03,
+            Security::Chacha20Poly1305 => 0x04,
+        }
+    }
+
+    fn key(self, key: &[u8; 16]) -> LessSafeKey {
+        match self {
+            Security::Aes128Gcm => aes_gcm(key),
+            // the key is stretched to 32 bytes by MD5
+            Security::Chacha20Poly1305 => {
+                let first = Md5::digest(key);
+                let second = Md5::digest(first);
+                let mut stretched = [0u8; 32];
+                stretched[..16].copy_from_slice(&first);
+                stretched[16..].copy_from_slice(&second);
+                LessSafeKey::new(
+                    UnboundKey::new(&CHACHA20_POLY1305, &stretched).expect("valid key length"),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VmessConfig {
+    addr: Address,
+    /// Derived from the user id
+    cmd_key: [u8; 16],
+    security: Security,
+    remarks: Option<String>,
+}
+
+impl VmessConfig {
+    /// Parse `vmess://` followed by base64 encoded JSON, with `add`, `port`
+    /// and `id` at least
+    pub fn from_url(url: &str) -> Result<Self, UrlParseError> {
+        let encoded = url
+            .strip_prefix("vmess://")
+            .ok_or(UrlParseError::InvalidScheme)?
+            .trim_end_matches('=');
+        let decoded = base64::decode_config(encoded, base64::STANDARD_NO_PAD)
+            .or_else(|_| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD))
+            .map_err(|_| UrlParseError::InvalidUserInfo)?;
+        let share = serde_json::from_slice::<Value>(&decoded)
+            .map_err(|_| UrlParseError::InvalidUserInfo)?;
+        // numbers are strings in most links, but not all
+        let field = |name: &str| match share.get(name) {
+            Some(Value::String(value)) if !value.is_empty() => Some(value.clone()),
+            Some(Value::Number(value)) => Some(value.to_string()),
+            _ => None,
+        };
+
+        let host = field("add").ok_or(UrlParseError::MissingHost)?;
+        let port = field("port")
+            .and_then(|port| port.parse::<u16>().ok())
+            .ok_or(UrlParseError::InvalidServerAddr)?;
+        let id = field("id")
+            .and_then(|id| parse_uuid(&id))
+            .ok_or(UrlParseError::InvalidAuthInfo)?;
+        // a non zero alter id means the legacy MD5 header
+        if !matches!(field("aid").as_deref(), None | Some("0")) {
+            return Err(UrlParseError::InvalidAuthInfo);
+        }
+        // other transports, e.g. `ws` and `grpc`, TLS and header
+        // obfuscation are not supported
+        let supported = matches!(field("net").as_deref(), None | Some("tcp"))
+            && matches!(field("tls").as_deref(), None | Some("none"))
+            && matches!(field("type").as_deref(), None | Some("none"));
+        if !supported {
+            return Err(UrlParseError::InvalidQueryString);
+        }
+        let security = match field("scy").as_deref() {
+            None | Some("auto") | Some("aes-128-gcm") => Security::Aes128Gcm,
+            Some("chacha20-poly1305") => Security::Chacha20Poly1305,
+            Some(_) => return Err(UrlParseError::InvalidMethod),
+        };
+
+        let addr = match host.parse::<IpAddr>() {
+            Ok(ip) => Address::SocketAddress((ip, port).into()),
+            Err(_) => Address::DomainNameAddress(host, port),
+        };
+        let mut cmd_key = [0u8; 16];
+        cmd_key.copy_from_slice(
+            &Md5::new()
+                .chain_update(id)
+                .chain_update(CMD_KEY_SALT)
+                .finalize(),
+        );
+
+        Ok(Self {
+            addr,
+            cmd_key,
+            security,
+            remarks: field("ps"),
+        })
+    }
+
+    #[inline]
+    pub fn addr(&self) -> &Address {
+        &self.addr
+    }
+
+    #[inline]
+    pub fn remarks(&self) -> Option<&String> {
+        self.remarks.as_ref()
+    }
+}
+
+/// Bytes of `b831b5e4-63b6-4105-a6eb-1e47389151bf`
+pub(super) fn parse_uuid(id: &str) -> Option<[u8; 16]> {
+    let hex = id.bytes().filter(|b| *b != b'-').collect::<Vec<_>>();
+    if hex.len() != 32 {
+        return None;
+    }
+
+    let mut uuid = [0u8; 16];
+    for (byte, pair) in uuid.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(uuid)
+}
+
+/// Tunnel to `target` over a stream connected to the server. The request
+/// header is sent now, the response header is read with the first data.
+pub async fn connect(
+    mut stream: TcpStream,
+    config: &VmessConfig,
+    target: &Address,
+) -> io::Result<VmessStream> {
+    let (request, body_key, body_iv, response_auth) = {
+        let mut rng = thread_rng();
+        let body_key = rng.gen::<[u8; 16]>();
+        let body_iv = rng.gen::<[u8; 16]>();
+        let response_auth = rng.gen::<u8>();
+        let padding = (0..rng.gen_range(0..16))
+            .map(|_| rng.gen::<u8>())
+            .collect::<Vec<_>>();
+
+        let header = request_header(
+            &body_key,
+            &body_iv,
+            response_auth,
+            config.security,
+            target,
+            &padding,
+        )?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let auth_id = auth_id(&config.cmd_key, now, rng.gen());
+        let request = seal_header(&config.cmd_key, &header, &auth_id, &rng.gen());
+
+        (request, body_key, body_iv, response_auth)
+    };
+    stream.write_all(&request).await?;
+
+    Ok(VmessStream::new(
+        stream,
+        config.security,
+        &body_key,
+        &body_iv,
+        response_auth,
+    ))
+}
+
+/// The command of the request, checksummed by FNV-1a
+fn request_header(
+    body_key: &[u8; 16],
+    body_iv: &[u8; 16],
+    response_auth: u8,
+    security: Security,
+    target: &Address,
+    padding: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(64 + padding.len());
+    header.push(VERSION);
+    header.extend_from_slice(body_iv);
+    header.extend_from_slice(body_key);
+    header.push(response_auth);
+    header.push(OPTION_CHUNK_STREAM);
+    header.push((padding.len() as u8) << 4 | security.id());
+    // reserved
+    header.push(0);
+    header.push(COMMAND_TCP);
+    put_target(&mut header, target)?;
+    header.extend_from_slice(padding);
+    let checksum = fnv1a(&header);
+    header.extend_from_slice(&checksum.to_be_bytes());
+
+    Ok(header)
+}
+
+/// Port and address of the target, the same for VMess and VLESS
+pub(super) fn put_target(buf: &mut Vec<u8>, target: &Address) -> io::Result<()> {
+    match target {
+        Address::SocketAddress(addr) => {
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    buf.push(0x01);
+                    buf.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    buf.push(0x03);
+                    buf.extend_from_slice(&ip.octets());
+                }
+            }
+        }
+        Address::DomainNameAddress(domain, port) => {
+            if domain.len() > u8::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain name is too long",
+                ));
+            }
+            buf.extend_from_slice(&port.to_be_bytes());
+            buf.push(0x02);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain.as_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+/// Identifies the user without revealing the id, it's the timestamp,
+/// random bytes and their CRC32, encrypted by a key derived from the
+/// command key
+fn auth_id(cmd_key: &[u8; 16], now: u64, random: [u8; 4]) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[..8].copy_from_slice(&now.to_be_bytes());
+    block[8..12].copy_from_slice(&random);
+    let checksum = crc32(&block[..12]);
+    block[12..].copy_from_slice(&checksum.to_be_bytes());
+
+    let key = kdf(cmd_key, &[b"AES Auth ID Encryption"]);
+    Aes128::new(GenericArray::from_slice(&key[..16]))
+        .encrypt_block(GenericArray::from_mut_slice(&mut block));
+
+    block
+}
+
+/// The auth id, the sealed length of the header, the connection nonce,
+/// and the sealed header
+fn seal_header(cmd_key: &[u8; 16], header: &[u8], auth_id: &[u8; 16], nonce: &[u8; 8]) -> Vec<u8> {
+    let seal = |key_label: &[u8], nonce_label: &[u8], mut data: Vec<u8>| {
+        let key = kdf(cmd_key, &[key_label, auth_id, nonce]);
+        let iv = kdf(cmd_key, &[nonce_label, auth_id, nonce]);
+        aes_gcm(&key[..16])
+            .seal_in_place_append_tag(nonce_of(&iv[..12]), Aad::from(auth_id), &mut data)
+            .expect("header is not too large");
+        data
+    };
+
+    let mut sealed = Vec::with_capacity(16 + 2 + TAG_LEN + 8 + header.len() + TAG_LEN);
+    sealed.extend_from_slice(auth_id);
+    sealed.extend(seal(
+        b"VMess Header AEAD Key_Length",
+        b"VMess Header AEAD Nonce_Length",
+        (header.len() as u16).to_be_bytes().to_vec(),
+    ));
+    sealed.extend_from_slice(nonce);
+    sealed.extend(seal(
+        b"VMess Header AEAD Key",
+        b"VMess Header AEAD Nonce",
+        header.to_vec(),
+    ));
+
+    sealed
+}
+
+/// The KDF of VMess, HMAC-SHA256 nested once for each element of `path`,
+/// i.e. the hash function of each HMAC is the HMAC of the previous level
+fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    let mut keys = Vec::with_capacity(path.len() + 1);
+    keys.push(KDF_SALT);
+    keys.extend_from_slice(path);
+
+    nested_hmac(&keys, &[key])
+}
+
+/// SHA-256 if `keys` is empty, otherwise the HMAC keyed by the last of
+/// them, over the `nested_hmac` of the others
+fn nested_hmac(keys: &[&[u8]], data: &[&[u8]]) -> [u8; 32] {
+    let mut output = [0u8; 32];
+    let (key, parents) = match keys.split_last() {
+        Some(split) => split,
+        None => {
+            let mut context = digest::Context::new(&digest::SHA256);
+            data.iter().for_each(|data| context.update(data));
+            output.copy_from_slice(context.finish().as_ref());
+            return output;
+        }
+    };
+
+    // keys are all shorter than the block size of SHA-256, they're just
+    // padded by zeros
+    let mut block = [0u8; 64];
+    block[..key.len()].copy_from_slice(key);
+    let inner_pad = block.map(|b| b ^ 0x36);
+    let outer_pad = block.map(|b| b ^ 0x5c);
+
+    let mut inner_data = Vec::with_capacity(data.len() + 1);
+    inner_data.push(&inner_pad[..]);
+    inner_data.extend_from_slice(data);
+    let inner = nested_hmac(parents, &inner_data);
+
+    nested_hmac(parents, &[&outer_pad, &inner])
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    let mut hash = 0x811c_9dc5u32;
+    for b in data {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+
+    hash
+}
+
+fn aes_gcm(key: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_128_GCM, key).expect("valid key length"))
+}
+
+fn nonce_of(iv: &[u8]) -> Nonce {
+    Nonce::try_assume_unique_for_key(iv).expect("valid nonce length")
+}
+
+fn sha256_16(data: &[u8]) -> [u8; 16] {
+    let mut output = [0u8; 16];
+    output.copy_from_slice(&digest::digest(&digest::SHA256, data).as_ref()[..16]);
+    output
+}
+
+/// Seals or opens the chunks of a body, the nonce is the count of chunks
+/// followed by bytes of the IV
+struct ChunkCipher {
+    key: LessSafeKey,
+    iv: [u8; 16],
+    count: u16,
+}
+
+impl ChunkCipher {
+    fn new(security: Security, key: &[u8; 16], iv: &[u8; 16]) -> Self {
+        Self {
+            key: security.key(key),
+            iv: *iv,
+            count: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        nonce[2..].copy_from_slice(&self.iv[2..12]);
+        self.count = self.count.wrapping_add(1);
+
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Append the length and the sealed `payload` to `output`, an empty
+    /// payload ends the body
+    fn seal(&mut self, payload: &[u8], output: &mut Vec<u8>) {
+        output.extend_from_slice(&((payload.len() + TAG_LEN) as u16).to_be_bytes());
+        let start = output.len();
+        output.extend_from_slice(payload);
+        let nonce = self.nonce();
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce, Aad::empty(), &mut output[start..])
+            .expect("chunk is not too large");
+        output.extend_from_slice(tag.as_ref());
+    }
+
+    fn open<'a>(&mut self, chunk: &'a mut [u8]) -> io::Result<&'a mut [u8]> {
+        let nonce = self.nonce();
+        self.key
+            .open_in_place(nonce, Aad::empty(), chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk of vmess"))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReadState {
+    HeaderLength,
+    Header(usize),
+    Length,
+    Payload(usize),
+    Eof,
+}
+
+/// A stream tunneled through a VMess server
+pub struct VmessStream {
+    stream: TcpStream,
+    response_auth: u8,
+    /// Key and IV of the response header
+    response_key: [u8; 16],
+    response_iv: [u8; 16],
+    sealer: ChunkCipher,
+    opener: ChunkCipher,
+
+    state: ReadState,
+    /// Received but not opened
+    received: Vec<u8>,
+    /// Opened but not read
+    plain: Vec<u8>,
+    plain_pos: usize,
+
+    /// Sealed but not sent
+    sealed: Vec<u8>,
+    sealed_pos: usize,
+    end_sealed: bool,
+}
+
+impl VmessStream {
+    fn new(
+        stream: TcpStream,
+        security: Security,
+        body_key: &[u8; 16],
+        body_iv: &[u8; 16],
+        response_auth: u8,
+    ) -> Self {
+        let response_key = sha256_16(body_key);
+        let response_iv = sha256_16(body_iv);
+
+        Self {
+            stream,
+            response_auth,
+            response_key,
+            response_iv,
+            sealer: ChunkCipher::new(security, body_key, body_iv),
+            opener: ChunkCipher::new(security, &response_key, &response_iv),
+            state: ReadState::HeaderLength,
+            received: Vec::with_capacity(READ_SIZE),
+            plain: Vec::new(),
+            plain_pos: 0,
+            sealed: Vec::new(),
+            sealed_pos: 0,
+            end_sealed: false,
+        }
+    }
+
+    /// Read until `needed` bytes are received, false if the server closed
+    /// the stream before
+    fn poll_receive(&mut self, cx: &mut Context<'_>, needed: usize) -> Poll<io::Result<bool>> {
+        while self.received.len() < needed {
+            let filled = self.received.len();
+            self.received.resize(needed.max(filled + READ_SIZE), 0);
+            let mut buf = ReadBuf::new(&mut self.received[filled..]);
+            let result = Pin::new(&mut self.stream).poll_read(cx, &mut buf);
+            let n = buf.filled().len();
+            self.received.truncate(filled + n);
+
+            ready!(result)?;
+            if n == 0 {
+                return Poll::Ready(Ok(false));
+            }
+        }
+
+        Poll::Ready(Ok(true))
+    }
+
+    /// Open the first `len` bytes received, by the state
+    fn open(&mut self, len: usize) -> io::Result<()> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let data = &mut self.received[..len];
+
+        self.state = match self.state {
+            ReadState::HeaderLength => {
+                let key = kdf(&self.response_key, &[b"AEAD Resp Header Len Key"]);
+                let iv = kdf(&self.response_iv, &[b"AEAD Resp Header Len IV"]);
+                let opened = aes_gcm(&key[..16])
+                    .open_in_place(nonce_of(&iv[..12]), Aad::empty(), data)
+                    .map_err(|_| invalid("invalid response header of vmess"))?;
+                ReadState::Header(u16::from_be_bytes([opened[0], opened[1]]) as usize)
+            }
+            ReadState::Header(_) => {
+                let key = kdf(&self.response_key, &[b"AEAD Resp Header Key"]);
+                let iv = kdf(&self.response_iv, &[b"AEAD Resp Header IV"]);
+                let opened = aes_gcm(&key[..16])
+                    .open_in_place(nonce_of(&iv[..12]), Aad::empty(), data)
+                    .map_err(|_| invalid("invalid response header of vmess"))?;
+                if opened.first() != Some(&self.response_auth) {
+                    return Err(invalid("response of vmess is not for the request"));
+                }
+                ReadState::Length
+            }
+            ReadState::Length => {
+                let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+                if len < TAG_LEN {
+                    return Err(invalid("invalid chunk length of vmess"));
+                }
+                ReadState::Payload(len)
+            }
+            ReadState::Payload(_) => {
+                let opened = self.opener.open(data)?;
+                if opened.is_empty() {
+                    ReadState::Eof
+                } else {
+                    self.plain.clear();
+                    self.plain.extend_from_slice(opened);
+                    self.plain_pos = 0;
+                    ReadState::Length
+                }
+            }
+            ReadState::Eof => ReadState::Eof,
+        };
+        self.received.drain(..len);
+
+        Ok(())
+    }
+
+    /// Send what's sealed
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.sealed_pos < self.sealed.len() {
+            let n =
+                ready!(Pin::new(&mut self.stream).poll_write(cx, &self.sealed[self.sealed_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sealed_pos += n;
+        }
+        self.sealed.clear();
+        self.sealed_pos = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsRawFd for VmessStream {
+    /// The socket connected to the server
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl AsyncRead for VmessStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let needed = match this.state {
+                ReadState::HeaderLength => 2 + TAG_LEN,
+                ReadState::Header(len) => len + TAG_LEN,
+                ReadState::Length => 2,
+                ReadState::Payload(len) => len,
+                ReadState::Eof => return Poll::Ready(Ok(())),
+            };
+            if !ready!(this.poll_receive(cx, needed))? {
+                // some servers close without the empty chunk
+                if this.state == ReadState::Length && this.received.is_empty() {
+                    this.state = ReadState::Eof;
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.open(needed)?;
+        }
+    }
+}
+
+impl AsyncWrite for VmessStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        // an empty chunk would end the body
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_PAYLOAD);
+        this.sealer.seal(&buf[..n], &mut this.sealed);
+        // it's accepted, what can't be sent now is sent by the next write
+        // or flush
+        if let Poll::Ready(Err(err)) = this.poll_send(cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.end_sealed {
+            this.sealer.seal(&[], &mut this.sealed);
+            this.end_sealed = true;
+        }
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::BlockDecrypt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const URL: &str = "vmess://eyJ2IjoiMiIsInBzIjoiaGsiLCJhZGQiOiJleGFtcGxlLmNvbSIsInBvcnQiOiIxMDA4NiIsImlkIjoiYjgzMWI1ZTQtNjNiNi00MTA1LWE2ZWItMWU0NzM4OTE1MWJmIiwiYWlkIjoiMCIsIm5ldCI6InRjcCIsInNjeSI6ImNoYWNoYTIwLXBvbHkxMzA1In0=";
+
+    fn url_of(json: &str) -> String {
+        format!("vmess://{}", base64::encode(json))
+    }
+
+    #[test]
+    fn parse() {
+        let config = VmessConfig::from_url(URL).unwrap();
+        assert_eq!(config.addr().to_string(), "example.com:10086");
+        assert_eq!(config.security, Security::Chacha20Poly1305);
+        assert_eq!(config.remarks().unwrap(), "hk");
+
+        // numbers, no padding and default security
+        let url = url_of(
+            r#"{"add":"10.0.0.1","port":443,"id":"b831b5e4-63b6-4105-a6eb-1e47389151bf","aid":0}"#,
+        );
+        let config = VmessConfig::from_url(url.trim_end_matches('=')).unwrap();
+        assert_eq!(config.addr().to_string(), "10.0.0.1:443");
+        assert_eq!(config.security, Security::Aes128Gcm);
+        assert_eq!(config.remarks(), None);
+
+        for json in [
+            // legacy header
+            r#"{"add":"example.com","port":"443","id":"b831b5e4-63b6-4105-a6eb-1e47389151bf","aid":"64"}"#,
+            r#"{"add":"example.com","port":"443","id":"b831b5e4-63b6-4105-a6eb-1e47389151bf","net":"ws"}"#,
+            r#"{"add":"example.com","port":"443","id":"b831b5e4-63b6-4105-a6eb-1e47389151bf","tls":"tls"}"#,
+            r#"{"add":"example.com","port":"443","id":"b831b5e4-63b6-4105-a6eb-1e47389151bf","scy":"none"}"#,
+            r#"{"add":"example.com","port":"443","id":"b831b5e4"}"#,
+            r#"{"add":"example.com","id":"b831b5e4-63b6-4105-a6eb-1e47389151bf"}"#,
+            r#"{"port":"443","id":"b831b5e4-63b6-4105-a6eb-1e47389151bf"}"#,
+        ] {
+            assert!(VmessConfig::from_url(&url_of(json)).is_err(), "{}", json);
+        }
+        assert!(VmessConfig::from_url("vmess://not base64").is_err());
+        assert!(VmessConfig::from_url("ss://YWVzLTEyOC1nY206cGFzcw@example.com:443").is_err());
+    }
+
+    #[test]
+    fn primitives() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9c_f968);
+
+        // by Python's hmac module
+        let hex = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+        assert_eq!(
+            hex(&kdf(b"key", &[])),
+            "385e28ac08671660f62ac976f5e64a31827aea172eff77cb2b046c52de9c08b0"
+        );
+        assert_eq!(
+            hex(&kdf(b"key", &[b"AES Auth ID Encryption"])),
+            "ffe9b1a9952b7c91b33c21540d8522207e7706c52fa3a95891f69efb3b88e087"
+        );
+        assert_eq!(
+            hex(&kdf(
+                b"key",
+                &[b"VMess Header AEAD Key", b"0123456789abcdef", b"nonce123"]
+            )),
+            "67bf4e7ec4fe51fe936b0361a53f691ba73f30af253eea21d941a2e30af8d7d1"
+        );
+    }
+
+    /// Open the request like a server, returns the header
+    fn open_request(cmd_key: &[u8; 16], request: &[u8]) -> Vec<u8> {
+        let mut auth_id = [0u8; 16];
+        auth_id.copy_from_slice(&request[..16]);
+        let mut block = auth_id;
+        let key = kdf(cmd_key, &[b"AES Auth ID Encryption"]);
+        Aes128::new(GenericArray::from_slice(&key[..16]))
+            .decrypt_block(GenericArray::from_mut_slice(&mut block));
+        assert_eq!(
+            crc32(&block[..12]).to_be_bytes(),
+            block[12..],
+            "auth id is not valid"
+        );
+
+        let nonce = &request[34..42];
+        let open = |key_label: &[u8], nonce_label: &[u8], data: &[u8]| {
+            let key = kdf(cmd_key, &[key_label, &auth_id, nonce]);
+            let iv = kdf(cmd_key, &[nonce_label, &auth_id, nonce]);
+            let mut data = data.to_vec();
+            let len = aes_gcm(&key[..16])
+                .open_in_place(nonce_of(&iv[..12]), Aad::from(&auth_id), &mut data)
+                .unwrap()
+                .len();
+            data.truncate(len);
+            data
+        };
+        let len = open(
+            b"VMess Header AEAD Key_Length",
+            b"VMess Header AEAD Nonce_Length",
+            &request[16..34],
+        );
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        assert_eq!(request.len(), 42 + len + TAG_LEN);
+
+        open(
+            b"VMess Header AEAD Key",
+            b"VMess Header AEAD Nonce",
+            &request[42..],
+        )
+    }
+
+    #[tokio::test]
+    async fn tunnel() {
+        let config = VmessConfig::from_url(URL).unwrap();
+        let cmd_key = config.cmd_key;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 1024];
+            let n = stream.read(&mut request).await.unwrap();
+            let header = open_request(&cmd_key, &request[..n]);
+
+            let (checked, checksum) = header.split_at(header.len() - 4);
+            assert_eq!(fnv1a(checked).to_be_bytes(), checksum);
+            assert_eq!(header[0], VERSION);
+            assert_eq!(header[34], OPTION_CHUNK_STREAM);
+            assert_eq!(header[35] & 0x0f, Security::Chacha20Poly1305.id());
+            assert_eq!(header[37], COMMAND_TCP);
+            let padding = (header[35] >> 4) as usize;
+            assert_eq!(
+                &header[38..header.len() - 4 - padding],
+                b"\x01\xbb\x02\x0bexample.com"
+            );
+
+            let mut body_iv = [0u8; 16];
+            let mut body_key = [0u8; 16];
+            body_iv.copy_from_slice(&header[1..17]);
+            body_key.copy_from_slice(&header[17..33]);
+            let response_auth = header[33];
+
+            // the response header, then a chunk and the end
+            let response_key = sha256_16(&body_key);
+            let response_iv = sha256_16(&body_iv);
+            let seal = |key_label: &[u8], iv_label: &[u8], mut data: Vec<u8>| {
+                let key = kdf(&response_key, &[key_label]);
+                let iv = kdf(&response_iv, &[iv_label]);
+                aes_gcm(&key[..16])
+                    .seal_in_place_append_tag(nonce_of(&iv[..12]), Aad::empty(), &mut data)
+                    .unwrap();
+                data
+            };
+            let mut response = seal(
+                b"AEAD Resp Header Len Key",
+                b"AEAD Resp Header Len IV",
+                vec![0, 4],
+            );
+            response.extend(seal(
+                b"AEAD Resp Header Key",
+                b"AEAD Resp Header IV",
+                vec![response_auth, 0, 0, 0],
+            ));
+            let mut sealer =
+                ChunkCipher::new(Security::Chacha20Poly1305, &response_key, &response_iv);
+            sealer.seal(b"hello", &mut response);
+            sealer.seal(b"", &mut response);
+            stream.write_all(&response).await.unwrap();
+
+            let mut body = Vec::new();
+            stream.read_to_end(&mut body).await.unwrap();
+            let mut opener = ChunkCipher::new(Security::Chacha20Poly1305, &body_key, &body_iv);
+            let mut payloads = Vec::new();
+            let mut rest = &mut body[..];
+            while !rest.is_empty() {
+                let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                let (chunk, next) = rest[2..].split_at_mut(len);
+                payloads.push(opener.open(chunk).unwrap().to_vec());
+                rest = next;
+            }
+            payloads
+        });
+
+        let target = Address::DomainNameAddress("example.com".to_string(), 443);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connect(stream, &config, &target).await.unwrap();
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        assert_eq!(server.await.unwrap(), vec![b"ping".to_vec(), Vec::new()]);
+    }
+}